@@ -1,10 +1,21 @@
 use anyhow::{anyhow, Result};
-use photo_scanner::domain::descriptions::DescriptionService;
+use photo_scanner::domain::descriptions::{BlurryPolicy, DescriptionService};
+use photo_scanner::domain::usage::{Prices, UsageMeter};
+use photo_scanner::domain::validation::DescriptionRules;
+use photo_scanner::outbound::backend::{BackendRole, ModelBackend};
+use photo_scanner::outbound::image_analysis::ImageCrateAnalyzer;
 use photo_scanner::outbound::image_provider::ImageCrateEncoder;
-use photo_scanner::outbound::openai::OpenAI;
+use photo_scanner::outbound::nominatim::NominatimGeocoder;
+use photo_scanner::outbound::ocr::TrOcrRecognizer;
+use photo_scanner::outbound::prompts::PromptTemplates;
+use photo_scanner::outbound::rate_limit::{RateLimited, RateLimits};
+use photo_scanner::outbound::resilience::{ResilienceOptions, Resilient};
+use photo_scanner::outbound::thumbnails::ThumbnailCache;
 use photo_scanner::outbound::xmp::XMPToolkitMetadata;
+use std::env::var;
 use std::path::PathBuf;
 use std::sync::Arc;
+use tracing::warn;
 use tracing_appender::rolling;
 use tracing_subscriber::EnvFilter;
 
@@ -21,11 +32,63 @@ async fn main() -> Result<()> {
         .with_env_filter(EnvFilter::from_default_env())
         .init();
 
-    // Initialize the OpenAI chat model.
-    let chat = Arc::new(OpenAI::new());
+    // Initialize the vision backend with the prompt templates selected for this run, throttled to
+    // the rate limits and budget, retrying failed calls and pausing while the backend is unavailable.
+    let prompts = PromptTemplates::from_env()?;
+    let language = prompts.language().to_string();
+    // Count the tokens, the time and the cost of the calls, priced with CHAT_PRICES
+    let usage_meter = Arc::new(UsageMeter::new(
+        "descriptions",
+        Prices::parse(&var("CHAT_PRICES").unwrap_or_default())?,
+    ));
+    let backend = ModelBackend::from_env(BackendRole::Vision, prompts)?
+        .with_usage_meter(Arc::clone(&usage_meter));
+    if let Err(e) = backend.log_models().await {
+        warn!("{:#}", e);
+    }
+    let chat = Arc::new(Resilient::new(
+        RateLimited::new(backend, RateLimits::from_env()?),
+        ResilienceOptions::from_env()?,
+    ));
+
+    // Initialize the image provider with the native resolution of each image model
+    let image_provider =
+        Arc::new(ImageCrateEncoder::from_env()?.with_thumbnail_cache(ThumbnailCache::from_env()));
+
+    // Initialize the image analyzer, which shares the thumbnails with the image provider
+    let image_analyzer =
+        Arc::new(ImageCrateAnalyzer::new().with_thumbnail_cache(ThumbnailCache::from_env()));
+
+    // Reading the text in the photos needs the local OCR model - skipped without OCR_MODEL_DIR
+    let text_recognizer = TrOcrRecognizer::from_env()?.map(Arc::new);
+
+    // Naming the places of the GPS coordinates needs a Nominatim server - skipped without
+    // GEOCODER_URL
+    let geocoder = NominatimGeocoder::from_env().map(Arc::new);
+
+    // What to do with blurry photos - describe (default), skip or tag
+    let blurry_policy = match var("BLURRY_POLICY") {
+        Ok(policy) => BlurryPolicy::try_from(policy.as_str())?,
+        Err(_) => BlurryPolicy::default(),
+    };
 
-    // Initialize the image provider
-    let image_provider = Arc::new(ImageCrateEncoder::new());
+    // Ask for a JSON object with title, keywords, scene type etc. instead of plain text
+    let structured_output = var("STRUCTURED_OUTPUT").is_ok_and(|value| value == "true");
+
+    // Check the descriptions against the rules of the prompt, in the language of the prompt
+    let mut rules = DescriptionRules::default().with_language(&language);
+    if let Ok(max_sentences) = var("DESCRIPTION_MAX_SENTENCES") {
+        rules = rules.with_max_sentences(max_sentences.parse()?);
+    }
+    if let Ok(max_chars) = var("DESCRIPTION_MAX_CHARS") {
+        rules = rules.with_max_chars(max_chars.parse()?);
+    }
+
+    // How often invalid descriptions are generated again, with the problems as feedback
+    let max_regenerations = match var("DESCRIPTION_REGENERATIONS") {
+        Ok(max_regenerations) => Some(max_regenerations.parse()?),
+        Err(_) => None,
+    };
 
     let xmp_toolkit = Arc::new(XMPToolkitMetadata::new());
 
@@ -36,9 +99,28 @@ async fn main() -> Result<()> {
     }
     let root_path = PathBuf::from(&args[1]);
 
-    let service = DescriptionService::new(image_provider, chat, xmp_toolkit);
+    let service = DescriptionService::new(
+        image_provider,
+        image_analyzer,
+        text_recognizer,
+        geocoder,
+        chat,
+        xmp_toolkit,
+    )
+    .with_blurry_policy(blurry_policy)
+    .with_structured_output(structured_output)
+    .with_validator(Arc::new(rules));
+    let service = match max_regenerations {
+        Some(max_regenerations) => service.with_max_regenerations(max_regenerations),
+        None => service,
+    };
 
     service.generate(&root_path).await?;
 
+    // Print the usage of the run and keep it as JSON report
+    let report = usage_meter.report();
+    let report_path = report.write(var("USAGE_REPORT_DIR").unwrap_or("logs".into()))?;
+    println!("{}Usage report: {}", report, report_path.display());
+
     Ok(())
 }
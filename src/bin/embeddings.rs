@@ -1,8 +1,17 @@
 use anyhow::{anyhow, Result};
 use photo_scanner::domain::embeddings::EmbeddingsService;
-use photo_scanner::outbound::openai::OpenAI;
+use photo_scanner::domain::usage::{Prices, UsageMeter};
+use photo_scanner::outbound::backend::{BackendRole, EmbeddingBackend, ModelBackend};
+use photo_scanner::outbound::clip::ClipEmbedder;
+use photo_scanner::outbound::embedding_cache::{CachedEmbedder, EmbeddingCache};
+use photo_scanner::outbound::image_analysis::ImageCrateAnalyzer;
+use photo_scanner::outbound::prompts::PromptTemplates;
 use photo_scanner::outbound::qdrant::QdrantClient;
+use photo_scanner::outbound::rate_limit::{RateLimited, RateLimits};
+use photo_scanner::outbound::resilience::{ResilienceOptions, Resilient};
+use photo_scanner::outbound::thumbnails::ThumbnailCache;
 use photo_scanner::outbound::xmp::XMPToolkitMetadata;
+use std::env::var;
 use std::path::PathBuf;
 use std::sync::Arc;
 use tracing_appender::rolling;
@@ -21,23 +30,73 @@ async fn main() -> Result<()> {
         .with_env_filter(EnvFilter::from_default_env())
         .init();
 
-    // Initialize the OpenAI chat model.
-    let chat = Arc::new(OpenAI::new());
+    // load env from .env file
+    dotenv::dotenv().ok();
+
+    // Count the tokens, the time and the cost of the calls, priced with CHAT_PRICES
+    let usage_meter = Arc::new(UsageMeter::new(
+        "embeddings",
+        Prices::parse(&var("CHAT_PRICES").unwrap_or_default())?,
+    ));
+
+    // Initialize the local embedding model, or else the embedding backend, throttled to the rate
+    // limits and budget, retrying failed calls and pausing while the backend is unavailable. Known
+    // texts are answered from the cache.
+    let text_embedder = EmbeddingBackend::from_env(|| {
+        Ok(Resilient::new(
+            RateLimited::new(
+                ModelBackend::from_env(BackendRole::Embeddings, PromptTemplates::default())?
+                    .with_usage_meter(Arc::clone(&usage_meter)),
+                RateLimits::from_env()?,
+            ),
+            ResilienceOptions::from_env()?,
+        ))
+    })?;
+    let text_embedder = Arc::new(CachedEmbedder::new(
+        text_embedder,
+        EmbeddingCache::from_env(),
+    ));
 
     let xmp_toolkit = Arc::new(XMPToolkitMetadata::new());
 
     let vector_db = Arc::new(QdrantClient::new()?);
 
-    // Get the folder path from command line arguments.
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        return Err(anyhow!("Please provide a path to the folder."));
+    // Initialize the image analyzer for the color palettes
+    let image_analyzer =
+        Arc::new(ImageCrateAnalyzer::new().with_thumbnail_cache(ThumbnailCache::from_env()));
+
+    // Initialize the optional CLIP model for the image embeddings
+    let image_embedder = ClipEmbedder::from_env()?
+        .map(|embedder| Arc::new(embedder.with_thumbnail_cache(ThumbnailCache::from_env())));
+
+    // Get the folder path and the optional `--recreate` flag from command line arguments.
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let recreate = args.iter().any(|arg| arg == "--recreate");
+    args.retain(|arg| arg != "--recreate");
+    if args.len() != 1 {
+        return Err(anyhow!(
+            "Please provide a path to the folder and optionally --recreate."
+        ));
     }
-    let root_path = PathBuf::from(&args[1]);
+    let root_path = PathBuf::from(&args[0]);
+
+    let service = EmbeddingsService::new(
+        text_embedder,
+        xmp_toolkit,
+        vector_db,
+        image_analyzer,
+        image_embedder,
+    );
+
+    // Create a missing collection, or drop all points and size it for the embedding model
+    service.prepare_collection(recreate).await?;
 
-    let service = EmbeddingsService::new(chat, xmp_toolkit, vector_db);
+    service.generate(&root_path).await?;
 
-    //service.create_collection().await?;
+    // Print the usage of the run and keep it as JSON report
+    let report = usage_meter.report();
+    let report_path = report.write(var("USAGE_REPORT_DIR").unwrap_or("logs".into()))?;
+    println!("{}Usage report: {}", report, report_path.display());
 
-    service.generate(&root_path).await
+    Ok(())
 }
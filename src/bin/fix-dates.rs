@@ -0,0 +1,67 @@
+use anyhow::{anyhow, Result};
+use photo_scanner::domain::dates::DateRepairService;
+use photo_scanner::outbound::xmp::XMPToolkitMetadata;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::info;
+
+// Proposals below this confidence are only shown, never written
+const MIN_CONFIDENCE: f32 = 0.5;
+
+/// Main entry point.
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Set up tracing for logging.
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_target(false)
+        .without_time()
+        .with_writer(std::io::stdout)
+        .init();
+
+    let xmp_toolkit = Arc::new(XMPToolkitMetadata::new());
+
+    // Get the folder path and the optional --apply flag from command line arguments.
+    let args: Vec<String> = std::env::args().collect();
+    let apply = args.iter().skip(1).any(|arg| arg == "--apply");
+    let paths: Vec<&String> = args
+        .iter()
+        .skip(1)
+        .filter(|arg| *arg != "--apply")
+        .collect();
+    if paths.len() != 1 {
+        return Err(anyhow!(
+            "Please provide a path to the folder and optionally --apply."
+        ));
+    }
+    let root_path = PathBuf::from(paths[0]);
+
+    let service = DateRepairService::new(xmp_toolkit);
+
+    let proposals = service.propose(&root_path)?;
+
+    // Show every candidate, the best one first
+    for proposal in &proposals {
+        info!("{}", proposal.path.display());
+        for candidate in &proposal.candidates {
+            info!(
+                "  {:.2} {} ({})",
+                candidate.confidence, candidate.created, candidate.source
+            );
+        }
+    }
+
+    if !apply {
+        info!(
+            "{} photos without capture date. Run again with --apply to write the proposals with a confidence of at least {:.2}.",
+            proposals.len(),
+            MIN_CONFIDENCE
+        );
+        return Ok(());
+    }
+
+    let updated = service.apply(&proposals, MIN_CONFIDENCE);
+    info!("Updated {} of {} photos.", updated, proposals.len());
+
+    Ok(())
+}
@@ -1,9 +1,20 @@
 use anyhow::{anyhow, Result};
-use photo_scanner::domain::models::VectorOutputListUtils;
-use photo_scanner::domain::ports::{Chat, VectorDB};
-use photo_scanner::outbound::openai::OpenAI;
+use futures::StreamExt;
+use photo_scanner::domain::conversation::{photo_context, ConversationService};
+use photo_scanner::domain::embeddings::{
+    boost_by_color, dominant_color_filter, hierarchy_branch_filter,
+};
+use photo_scanner::domain::models::{VectorName, VectorOutputListUtils, COLOR_NAMES};
+use photo_scanner::domain::ports::{AnswerGenerator, ImageEmbedder, TextEmbedder, VectorDB};
+use photo_scanner::outbound::backend::{BackendRole, EmbeddingBackend, ModelBackend};
+use photo_scanner::outbound::clip::ClipEmbedder;
+use photo_scanner::outbound::embedding_cache::{CachedEmbedder, EmbeddingCache};
+use photo_scanner::outbound::prompts::PromptTemplates;
 use photo_scanner::outbound::qdrant::QdrantClient;
-use std::collections::HashMap;
+use photo_scanner::outbound::rate_limit::{RateLimited, RateLimits};
+use std::env::var;
+use std::io::{stdin, stdout, BufRead, Write};
+use std::path::PathBuf;
 use std::sync::Arc;
 use tracing::{debug, info, warn};
 
@@ -18,25 +29,137 @@ async fn main() -> Result<()> {
         .with_writer(std::io::stdout)
         .init();
 
-    // Initialize the OpenAI chat model.
-    let chat = Arc::new(OpenAI::new());
+    // Initialize the answer backend with the prompt templates selected for this run and the local
+    // embedding model, or else the embedding backend sharing the rate limits and budget. Known
+    // questions are embedded from the cache.
+    let answer_generator = Arc::new(RateLimited::new(
+        ModelBackend::from_env(BackendRole::Answers, PromptTemplates::from_env()?)?,
+        RateLimits::from_env()?,
+    ));
+    let text_embedder = Arc::new(CachedEmbedder::new(
+        EmbeddingBackend::from_env(|| {
+            Ok(answer_generator.share(ModelBackend::from_env(
+                BackendRole::Embeddings,
+                PromptTemplates::default(),
+            )?))
+        })?,
+        EmbeddingCache::from_env(),
+    ));
 
     let vector_db = Arc::new(QdrantClient::new()?);
 
-    // Get the folder path from command line arguments.
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        return Err(anyhow!("Please provide question"));
+    // Get the question, an optional keyword branch (e.g. "Places|Italy") and the optional flags
+    // `--sharp-only`, `--color <name>` (boost), `--mostly <name>` (filter), `--clip` (search the
+    // image embeddings with the question) and `--similar <path>` (search the image embeddings with
+    // a photo) and `--chat` (ask follow-up questions) from command line arguments.
+    let mut positional = Vec::new();
+    let mut sharp_only = false;
+    let mut boost_color = None;
+    let mut dominant_color = None;
+    let mut clip = false;
+    let mut similar = None;
+    let mut chat = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--sharp-only" => sharp_only = true,
+            "--color" => boost_color = Some(color_arg(args.next())?),
+            "--mostly" => dominant_color = Some(color_arg(args.next())?),
+            "--clip" => clip = true,
+            "--chat" => chat = true,
+            "--similar" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| anyhow!("Please provide a photo after --similar"))?;
+                similar = Some(PathBuf::from(path));
+            }
+            _ => positional.push(arg),
+        }
     }
-    let question = &args[1];
-    let embeddings = chat.get_embeddings(vec![question.to_string()]).await?;
+    // Searching for similar photos and conversations work without question
+    if (positional.is_empty() && similar.is_none() && !chat) || positional.len() > 2 {
+        return Err(anyhow!(
+            "Please provide question and optionally a keyword branch"
+        ));
+    }
+    let question = positional.first();
+    let mut filter = positional
+        .get(1)
+        .map(|branch| hierarchy_branch_filter(branch))
+        .unwrap_or_default();
+    if sharp_only {
+        filter.insert("blurry".to_string(), "false".to_string());
+    }
+    if let Some(color) = &dominant_color {
+        filter.extend(dominant_color_filter(color));
+    }
+
+    if chat {
+        if clip || similar.is_some() || boost_color.is_some() {
+            return Err(anyhow!(
+                "--chat searches the descriptions, without --clip, --similar or --color"
+            ));
+        }
+        let mut service =
+            ConversationService::new(Arc::clone(&answer_generator), text_embedder, vector_db);
+        if let Ok(window) = var("CONVERSATION_WINDOW") {
+            service = service.with_window(window.parse()?);
+        }
+
+        // Start with the question of the command line, then read the follow-ups until an empty
+        // line or the end of the input
+        let mut next_question = question.cloned();
+        let mut lines = stdin().lock().lines();
+        loop {
+            let question = match next_question.take() {
+                Some(question) => question,
+                None => {
+                    print!("> ");
+                    stdout().flush()?;
+                    match lines.next() {
+                        Some(line) => line?,
+                        None => break,
+                    }
+                }
+            };
+            if question.trim().is_empty() {
+                break;
+            }
+            let answer = service.ask(question.trim(), filter.clone()).await?;
+            println!("{}\n", answer);
+        }
+
+        answer_generator.log_spend();
+        return Ok(());
+    }
+
+    // The image searches need the local CLIP model
+    let (vector_name, embedding) = if clip || similar.is_some() {
+        let image_embedder = ClipEmbedder::from_env()?
+            .ok_or_else(|| anyhow!("CLIP_MODEL_DIR must be set for image searches"))?;
+        let embedding = match (&similar, question) {
+            (Some(path), _) => image_embedder.embed_image(path)?,
+            (None, Some(question)) => image_embedder.embed_text(question)?,
+            (None, None) => unreachable!("a question is required without --similar"),
+        };
+        (VectorName::Image, embedding)
+    } else {
+        let question = question.expect("a question is required without --similar");
+        let mut embeddings = text_embedder
+            .get_query_embeddings(vec![question.to_string()])
+            .await?;
+        (VectorName::Description, embeddings.remove(0))
+    };
 
     let mut result = vector_db
-        .search_points("photos", embeddings[0].as_slice(), HashMap::new())
+        .search_points("photos", vector_name, embedding.as_slice(), filter)
         .await?;
 
-    // Sort the results by score.
-    result.sort_by_score();
+    // Sort the results by score, raising photos with a lot of the requested color.
+    match &boost_color {
+        Some(color) => boost_by_color(&mut result, color),
+        None => result.sort_by_score(),
+    }
 
     if result.is_empty() {
         warn!(
@@ -46,16 +169,45 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
-    let result: Vec<String> = result
-        .iter()
-        .map(|r| r.payload.get("description").cloned().unwrap_or_default())
-        .collect();
+    // Without question, list the similar photos
+    let Some(question) = question else {
+        for r in &result {
+            info!("{}", r.payload.get("path").cloned().unwrap_or_default());
+        }
+        return Ok(());
+    };
+
+    let result: Vec<String> = result.iter().map(photo_context).collect();
 
     debug!("{:?}", result);
 
-    let result = chat.process_search_result(question, &result).await?;
+    // Print the answer as it is generated
+    let mut answer = answer_generator
+        .stream_search_result(question, &[], &result)
+        .await?;
+    let mut stdout = stdout().lock();
+    while let Some(fragment) = answer.next().await {
+        write!(stdout, "{}", fragment?)?;
+        stdout.flush()?;
+    }
+    writeln!(stdout)?;
 
-    info!("{}", result);
+    answer_generator.log_spend();
 
     Ok(())
 }
+
+/// Validates the value of a color flag against the named colors.
+fn color_arg(value: Option<String>) -> Result<String> {
+    let color = value
+        .map(|color| color.to_ascii_lowercase())
+        .ok_or_else(|| anyhow!("Please provide a color after the color flag"))?;
+    if !COLOR_NAMES.contains(&color.as_str()) {
+        return Err(anyhow!(
+            "Unknown color {} - use one of {}",
+            color,
+            COLOR_NAMES.join(", ")
+        ));
+    }
+    Ok(color)
+}
@@ -0,0 +1,75 @@
+use anyhow::{anyhow, Result};
+use futures::{stream::iter, StreamExt};
+use indicatif::{ProgressBar, ProgressStyle};
+use photo_scanner::domain::file_utils::list_jpeg_files;
+use photo_scanner::outbound::thumbnails::ThumbnailCache;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{error, info};
+use tracing_appender::rolling;
+use tracing_subscriber::EnvFilter;
+
+// Maximum number of images decoded at the same time
+const MAX_CONCURRENT_TASKS: usize = 4;
+
+/// Main entry point.
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Set up tracing for logging.
+    let file_appender = rolling::never("logs", "thumbnails.log");
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_writer(file_appender)
+        .with_target(false)
+        .without_time()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
+
+    let cache = Arc::new(ThumbnailCache::from_env());
+
+    // Get the folder path and the optional `--prune` flag from command line arguments.
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let prune = args.iter().any(|arg| arg == "--prune");
+    args.retain(|arg| arg != "--prune");
+    if args.len() != 1 {
+        return Err(anyhow!("Please provide a path to the folder."));
+    }
+    let root_path = PathBuf::from(&args[0]);
+
+    let files_list = list_jpeg_files(root_path)?;
+
+    let progress_bar = Arc::new(ProgressBar::new(files_list.len() as u64));
+    progress_bar.set_style(
+        ProgressStyle::default_bar()
+            .template("Processing [{elapsed_precise}] [{wide_bar}] {pos}/{len} ({eta})")?,
+    );
+
+    // Decoding is CPU bound - run it on the blocking thread pool
+    iter(files_list.clone())
+        .for_each_concurrent(MAX_CONCURRENT_TASKS, |path| {
+            let cache = Arc::clone(&cache);
+            let progress_bar = Arc::clone(&progress_bar);
+            async move {
+                let size = cache.sizes()[0];
+                let result =
+                    tokio::task::spawn_blocking(move || cache.get_or_create(&path, size)).await;
+                match result {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => error!("Error creating thumbnails: {}", e),
+                    Err(e) => error!("Error joining thumbnail task: {}", e),
+                }
+                progress_bar.inc(1);
+            }
+        })
+        .await;
+
+    progress_bar.finish();
+
+    // Remove the thumbnails of edited and deleted photos
+    if prune {
+        let removed = tokio::task::spawn_blocking(move || cache.prune(&files_list)).await??;
+        info!("Removed {} thumbnails", removed);
+    }
+
+    Ok(())
+}
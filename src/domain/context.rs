@@ -0,0 +1,276 @@
+use super::{
+    models::DescriptionContext,
+    ports::{Geocoder, XMPMetadata},
+};
+use chrono::{Datelike, NaiveDate};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tracing::warn;
+
+// Between the tropics the year has no such seasons
+const TROPICS_LATITUDE: f64 = 23.44;
+const SEASONS: [&str; 4] = ["winter", "spring", "summer", "autumn"];
+
+/// The descriptions of the photos taken before and after a photo in the same folder.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Neighbours {
+    pub previous: Option<String>,
+    pub next: Option<String>,
+}
+
+/// Collects what is known about a photo besides its pixels into the hints for the image model -
+/// persons, folder, place, date and season, camera settings and the descriptions of the photos
+/// taken before and after.
+pub struct DescriptionContextBuilder<X, G>
+where
+    X: XMPMetadata,
+    G: Geocoder,
+{
+    xmp_metadata: Arc<X>,
+    geocoder: Option<Arc<G>>,
+}
+
+impl<X, G> DescriptionContextBuilder<X, G>
+where
+    X: XMPMetadata,
+    G: Geocoder,
+{
+    /// Creates the builder - without geocoder, the coordinates are passed on unnamed.
+    pub fn new(xmp_metadata: Arc<X>, geocoder: Option<Arc<G>>) -> Self {
+        DescriptionContextBuilder {
+            xmp_metadata,
+            geocoder,
+        }
+    }
+
+    /// Builds the context of a photo. Missing or unreadable metadata leaves the hint out.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The photo.
+    /// * `neighbours` - The descriptions of the photos taken before and after, see [`capture_order`].
+    ///
+    /// # Returns
+    ///
+    /// * `DescriptionContext` - The hints for the image model, without feedback.
+    pub async fn build(&self, path: &Path, neighbours: Neighbours) -> DescriptionContext {
+        let persons = self.xmp_metadata.get_persons(path).unwrap_or_else(|e| {
+            warn!("Error extracting persons from {}: {}", path.display(), e);
+            Vec::new()
+        });
+
+        // Camera settings are an optional hint, e.g. for long exposures or macro shots.
+        let camera = match self.xmp_metadata.get_camera_info(path) {
+            Ok(camera_info) => camera_info.hints(),
+            Err(e) => {
+                warn!(
+                    "Error extracting camera info from {}: {}",
+                    path.display(),
+                    e
+                );
+                Vec::new()
+            }
+        };
+
+        // The folder name often names the place or the event.
+        let folder = path
+            .parent()
+            .and_then(|p| p.file_name()?.to_str().map(str::to_string));
+
+        let location = self.xmp_metadata.get_geolocation(path).unwrap_or_else(|e| {
+            warn!("Error extracting location from {}: {}", path.display(), e);
+            None
+        });
+        let coordinates = location.as_deref().and_then(parse_coordinates);
+        let place = match (&self.geocoder, coordinates) {
+            (Some(geocoder), Some((latitude, longitude))) => geocoder
+                .reverse_geocode(latitude, longitude)
+                .await
+                .unwrap_or_else(|e| {
+                    warn!("Error looking up the place of {}: {}", path.display(), e);
+                    None
+                }),
+            _ => None,
+        };
+
+        let created = self
+            .xmp_metadata
+            .get_created(path)
+            .ok()
+            .map(|created| created.date_naive());
+        let date = created.map(|created| created.format("%Y-%m-%d").to_string());
+        let season = created
+            .and_then(|created| season(created, coordinates.map(|(latitude, _)| latitude)))
+            .map(str::to_string);
+
+        DescriptionContext {
+            persons,
+            folder,
+            location,
+            place,
+            date,
+            season,
+            camera,
+            previous_description: neighbours.previous,
+            next_description: neighbours.next,
+            feedback: Vec::new(),
+        }
+    }
+
+    /// Returns the existing description of a neighbouring photo.
+    pub fn description(&self, path: &Path) -> Option<String> {
+        self.xmp_metadata
+            .get_description(path)
+            .ok()
+            .flatten()
+            .filter(|description| !description.trim().is_empty())
+    }
+}
+
+/// Groups the photos by folder, each folder in the order the photos were taken. Photos without
+/// capture date follow in the order of their names.
+pub fn capture_order<X: XMPMetadata>(files: &[PathBuf], xmp_metadata: &X) -> Vec<Vec<PathBuf>> {
+    let mut folders: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    for path in files {
+        let folder = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        folders.entry(folder).or_default().push(path.clone());
+    }
+
+    folders
+        .into_values()
+        .map(|mut paths| {
+            paths.sort_by_cached_key(|path| {
+                let created = xmp_metadata.get_created(path).ok();
+                (created.is_none(), created, path.clone())
+            });
+            paths
+        })
+        .collect()
+}
+
+/// Returns the meteorological season of the date, turned around on the southern hemisphere.
+/// Without latitude the northern hemisphere is assumed, between the tropics there is none.
+pub fn season(date: NaiveDate, latitude: Option<f64>) -> Option<&'static str> {
+    if latitude.is_some_and(|latitude| latitude.abs() < TROPICS_LATITUDE) {
+        return None;
+    }
+    // December, January and February are the winter of the north
+    let index = (date.month() % 12 / 3) as usize;
+    let index = if latitude.is_some_and(|latitude| latitude < 0.0) {
+        (index + 2) % 4
+    } else {
+        index
+    };
+    Some(SEASONS[index])
+}
+
+/// Parses the coordinates of the XMP metadata, e.g. `43.4682,11.8801`.
+fn parse_coordinates(location: &str) -> Option<(f64, f64)> {
+    let (latitude, longitude) = location.split_once(',')?;
+    Some((
+        latitude.trim().parse().ok()?,
+        longitude.trim().parse().ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbound::{test_mocks::tests::GeocoderMock, xmp::XMPToolkitMetadata};
+    use anyhow::Result;
+    use chrono::DateTime;
+    use std::fs::{copy, create_dir_all};
+
+    #[test]
+    fn test_season() {
+        let date = |date: &str| NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap();
+        assert_eq!(season(date("2023-07-14"), Some(43.5)), Some("summer"));
+        assert_eq!(season(date("2023-12-01"), None), Some("winter"));
+        assert_eq!(season(date("2023-04-30"), Some(45.0)), Some("spring"));
+        assert_eq!(season(date("2023-07-14"), Some(-33.9)), Some("winter"));
+        assert_eq!(season(date("2023-10-02"), Some(-33.9)), Some("spring"));
+        assert_eq!(season(date("2023-07-14"), Some(1.3)), None);
+    }
+
+    #[test]
+    fn test_capture_order() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let sicily = temp_dir.path().join("Sicily");
+        let norway = temp_dir.path().join("Norway");
+        create_dir_all(&sicily)?;
+        create_dir_all(&norway)?;
+        let files = [
+            sicily.join("IMG_0000.jpg"),
+            norway.join("IMG_0001.jpg"),
+            sicily.join("IMG_0001.jpg"),
+            sicily.join("IMG_0002.jpg"),
+        ];
+        copy("testdata/example-no-xmp-no-exif.jpg", &files[0])?;
+        copy("testdata/example-no-xmp-no-exif.jpg", &files[1])?;
+        copy("testdata/example-full.jpg", &files[2])?;
+        copy("testdata/example-full.jpg", &files[3])?;
+
+        // The evening photo has the lower number, e.g. taken with another camera
+        let xmp_metadata = XMPToolkitMetadata::new();
+        xmp_metadata.set_created(
+            &files[2],
+            &DateTime::parse_from_rfc3339("2023-07-14T18:30:00+02:00")?,
+        )?;
+        xmp_metadata.set_created(
+            &files[3],
+            &DateTime::parse_from_rfc3339("2023-07-14T09:00:00+02:00")?,
+        )?;
+
+        assert_eq!(
+            capture_order(&files, &xmp_metadata),
+            vec![
+                vec![files[1].clone()],
+                // The photo without capture date comes last
+                vec![files[3].clone(), files[2].clone(), files[0].clone()],
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_build() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let harbour = temp_dir.path().join("IMG_0001.jpg");
+        let photo = temp_dir.path().join("IMG_0002.jpg");
+        copy("testdata/example-full.jpg", &harbour)?;
+        copy("testdata/example-gps.jpg", &photo)?;
+
+        let xmp_metadata = Arc::new(XMPToolkitMetadata::new());
+        xmp_metadata.set_description(&harbour, "Fishing boats in the harbour")?;
+        xmp_metadata.set_created(
+            &photo,
+            &DateTime::parse_from_rfc3339("2023-07-14T18:30:00+02:00")?,
+        )?;
+
+        let builder = DescriptionContextBuilder::new(xmp_metadata, Some(Arc::new(GeocoderMock)));
+        let neighbours = Neighbours {
+            previous: builder.description(&harbour),
+            next: None,
+        };
+        let context = builder.build(&photo, neighbours).await;
+
+        assert_eq!(
+            context.location.as_deref(),
+            Some("43.468243333333334,11.880171666666667")
+        );
+        assert_eq!(context.place.as_deref(), Some("Arezzo, Tuscany, Italy"));
+        assert_eq!(context.date.as_deref(), Some("2023-07-14"));
+        assert_eq!(context.season.as_deref(), Some("summer"));
+        assert_eq!(
+            context.previous_description.as_deref(),
+            Some("Fishing boats in the harbour")
+        );
+        assert_eq!(context.next_description, None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,415 @@
+use super::{
+    embeddings::COLLECTION_NAME,
+    models::{
+        ChatError, FollowUp, SearchScope, Turn, VectorName, VectorOutput, VectorOutputListUtils,
+    },
+    ports::{AnswerGenerator, TextEmbedder, VectorDB},
+};
+use anyhow::{anyhow, Result};
+use std::{collections::HashMap, sync::Arc};
+use tracing::{debug, warn};
+
+// How many earlier questions a follow-up sees by default
+const DEFAULT_WINDOW: usize = 3;
+// The search finds the 10 best photos, also when searching several folders
+const MAX_PHOTOS: usize = 10;
+// The payload field with the name of the folder of a photo
+const FOLDER: &str = "folder";
+
+/// Searches the photos in a conversation, so that follow-ups like "and which of those were at the
+/// beach?" or "show me more from that day" refer to the earlier questions and the photos found
+/// for them.
+///
+/// Only the turns within the context window are remembered, which keeps the prompts small.
+pub struct ConversationService<G, C, V>
+where
+    G: AnswerGenerator,
+    C: TextEmbedder,
+    V: VectorDB,
+{
+    answer_generator: Arc<G>,
+    text_embedder: Arc<C>,
+    vector_db: Arc<V>,
+    window: usize,
+    turns: Vec<Turn>,
+}
+
+impl<G, C, V> ConversationService<G, C, V>
+where
+    G: AnswerGenerator,
+    C: TextEmbedder,
+    V: VectorDB,
+{
+    pub fn new(answer_generator: Arc<G>, text_embedder: Arc<C>, vector_db: Arc<V>) -> Self {
+        ConversationService {
+            answer_generator,
+            text_embedder,
+            vector_db,
+            window: DEFAULT_WINDOW,
+            turns: Vec::new(),
+        }
+    }
+
+    /// Remembers the given number of earlier turns, none makes every question stand alone.
+    pub fn with_window(mut self, window: usize) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Returns the remembered turns, the oldest first.
+    pub fn turns(&self) -> &[Turn] {
+        &self.turns
+    }
+
+    /// Answers the next question of the conversation.
+    ///
+    /// A follow-up is rewritten by the answer model into a search which stands alone, either of
+    /// the photos found before, of their folders or of all photos. The answer sees the photos
+    /// found now as well as the ones found before, along with the earlier turns.
+    ///
+    /// # Arguments
+    ///
+    /// * `question` - The question, e.g. a follow-up like "show me more from that day".
+    /// * `filter` - The payload the photos must have, kept for the whole conversation.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<String>` - The answer, or an error.
+    pub async fn ask(&mut self, question: &str, filter: HashMap<String, String>) -> Result<String> {
+        let follow_up = self.follow_up(question).await?;
+        debug!("Searching for {:?}", follow_up);
+
+        let (photos, folders) = match follow_up.scope {
+            // The answer is among the photos found before, so nothing new is searched
+            SearchScope::Earlier => (self.context(&[]), self.folders()),
+            SearchScope::Folders => {
+                let results = self.search_folders(&follow_up.query, filter).await?;
+                (
+                    results.iter().map(photo_context).collect(),
+                    folders(&results),
+                )
+            }
+            SearchScope::All => {
+                let results = self.search(&follow_up.query, filter).await?;
+                (
+                    results.iter().map(photo_context).collect(),
+                    folders(&results),
+                )
+            }
+        };
+
+        let context = self.context(&photos);
+        let answer = self
+            .answer_generator
+            .process_search_result(question, &self.turns, &context)
+            .await?;
+
+        self.turns.push(Turn {
+            question: question.to_string(),
+            photos,
+            folders,
+            answer: answer.clone(),
+        });
+        if self.turns.len() > self.window {
+            self.turns.drain(..self.turns.len() - self.window);
+        }
+
+        Ok(answer)
+    }
+
+    /// Rewrites a follow-up into the search for it - the first question is searched as it is.
+    ///
+    /// If the answer model fails to rewrite it, the follow-up is searched in all photos, preceded
+    /// by the earlier questions of the window.
+    async fn follow_up(&self, question: &str) -> Result<FollowUp> {
+        if self.turns.is_empty() {
+            return Ok(FollowUp {
+                query: question.to_string(),
+                scope: SearchScope::All,
+            });
+        }
+
+        match self
+            .answer_generator
+            .rewrite_follow_up(question, &self.turns)
+            .await
+        {
+            Ok(follow_up) => Ok(follow_up),
+            Err(e) if ChatError::is_budget_exhausted(&e) => Err(e),
+            Err(e) => {
+                warn!("Failed to rewrite the follow-up {:?}: {:#}", question, e);
+                Ok(FollowUp {
+                    query: self.search_text(question),
+                    scope: SearchScope::All,
+                })
+            }
+        }
+    }
+
+    /// Searches the photos matching the text and the filter, the best first.
+    async fn search(
+        &self,
+        text: &str,
+        filter: HashMap<String, String>,
+    ) -> Result<Vec<VectorOutput>> {
+        let embedding = self
+            .text_embedder
+            .get_query_embeddings(vec![text.to_string()])
+            .await?
+            .pop()
+            .ok_or_else(|| anyhow!("No embedding returned for the question"))?;
+        let mut results = self
+            .vector_db
+            .search_points(
+                COLLECTION_NAME,
+                VectorName::Description,
+                embedding.as_slice(),
+                filter,
+            )
+            .await?;
+        results.sort_by_score();
+        Ok(results)
+    }
+
+    /// Searches the photos matching the text in the folders of the photos found before, e.g. the
+    /// other photos of that day, the best first.
+    ///
+    /// A filter on the folder already narrows the whole conversation, so it is kept.
+    async fn search_folders(
+        &self,
+        text: &str,
+        filter: HashMap<String, String>,
+    ) -> Result<Vec<VectorOutput>> {
+        let folders = self.folders();
+        if folders.is_empty() || filter.contains_key(FOLDER) {
+            return self.search(text, filter).await;
+        }
+
+        let mut results: Vec<VectorOutput> = Vec::new();
+        for folder in folders {
+            let mut folder_filter = filter.clone();
+            folder_filter.insert(FOLDER.to_string(), folder);
+            for result in self.search(text, folder_filter).await? {
+                if !results.iter().any(|found| found.id == result.id) {
+                    results.push(result);
+                }
+            }
+        }
+        results.sort_by_score();
+        results.truncate(MAX_PHOTOS);
+        Ok(results)
+    }
+
+    /// Returns the folders of the photos found before, the latest first and each folder once.
+    fn folders(&self) -> Vec<String> {
+        let mut folders: Vec<String> = Vec::new();
+        for turn in self.turns.iter().rev() {
+            for folder in &turn.folders {
+                if !folders.contains(folder) {
+                    folders.push(folder.clone());
+                }
+            }
+        }
+        folders
+    }
+
+    /// Returns the earlier questions of the window followed by the question, to search for a
+    /// follow-up which could not be rewritten.
+    fn search_text(&self, question: &str) -> String {
+        self.turns
+            .iter()
+            .map(|turn| turn.question.as_str())
+            .chain([question])
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Returns the photos to answer from - the ones found now, followed by the ones found for the
+    /// earlier questions of the window, the latest first and each photo once.
+    fn context(&self, photos: &[String]) -> Vec<String> {
+        let mut context = photos.to_vec();
+        for turn in self.turns.iter().rev() {
+            for photo in &turn.photos {
+                if !context.contains(photo) {
+                    context.push(photo.clone());
+                }
+            }
+        }
+        context
+    }
+}
+
+/// Returns the folders of the found photos, each folder once.
+fn folders(results: &[VectorOutput]) -> Vec<String> {
+    let mut folders: Vec<String> = Vec::new();
+    for folder in results
+        .iter()
+        .filter_map(|result| result.payload.get(FOLDER))
+    {
+        if !folders.contains(folder) {
+            folders.push(folder.clone());
+        }
+    }
+    folders
+}
+
+/// Describes a found photo for the answer - its description and the text recognized in it.
+pub fn photo_context(result: &VectorOutput) -> String {
+    let description = result
+        .payload
+        .get("description")
+        .cloned()
+        .unwrap_or_default();
+    match result.payload.get("text") {
+        Some(text) => format!("{}\nText in the photo: {}", description, text),
+        None => description,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::models::VectorInput,
+        outbound::test_mocks::tests::{AnswerRecorderMock, ModelMock, VectorDBMock},
+    };
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_conversation() -> Result<()> {
+        let vector_db = Arc::new(VectorDBMock::new());
+        vector_db.create_collection(COLLECTION_NAME, 3).await?;
+        let photo = VectorInput::new(
+            1,
+            vec![0.1, 0.2, 0.3],
+            HashMap::from([
+                ("description".to_string(), json!("A beach in Sicily")),
+                ("folder".to_string(), json!("Cefalù")),
+            ]),
+        );
+        vector_db.upsert_points(COLLECTION_NAME, &[photo]).await?;
+
+        let answer_generator = Arc::new(AnswerRecorderMock::default());
+        let mut service = ConversationService::new(
+            answer_generator.clone(),
+            Arc::new(ModelMock),
+            vector_db.clone(),
+        )
+        .with_window(1);
+
+        service.ask("Photos from Sicily", HashMap::new()).await?;
+        assert_eq!(
+            service.search_text("Only the beach"),
+            "Photos from Sicily\nOnly the beach"
+        );
+
+        // The follow-up sees the earlier answer, and the photo found before only once
+        let other = VectorInput::new(
+            2,
+            vec![0.1, 0.2, 0.3],
+            HashMap::from([
+                ("description".to_string(), json!("A market in Palermo")),
+                ("text".to_string(), json!("MERCATO")),
+            ]),
+        );
+        vector_db.upsert_points(COLLECTION_NAME, &[other]).await?;
+        service.ask("Only the beach", HashMap::new()).await?;
+
+        let calls = answer_generator.calls();
+        assert_eq!(calls[0].0, "Photos from Sicily");
+        assert!(calls[0].1.is_empty());
+        assert_eq!(calls[1].0, "Only the beach");
+        assert_eq!(
+            calls[1].1,
+            vec![Turn {
+                question: "Photos from Sicily".to_string(),
+                photos: vec!["A beach in Sicily".to_string()],
+                folders: vec!["Cefalù".to_string()],
+                answer: "answer 1".to_string(),
+            }]
+        );
+        assert_eq!(
+            calls[1].2,
+            vec![
+                "A beach in Sicily".to_string(),
+                "A market in Palermo\nText in the photo: MERCATO".to_string()
+            ]
+        );
+
+        // Beyond the window the oldest turn is forgotten
+        assert_eq!(service.turns().len(), 1);
+        assert_eq!(service.turns()[0].question, "Only the beach");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_conversation_follow_up_scope() -> Result<()> {
+        let vector_db = Arc::new(VectorDBMock::new());
+        vector_db.create_collection(COLLECTION_NAME, 3).await?;
+        let photo = |id: u64, description: &str, folder: &str| {
+            VectorInput::new(
+                id,
+                vec![0.1, 0.2, 0.3],
+                HashMap::from([
+                    ("description".to_string(), json!(description)),
+                    ("folder".to_string(), json!(folder)),
+                ]),
+            )
+        };
+        vector_db
+            .upsert_points(COLLECTION_NAME, &[photo(1, "A beach", "2023-07-14 Cefalù")])
+            .await?;
+
+        let answer_generator = Arc::new(AnswerRecorderMock::with_follow_ups(vec![
+            FollowUp {
+                query: "More photos of the beach in Cefalù".to_string(),
+                scope: SearchScope::Folders,
+            },
+            FollowUp {
+                query: "Photos of the beach with boats".to_string(),
+                scope: SearchScope::Earlier,
+            },
+        ]));
+        let mut service = ConversationService::new(
+            answer_generator.clone(),
+            Arc::new(ModelMock),
+            vector_db.clone(),
+        );
+        service.ask("Photos of the beach", HashMap::new()).await?;
+
+        // "That day" searches the folder of the photo found before only
+        vector_db
+            .upsert_points(
+                COLLECTION_NAME,
+                &[
+                    photo(2, "Boats on the beach", "2023-07-14 Cefalù"),
+                    photo(3, "A market", "2023-07-15 Palermo"),
+                ],
+            )
+            .await?;
+        service
+            .ask("Show me more from that day", HashMap::new())
+            .await?;
+        assert_eq!(service.turns()[1].folders, vec!["2023-07-14 Cefalù"]);
+        assert_eq!(service.turns()[1].photos.len(), 2);
+        assert!(!service.turns()[1].photos.contains(&"A market".to_string()));
+
+        // "Those" are answered from the photos found before, without searching again
+        service
+            .ask("Which of those have boats?", HashMap::new())
+            .await?;
+        let calls = answer_generator.calls();
+        assert_eq!(calls[2].0, "Which of those have boats?");
+        assert_eq!(calls[2].1.len(), 2);
+        assert_eq!(calls[2].2.len(), 2);
+        assert!(!calls[2].2.contains(&"A market".to_string()));
+
+        // Something new is searched in all photos
+        service.ask("Photos of a market", HashMap::new()).await?;
+        let calls = answer_generator.calls();
+        assert!(calls[3].2.contains(&"A market".to_string()));
+
+        Ok(())
+    }
+}
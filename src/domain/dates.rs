@@ -0,0 +1,351 @@
+use super::{
+    file_utils::list_jpeg_files,
+    models::{DateCandidate, DateProposal, DateSource},
+    ports::XMPMetadata,
+};
+use anyhow::Result;
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use regex::Regex;
+use std::{
+    collections::BTreeMap,
+    fs::metadata,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock},
+};
+use tracing::{error, info, warn};
+
+// Same default as the XMP metadata: dates without timezone are taken as UTC+1 (Zurich)
+const DEFAULT_OFFSET_SECONDS: i32 = 3600;
+
+// Camera and phone names with date and time, e.g. IMG_20230715_123045.jpg, PXL_20230408_060152625.jpg
+// or 2023-07-15 12.30.45.jpg
+static FILE_NAME_DATE_TIME: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?:^|\D)((?:19|20)\d{2})-?(\d{2})-?(\d{2})[ _T-]?(\d{2})[.:_-]?(\d{2})[.:_-]?(\d{2})",
+    )
+    .unwrap()
+});
+// Names with a date only, e.g. WhatsApp's IMG-20230715-WA0001.jpg
+static FILE_NAME_DATE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?:^|\D)((?:19|20)\d{2})-?(\d{2})-?(\d{2})(?:\D|$)").unwrap());
+// Folder names starting with a year, optionally followed by month and day, e.g. 2023, 2023-07 or 2023-07-15 sicily
+static FOLDER_NAME_DATE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^((?:19|20)\d{2})(?:[-_ ]?(\d{2}))?(?:[-_ ]?(\d{2}))?(?:\D|$)").unwrap()
+});
+
+pub struct DateRepairService<X>
+where
+    X: XMPMetadata,
+{
+    xmp_metadata: Arc<X>,
+}
+
+impl<X> DateRepairService<X>
+where
+    X: XMPMetadata,
+{
+    pub fn new(xmp_metadata: Arc<X>) -> Self {
+        DateRepairService { xmp_metadata }
+    }
+
+    /// Proposes capture dates for all photos below the root path which have none in their metadata.
+    pub fn propose(&self, root_path: &PathBuf) -> Result<Vec<DateProposal>> {
+        // Group the files by folder and sort them by name to get the shooting sequence
+        let mut folders: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+        for path in list_jpeg_files(root_path)? {
+            let folder = path.parent().map(Path::to_path_buf).unwrap_or_default();
+            folders.entry(folder).or_default().push(path);
+        }
+
+        let mut proposals = Vec::new();
+        for files in folders.values_mut() {
+            files.sort();
+
+            let created: Vec<Option<DateTime<FixedOffset>>> = files
+                .iter()
+                .map(|path| self.xmp_metadata.get_created(path).ok())
+                .collect();
+
+            for (index, path) in files.iter().enumerate() {
+                if created[index].is_some() {
+                    continue;
+                }
+
+                let mut candidates = Vec::new();
+                candidates.extend(date_from_file_name(path));
+                candidates.extend(date_from_neighbours(&created, index));
+                candidates.extend(date_from_folder_name(path));
+                candidates.extend(date_from_file_modified(path));
+                candidates.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+
+                proposals.push(DateProposal {
+                    path: path.clone(),
+                    candidates,
+                });
+            }
+        }
+
+        Ok(proposals)
+    }
+
+    /// Writes the best candidate of each proposal which reaches the minimum confidence.
+    ///
+    /// Returns the number of photos that have been updated.
+    pub fn apply(&self, proposals: &[DateProposal], min_confidence: f32) -> u64 {
+        let mut updated = 0;
+        for proposal in proposals {
+            let Some(candidate) = proposal.best() else {
+                warn!("Skipping {}: no date found", proposal.path.display());
+                continue;
+            };
+
+            if candidate.confidence < min_confidence {
+                warn!(
+                    "Skipping {}: confidence {:.2} below {:.2}",
+                    proposal.path.display(),
+                    candidate.confidence,
+                    min_confidence
+                );
+                continue;
+            }
+
+            match self
+                .xmp_metadata
+                .set_created(&proposal.path, &candidate.created)
+            {
+                Ok(()) => {
+                    info!(
+                        "Updated: [{}] {} from {}",
+                        proposal.path.display(),
+                        candidate.created,
+                        candidate.source
+                    );
+                    updated += 1;
+                }
+                Err(e) => error!(
+                    "Error storing created date for {}: {}",
+                    proposal.path.display(),
+                    e
+                ),
+            }
+        }
+        updated
+    }
+}
+
+/// Infers the capture date from camera, phone and messenger file name patterns.
+fn date_from_file_name(path: &Path) -> Option<DateCandidate> {
+    let file_name = path.file_stem()?.to_str()?;
+
+    if let Some(captures) = FILE_NAME_DATE_TIME.captures(file_name) {
+        let number = |i: usize| captures[i].parse::<u32>().ok();
+        let date = NaiveDate::from_ymd_opt(captures[1].parse().ok()?, number(2)?, number(3)?);
+        let time = NaiveTime::from_hms_opt(number(4)?, number(5)?, number(6)?);
+        if let (Some(date), Some(time)) = (date, time) {
+            return Some(DateCandidate {
+                created: with_default_offset(date.and_time(time))?,
+                source: DateSource::FileName,
+                confidence: 0.9,
+            });
+        }
+    }
+
+    let captures = FILE_NAME_DATE.captures(file_name)?;
+    let date = NaiveDate::from_ymd_opt(
+        captures[1].parse().ok()?,
+        captures[2].parse().ok()?,
+        captures[3].parse().ok()?,
+    )?;
+    Some(DateCandidate {
+        created: with_default_offset(date.and_time(NaiveTime::MIN))?,
+        source: DateSource::FileName,
+        confidence: 0.8,
+    })
+}
+
+/// Infers the capture date from the closest photos with a known date in the same sequence.
+fn date_from_neighbours(
+    created: &[Option<DateTime<FixedOffset>>],
+    index: usize,
+) -> Option<DateCandidate> {
+    let previous = created[..index].iter().rev().find_map(|c| *c);
+    let next = created[index + 1..].iter().find_map(|c| *c);
+
+    match (previous, next) {
+        // Between two known photos - take the middle
+        (Some(previous), Some(next)) => Some(DateCandidate {
+            created: previous + (next - previous) / 2,
+            source: DateSource::Neighbours,
+            confidence: 0.7,
+        }),
+        (Some(neighbour), None) | (None, Some(neighbour)) => Some(DateCandidate {
+            created: neighbour,
+            source: DateSource::Neighbours,
+            confidence: 0.5,
+        }),
+        (None, None) => None,
+    }
+}
+
+/// Infers the capture date from the closest folder name starting with a year.
+fn date_from_folder_name(path: &Path) -> Option<DateCandidate> {
+    path.ancestors().skip(1).find_map(|folder| {
+        let name = folder.file_name()?.to_str()?;
+        let captures = FOLDER_NAME_DATE.captures(name)?;
+
+        let year = captures[1].parse().ok()?;
+        let month = captures.get(2).and_then(|m| m.as_str().parse().ok());
+        let day = captures.get(3).and_then(|d| d.as_str().parse().ok());
+
+        // The more precise the folder name, the more we trust it
+        let (date, confidence) = match (month, day) {
+            (Some(month), Some(day)) => (NaiveDate::from_ymd_opt(year, month, day)?, 0.6),
+            (Some(month), None) => (NaiveDate::from_ymd_opt(year, month, 1)?, 0.4),
+            _ => (NaiveDate::from_ymd_opt(year, 1, 1)?, 0.3),
+        };
+
+        Some(DateCandidate {
+            created: with_default_offset(date.and_time(NaiveTime::MIN))?,
+            source: DateSource::FolderName,
+            confidence,
+        })
+    })
+}
+
+/// Uses the modification time of the file - the last resort, as copying files usually changes it.
+fn date_from_file_modified(path: &Path) -> Option<DateCandidate> {
+    let modified = metadata(path).ok()?.modified().ok()?;
+    let modified: DateTime<chrono::Utc> = modified.into();
+    let offset = FixedOffset::east_opt(DEFAULT_OFFSET_SECONDS)?;
+
+    Some(DateCandidate {
+        created: modified.with_timezone(&offset),
+        source: DateSource::FileModified,
+        confidence: 0.2,
+    })
+}
+
+fn with_default_offset(date_time: NaiveDateTime) -> Option<DateTime<FixedOffset>> {
+    FixedOffset::east_opt(DEFAULT_OFFSET_SECONDS)?
+        .from_local_datetime(&date_time)
+        .single()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbound::xmp::XMPToolkitMetadata;
+    use std::fs::{copy, create_dir};
+
+    fn date(y: i32, m: u32, d: u32, h: u32, min: u32, s: u32) -> DateTime<FixedOffset> {
+        FixedOffset::east_opt(DEFAULT_OFFSET_SECONDS)
+            .unwrap()
+            .with_ymd_and_hms(y, m, d, h, min, s)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_date_from_file_name() {
+        let candidate = date_from_file_name(Path::new("IMG_20230715_123045.jpg")).unwrap();
+        assert_eq!(candidate.created, date(2023, 7, 15, 12, 30, 45));
+        assert_eq!(candidate.confidence, 0.9);
+
+        let candidate = date_from_file_name(Path::new("PXL_20230408_060152625.jpg")).unwrap();
+        assert_eq!(candidate.created, date(2023, 4, 8, 6, 1, 52));
+
+        let candidate = date_from_file_name(Path::new("2023-07-15 12.30.45.jpg")).unwrap();
+        assert_eq!(candidate.created, date(2023, 7, 15, 12, 30, 45));
+
+        let candidate = date_from_file_name(Path::new("IMG-20230715-WA0001.jpg")).unwrap();
+        assert_eq!(candidate.created, date(2023, 7, 15, 0, 0, 0));
+        assert_eq!(candidate.confidence, 0.8);
+
+        assert!(date_from_file_name(Path::new("4L2A3805.jpg")).is_none());
+        assert!(date_from_file_name(Path::new("IMG_20231345_123045.jpg")).is_none());
+    }
+
+    #[test]
+    fn test_date_from_folder_name() {
+        let candidate = date_from_folder_name(Path::new("/photos/2023/sizilien/a.jpg")).unwrap();
+        assert_eq!(candidate.created, date(2023, 1, 1, 0, 0, 0));
+        assert_eq!(candidate.confidence, 0.3);
+
+        let candidate = date_from_folder_name(Path::new("/photos/2023/2023-07 sizilien/a.jpg"));
+        assert_eq!(candidate.unwrap().created, date(2023, 7, 1, 0, 0, 0));
+
+        let candidate = date_from_folder_name(Path::new("/photos/2023-07-15/a.jpg")).unwrap();
+        assert_eq!(candidate.created, date(2023, 7, 15, 0, 0, 0));
+        assert_eq!(candidate.confidence, 0.6);
+
+        assert!(date_from_folder_name(Path::new("/photos/sizilien/a.jpg")).is_none());
+    }
+
+    #[test]
+    fn test_date_from_neighbours() {
+        let created = vec![
+            Some(date(2023, 7, 15, 12, 0, 0)),
+            None,
+            Some(date(2023, 7, 15, 14, 0, 0)),
+            None,
+        ];
+
+        let candidate = date_from_neighbours(&created, 1).unwrap();
+        assert_eq!(candidate.created, date(2023, 7, 15, 13, 0, 0));
+        assert_eq!(candidate.confidence, 0.7);
+
+        let candidate = date_from_neighbours(&created, 3).unwrap();
+        assert_eq!(candidate.created, date(2023, 7, 15, 14, 0, 0));
+        assert_eq!(candidate.confidence, 0.5);
+
+        assert!(date_from_neighbours(&[None], 0).is_none());
+    }
+
+    #[test]
+    fn test_propose_and_apply() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let folder = temp_dir.path().join("2023");
+        create_dir(&folder)?;
+
+        // A photo with a known date and one without any date
+        let dated_file_path = folder.join("a.jpg");
+        copy("testdata/example-no-xmp.jpg", &dated_file_path)?;
+        let undated_file_path = folder.join("IMG_20230715_123045.jpg");
+        copy(
+            "testdata/example-no-xmp-no-exif-no-photoshop.jpg",
+            &undated_file_path,
+        )?;
+
+        let xmp_metadata = Arc::new(XMPToolkitMetadata::new());
+        let service = DateRepairService::new(xmp_metadata.clone());
+
+        let proposals = service.propose(&temp_dir.path().into())?;
+        assert_eq!(proposals.len(), 1);
+        assert_eq!(proposals[0].path, undated_file_path);
+
+        let sources: Vec<DateSource> = proposals[0].candidates.iter().map(|c| c.source).collect();
+        assert_eq!(
+            sources,
+            vec![
+                DateSource::FileName,
+                DateSource::Neighbours,
+                DateSource::FolderName,
+                DateSource::FileModified
+            ]
+        );
+
+        // Nothing reaches this confidence
+        assert_eq!(service.apply(&proposals, 0.95), 0);
+        assert!(xmp_metadata.get_created(&undated_file_path).is_err());
+
+        assert_eq!(service.apply(&proposals, 0.5), 1);
+        assert_eq!(
+            xmp_metadata.get_created(&undated_file_path)?,
+            date(2023, 7, 15, 12, 30, 45)
+        );
+
+        // The repaired photo no longer needs a proposal
+        assert!(service.propose(&temp_dir.path().into())?.is_empty());
+
+        Ok(())
+    }
+}
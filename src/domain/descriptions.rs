@@ -1,49 +1,181 @@
 use super::{
+    context::{capture_order, DescriptionContextBuilder, Neighbours},
     file_utils::list_jpeg_files,
-    ports::{Chat, ImageEncoder, XMPMetadata},
+    models::{ChatError, DescriptionContext, EncodedImage, ImageAnalysis, ImageQuality},
+    ports::{
+        DescriptionValidator, Geocoder, ImageAnalyzer, ImageEncoder, TextRecognizer,
+        VisionDescriber, XMPMetadata,
+    },
+    usage::in_folder,
+    validation::{DescriptionRules, IMAGE_REFERENCE_PATTERN},
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use futures::{stream::iter, StreamExt};
 use indicatif::{ProgressBar, ProgressStyle};
 use regex::Regex;
 use std::{
+    fmt,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::Instant,
 };
 use tracing::{error, info, warn};
 
-// Maximum number of concurrent tasks for multimodal API
+// Maximum number of folders described concurrently by the multimodal API
 const MAX_CONCURRENT_TASKS: usize = 2;
+// How often a description failing validation is generated again, with the problems as feedback
+const MAX_REGENERATIONS: u32 = 2;
+// Hierarchical keyword added to blurry photos
+const BLURRY_SUBJECT: &str = "Quality|Blurry";
+// How answers refusing to describe a photo start, lowercased
+const REFUSALS: [&str; 9] = [
+    "i'm sorry",
+    "i am sorry",
+    "sorry,",
+    "i cannot",
+    "i can't",
+    "i'm unable",
+    "i am unable",
+    "i'm not able",
+    "as an ai",
+];
 
-pub struct DescriptionService<C, X, I>
+/// What to do with photos that are too blurry to be worth describing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BlurryPolicy {
+    /// Describe blurry photos like any other.
+    #[default]
+    Describe,
+    /// Leave blurry photos without description.
+    Skip,
+    /// Describe blurry photos and add the `Quality|Blurry` keyword.
+    Tag,
+}
+
+impl TryFrom<&str> for BlurryPolicy {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "describe" => Ok(BlurryPolicy::Describe),
+            "skip" => Ok(BlurryPolicy::Skip),
+            "tag" => Ok(BlurryPolicy::Tag),
+            _ => Err(anyhow!("Unsupported blurry policy {}", value)),
+        }
+    }
+}
+
+/// The description with the fewest problems of a model which never passed the validation.
+#[derive(Debug)]
+struct InvalidDescription {
+    description: String,
+    analysis: Option<ImageAnalysis>,
+    problems: Vec<String>,
+}
+
+impl fmt::Display for InvalidDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid description \"{}\" - {}",
+            self.description,
+            self.problems.join("; ")
+        )
+    }
+}
+
+impl std::error::Error for InvalidDescription {}
+
+pub struct DescriptionService<C, X, I, A, T, G>
 where
-    C: Chat,
+    C: VisionDescriber,
     X: XMPMetadata,
     I: ImageEncoder,
+    A: ImageAnalyzer,
+    T: TextRecognizer,
+    G: Geocoder,
 {
     image_provider: Arc<I>,
-    chat: Arc<C>,
+    image_analyzer: Arc<A>,
+    text_recognizer: Option<Arc<T>>,
+    vision_describer: Arc<C>,
     xmp_metadata: Arc<X>,
+    context_builder: DescriptionContextBuilder<X, G>,
+    blurry_policy: BlurryPolicy,
+    structured_output: bool,
+    validator: Arc<dyn DescriptionValidator + Send + Sync>,
+    max_regenerations: u32,
 }
 
-impl<C, X, I> DescriptionService<C, X, I>
+impl<C, X, I, A, T, G> DescriptionService<C, X, I, A, T, G>
 where
-    C: Chat,
+    C: VisionDescriber,
     X: XMPMetadata,
-    I: ImageEncoder,
+    I: ImageEncoder + Send + Sync + 'static,
+    A: ImageAnalyzer + Send + Sync + 'static,
+    T: TextRecognizer + Send + Sync + 'static,
+    G: Geocoder,
 {
-    pub fn new(image_provider: Arc<I>, chat: Arc<C>, xmp_metadata: Arc<X>) -> Self {
+    /// Creates the service - without text recognizer, no text is read from the photos, and
+    /// without geocoder, the coordinates are passed on unnamed.
+    pub fn new(
+        image_provider: Arc<I>,
+        image_analyzer: Arc<A>,
+        text_recognizer: Option<Arc<T>>,
+        geocoder: Option<Arc<G>>,
+        vision_describer: Arc<C>,
+        xmp_metadata: Arc<X>,
+    ) -> Self {
         DescriptionService {
             image_provider,
-            chat,
+            image_analyzer,
+            text_recognizer,
+            vision_describer,
+            context_builder: DescriptionContextBuilder::new(Arc::clone(&xmp_metadata), geocoder),
             xmp_metadata,
+            blurry_policy: BlurryPolicy::default(),
+            structured_output: false,
+            validator: Arc::new(DescriptionRules::default()),
+            max_regenerations: MAX_REGENERATIONS,
         }
     }
 
+    pub fn with_blurry_policy(mut self, blurry_policy: BlurryPolicy) -> Self {
+        self.blurry_policy = blurry_policy;
+        self
+    }
+
+    /// Asks the model for a JSON object with title, keywords, scene type, people count, setting
+    /// and confidence besides the description, falling back to plain text if it fails.
+    pub fn with_structured_output(mut self, structured_output: bool) -> Self {
+        self.structured_output = structured_output;
+        self
+    }
+
+    /// Replaces the default [`DescriptionRules`] the descriptions are checked with.
+    pub fn with_validator(
+        mut self,
+        validator: Arc<dyn DescriptionValidator + Send + Sync>,
+    ) -> Self {
+        self.validator = validator;
+        self
+    }
+
+    /// How often a description failing validation is generated again before keeping the best one.
+    pub fn with_max_regenerations(mut self, max_regenerations: u32) -> Self {
+        self.max_regenerations = max_regenerations;
+        self
+    }
+
     pub async fn generate(&self, root_path: &PathBuf) -> Result<u64> {
-        // Traverse the files and process them with limited concurrency.
+        // Within a folder the photos are described one after another in the order they were
+        // taken, so each sees the description of the photo taken before. The folders are
+        // described concurrently.
         let files_list = list_jpeg_files(root_path)?;
+        let folders = capture_order(&files_list, self.xmp_metadata.as_ref());
 
         // Create a progress bar with the total length of the vector.
         let progress_bar = Arc::new(ProgressBar::new(files_list.len() as u64));
@@ -53,91 +185,379 @@ where
             )?,
         );
 
-        iter(files_list)
-            .for_each_concurrent(MAX_CONCURRENT_TASKS, |path| {
+        // Once the budget of the chat backend is spent, the remaining files are left alone.
+        let budget_exhausted = AtomicBool::new(false);
+
+        iter(folders)
+            .for_each_concurrent(MAX_CONCURRENT_TASKS, |paths| {
                 let progress_bar = Arc::clone(&progress_bar);
-                let message = path
-                    .parent()
-                    .expect("Failed to get parent directory ")
-                    .display()
-                    .to_string();
+                let budget_exhausted = &budget_exhausted;
                 async move {
-                    progress_bar.inc(1);
-                    progress_bar.set_message(message);
+                    let mut previous = None;
+                    for (index, path) in paths.iter().enumerate() {
+                        if budget_exhausted.load(Ordering::SeqCst) {
+                            return;
+                        }
+                        progress_bar.inc(1);
+                        progress_bar.set_message(
+                            path.parent()
+                                .expect("Failed to get parent directory ")
+                                .display()
+                                .to_string(),
+                        );
 
-                    // Skip files that do not need processing.
-                    let description = self.xmp_metadata.get_description(&path).unwrap_or_default();
-                    if can_be_skipped(description, &path) {
-                        return;
+                        // The photo taken after has a description only if it has been described
+                        // in an earlier run.
+                        let neighbours = Neighbours {
+                            previous: previous.take(),
+                            next: paths
+                                .get(index + 1)
+                                .and_then(|next| self.context_builder.description(next)),
+                        };
+                        previous = self.process(path, neighbours, budget_exhausted).await;
                     }
+                }
+            })
+            .await;
 
-                    let start_time = Instant::now();
+        // Keep the position of a stopped run, finishing would move it to the end
+        if budget_exhausted.load(Ordering::SeqCst) {
+            progress_bar.abandon_with_message("Stopped, the daily budget is spent.");
+        } else {
+            progress_bar.finish_with_message("All items have been processed.");
+        }
 
-                    // Extract persons from the image, handling any errors.
-                    let persons = match self.xmp_metadata.get_persons(&path) {
-                        Ok(persons) => persons,
-                        Err(e) => {
-                            warn!("Error extracting persons from {}: {}", path.display(), e);
-                            Vec::new() // Default to an empty list if extraction fails.
-                        }
-                    };
-
-                    // Resize and encode the image as base64.
-                    let image_base64 =
-                        match self.image_provider.resize_and_base64encode_image(&path) {
-                            Ok(encoded) => encoded,
-                            Err(e) => {
-                                error!("Error encoding image {}: {}", path.display(), e);
-                                return;
-                            }
-                        };
+        Ok(progress_bar.position())
+    }
 
-                    // Optionally get the folder name for additional context.
-                    let folder_name: Option<String> = path
-                        .parent()
-                        .and_then(|p| p.file_name()?.to_str().map(str::to_string));
-
-                    // Generate a description using the chat model.
-                    let description = match self
-                        .chat
-                        .get_image_description(&image_base64, &persons, &folder_name)
-                        .await
-                    {
-                        Ok(desc) => desc,
-                        Err(e) => {
-                            error!("Error generating description for {}: {}", path.display(), e);
-                            return;
-                        }
-                    };
+    /// Describes a photo unless it already has a description, and stores it in the XMP metadata.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The photo.
+    /// * `neighbours` - The descriptions of the photos taken before and after.
+    /// * `budget_exhausted` - Set once the budget of the chat backend is spent.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - The description of the photo, the existing or the generated one, to be
+    ///   passed on to the photo taken after.
+    async fn process(
+        &self,
+        path: &Path,
+        neighbours: Neighbours,
+        budget_exhausted: &AtomicBool,
+    ) -> Option<String> {
+        // Skip files that do not need processing.
+        let description = self.xmp_metadata.get_description(path).unwrap_or_default();
+        if can_be_skipped(description.clone(), path) {
+            return description;
+        }
 
-                    /* if let Err(e) = chat.get_embedding(&description).await {
-                        error!("Error getting embedding for {}: {}", &path.display(), e);
-                    } */
+        // Handle photos that are too blurry according to the policy.
+        let is_blurry = self
+            .image_quality(path)
+            .await
+            .is_some_and(|quality| quality.is_blurry());
+        if is_blurry {
+            match self.blurry_policy {
+                BlurryPolicy::Describe => {}
+                BlurryPolicy::Skip => {
+                    info!("Blurry: [{}] skipped", path.display());
+                    return None;
+                }
+                BlurryPolicy::Tag => self.tag_blurry(path),
+            }
+        }
 
-                    if let Err(e) = self.xmp_metadata.set_description(&path, &description) {
-                        error!(
-                            "Error storing XMP description for {}: {}",
-                            path.display(),
-                            e
-                        );
+        // Read the text on signs, documents and screenshots once.
+        self.recognize_text(path).await;
+
+        let start_time = Instant::now();
+
+        // The hints for the model - persons, place, date, camera settings and the
+        // descriptions of the photos taken before and after.
+        let context = self.context_builder.build(path, neighbours).await;
+
+        // Generate a description using the chat model, counting the usage per folder.
+        let folder = path
+            .parent()
+            .map(|parent| parent.display().to_string())
+            .unwrap_or_default();
+        let (description, analysis, model) =
+            match in_folder(folder, self.describe(path, &context)).await {
+                Ok(described) => described,
+                Err(e) if ChatError::is_budget_exhausted(&e) => {
+                    if !budget_exhausted.swap(true, Ordering::SeqCst) {
+                        warn!("Stopping the run: {:#}", e);
                     }
+                    return None;
+                }
+                Err(e) => {
+                    error!("Error generating description for {}: {}", path.display(), e);
+                    return None;
+                }
+            };
+
+        /* if let Err(e) = chat.get_embedding(&description).await {
+            error!("Error getting embedding for {}: {}", &path.display(), e);
+        } */
+
+        if let Err(e) = self.xmp_metadata.set_description(path, &description) {
+            error!(
+                "Error storing XMP description for {}: {}",
+                path.display(),
+                e
+            );
+        }
+
+        // A plain text description removes the analysis of an earlier run
+        if let Err(e) = self
+            .xmp_metadata
+            .set_image_analysis(path, analysis.as_ref())
+        {
+            error!("Error storing XMP analysis for {}: {}", path.display(), e);
+        }
+
+        // Remember the prompt version, so descriptions can be compared and regenerated.
+        let prompt = self.vision_describer.description_prompt();
+        if let Err(e) = self.xmp_metadata.set_description_prompt(path, &prompt) {
+            error!("Error storing XMP prompt for {}: {}", path.display(), e);
+        }
+
+        // Remember the model as well, it may have been one of the fallbacks.
+        if let Err(e) = self.xmp_metadata.set_description_model(path, &model) {
+            error!("Error storing XMP model for {}: {}", path.display(), e);
+        }
 
-                    // Log the time taken and other details.
-                    let duration = Instant::now() - start_time;
-                    info!(
-                        "Generated: [{}] \"{}\", Time taken: {:.2} seconds, Persons: {:?}",
+        // Log the time taken and other details.
+        let duration = Instant::now() - start_time;
+        info!(
+            "Generated: [{}] \"{}\", Time taken: {:.2} seconds, Persons: {:?}, Prompt: {}, Model: {}",
+            path.display(),
+            description,
+            duration.as_secs_f64(),
+            context.persons,
+            prompt,
+            model
+        );
+
+        Some(description)
+    }
+
+    /// Generates the description with the first image model which succeeds, falling back to the
+    /// next one if the photo cannot be encoded for a model, or the model fails, times out, refuses
+    /// or answers with an invalid description. The photo is encoded for each model, e.g. in its
+    /// native resolution.
+    ///
+    /// If every model answers with invalid descriptions only, the one with the fewest problems
+    /// across all models is kept.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(String, Option<ImageAnalysis>, String)>` - The description, the analysis if structured output is enabled and the model which wrote them.
+    async fn describe(
+        &self,
+        path: &Path,
+        context: &DescriptionContext,
+    ) -> Result<(String, Option<ImageAnalysis>, String)> {
+        let mut last_error = anyhow!("No image model configured");
+        let mut best: Option<(InvalidDescription, String)> = None;
+        for model in self.vision_describer.image_models() {
+            // Resize and encode the image as base64 on the blocking thread pool. A photo which
+            // cannot be encoded for this model may still be encoded for the next one.
+            let image_provider = Arc::clone(&self.image_provider);
+            let (source, encoding_model) = (path.to_path_buf(), model.clone());
+            let images = match blocking(move || {
+                image_provider.resize_and_base64encode_image(&source, &encoding_model)
+            })
+            .await
+            {
+                Ok(images) => images,
+                Err(e) => {
+                    warn!("Error encoding {} for {}: {:#}", path.display(), model, e);
+                    last_error = anyhow!("Error encoding image for {}: {}", model, e);
+                    continue;
+                }
+            };
+            match self.describe_with(path, &images, context, &model).await {
+                Ok((description, analysis)) => return Ok((description, analysis, model)),
+                Err(e) if ChatError::is_budget_exhausted(&e) => return Err(e),
+                Err(e) => {
+                    warn!(
+                        "Model {} failed to describe {}: {:#}",
+                        model,
                         path.display(),
-                        description,
-                        duration.as_secs_f64(),
-                        persons
+                        e
                     );
+                    match e.downcast::<InvalidDescription>() {
+                        Ok(invalid) => {
+                            if best.as_ref().is_none_or(|(best, _)| {
+                                invalid.problems.len() < best.problems.len()
+                            }) {
+                                best = Some((invalid, model));
+                            }
+                        }
+                        Err(e) => last_error = e,
+                    }
                 }
-            })
-            .await;
+            }
+        }
 
-        progress_bar.finish_with_message("All items have been processed.");
+        let (invalid, model) = best.ok_or(last_error)?;
+        warn!(
+            "Keeping the description of {} by {} with {} problem(s)",
+            path.display(),
+            model,
+            invalid.problems.len()
+        );
+        Ok((invalid.description, invalid.analysis, model))
+    }
 
-        Ok(progress_bar.position())
+    /// Generates the description with one model, again with the problems as feedback while it
+    /// fails validation. After the last attempt, the error carries the description with the
+    /// fewest problems as [`InvalidDescription`].
+    async fn describe_with(
+        &self,
+        path: &Path,
+        images: &[EncodedImage],
+        context: &DescriptionContext,
+        model: &str,
+    ) -> Result<(String, Option<ImageAnalysis>)> {
+        let mut context = context.clone();
+        let mut best: Option<InvalidDescription> = None;
+
+        for attempt in 0..=self.max_regenerations {
+            let (description, analysis) = self.generate_once(path, images, &context, model).await?;
+            let problems = self.validator.validate(&description, &context);
+            if problems.is_empty() {
+                return Ok((description, analysis));
+            }
+
+            warn!(
+                "Invalid description of {} by {}, attempt {}: \"{}\" - {}",
+                path.display(),
+                model,
+                attempt + 1,
+                description,
+                problems.join("; ")
+            );
+            if best
+                .as_ref()
+                .is_none_or(|best| problems.len() < best.problems.len())
+            {
+                best = Some(InvalidDescription {
+                    description,
+                    analysis,
+                    problems: problems.clone(),
+                });
+            }
+            context.feedback = problems;
+        }
+
+        Err(best.expect("at least one attempt").into())
+    }
+
+    /// Generates the description with one model, as part of a JSON object if structured output is enabled.
+    async fn generate_once(
+        &self,
+        path: &Path,
+        images: &[EncodedImage],
+        context: &DescriptionContext,
+        model: &str,
+    ) -> Result<(String, Option<ImageAnalysis>)> {
+        if self.structured_output {
+            let analysis = self
+                .vision_describer
+                .get_image_analysis(images, context, model)
+                .await
+                .and_then(|analysis| {
+                    validate_description(&analysis.caption)?;
+                    Ok(analysis)
+                });
+            match analysis {
+                Ok(analysis) => return Ok((analysis.caption.clone(), Some(analysis))),
+                Err(e) if ChatError::is_budget_exhausted(&e) => return Err(e),
+                Err(e) => warn!(
+                    "Falling back to a plain text description for {}: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+
+        let description = self
+            .vision_describer
+            .get_image_description(images, context, model)
+            .await?;
+        validate_description(&description)?;
+        Ok((description, None))
+    }
+
+    /// Returns the quality scores stored in the XMP metadata, analyzing and storing them if missing.
+    async fn image_quality(&self, path: &Path) -> Option<ImageQuality> {
+        if let Ok(Some(quality)) = self.xmp_metadata.get_image_quality(path) {
+            return Some(quality);
+        }
+
+        let image_analyzer = Arc::clone(&self.image_analyzer);
+        let source = path.to_path_buf();
+        let quality = match blocking(move || image_analyzer.analyze_quality(&source)).await {
+            Ok(quality) => quality,
+            Err(e) => {
+                warn!("Error analyzing quality of {}: {}", path.display(), e);
+                return None;
+            }
+        };
+
+        if let Err(e) = self.xmp_metadata.set_image_quality(path, &quality) {
+            error!("Error storing XMP quality for {}: {}", path.display(), e);
+        }
+
+        Some(quality)
+    }
+
+    /// Stores the text recognized in a photo in the XMP metadata, unless that happened before.
+    async fn recognize_text(&self, path: &Path) {
+        let Some(text_recognizer) = &self.text_recognizer else {
+            return;
+        };
+        if let Ok(Some(_)) = self.xmp_metadata.get_recognized_text(path) {
+            return;
+        }
+
+        let text_recognizer = Arc::clone(text_recognizer);
+        let source = path.to_path_buf();
+        let text = match blocking(move || text_recognizer.recognize_text(&source)).await {
+            Ok(lines) => lines.join("\n"),
+            Err(e) => {
+                warn!("Error recognizing text in {}: {}", path.display(), e);
+                return;
+            }
+        };
+
+        match self.xmp_metadata.set_recognized_text(path, &text) {
+            Ok(()) if text.is_empty() => {}
+            Ok(()) => info!("Text: [{}] {}", path.display(), text.replace('\n', " / ")),
+            Err(e) => error!("Error storing XMP text for {}: {}", path.display(), e),
+        }
+    }
+
+    /// Adds the blurry keyword to the hierarchical subjects of a photo.
+    fn tag_blurry(&self, path: &Path) {
+        let mut subjects = self
+            .xmp_metadata
+            .get_hierarchical_subjects(path)
+            .unwrap_or_default();
+        if subjects.iter().any(|s| s == BLURRY_SUBJECT) {
+            return;
+        }
+
+        subjects.push(BLURRY_SUBJECT.to_string());
+        match self.xmp_metadata.set_hierarchical_subjects(path, &subjects) {
+            Ok(()) => info!("Blurry: [{}] tagged", path.display()),
+            Err(e) => error!("Error tagging blurry photo {}: {}", path.display(), e),
+        }
     }
 }
 
@@ -146,7 +566,7 @@ fn can_be_skipped(description: Option<String>, path: &Path) -> bool {
     // Skip files that already have an XMP description.
     match description {
         Some(description) => {
-            let re = Regex::new(r"(?i)\b(image|photo|picture|photograph)\b").unwrap();
+            let re = Regex::new(IMAGE_REFERENCE_PATTERN).unwrap();
             if re.is_match(&description) {
                 info!("Reprocessed: [{}] \"{}\"", path.display(), description,);
                 false
@@ -159,18 +579,54 @@ fn can_be_skipped(description: Option<String>, path: &Path) -> bool {
     }
 }
 
+/// Rejects empty answers and refusals like "I'm sorry, I can't help with that".
+fn validate_description(description: &str) -> Result<()> {
+    let normalized = description.trim().to_lowercase().replace('’', "'");
+    if normalized.is_empty() {
+        return Err(anyhow!("The description is empty"));
+    }
+    if REFUSALS
+        .iter()
+        .any(|refusal| normalized.starts_with(refusal))
+    {
+        return Err(anyhow!("The model refused: {}", description.trim()));
+    }
+    Ok(())
+}
+
+/// Runs CPU bound work, e.g. the image analysis or the OCR, on the blocking thread pool.
+pub(crate) async fn blocking<R, F>(work: F) -> Result<R>
+where
+    R: Send + 'static,
+    F: FnOnce() -> Result<R> + Send + 'static,
+{
+    tokio::task::spawn_blocking(work).await?
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
         domain::{
-            descriptions::{can_be_skipped, DescriptionService},
+            descriptions::{
+                can_be_skipped, validate_description, BlurryPolicy, DescriptionService,
+                BLURRY_SUBJECT,
+            },
+            models::ImageQuality,
             ports::XMPMetadata,
         },
         outbound::{
-            image_provider::ImageCrateEncoder, test_mocks::tests::ChatMock, xmp::XMPToolkitMetadata,
+            image_analysis::ImageCrateAnalyzer,
+            image_provider::ImageCrateEncoder,
+            test_mocks::tests::{
+                BudgetExhaustedDescriberMock, FailingEncoderMock, GeocoderMock,
+                HedgingDescriberMock, ModelMock, RefusingDescriberMock, SeriesDescriberMock,
+                StubbornDescriberMock, TextRecognizerMock,
+            },
+            xmp::XMPToolkitMetadata,
         },
     };
     use anyhow::Result;
+    use chrono::DateTime;
     use std::{
         fs::{copy, remove_file},
         path::{Path, PathBuf},
@@ -197,11 +653,20 @@ mod tests {
 
         // Initialize dependencies
         let image_provider = Arc::new(ImageCrateEncoder::new());
-        let chat = Arc::new(ChatMock);
+        let image_analyzer = Arc::new(ImageCrateAnalyzer::new());
+        let chat = Arc::new(ModelMock);
         let xmp_metadata = Arc::new(XMPToolkitMetadata::new());
 
         // Create the DescriptionService instance
-        let service = DescriptionService::new(image_provider, chat, xmp_metadata.clone());
+        let text_recognizer = Arc::new(TextRecognizerMock);
+        let service = DescriptionService::new(
+            image_provider,
+            image_analyzer,
+            Some(text_recognizer),
+            None::<Arc<GeocoderMock>>,
+            chat,
+            xmp_metadata.clone(),
+        );
 
         // Generate descriptions for the files in the temporary directory
         let result = service.generate(&temp_dir.path().into()).await;
@@ -214,6 +679,29 @@ mod tests {
         // Verify the content of the XMP file
         assert_eq!(contents, Some("description".to_string()));
 
+        // The quality scores and the recognized text have been stored along the description
+        assert!(xmp_metadata
+            .get_image_quality(&destination_file_path1)?
+            .is_some());
+        assert_eq!(
+            xmp_metadata.get_recognized_text(&destination_file_path1)?,
+            Some("TRATTORIA DA NINO".to_string())
+        );
+        assert_eq!(
+            xmp_metadata.get_description_prompt(&destination_file_path1)?,
+            Some("mock@1".to_string())
+        );
+
+        // The photo described before is neither analyzed nor read
+        assert_eq!(
+            xmp_metadata.get_image_quality(&destination_file_path3)?,
+            None
+        );
+        assert_eq!(
+            xmp_metadata.get_recognized_text(&destination_file_path3)?,
+            None
+        );
+
         // Clean up by deleting the temporary file(s)
         remove_file(&destination_file_path1)?;
         remove_file(&destination_file_path2)?;
@@ -221,6 +709,316 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_generate_descriptions_structured() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let destination_file_path = temp_dir.path().join("example-full.jpg");
+        copy("testdata/example-full.jpg", &destination_file_path)?;
+
+        let xmp_metadata = Arc::new(XMPToolkitMetadata::new());
+        let service = DescriptionService::new(
+            Arc::new(ImageCrateEncoder::new()),
+            Arc::new(ImageCrateAnalyzer::new()),
+            None::<Arc<TextRecognizerMock>>,
+            None::<Arc<GeocoderMock>>,
+            Arc::new(ModelMock),
+            xmp_metadata.clone(),
+        )
+        .with_structured_output(true);
+
+        assert_eq!(service.generate(&temp_dir.path().into()).await?, 1);
+
+        // The caption is the description, the other fields are stored next to it
+        let analysis = xmp_metadata
+            .get_image_analysis(&destination_file_path)?
+            .expect("The analysis has been stored");
+        assert_eq!(analysis.caption, "description");
+        assert_eq!(analysis.title, "title");
+        assert_eq!(analysis.keywords, vec!["keyword"]);
+
+        // Described again as plain text, the fields of the earlier analysis are removed
+        xmp_metadata.set_description(&destination_file_path, "A photo of a beach")?;
+        let service = DescriptionService::new(
+            Arc::new(ImageCrateEncoder::new()),
+            Arc::new(ImageCrateAnalyzer::new()),
+            None::<Arc<TextRecognizerMock>>,
+            None::<Arc<GeocoderMock>>,
+            Arc::new(ModelMock),
+            xmp_metadata.clone(),
+        );
+        assert_eq!(service.generate(&temp_dir.path().into()).await?, 1);
+        assert_eq!(
+            xmp_metadata.get_description(&destination_file_path)?,
+            Some("description".to_string())
+        );
+        assert_eq!(
+            xmp_metadata.get_image_analysis(&destination_file_path)?,
+            None
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_generate_descriptions_fallback_model() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let destination_file_path = temp_dir.path().join("example-full.jpg");
+        copy("testdata/example-full.jpg", &destination_file_path)?;
+
+        let xmp_metadata = Arc::new(XMPToolkitMetadata::new());
+        let service = DescriptionService::new(
+            Arc::new(ImageCrateEncoder::new()),
+            Arc::new(ImageCrateAnalyzer::new()),
+            None::<Arc<TextRecognizerMock>>,
+            None::<Arc<GeocoderMock>>,
+            Arc::new(RefusingDescriberMock),
+            xmp_metadata.clone(),
+        );
+
+        // The primary model refuses, so the description comes from the fallback
+        service.generate(&temp_dir.path().into()).await?;
+        assert_eq!(
+            xmp_metadata.get_description(&destination_file_path)?,
+            Some("description".to_string())
+        );
+        assert_eq!(
+            xmp_metadata.get_description_model(&destination_file_path)?,
+            Some("mock".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_generate_descriptions_fallback_model_encoding() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let destination_file_path = temp_dir.path().join("example-full.jpg");
+        copy("testdata/example-full.jpg", &destination_file_path)?;
+
+        let xmp_metadata = Arc::new(XMPToolkitMetadata::new());
+        let service = DescriptionService::new(
+            Arc::new(FailingEncoderMock {
+                failing_model: "refusing".to_string(),
+            }),
+            Arc::new(ImageCrateAnalyzer::new()),
+            None::<Arc<TextRecognizerMock>>,
+            None::<Arc<GeocoderMock>>,
+            Arc::new(RefusingDescriberMock),
+            xmp_metadata.clone(),
+        );
+
+        // The photo cannot be encoded for the primary model, so the fallback describes it
+        service.generate(&temp_dir.path().into()).await?;
+        assert_eq!(
+            xmp_metadata.get_description_model(&destination_file_path)?,
+            Some("mock".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_generate_descriptions_regenerate_invalid() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let destination_file_path = temp_dir.path().join("example-full.jpg");
+        copy("testdata/example-full.jpg", &destination_file_path)?;
+
+        let xmp_metadata = Arc::new(XMPToolkitMetadata::new());
+        let service = DescriptionService::new(
+            Arc::new(ImageCrateEncoder::new()),
+            Arc::new(ImageCrateAnalyzer::new()),
+            None::<Arc<TextRecognizerMock>>,
+            None::<Arc<GeocoderMock>>,
+            Arc::new(HedgingDescriberMock),
+            xmp_metadata.clone(),
+        );
+
+        // The hedged description is rejected and generated again with the feedback
+        service.generate(&temp_dir.path().into()).await?;
+        assert_eq!(
+            xmp_metadata.get_description(&destination_file_path)?,
+            Some("A sandy beach at sunset.".to_string())
+        );
+
+        // Without regenerations the invalid description is kept
+        copy("testdata/example-full.jpg", &destination_file_path)?;
+        let service = service.with_max_regenerations(0);
+        service.generate(&temp_dir.path().into()).await?;
+        assert_eq!(
+            xmp_metadata.get_description(&destination_file_path)?,
+            Some("Most likely a beach.".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_generate_descriptions_fallback_invalid() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let destination_file_path = temp_dir.path().join("example-full.jpg");
+        copy("testdata/example-full.jpg", &destination_file_path)?;
+
+        let xmp_metadata = Arc::new(XMPToolkitMetadata::new());
+        let service = DescriptionService::new(
+            Arc::new(ImageCrateEncoder::new()),
+            Arc::new(ImageCrateAnalyzer::new()),
+            None::<Arc<TextRecognizerMock>>,
+            None::<Arc<GeocoderMock>>,
+            Arc::new(StubbornDescriberMock),
+            xmp_metadata.clone(),
+        );
+
+        // The primary model hedges despite the feedback, so the fallback writes the description
+        service.generate(&temp_dir.path().into()).await?;
+        assert_eq!(
+            xmp_metadata.get_description(&destination_file_path)?,
+            Some("description".to_string())
+        );
+        assert_eq!(
+            xmp_metadata.get_description_model(&destination_file_path)?,
+            Some("mock".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_description() {
+        assert!(validate_description("A sandy beach at sunset.").is_ok());
+        assert!(validate_description("  ").is_err());
+        assert!(validate_description("I’m sorry, but I can't identify people.").is_err());
+        assert!(validate_description("As an AI, I cannot see images.").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_generate_descriptions_capture_order() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let evening = temp_dir.path().join("a.jpg");
+        let morning = temp_dir.path().join("b.jpg");
+        copy("testdata/example-full.jpg", &evening)?;
+        copy("testdata/example-full.jpg", &morning)?;
+
+        let xmp_metadata = Arc::new(XMPToolkitMetadata::new());
+        xmp_metadata.set_created(
+            &evening,
+            &DateTime::parse_from_rfc3339("2023-07-14T18:30:00+02:00")?,
+        )?;
+        xmp_metadata.set_created(
+            &morning,
+            &DateTime::parse_from_rfc3339("2023-07-14T09:00:00+02:00")?,
+        )?;
+
+        let service = DescriptionService::new(
+            Arc::new(ImageCrateEncoder::new()),
+            Arc::new(ImageCrateAnalyzer::new()),
+            None::<Arc<TextRecognizerMock>>,
+            None::<Arc<GeocoderMock>>,
+            Arc::new(SeriesDescriberMock),
+            xmp_metadata.clone(),
+        );
+        service.generate(&temp_dir.path().into()).await?;
+
+        // The photo taken later sees the description just generated for the morning photo
+        assert_eq!(
+            xmp_metadata.get_description(&morning)?,
+            Some("A beach in the morning.".to_string())
+        );
+        assert_eq!(
+            xmp_metadata.get_description(&evening)?,
+            Some("A beach in the morning. Later the sun sets.".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_generate_descriptions_budget_exhausted() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        for name in ["a.jpg", "b.jpg", "c.jpg"] {
+            copy("testdata/example-full.jpg", temp_dir.path().join(name))?;
+        }
+
+        let xmp_metadata = Arc::new(XMPToolkitMetadata::new());
+        let service = DescriptionService::new(
+            Arc::new(ImageCrateEncoder::new()),
+            Arc::new(ImageCrateAnalyzer::new()),
+            None::<Arc<TextRecognizerMock>>,
+            None::<Arc<GeocoderMock>>,
+            Arc::new(BudgetExhaustedDescriberMock),
+            xmp_metadata.clone(),
+        );
+
+        // The run ends without error once the photo in flight has failed, the photos of a folder
+        // are described one after another
+        let processed = service.generate(&temp_dir.path().into()).await?;
+        assert_eq!(processed, 1);
+        for name in ["a.jpg", "b.jpg", "c.jpg"] {
+            let description = xmp_metadata.get_description(&temp_dir.path().join(name))?;
+            assert_eq!(description, None);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_generate_descriptions_blurry() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+
+        let destination_file_path = temp_dir.path().join("example-full.jpg");
+        // Copy an existing JPEG file to the temporary directory
+        let source_file = PathBuf::from("testdata/example-full.jpg");
+        copy(&source_file, &destination_file_path)?;
+
+        let xmp_metadata = Arc::new(XMPToolkitMetadata::new());
+
+        // Pretend an earlier analysis found the photo hopelessly blurry
+        let quality = ImageQuality {
+            sharpness: 1.0,
+            ..ImageQuality::default()
+        };
+        xmp_metadata.set_image_quality(&destination_file_path, &quality)?;
+
+        let service = |policy| {
+            DescriptionService::new(
+                Arc::new(ImageCrateEncoder::new()),
+                Arc::new(ImageCrateAnalyzer::new()),
+                None::<Arc<TextRecognizerMock>>,
+                None::<Arc<GeocoderMock>>,
+                Arc::new(ModelMock),
+                xmp_metadata.clone(),
+            )
+            .with_blurry_policy(policy)
+        };
+
+        // Skipped photos are neither described nor tagged
+        service(BlurryPolicy::Skip)
+            .generate(&temp_dir.path().into())
+            .await?;
+        assert_eq!(xmp_metadata.get_description(&destination_file_path)?, None);
+
+        // Tagged photos get described and the blurry keyword
+        service(BlurryPolicy::Tag)
+            .generate(&temp_dir.path().into())
+            .await?;
+        assert_eq!(
+            xmp_metadata.get_description(&destination_file_path)?,
+            Some("description".to_string())
+        );
+        assert_eq!(
+            xmp_metadata.get_hierarchical_subjects(&destination_file_path)?,
+            vec![BLURRY_SUBJECT.to_string()]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_blurry_policy_try_from() {
+        assert_eq!(BlurryPolicy::try_from("Skip").unwrap(), BlurryPolicy::Skip);
+        assert_eq!(BlurryPolicy::try_from("tag").unwrap(), BlurryPolicy::Tag);
+        assert!(BlurryPolicy::try_from("delete").is_err());
+    }
+
     #[test]
     fn test_can_be_skipped() {
         // Test case 1: No description
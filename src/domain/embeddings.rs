@@ -1,56 +1,125 @@
 use super::{
+    descriptions::blocking,
     file_utils::list_jpeg_files,
-    ports::{Chat, VectorDB, XMPMetadata},
+    ports::{ImageAnalyzer, ImageEmbedder, TextEmbedder, VectorDB, XMPMetadata},
+    usage::in_folders,
 };
-use crate::domain::models::VectorInput;
-use anyhow::Result;
+use crate::domain::models::{
+    hierarchy_levels, CameraInfo, ChatError, ImageAnalysis, ImageQuality, PaletteColor,
+    VectorInput, VectorName, VectorOutputList, HIERARCHY_SEPARATOR,
+};
+use anyhow::{bail, Result};
 use futures::stream::{iter, StreamExt};
 use indicatif::{ProgressBar, ProgressStyle};
+use serde_json::{json, Value};
 use std::{
+    cmp::Ordering,
     collections::HashMap,
     hash::{DefaultHasher, Hash, Hasher},
     path::PathBuf,
     sync::Arc,
-    time::Duration,
 };
-use tokio::time::sleep;
 use tracing::{error, info, warn};
 
 // Maximum number of chunks for embeddings API
 const CHUNK_SIZE: usize = 25;
-const COLLECTION_NAME: &str = "photos";
+pub(crate) const COLLECTION_NAME: &str = "photos";
+// Raised whenever the payload gains fields, so that the points stored before are upserted once more
+const PAYLOAD_VERSION: u32 = 1;
+// How much the share of a color in a photo raises its search score when boosting by color
+const COLOR_BOOST: f32 = 0.2;
 
-pub struct EmbeddingsService<C, V, X>
+pub struct EmbeddingsService<C, V, X, A, E>
 where
-    C: Chat,
+    C: TextEmbedder,
     V: VectorDB,
     X: XMPMetadata,
+    A: ImageAnalyzer,
+    E: ImageEmbedder,
 {
-    chat: Arc<C>,
+    text_embedder: Arc<C>,
     xmp_metadata: Arc<X>,
     vector_db: Arc<V>,
+    image_analyzer: Arc<A>,
+    image_embedder: Option<Arc<E>>,
 }
 
-impl<C, V, X> EmbeddingsService<C, V, X>
+impl<C, V, X, A, E> EmbeddingsService<C, V, X, A, E>
 where
-    C: Chat,
+    C: TextEmbedder,
     V: VectorDB,
     X: XMPMetadata,
+    A: ImageAnalyzer + Send + Sync + 'static,
+    E: ImageEmbedder + Send + Sync + 'static,
 {
-    pub fn new(chat: Arc<C>, xmp_metadata: Arc<X>, vector_db: Arc<V>) -> Self {
+    /// Creates the service - without image embedder, only the descriptions are embedded.
+    pub fn new(
+        text_embedder: Arc<C>,
+        xmp_metadata: Arc<X>,
+        vector_db: Arc<V>,
+        image_analyzer: Arc<A>,
+        image_embedder: Option<Arc<E>>,
+    ) -> Self {
         EmbeddingsService {
-            chat,
+            text_embedder,
             xmp_metadata,
             vector_db,
+            image_analyzer,
+            image_embedder,
         }
     }
 
-    pub async fn create_collection(&self) -> Result<()> {
+    /// Recreates the collection, sized for the embeddings of the text embedder.
+    pub async fn create_collection(&self) -> Result<()>
+    where
+        C: Sync,
+    {
+        let dimensions = self.text_embedder.embedding_dimension().await?;
+        info!(
+            "Creating collection {} for {} dimensional embeddings of {}",
+            COLLECTION_NAME,
+            dimensions,
+            self.text_embedder.embedding_model()
+        );
         self.vector_db.delete_collection(COLLECTION_NAME).await?;
-        self.vector_db.create_collection(COLLECTION_NAME).await?;
+        self.vector_db
+            .create_collection(COLLECTION_NAME, dimensions as u64)
+            .await?;
         Ok(())
     }
 
+    /// Creates the collection if it is missing, or recreates it when asked to.
+    ///
+    /// Collections created before the `description` and `image` vectors cannot store the points,
+    /// so they stop the run until they are recreated.
+    ///
+    /// # Arguments
+    ///
+    /// * `recreate` - Deletes the existing collection with all its points first.
+    pub async fn prepare_collection(&self, recreate: bool) -> Result<()>
+    where
+        C: Sync,
+    {
+        if recreate {
+            return self.create_collection().await;
+        }
+
+        match self.vector_db.collection_vectors(COLLECTION_NAME).await? {
+            None => self.create_collection().await,
+            Some(vectors)
+                if [VectorName::Description, VectorName::Image]
+                    .iter()
+                    .all(|name| vectors.iter().any(|vector| vector == name.as_str())) =>
+            {
+                Ok(())
+            }
+            Some(_) => bail!(
+                "Collection {} has no description and image vectors, recreate it with --recreate",
+                COLLECTION_NAME
+            ),
+        }
+    }
+
     pub async fn generate(&self, root_path: &PathBuf) -> Result<()> {
         let files_list = list_jpeg_files(root_path)?;
 
@@ -65,8 +134,13 @@ where
 
         for chunk in chunks {
             progress_bar.inc(chunk.len() as u64);
-            if let Err(e) = self.process_paths(chunk.to_vec()).await {
-                error!("Error processing chunk: {}", e);
+            match self.process_paths(chunk.to_vec()).await {
+                Ok(()) => {}
+                Err(e) if ChatError::is_budget_exhausted(&e) => {
+                    warn!("Stopping the run: {:#}", e);
+                    break;
+                }
+                Err(e) => error!("Error processing chunk: {}", e),
             }
         }
 
@@ -79,7 +153,16 @@ where
         struct EmbeddingTask {
             id: u64,
             description: String,
+            text: String,
+            prompt: Option<String>,
+            model: Option<String>,
+            analysis: Option<ImageAnalysis>,
             path: PathBuf,
+            hierarchical_subjects: Vec<String>,
+            camera_info: CameraInfo,
+            quality: Option<ImageQuality>,
+            palette: Vec<PaletteColor>,
+            image_embedding: Option<Vec<f32>>,
         }
 
         let path_futures = paths.into_iter().map(|path| async move {
@@ -95,14 +178,71 @@ where
                 }
             };
 
+            // The text recognized in the photo is optional - log and continue without it
+            let text = match self.xmp_metadata.get_recognized_text(&path) {
+                Ok(text) => text.unwrap_or_default(),
+                Err(e) => {
+                    warn!("Error extracting text from {}: {}", path.display(), e);
+                    String::new()
+                }
+            };
+
+            // The prompt version allows to compare and regenerate descriptions
+            let prompt = match self.xmp_metadata.get_description_prompt(&path) {
+                Ok(prompt) => prompt,
+                Err(e) => {
+                    warn!("Error extracting prompt from {}: {}", path.display(), e);
+                    None
+                }
+            };
+
+            // The model which wrote the description, the primary one or a fallback
+            let model = match self.xmp_metadata.get_description_model(&path) {
+                Ok(model) => model,
+                Err(e) => {
+                    warn!("Error extracting model from {}: {}", path.display(), e);
+                    None
+                }
+            };
+
+            // Title, keywords and the other fields are only present for JSON descriptions
+            let analysis = match self.xmp_metadata.get_image_analysis(&path) {
+                Ok(analysis) => analysis,
+                Err(e) => {
+                    warn!("Error extracting analysis from {}: {}", path.display(), e);
+                    None
+                }
+            };
+
             // Generate a unique ID for the path
             let id = generate_hash(&path);
 
             // Check for existing entry in the vector database
             if let Ok(Some(existing_entry)) = self.vector_db.find_by_id(COLLECTION_NAME, &id).await
             {
+                let existing_text = existing_entry.payload.get("text");
+                let same_text = match existing_text {
+                    Some(existing_text) => *existing_text == text,
+                    None => text.is_empty(),
+                };
+                // Points stored before the current payload fields or without the image vector
+                // are upserted again, even if the description has not changed
+                let current_payload = existing_entry
+                    .payload
+                    .get("payload_version")
+                    .and_then(|version| version.parse::<u32>().ok())
+                    .is_some_and(|version| version >= PAYLOAD_VERSION);
+                let has_image_vector = self.image_embedder.is_none()
+                    || existing_entry
+                        .payload
+                        .get("image_vector")
+                        .is_some_and(|flag| flag == "true");
                 if let Some(existing_description) = existing_entry.payload.get("description") {
-                    if existing_description.contains(&description) {
+                    if existing_description.contains(&description)
+                        && same_text
+                        && current_payload
+                        && has_image_vector
+                    {
                         // Skip if the description matches
                         info!(
                             "Skipping {}: existing ID with the same description",
@@ -113,11 +253,84 @@ where
                 }
             }
 
+            // Hierarchical keywords are optional - log and continue without them
+            let hierarchical_subjects = match self.xmp_metadata.get_hierarchical_subjects(&path) {
+                Ok(subjects) => subjects,
+                Err(e) => {
+                    warn!(
+                        "Error extracting hierarchical subjects from {}: {}",
+                        path.display(),
+                        e
+                    );
+                    Vec::new()
+                }
+            };
+
+            // Camera settings are optional as well
+            let camera_info = match self.xmp_metadata.get_camera_info(&path) {
+                Ok(camera_info) => camera_info,
+                Err(e) => {
+                    warn!(
+                        "Error extracting camera info from {}: {}",
+                        path.display(),
+                        e
+                    );
+                    CameraInfo::default()
+                }
+            };
+
+            // Quality scores are only present once the descriptions have been generated
+            let quality = match self.xmp_metadata.get_image_quality(&path) {
+                Ok(quality) => quality,
+                Err(e) => {
+                    warn!("Error extracting quality from {}: {}", path.display(), e);
+                    None
+                }
+            };
+
+            // The color palette is computed from the pixels, so searches can filter by color.
+            // The clustering runs on the blocking thread pool to keep the other paths going.
+            let image_analyzer = Arc::clone(&self.image_analyzer);
+            let source = path.clone();
+            let palette = match blocking(move || image_analyzer.extract_palette(&source)).await {
+                Ok(palette) => palette,
+                Err(e) => {
+                    warn!("Error extracting palette from {}: {}", path.display(), e);
+                    Vec::new()
+                }
+            };
+
+            // The image embedding finds what the description missed, with CLIP on the blocking
+            // thread pool as well
+            let image_embedding = match &self.image_embedder {
+                Some(image_embedder) => {
+                    let image_embedder = Arc::clone(image_embedder);
+                    let source = path.clone();
+                    match blocking(move || image_embedder.embed_image(&source)).await {
+                        Ok(embedding) => Some(embedding),
+                        Err(e) => {
+                            warn!("Error embedding image {}: {}", path.display(), e);
+                            None
+                        }
+                    }
+                }
+                None => None,
+            };
+
             // No match found, create and return the task
             Some(EmbeddingTask {
                 id,
                 description,
+                text,
+                prompt,
+                model,
+                analysis,
                 path,
+                hierarchical_subjects,
+                camera_info,
+                quality,
+                palette,
+                image_embedding,
             })
         });
 
@@ -132,18 +345,34 @@ where
             return Ok(());
         }
 
-        // to avoid rate limiting, sleep for a while
-        sleep(Duration::from_millis(100)).await;
-
+        // The text in the photo makes signs, menus and documents searchable by their content
         let descriptions: Vec<_> = embedding_tasks
             .iter()
-            .map(|task| task.description.clone())
+            .map(|task| match task.text.is_empty() {
+                true => task.description.clone(),
+                false => format!("{}\nText in the photo: {}", task.description, task.text),
+            })
             .collect();
-        let embeddings = self.chat.get_embeddings(descriptions).await?;
+
+        // The usage of the batch is split between the folders by the lengths of the texts
+        let shares: Vec<(String, f64)> = embedding_tasks
+            .iter()
+            .zip(&descriptions)
+            .map(|(task, text)| {
+                let folder = task
+                    .path
+                    .parent()
+                    .map(|parent| parent.display().to_string())
+                    .unwrap_or_default();
+                (folder, text.chars().count() as f64)
+            })
+            .collect();
+        let embeddings =
+            in_folders(shares, self.text_embedder.get_embeddings(descriptions)).await?;
 
         let inputs: Vec<VectorInput> = embedding_tasks
             .into_iter()
-            .zip(embeddings.into_iter())
+            .zip(embeddings)
             .map(|(task, embedding)| {
                 let folder_name = task
                     .path
@@ -153,13 +382,37 @@ where
                     .unwrap_or("Unknown")
                     .to_string();
 
-                let payload = HashMap::from([
-                    ("path".to_string(), task.path.display().to_string()),
-                    ("description".to_string(), task.description.clone()),
-                    ("folder".to_string(), folder_name),
+                let mut payload = HashMap::from([
+                    ("path".to_string(), json!(task.path.display().to_string())),
+                    ("description".to_string(), json!(task.description)),
+                    ("folder".to_string(), json!(folder_name)),
+                    ("payload_version".to_string(), json!(PAYLOAD_VERSION)),
+                    (
+                        "image_vector".to_string(),
+                        json!(task.image_embedding.is_some()),
+                    ),
                 ]);
+                if !task.text.is_empty() {
+                    payload.insert("text".to_string(), json!(task.text));
+                }
+                if let Some(prompt) = &task.prompt {
+                    payload.insert("prompt".to_string(), json!(prompt));
+                }
+                if let Some(model) = &task.model {
+                    payload.insert("model".to_string(), json!(model));
+                }
+                if let Some(analysis) = &task.analysis {
+                    payload.extend(analysis_payload(analysis));
+                }
+                payload.extend(hierarchy_payload(&task.hierarchical_subjects));
+                payload.extend(camera_payload(&task.camera_info));
+                if let Some(quality) = &task.quality {
+                    payload.extend(quality_payload(quality));
+                }
+                payload.extend(palette_payload(&task.palette));
 
                 VectorInput::new(task.id, embedding, payload)
+                    .with_image_embedding(task.image_embedding)
             })
             .collect();
 
@@ -172,6 +425,165 @@ where
     }
 }
 
+/// Returns the payload key holding the hierarchical keyword branches of the given depth.
+fn hierarchy_payload_key(depth: usize) -> String {
+    format!("hierarchy_level_{}", depth)
+}
+
+/// Builds one payload field per hierarchy depth, each holding the branches of that depth.
+fn hierarchy_payload(subjects: &[String]) -> HashMap<String, Value> {
+    hierarchy_levels(subjects)
+        .into_iter()
+        .map(|(depth, branches)| (hierarchy_payload_key(depth), json!(branches)))
+        .collect()
+}
+
+/// Builds the payload fields for the known camera settings so that searches can filter on them.
+fn camera_payload(camera_info: &CameraInfo) -> HashMap<String, Value> {
+    let fields = [
+        ("camera_make", camera_info.make.as_ref().map(|v| json!(v))),
+        ("camera_model", camera_info.model.as_ref().map(|v| json!(v))),
+        (
+            "lens_model",
+            camera_info.lens_model.as_ref().map(|v| json!(v)),
+        ),
+        ("focal_length", camera_info.focal_length.map(|v| json!(v))),
+        (
+            "focal_length_35mm",
+            camera_info.focal_length_35mm.map(|v| json!(v)),
+        ),
+        ("aperture", camera_info.aperture.map(|v| json!(v))),
+        ("iso", camera_info.iso.map(|v| json!(v))),
+        ("exposure_time", camera_info.exposure_time.map(|v| json!(v))),
+    ];
+
+    fields
+        .into_iter()
+        .filter_map(|(key, value)| value.map(|value| (key.to_string(), value)))
+        .collect()
+}
+
+/// Builds the payload fields for the quality scores, including a `blurry` flag to filter on.
+fn quality_payload(quality: &ImageQuality) -> HashMap<String, Value> {
+    HashMap::from([
+        ("sharpness".to_string(), json!(quality.sharpness)),
+        (
+            "highlights_clipped".to_string(),
+            json!(quality.highlights_clipped),
+        ),
+        (
+            "shadows_clipped".to_string(),
+            json!(quality.shadows_clipped),
+        ),
+        ("noise".to_string(), json!(quality.noise)),
+        ("blurry".to_string(), json!(quality.is_blurry())),
+    ])
+}
+
+/// Builds the payload fields for the machine readable description, besides the caption which is
+/// the description.
+fn analysis_payload(analysis: &ImageAnalysis) -> HashMap<String, Value> {
+    HashMap::from([
+        ("title".to_string(), json!(analysis.title)),
+        ("keywords".to_string(), json!(analysis.keywords)),
+        ("scene_type".to_string(), json!(analysis.scene_type)),
+        ("people_count".to_string(), json!(analysis.people_count)),
+        ("setting".to_string(), json!(analysis.setting.as_str())),
+        ("confidence".to_string(), json!(analysis.confidence)),
+    ])
+}
+
+/// Returns the payload key holding the share of a named color in a photo.
+fn color_payload_key(color: &str) -> String {
+    format!("color_{}", color)
+}
+
+/// Builds the payload fields for the color palette: the palette itself, the named colors it
+/// contains, the dominant named color and the share of each named color.
+fn palette_payload(palette: &[PaletteColor]) -> HashMap<String, Value> {
+    if palette.is_empty() {
+        return HashMap::new();
+    }
+
+    // Several palette colors may fall into the same named bucket
+    let mut weights: Vec<(&str, f32)> = Vec::new();
+    for color in palette {
+        match weights.iter_mut().find(|(name, _)| *name == color.name()) {
+            Some((_, weight)) => *weight += color.weight,
+            None => weights.push((color.name(), color.weight)),
+        }
+    }
+    weights.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let colors: Vec<Value> = palette
+        .iter()
+        .map(|color| json!({"hex": color.hex(), "name": color.name(), "weight": color.weight}))
+        .collect();
+    let names: Vec<&str> = weights.iter().map(|(name, _)| *name).collect();
+
+    let mut payload = HashMap::from([
+        ("palette".to_string(), json!(colors)),
+        ("colors".to_string(), json!(names)),
+        ("dominant_color".to_string(), json!(weights[0].0)),
+    ]);
+    payload.extend(
+        weights
+            .iter()
+            .map(|(name, weight)| (color_payload_key(name), json!(weight))),
+    );
+    payload
+}
+
+/// Builds a search filter matching all photos where the given named color dominates.
+///
+/// # Arguments
+///
+/// * `color` - One of the named colors in `COLOR_NAMES`, e.g. `orange`.
+pub fn dominant_color_filter(color: &str) -> HashMap<String, String> {
+    HashMap::from([("dominant_color".to_string(), color.to_string())])
+}
+
+/// Raises the scores of search results by the share of the given named color and sorts them again.
+///
+/// # Arguments
+///
+/// * `results` - The search results, which need the `color_<name>` payload fields.
+/// * `color` - One of the named colors in `COLOR_NAMES`, e.g. `orange`.
+pub fn boost_by_color(results: &mut VectorOutputList, color: &str) {
+    let key = color_payload_key(color);
+    for result in results.iter_mut() {
+        let weight: f32 = result
+            .payload
+            .get(&key)
+            .and_then(|weight| weight.parse().ok())
+            .unwrap_or_default();
+        result.score = result.score.map(|score| score + COLOR_BOOST * weight);
+    }
+    results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+}
+
+/// Builds a search filter matching all photos tagged with the given branch or any of its children.
+///
+/// # Arguments
+///
+/// * `branch` - A hierarchical keyword branch, e.g. `Places|Italy`.
+pub fn hierarchy_branch_filter(branch: &str) -> HashMap<String, String> {
+    let levels: Vec<&str> = branch
+        .split(HIERARCHY_SEPARATOR)
+        .map(str::trim)
+        .filter(|level| !level.is_empty())
+        .collect();
+
+    if levels.is_empty() {
+        return HashMap::new();
+    }
+
+    HashMap::from([(
+        hierarchy_payload_key(levels.len()),
+        levels.join(&HIERARCHY_SEPARATOR.to_string()),
+    )])
+}
+
 fn generate_hash(path: &PathBuf) -> u64 {
     let mut hasher = DefaultHasher::new();
     path.hash(&mut hasher);
@@ -180,18 +592,27 @@ fn generate_hash(path: &PathBuf) -> u64 {
 
 #[cfg(test)]
 pub mod tests {
-    use crate::domain::ports::VectorDB;
+    use crate::domain::ports::{VectorDB, XMPMetadata};
     use crate::{
         domain::{
-            embeddings::{generate_hash, EmbeddingsService, COLLECTION_NAME},
-            models::VectorInput,
+            embeddings::{
+                analysis_payload, boost_by_color, camera_payload, dominant_color_filter,
+                generate_hash, hierarchy_branch_filter, hierarchy_payload, palette_payload,
+                quality_payload, EmbeddingsService, COLLECTION_NAME, PAYLOAD_VERSION,
+            },
+            models::{
+                CameraInfo, ImageAnalysis, ImageQuality, PaletteColor, Setting, VectorInput,
+                VectorName, VectorOutput,
+            },
         },
         outbound::{
-            test_mocks::tests::{ChatMock, VectorDBMock},
+            image_analysis::ImageCrateAnalyzer,
+            test_mocks::tests::{ImageEmbedderMock, ModelMock, VectorDBMock},
             xmp::XMPToolkitMetadata,
         },
     };
     use anyhow::Result;
+    use serde_json::json;
     use std::collections::HashMap;
     use std::{
         fs::{copy, remove_file},
@@ -213,19 +634,56 @@ pub mod tests {
         copy(&source_file, &destination_file_path2)?;
 
         // Initialize dependencies
-        let chat = Arc::new(ChatMock);
+        let chat = Arc::new(ModelMock);
         let xmp_metadata = Arc::new(XMPToolkitMetadata::new());
         let vector_db = Arc::new(VectorDBMock::new());
-        vector_db.create_collection(COLLECTION_NAME).await?;
+        vector_db.create_collection(COLLECTION_NAME, 3).await?;
+
+        // The text recognized in the photo is stored along the description
+        xmp_metadata.set_recognized_text(&destination_file_path2, "TRATTORIA DA NINO")?;
 
         // Create the DescriptionService instance
-        let service = EmbeddingsService::new(chat, xmp_metadata.clone(), vector_db);
+        let service = EmbeddingsService::new(
+            chat,
+            xmp_metadata.clone(),
+            vector_db.clone(),
+            Arc::new(ImageCrateAnalyzer::new()),
+            Some(Arc::new(ImageEmbedderMock)),
+        );
 
         // Generate descriptions for the files in the temporary directory
         let result = service.generate(&temp_dir.path().into()).await;
 
         assert!(result.is_ok());
 
+        // The palette has been stored along the description
+        let entry = vector_db
+            .find_by_id(COLLECTION_NAME, &generate_hash(&destination_file_path2))
+            .await?
+            .expect("The described photo has been stored");
+        assert!(entry.payload.contains_key("dominant_color"));
+        assert!(entry.payload["text"].contains("TRATTORIA DA NINO"));
+
+        // A changed text is stored again, even if it is part of the previous one
+        xmp_metadata.set_recognized_text(&destination_file_path2, "TRATTORIA")?;
+        service.generate(&temp_dir.path().into()).await?;
+        let entry = vector_db
+            .find_by_id(COLLECTION_NAME, &generate_hash(&destination_file_path2))
+            .await?
+            .expect("The described photo has been stored");
+        assert_eq!(entry.payload["text"], "TRATTORIA");
+
+        // The image embedding makes the photo searchable by image
+        let found = vector_db
+            .search_points(
+                COLLECTION_NAME,
+                VectorName::Image,
+                &[1.0, 0.0, 0.0],
+                HashMap::new(),
+            )
+            .await?;
+        assert_eq!(found.len(), 1);
+
         // Clean up by deleting the temporary file(s)
         remove_file(&destination_file_path1)?;
         remove_file(&destination_file_path2)?;
@@ -243,38 +701,273 @@ pub mod tests {
         copy(&source_file, &destination_file_path2)?;
 
         // Initialize dependencies
-        let chat = Arc::new(ChatMock);
+        let chat = Arc::new(ModelMock);
         let xmp_metadata = Arc::new(XMPToolkitMetadata::new());
         let vector_db = Arc::new(VectorDBMock::new());
 
         let id_path2 = generate_hash(&destination_file_path2);
 
+        // A point stored before the payload version, without the new payload fields
         let input = vec![VectorInput::new(
             id_path2,
             vec![0.1, 0.2, 0.3],
             HashMap::from([(
                 "description".to_string(),
-                "Existing description".to_string(), // has to be inside the referenced file
+                json!("Existing description"), // has to be inside the referenced file
             )]),
         )];
 
-        vector_db.create_collection(COLLECTION_NAME).await?;
+        vector_db.create_collection(COLLECTION_NAME, 3).await?;
         vector_db.upsert_points(COLLECTION_NAME, &input).await?;
 
         // Create the DescriptionService instance
-        let service = EmbeddingsService::new(chat, xmp_metadata.clone(), vector_db);
+        let service = EmbeddingsService::new(
+            chat,
+            xmp_metadata.clone(),
+            vector_db.clone(),
+            Arc::new(ImageCrateAnalyzer::new()),
+            Some(Arc::new(ImageEmbedderMock)),
+        );
 
         // Generate descriptions for the files in the temporary directory
         let result = service.generate(&temp_dir.path().into()).await;
 
         assert!(result.is_ok());
 
+        // The old point has been upserted again with the payload fields and the image vector
+        let entry = vector_db
+            .find_by_id(COLLECTION_NAME, &id_path2)
+            .await?
+            .expect("The photo has been stored");
+        assert_eq!(
+            entry.payload["payload_version"],
+            PAYLOAD_VERSION.to_string()
+        );
+        assert_eq!(entry.payload["image_vector"], "true");
+        assert!(entry.payload.contains_key("dominant_color"));
+
+        // A current point with the same description is skipped and keeps its payload
+        let mut payload = HashMap::from([
+            ("description".to_string(), json!("Existing description")),
+            ("payload_version".to_string(), json!(PAYLOAD_VERSION)),
+            ("image_vector".to_string(), json!(true)),
+        ]);
+        vector_db
+            .upsert_points(
+                COLLECTION_NAME,
+                &[VectorInput::new(
+                    id_path2,
+                    vec![0.1, 0.2, 0.3],
+                    payload.clone(),
+                )],
+            )
+            .await?;
+        service.generate(&temp_dir.path().into()).await?;
+        let entry = vector_db
+            .find_by_id(COLLECTION_NAME, &id_path2)
+            .await?
+            .expect("The photo has been stored");
+        assert!(!entry.payload.contains_key("dominant_color"));
+
+        // Without the image vector, the point is upserted again
+        payload.insert("image_vector".to_string(), json!(false));
+        vector_db
+            .upsert_points(
+                COLLECTION_NAME,
+                &[VectorInput::new(id_path2, vec![0.1, 0.2, 0.3], payload)],
+            )
+            .await?;
+        service.generate(&temp_dir.path().into()).await?;
+        let entry = vector_db
+            .find_by_id(COLLECTION_NAME, &id_path2)
+            .await?
+            .expect("The photo has been stored");
+        assert!(entry.payload.contains_key("dominant_color"));
+
         // Clean up by deleting the temporary file(s)
         remove_file(&destination_file_path2)?;
 
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_prepare_collection() -> Result<()> {
+        let vector_db = Arc::new(VectorDBMock::new().with_unnamed_vector());
+        let service = EmbeddingsService::new(
+            Arc::new(ModelMock),
+            Arc::new(XMPToolkitMetadata::new()),
+            vector_db.clone(),
+            Arc::new(ImageCrateAnalyzer::new()),
+            None::<Arc<ImageEmbedderMock>>,
+        );
+
+        // A missing collection is created
+        service.prepare_collection(false).await?;
+        assert!(vector_db
+            .collection_vectors(COLLECTION_NAME)
+            .await?
+            .is_some());
+
+        // A collection with a single unnamed vector stops the run until it is recreated
+        let vector_db = Arc::new(VectorDBMock::new().with_unnamed_vector());
+        vector_db.create_collection(COLLECTION_NAME, 3).await?;
+        let service = EmbeddingsService::new(
+            Arc::new(ModelMock),
+            Arc::new(XMPToolkitMetadata::new()),
+            vector_db.clone(),
+            Arc::new(ImageCrateAnalyzer::new()),
+            None::<Arc<ImageEmbedderMock>>,
+        );
+        let error = service.prepare_collection(false).await.unwrap_err();
+        assert!(error.to_string().contains("--recreate"));
+
+        service.prepare_collection(true).await?;
+        assert_eq!(
+            vector_db.collection_vectors(COLLECTION_NAME).await?,
+            Some(vec!["description".to_string(), "image".to_string()])
+        );
+        service.prepare_collection(false).await?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hierarchy_payload() {
+        let subjects = vec![
+            "Places|Italy|Sicily".to_string(),
+            "People|Family|Anna".to_string(),
+        ];
+
+        let payload = hierarchy_payload(&subjects);
+
+        assert_eq!(payload.len(), 3);
+        assert_eq!(payload["hierarchy_level_1"], json!(["Places", "People"]));
+        assert_eq!(
+            payload["hierarchy_level_2"],
+            json!(["Places|Italy", "People|Family"])
+        );
+        assert_eq!(
+            payload["hierarchy_level_3"],
+            json!(["Places|Italy|Sicily", "People|Family|Anna"])
+        );
+    }
+
+    #[test]
+    fn test_camera_payload() {
+        let camera_info = CameraInfo {
+            make: Some("Canon".to_string()),
+            model: Some("Canon EOS 5D Mark III".to_string()),
+            aperture: Some(7.1),
+            iso: Some(100),
+            ..CameraInfo::default()
+        };
+
+        let payload = camera_payload(&camera_info);
+
+        // Unknown settings are left out of the payload
+        assert_eq!(payload.len(), 4);
+        assert_eq!(payload["camera_make"], json!("Canon"));
+        assert_eq!(payload["camera_model"], json!("Canon EOS 5D Mark III"));
+        assert_eq!(payload["aperture"], json!(7.1f32));
+        assert_eq!(payload["iso"], json!(100));
+
+        assert!(camera_payload(&CameraInfo::default()).is_empty());
+    }
+
+    #[test]
+    fn test_quality_payload() {
+        let quality = ImageQuality {
+            sharpness: 5.0,
+            noise: 1.5,
+            ..ImageQuality::default()
+        };
+
+        let payload = quality_payload(&quality);
+
+        assert_eq!(payload["sharpness"], json!(5.0f32));
+        assert_eq!(payload["noise"], json!(1.5f32));
+        assert_eq!(payload["blurry"], json!(true));
+    }
+
+    #[test]
+    fn test_analysis_payload() {
+        let analysis = ImageAnalysis {
+            caption: "A sunny beach.".to_string(),
+            title: "Beach".to_string(),
+            keywords: vec!["beach".to_string(), "sea".to_string()],
+            scene_type: "landscape".to_string(),
+            people_count: 2,
+            setting: Setting::Outdoor,
+            confidence: 0.75,
+        };
+
+        let payload = analysis_payload(&analysis);
+
+        assert_eq!(payload.len(), 6);
+        assert_eq!(payload["keywords"], json!(["beach", "sea"]));
+        assert_eq!(payload["people_count"], json!(2));
+        assert_eq!(payload["setting"], json!("outdoor"));
+    }
+
+    #[test]
+    fn test_palette_payload() {
+        let palette = [
+            PaletteColor {
+                rgb: [255, 140, 0],
+                weight: 0.4,
+            },
+            PaletteColor {
+                rgb: [20, 60, 200],
+                weight: 0.35,
+            },
+            PaletteColor {
+                rgb: [250, 120, 10],
+                weight: 0.25,
+            },
+        ];
+
+        let payload = palette_payload(&palette);
+
+        // Both oranges add up to the dominant color
+        assert_eq!(payload["dominant_color"], json!("orange"));
+        assert_eq!(payload["colors"], json!(["orange", "blue"]));
+        assert_eq!(payload["color_orange"], json!(0.65f32));
+        assert_eq!(payload["color_blue"], json!(0.35f32));
+        assert_eq!(payload["palette"][0]["hex"], json!("#ff8c00"));
+
+        assert!(palette_payload(&[]).is_empty());
+        assert_eq!(dominant_color_filter("orange")["dominant_color"], "orange");
+    }
+
+    #[test]
+    fn test_boost_by_color() {
+        let result = |id, score: f32, orange: &str| VectorOutput {
+            id,
+            score: Some(score),
+            payload: HashMap::from([("color_orange".to_string(), orange.to_string())]),
+        };
+        let mut results = vec![result(1, 0.8, "0.0"), result(2, 0.75, "0.9")];
+
+        boost_by_color(&mut results, "orange");
+
+        assert_eq!(results[0].id, 2);
+        assert_eq!(results[1].score, Some(0.8));
+    }
+
+    #[test]
+    fn test_hierarchy_branch_filter() {
+        let filter = hierarchy_branch_filter("Places|Italy");
+        assert_eq!(
+            filter,
+            HashMap::from([("hierarchy_level_2".to_string(), "Places|Italy".to_string())])
+        );
+
+        let filter = hierarchy_branch_filter(" Places | Italy |");
+        assert_eq!(filter["hierarchy_level_2"], "Places|Italy");
+
+        assert!(hierarchy_branch_filter("").is_empty());
+    }
+
     #[test]
     fn test_generate_hash() {
         // Test case 1: Same path should generate same hash
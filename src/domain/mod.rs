@@ -1,5 +1,10 @@
+pub mod context;
+pub mod conversation;
+pub mod dates;
 pub mod descriptions;
 pub mod embeddings;
 pub mod file_utils;
 pub mod models;
 pub mod ports;
+pub mod usage;
+pub mod validation;
@@ -1,4 +1,17 @@
-use std::collections::HashMap;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, FixedOffset};
+use futures::stream::BoxStream;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::PathBuf,
+    time::Duration,
+};
+
+/// Separator used between the levels of a hierarchical keyword, e.g. `Places|Italy|Sicily`.
+pub const HIERARCHY_SEPARATOR: char = '|';
 
 #[derive(Debug, Clone, Default)]
 pub struct VectorOutput {
@@ -50,21 +63,559 @@ impl VectorOutputListUtils for VectorOutputList {
     }
 }
 
+/// The vectors stored for each photo.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorName {
+    /// The text embedding of the description.
+    Description,
+    /// The CLIP embedding of the image itself.
+    Image,
+}
+
+impl VectorName {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VectorName::Description => "description",
+            VectorName::Image => "image",
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct VectorInput {
     pub id: u64,
+    /// The text embedding of the description.
     pub embedding: Vec<f32>,
-    pub payload: HashMap<String, String>,
+    /// The optional CLIP embedding of the image.
+    pub image_embedding: Option<Vec<f32>>,
+    pub payload: HashMap<String, Value>,
 }
 
 impl VectorInput {
-    pub fn new(id: u64, embedding: Vec<f32>, payload: HashMap<String, String>) -> Self {
+    pub fn new(id: u64, embedding: Vec<f32>, payload: HashMap<String, Value>) -> Self {
         Self {
             id,
             embedding,
+            image_embedding: None,
             payload,
         }
     }
+
+    pub fn with_image_embedding(mut self, image_embedding: Option<Vec<f32>>) -> Self {
+        self.image_embedding = image_embedding;
+        self
+    }
+}
+
+/// An image encoded for a multimodal model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncodedImage {
+    /// The base64 encoded image data.
+    pub base64: String,
+    /// The mime type of the image data, e.g. `image/jpeg`.
+    pub mime_type: String,
+}
+
+// Photos with a sharpness (variance of the Laplacian) below this are too blurry to be useful
+const BLURRY_SHARPNESS: f32 = 20.0;
+
+/// Technical quality scores of a photo, computed locally from its pixels.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ImageQuality {
+    /// Variance of the Laplacian of the grayscale image - low values mean blurry.
+    pub sharpness: f32,
+    /// Fraction of pixels with clipped highlights (almost white).
+    pub highlights_clipped: f32,
+    /// Fraction of pixels with clipped shadows (almost black).
+    pub shadows_clipped: f32,
+    /// Estimated standard deviation of the image noise.
+    pub noise: f32,
+}
+
+impl ImageQuality {
+    /// Returns true if the photo is too blurry to be worth describing.
+    pub fn is_blurry(&self) -> bool {
+        self.sharpness < BLURRY_SHARPNESS
+    }
+}
+
+/// The named color buckets palette colors are sorted into, so that searches can refer to them.
+pub const COLOR_NAMES: [&str; 12] = [
+    "red", "orange", "yellow", "green", "cyan", "blue", "purple", "pink", "brown", "black",
+    "white", "gray",
+];
+
+/// One of the dominant colors of a photo.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PaletteColor {
+    pub rgb: [u8; 3],
+    /// Fraction of the pixels closest to this color.
+    pub weight: f32,
+}
+
+impl PaletteColor {
+    /// Returns the color as hex string, e.g. `#ff8800`.
+    pub fn hex(&self) -> String {
+        let [r, g, b] = self.rgb;
+        format!("#{:02x}{:02x}{:02x}", r, g, b)
+    }
+
+    /// Returns the name of the color bucket, one of `COLOR_NAMES`.
+    pub fn name(&self) -> &'static str {
+        color_name(self.rgb)
+    }
+}
+
+/// Sorts a color into one of the buckets in `COLOR_NAMES` by its hue, saturation and brightness.
+pub fn color_name(rgb: [u8; 3]) -> &'static str {
+    let [r, g, b] = rgb.map(|c| c as f32 / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+    let saturation = if max > 0.0 { delta / max } else { 0.0 };
+
+    if max < 0.2 {
+        return "black";
+    }
+    if saturation < 0.2 {
+        return if max > 0.85 { "white" } else { "gray" };
+    }
+
+    let hue = if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+
+    match hue {
+        h if h < 15.0 => "red",
+        // Dark oranges look brown
+        h if h < 45.0 && max < 0.6 => "brown",
+        h if h < 45.0 => "orange",
+        h if h < 70.0 => "yellow",
+        h if h < 165.0 => "green",
+        h if h < 200.0 => "cyan",
+        h if h < 260.0 => "blue",
+        h if h < 290.0 => "purple",
+        h if h < 340.0 => "pink",
+        _ => "red",
+    }
+}
+
+/// Camera and exposure settings of a photo as found in its metadata.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CameraInfo {
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub lens_model: Option<String>,
+    /// Focal length in millimeters.
+    pub focal_length: Option<f32>,
+    /// Focal length in millimeters equivalent to a 35mm film camera.
+    pub focal_length_35mm: Option<f32>,
+    /// Aperture as f-number.
+    pub aperture: Option<f32>,
+    pub iso: Option<u32>,
+    /// Exposure time in seconds.
+    pub exposure_time: Option<f32>,
+}
+
+impl CameraInfo {
+    /// Returns true if none of the camera settings are known.
+    pub fn is_empty(&self) -> bool {
+        *self == CameraInfo::default()
+    }
+
+    /// Describes notable shooting conditions which help the model to interpret the photo,
+    /// e.g. long exposures, macro or telephoto shots and low light.
+    pub fn hints(&self) -> Vec<String> {
+        let mut hints = Vec::new();
+
+        if let Some(exposure_time) = self.exposure_time {
+            if exposure_time >= 0.5 {
+                hints.push(format!("long exposure of {} seconds", exposure_time));
+            } else if exposure_time <= 1.0 / 2000.0 {
+                hints.push("very short exposure freezing fast motion".to_string());
+            }
+        }
+
+        let is_macro = self
+            .lens_model
+            .as_ref()
+            .is_some_and(|lens| lens.to_lowercase().contains("macro"));
+        if is_macro {
+            hints.push("macro lens close-up".to_string());
+        }
+
+        // Only the 35mm equivalent tells whether a lens is wide or long - fall back to the real focal length
+        if let Some(focal_length) = self.focal_length_35mm.or(self.focal_length) {
+            if focal_length >= 200.0 {
+                hints.push(format!("telephoto shot at {}mm", focal_length));
+            } else if focal_length <= 16.0 && self.focal_length_35mm.is_some() {
+                hints.push(format!("ultra wide angle shot at {}mm", focal_length));
+            }
+        }
+
+        if self.iso.is_some_and(|iso| iso >= 3200) {
+            hints.push("low light scene shot at high ISO".to_string());
+        }
+
+        hints
+    }
+}
+
+/// The text fragments of an answer in the order they are generated - `Send` and `'static`, so
+/// they can be printed as they arrive or passed on, e.g. as server-sent events.
+pub type AnswerStream = BoxStream<'static, Result<String>>;
+
+/// What is known about a photo besides its pixels - the hints for the image model.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DescriptionContext {
+    /// Names of the people in the photo.
+    pub persons: Vec<String>,
+    /// Name of the folder holding the photo, often the place or the event.
+    pub folder: Option<String>,
+    /// GPS coordinates as decimal latitude and longitude.
+    pub location: Option<String>,
+    /// Name of the place at the coordinates, e.g. Cefalù, Sicily, Italy.
+    pub place: Option<String>,
+    /// Capture date, e.g. 2023-07-14.
+    pub date: Option<String>,
+    /// Season at the capture date and place, e.g. summer.
+    pub season: Option<String>,
+    /// Notable camera settings, e.g. a long exposure.
+    pub camera: Vec<String>,
+    /// Descriptions of the photos taken before and after in the same folder, so that the
+    /// descriptions of a trip read as one story.
+    pub previous_description: Option<String>,
+    pub next_description: Option<String>,
+    /// The problems of a rejected description, to be fixed when generating it again.
+    pub feedback: Vec<String>,
+}
+
+/// Whether a photo has been taken inside or outside.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Setting {
+    Indoor,
+    Outdoor,
+    Unknown,
+}
+
+impl Setting {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Setting::Indoor => "indoor",
+            Setting::Outdoor => "outdoor",
+            Setting::Unknown => "unknown",
+        }
+    }
+}
+
+impl TryFrom<&str> for Setting {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value {
+            "indoor" => Ok(Setting::Indoor),
+            "outdoor" => Ok(Setting::Outdoor),
+            "unknown" => Ok(Setting::Unknown),
+            _ => Err(anyhow!("Unknown setting {}", value)),
+        }
+    }
+}
+
+// More keywords are a sign of a rambling model
+const MAX_KEYWORDS: usize = 20;
+
+/// Machine readable description of a photo, as answered by the image model.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImageAnalysis {
+    /// The description of the photo in 2-3 sentences.
+    pub caption: String,
+    /// A short title of a few words.
+    pub title: String,
+    pub keywords: Vec<String>,
+    /// The kind of scene, e.g. landscape, portrait or street.
+    pub scene_type: String,
+    pub people_count: u32,
+    pub setting: Setting,
+    /// How sure the model is about the description, from 0 to 1.
+    pub confidence: f32,
+}
+
+impl ImageAnalysis {
+    /// Returns the JSON schema the model has to answer with.
+    pub fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "caption": { "type": "string", "description": "The description of the photo in 2-3 sentences" },
+                "title": { "type": "string", "description": "A short title of a few words" },
+                "keywords": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "maxItems": MAX_KEYWORDS
+                },
+                "scene_type": { "type": "string", "description": "The kind of scene, e.g. landscape, portrait or street" },
+                "people_count": { "type": "integer", "minimum": 0 },
+                "setting": { "type": "string", "enum": ["indoor", "outdoor", "unknown"] },
+                "confidence": { "type": "number", "minimum": 0, "maximum": 1 }
+            },
+            "required": ["caption", "title", "keywords", "scene_type", "people_count", "setting", "confidence"],
+            "additionalProperties": false
+        })
+    }
+
+    /// Parses and validates the answer of the model against the schema.
+    ///
+    /// Text around the JSON object, e.g. a markdown code fence, is ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - A string slice with the answer of the model.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Self>` - The analysis, or an error describing why the answer is invalid.
+    pub fn from_json(text: &str) -> Result<Self> {
+        let object = match (text.find('{'), text.rfind('}')) {
+            (Some(start), Some(end)) if start < end => &text[start..=end],
+            _ => return Err(anyhow!("The answer contains no JSON object")),
+        };
+
+        let analysis: ImageAnalysis = serde_json::from_str(object)?;
+        analysis.validate()?;
+        Ok(analysis)
+    }
+
+    /// Checks the constraints of the schema which the types do not express.
+    fn validate(&self) -> Result<()> {
+        if self.caption.trim().is_empty() {
+            return Err(anyhow!("caption must not be empty"));
+        }
+        if self.title.trim().is_empty() {
+            return Err(anyhow!("title must not be empty"));
+        }
+        if self.keywords.len() > MAX_KEYWORDS {
+            return Err(anyhow!("keywords must have at most {} items", MAX_KEYWORDS));
+        }
+        if self
+            .keywords
+            .iter()
+            .any(|keyword| keyword.trim().is_empty())
+        {
+            return Err(anyhow!("keywords must not be empty"));
+        }
+        if !(0.0..=1.0).contains(&self.confidence) {
+            return Err(anyhow!("confidence must be between 0 and 1"));
+        }
+        Ok(())
+    }
+}
+
+/// Failures of a chat backend which the services handle besides logging them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChatError {
+    /// The backend refused the call for now - retry after the given time, if known.
+    RateLimited { retry_after: Option<Duration> },
+    /// The spending cap of the day has been reached - the run should stop.
+    BudgetExhausted { spent: f64, budget: f64 },
+}
+
+impl ChatError {
+    /// Returns true if the error, or one of its causes, is an exhausted budget.
+    pub fn is_budget_exhausted(error: &anyhow::Error) -> bool {
+        error.chain().any(|cause| {
+            matches!(
+                cause.downcast_ref::<ChatError>(),
+                Some(ChatError::BudgetExhausted { .. })
+            )
+        })
+    }
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatError::RateLimited {
+                retry_after: Some(retry_after),
+            } => write!(
+                f,
+                "rate limited, retry after {:.1} seconds",
+                retry_after.as_secs_f64()
+            ),
+            ChatError::RateLimited { retry_after: None } => write!(f, "rate limited"),
+            ChatError::BudgetExhausted { spent, budget } => write!(
+                f,
+                "daily budget of {:.2} exhausted, spent {:.2}",
+                budget, spent
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ChatError {}
+
+/// Where an inferred capture date comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DateSource {
+    /// A date encoded in the file name, e.g. `IMG_20230715_123045.jpg`.
+    FileName,
+    /// The capture dates of the previous and next photos in the same folder.
+    Neighbours,
+    /// A date encoded in one of the folder names, e.g. `2023/07-sicily`.
+    FolderName,
+    /// The modification time of the file.
+    FileModified,
+}
+
+impl fmt::Display for DateSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DateSource::FileName => "file name",
+            DateSource::Neighbours => "neighbouring photos",
+            DateSource::FolderName => "folder name",
+            DateSource::FileModified => "file modification time",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A capture date inferred for a photo without one in its metadata.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DateCandidate {
+    pub created: DateTime<FixedOffset>,
+    pub source: DateSource,
+    /// Confidence between 0 and 1 that the date is correct.
+    pub confidence: f32,
+}
+
+/// All capture dates inferred for a single photo, ordered by descending confidence.
+#[derive(Debug, Clone)]
+pub struct DateProposal {
+    pub path: PathBuf,
+    pub candidates: Vec<DateCandidate>,
+}
+
+impl DateProposal {
+    /// Returns the candidate with the highest confidence, if any.
+    pub fn best(&self) -> Option<&DateCandidate> {
+        self.candidates.first()
+    }
+}
+
+/// Splits hierarchical keywords into their individual levels.
+///
+/// `Places|Italy|Sicily` yields `Places`, `Italy` and `Sicily`. Empty levels are dropped and
+/// every keyword is returned only once, in order of first appearance.
+pub fn flatten_hierarchical_subjects(subjects: &[String]) -> Vec<String> {
+    let mut keywords: Vec<String> = Vec::new();
+    for level in subjects
+        .iter()
+        .flat_map(|subject| subject.split(HIERARCHY_SEPARATOR))
+        .map(str::trim)
+        .filter(|level| !level.is_empty())
+    {
+        if !keywords.iter().any(|k| k == level) {
+            keywords.push(level.to_string());
+        }
+    }
+    keywords
+}
+
+/// Groups the branches of hierarchical keywords by their depth (starting at 1).
+///
+/// `Places|Italy|Sicily` yields `Places` at depth 1, `Places|Italy` at depth 2 and
+/// `Places|Italy|Sicily` at depth 3, so that a branch can be matched on its own depth.
+pub fn hierarchy_levels(subjects: &[String]) -> BTreeMap<usize, Vec<String>> {
+    let mut levels: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    for subject in subjects {
+        let parts: Vec<&str> = subject
+            .split(HIERARCHY_SEPARATOR)
+            .map(str::trim)
+            .filter(|level| !level.is_empty())
+            .collect();
+
+        for depth in 1..=parts.len() {
+            let branch = parts[..depth].join(&HIERARCHY_SEPARATOR.to_string());
+            let branches = levels.entry(depth).or_default();
+            if !branches.contains(&branch) {
+                branches.push(branch);
+            }
+        }
+    }
+    levels
+}
+
+/// A question of a conversation with the photos found for it and the answer.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Turn {
+    pub question: String,
+    pub photos: Vec<String>,
+    /// The folders of the photos, e.g. the days or places of a trip.
+    pub folders: Vec<String>,
+    pub answer: String,
+}
+
+/// Which photos the search of a follow-up question is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchScope {
+    /// The whole collection - the question asks about something new.
+    All,
+    /// Only the photos found for the earlier questions, e.g. "which of those were at the beach?".
+    Earlier,
+    /// The folders of the photos found before, e.g. "show me more from that day".
+    Folders,
+}
+
+/// A follow-up question rewritten by the answer model into a search which stands alone.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FollowUp {
+    /// The text to search for, with what "those", "there" or "that day" refer to spelled out.
+    pub query: String,
+    pub scope: SearchScope,
+}
+
+impl FollowUp {
+    /// Returns the JSON schema the model has to answer with.
+    pub fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "query": { "type": "string", "description": "The question as search query which stands alone" },
+                "scope": { "type": "string", "enum": ["all", "earlier", "folders"] }
+            },
+            "required": ["query", "scope"],
+            "additionalProperties": false
+        })
+    }
+
+    /// Parses the answer of the model, ignoring the text around the JSON object.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - A string slice with the answer of the model.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Self>` - The rewritten search, or an error describing why the answer is invalid.
+    pub fn from_json(text: &str) -> Result<Self> {
+        let object = match (text.find('{'), text.rfind('}')) {
+            (Some(start), Some(end)) if start < end => &text[start..=end],
+            _ => return Err(anyhow!("The answer contains no JSON object")),
+        };
+
+        let follow_up: FollowUp = serde_json::from_str(object)?;
+        if follow_up.query.trim().is_empty() {
+            return Err(anyhow!("query must not be empty"));
+        }
+        Ok(follow_up)
+    }
 }
 
 #[cfg(test)]
@@ -125,4 +676,160 @@ mod tests {
         assert_eq!(output_list[0].score, Some(0.5));
         assert_eq!(output_list[1].score, Some(0.8));
     }
+
+    #[test]
+    fn test_color_name() {
+        assert_eq!(color_name([255, 0, 0]), "red");
+        assert_eq!(color_name([255, 140, 0]), "orange");
+        assert_eq!(color_name([120, 70, 20]), "brown");
+        assert_eq!(color_name([250, 220, 30]), "yellow");
+        assert_eq!(color_name([30, 160, 40]), "green");
+        assert_eq!(color_name([20, 60, 200]), "blue");
+        assert_eq!(color_name([255, 105, 180]), "pink");
+        assert_eq!(color_name([10, 10, 10]), "black");
+        assert_eq!(color_name([245, 245, 240]), "white");
+        assert_eq!(color_name([128, 128, 128]), "gray");
+
+        for rgb in [[255, 0, 0], [0, 255, 255], [128, 0, 255], [64, 64, 64]] {
+            assert!(COLOR_NAMES.contains(&color_name(rgb)));
+        }
+
+        let color = PaletteColor {
+            rgb: [255, 136, 0],
+            weight: 0.5,
+        };
+        assert_eq!(color.hex(), "#ff8800");
+        assert_eq!(color.name(), "orange");
+    }
+
+    #[test]
+    fn test_image_analysis_from_json() {
+        let text = r#"```json
+{"caption": "A sunny beach.", "title": "Beach", "keywords": ["beach", "sea"], "scene_type": "landscape", "people_count": 0, "setting": "outdoor", "confidence": 0.9}
+```"#;
+        let analysis = ImageAnalysis::from_json(text).unwrap();
+        assert_eq!(analysis.title, "Beach");
+        assert_eq!(analysis.keywords, vec!["beach", "sea"]);
+        assert_eq!(analysis.setting, Setting::Outdoor);
+
+        // Missing fields, unknown values and violated constraints are rejected
+        assert!(ImageAnalysis::from_json("A sunny beach.").is_err());
+        assert!(ImageAnalysis::from_json(r#"{"caption": "A sunny beach."}"#).is_err());
+        let invalid = [
+            text.replace("outdoor", "underwater"),
+            text.replace("0.9", "90"),
+            text.replace("\"people_count\": 0", "\"people_count\": -1"),
+            text.replace("\"Beach\"", "\"\""),
+            text.replace("\"confidence\"", "\"mood\": \"happy\", \"confidence\""),
+        ];
+        for text in invalid {
+            assert!(ImageAnalysis::from_json(&text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_follow_up_from_json() {
+        let follow_up =
+            FollowUp::from_json(r#"{"query": "Beach photos from Sicily", "scope": "earlier"}"#)
+                .unwrap();
+        assert_eq!(follow_up.query, "Beach photos from Sicily");
+        assert_eq!(follow_up.scope, SearchScope::Earlier);
+
+        assert!(FollowUp::from_json("Beach photos from Sicily").is_err());
+        assert!(FollowUp::from_json(r#"{"query": "Beach", "scope": "yesterday"}"#).is_err());
+        assert!(FollowUp::from_json(r#"{"query": " ", "scope": "all"}"#).is_err());
+    }
+
+    #[test]
+    fn test_image_quality_is_blurry() {
+        let quality = ImageQuality {
+            sharpness: 5.0,
+            ..ImageQuality::default()
+        };
+        assert!(quality.is_blurry());
+
+        let quality = ImageQuality {
+            sharpness: 250.0,
+            ..ImageQuality::default()
+        };
+        assert!(!quality.is_blurry());
+    }
+
+    #[test]
+    fn test_camera_info_hints() {
+        assert!(CameraInfo::default().is_empty());
+        assert!(CameraInfo::default().hints().is_empty());
+
+        let camera_info = CameraInfo {
+            make: Some("Canon".to_string()),
+            lens_model: Some("EF100mm f/2.8L Macro IS USM".to_string()),
+            focal_length: Some(100.0),
+            exposure_time: Some(30.0),
+            iso: Some(6400),
+            ..CameraInfo::default()
+        };
+        assert!(!camera_info.is_empty());
+        assert_eq!(
+            camera_info.hints(),
+            vec![
+                "long exposure of 30 seconds",
+                "macro lens close-up",
+                "low light scene shot at high ISO"
+            ]
+        );
+
+        let camera_info = CameraInfo {
+            focal_length: Some(4.4),
+            focal_length_35mm: Some(400.0),
+            exposure_time: Some(1.0 / 4000.0),
+            ..CameraInfo::default()
+        };
+        assert_eq!(
+            camera_info.hints(),
+            vec![
+                "very short exposure freezing fast motion",
+                "telephoto shot at 400mm"
+            ]
+        );
+
+        // A short real focal length alone does not tell about the angle of view
+        let camera_info = CameraInfo {
+            focal_length: Some(4.4),
+            exposure_time: Some(1.0 / 100.0),
+            ..CameraInfo::default()
+        };
+        assert!(camera_info.hints().is_empty());
+    }
+
+    #[test]
+    fn test_flatten_hierarchical_subjects() {
+        let subjects = vec![
+            "Places|Italy|Sicily".to_string(),
+            "Places|Italy|Rome".to_string(),
+            "People|Family|Anna".to_string(),
+        ];
+
+        let keywords = flatten_hierarchical_subjects(&subjects);
+
+        assert_eq!(
+            keywords,
+            vec!["Places", "Italy", "Sicily", "Rome", "People", "Family", "Anna"]
+        );
+    }
+
+    #[test]
+    fn test_hierarchy_levels() {
+        let subjects = vec![
+            "Places|Italy|Sicily".to_string(),
+            "Places|Italy|Rome".to_string(),
+            "People| Family |".to_string(),
+        ];
+
+        let levels = hierarchy_levels(&subjects);
+
+        assert_eq!(levels.len(), 3);
+        assert_eq!(levels[&1], vec!["Places", "People"]);
+        assert_eq!(levels[&2], vec!["Places|Italy", "People|Family"]);
+        assert_eq!(levels[&3], vec!["Places|Italy|Sicily", "Places|Italy|Rome"]);
+    }
 }
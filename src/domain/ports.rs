@@ -1,27 +1,86 @@
-use super::models::{VectorInput, VectorOutput, VectorOutputList};
-use anyhow::Result;
+use super::models::{
+    AnswerStream, CameraInfo, DescriptionContext, EncodedImage, FollowUp, ImageAnalysis,
+    ImageQuality, PaletteColor, Turn, VectorInput, VectorName, VectorOutput, VectorOutputList,
+};
+use anyhow::{anyhow, Result};
 use chrono::{DateTime, FixedOffset};
 use std::{collections::HashMap, future::Future, path::Path, vec::Vec};
 
-pub trait Chat {
-    /// Asynchronously generates a description for a given base64 encoded image.
+/// A trait for describing photos with multimodal models.
+pub trait VisionDescriber {
+    /// Asynchronously generates a description for a given encoded image.
     ///
     /// # Arguments
     ///
-    /// * `image_base64` - A string slice that contains the base64 encoded image.
-    /// * `persons` - A slice of strings that contains the names of people in the image.
-    /// * `folder_name` - An optional string slice that represents a folder name for context.
+    /// * `images` - A slice of base64 encoded images of the photo - an overview, optionally followed by detail tiles.
+    /// * `context` - A reference to the hints about the photo, e.g. the persons, the folder name and the camera settings.
+    /// * `model` - The name of the multimodal model to ask, one of [`VisionDescriber::image_models`].
     ///
     /// # Returns
     ///
     /// * `Result<String>` - A Result containing a String that represents the description of the image, or an error.
     fn get_image_description(
         &self,
-        image_base64: &str,
-        persons: &[String],
-        folder_name: &Option<String>,
+        images: &[EncodedImage],
+        context: &DescriptionContext,
+        model: &str,
     ) -> impl Future<Output = Result<String>> + Send;
 
+    /// Asynchronously generates a machine readable description for a given encoded image.
+    ///
+    /// The model answers with a JSON object which is validated against [`ImageAnalysis::schema`].
+    /// Invalid answers are sent back once with a repair prompt.
+    ///
+    /// # Arguments
+    ///
+    /// * `images` - A slice of base64 encoded images of the photo - an overview, optionally followed by detail tiles.
+    /// * `context` - A reference to the hints about the photo, e.g. the persons, the folder name and the camera settings.
+    /// * `model` - The name of the multimodal model to ask, one of [`VisionDescriber::image_models`].
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ImageAnalysis>` - A Result containing the validated analysis, or an error if the answer could not be repaired.
+    fn get_image_analysis(
+        &self,
+        images: &[EncodedImage],
+        context: &DescriptionContext,
+        model: &str,
+    ) -> impl Future<Output = Result<ImageAnalysis>> + Send;
+
+    /// Returns the multimodal models for the descriptions in the order to try them - the primary
+    /// model first, followed by its fallbacks.
+    fn image_models(&self) -> Vec<String>;
+
+    /// Returns the name and version of the prompt used for the image descriptions, e.g. `traveler@1`.
+    fn description_prompt(&self) -> String;
+}
+
+/// A trait for embedding texts, e.g. the descriptions and the questions of the searches.
+pub trait TextEmbedder {
+    /// Returns the name of the model generating the embeddings, e.g. `mxbai-embed-large`.
+    fn embedding_model(&self) -> String;
+
+    /// Asynchronously returns the number of dimensions of the embeddings, e.g. 1024 for
+    /// `mxbai-embed-large`.
+    ///
+    /// By default a probe text is embedded, backends knowing their model report it right away.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<usize>` - A Result containing the length of the embedding vectors, or an error.
+    fn embedding_dimension(&self) -> impl Future<Output = Result<usize>> + Send
+    where
+        Self: Sync,
+    {
+        async {
+            let embeddings = self.get_embeddings(vec!["dimension".to_string()]).await?;
+            embeddings
+                .first()
+                .map(Vec::len)
+                .ok_or_else(|| anyhow!("No embedding returned for the probe text"))
+        }
+    }
+
     /// Asynchronously generates embeddings for a given list of texts.
     ///
     /// # Arguments
@@ -36,11 +95,34 @@ pub trait Chat {
         texts: Vec<String>,
     ) -> impl Future<Output = Result<Vec<Vec<f32>>>> + Send;
 
+    /// Asynchronously generates embeddings for the questions of searches.
+    ///
+    /// Some models embed the questions differently from the texts searched, e.g. e5 with the
+    /// `query: ` and `passage: ` prefixes. By default the questions are embedded like any text.
+    ///
+    /// # Arguments
+    ///
+    /// * `queries` - A vector of strings with the questions for which to generate embeddings.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<Vec<f32>>>` - A Result containing a vector of float vectors that represent the embeddings, or an error.
+    fn get_query_embeddings(
+        &self,
+        queries: Vec<String>,
+    ) -> impl Future<Output = Result<Vec<Vec<f32>>>> + Send {
+        self.get_embeddings(queries)
+    }
+}
+
+/// A trait for answering the questions of the searches from the found photos.
+pub trait AnswerGenerator {
     /// Asynchronously processes the results of a search query and returns a response.
     ///
     /// # Arguments
     ///
     /// * `question` - A string slice that contains the search query.
+    /// * `history` - The earlier questions of the conversation with their answers, the oldest first.
     /// * `options` - A slice of strings that contain additional context or parameters for the search.
     ///
     /// # Returns
@@ -49,22 +131,164 @@ pub trait Chat {
     fn process_search_result(
         &self,
         question: &str,
+        history: &[Turn],
         options: &[String],
     ) -> impl Future<Output = Result<String>> + Send;
+
+    /// Asynchronously processes the results of a search query, streaming the response as it is generated.
+    ///
+    /// # Arguments
+    ///
+    /// * `question` - A string slice that contains the search query.
+    /// * `history` - The earlier questions of the conversation with their answers, the oldest first.
+    /// * `options` - A slice of strings that contain additional context or parameters for the search.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<AnswerStream>` - A Result containing the stream of the text fragments of the response, or an error if the request failed.
+    fn stream_search_result(
+        &self,
+        question: &str,
+        history: &[Turn],
+        options: &[String],
+    ) -> impl Future<Output = Result<AnswerStream>> + Send;
+
+    /// Asynchronously rewrites a follow-up question of a conversation into a search which stands
+    /// alone, using the earlier questions and the photos found for them.
+    ///
+    /// # Arguments
+    ///
+    /// * `question` - A string slice that contains the follow-up question.
+    /// * `history` - The earlier questions of the conversation with their photos and answers, the oldest first.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<FollowUp>` - A Result containing the text to search for and which photos to search, or an error.
+    fn rewrite_follow_up(
+        &self,
+        question: &str,
+        history: &[Turn],
+    ) -> impl Future<Output = Result<FollowUp>> + Send;
+}
+
+/// A trait for checking the generated descriptions, e.g. against the rules of the prompt.
+pub trait DescriptionValidator {
+    /// Checks a description.
+    ///
+    /// # Arguments
+    ///
+    /// * `description` - A string slice with the generated description.
+    /// * `context` - A reference to the hints the description has been generated with, e.g. the persons.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<String>` - The problems found, phrased as instructions for the model - empty if the description is fine.
+    fn validate(&self, description: &str, context: &DescriptionContext) -> Vec<String>;
 }
 
 /// A trait for encoding images into base64 strings.
 pub trait ImageEncoder {
-    /// Resizes an image and encodes it into a base64 string.
+    /// Resizes an image and encodes it into base64 strings.
+    ///
+    /// Images with an extreme aspect ratio, e.g. panoramas, are encoded as an overview followed by
+    /// overlapping tiles, so that their details survive the resizing.
     ///
     /// # Arguments
     ///
     /// * `image_path` - A reference to the path of the image to be resized and encoded.
+    /// * `model` - The name of the multimodal model the image is encoded for, e.g. for its native resolution.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<EncodedImage>>` - A Result containing the overview and the tiles in reading order, each with its mime type, or an error.
+    fn resize_and_base64encode_image(
+        &self,
+        image_path: &Path,
+        model: &str,
+    ) -> Result<Vec<EncodedImage>>;
+}
+
+/// A trait for analyzing images locally, without any model.
+pub trait ImageAnalyzer {
+    /// Computes the technical quality scores of an image.
+    ///
+    /// # Arguments
+    ///
+    /// * `image_path` - A reference to the path of the image to be analyzed.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ImageQuality>` - A Result containing the sharpness, exposure clipping and noise scores, or an error.
+    fn analyze_quality(&self, image_path: &Path) -> Result<ImageQuality>;
+
+    /// Extracts the dominant colors of an image.
+    ///
+    /// # Arguments
+    ///
+    /// * `image_path` - A reference to the path of the image to be analyzed.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<PaletteColor>>` - A Result containing the dominant colors sorted by descending weight, or an error.
+    fn extract_palette(&self, image_path: &Path) -> Result<Vec<PaletteColor>>;
+}
+
+/// A trait for embedding images and texts into a shared vector space, e.g. with CLIP.
+pub trait ImageEmbedder {
+    /// Computes the embedding of an image.
+    ///
+    /// # Arguments
+    ///
+    /// * `image_path` - A reference to the path of the image to be embedded.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<f32>>` - A Result containing the normalized embedding of the image, or an error.
+    fn embed_image(&self, image_path: &Path) -> Result<Vec<f32>>;
+
+    /// Computes the embedding of a text, comparable to the image embeddings.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - A string slice with the text to be embedded, e.g. a search query.
     ///
     /// # Returns
     ///
-    /// * `Result<String>` - A Result containing a String that represents the base64 encoded image, or an error.
-    fn resize_and_base64encode_image(&self, image_path: &Path) -> Result<String>;
+    /// * `Result<Vec<f32>>` - A Result containing the normalized embedding of the text, or an error.
+    fn embed_text(&self, text: &str) -> Result<Vec<f32>>;
+}
+
+/// A trait for naming the places of GPS coordinates (reverse geocoding).
+pub trait Geocoder {
+    /// Asynchronously looks up the place at the coordinates.
+    ///
+    /// # Arguments
+    ///
+    /// * `latitude` - The latitude in decimal degrees.
+    /// * `longitude` - The longitude in decimal degrees.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<String>>` - A Result containing the name of the place, e.g. `Cefalù, Sicily, Italy`, None if the place has no name, or an error.
+    fn reverse_geocode(
+        &self,
+        latitude: f64,
+        longitude: f64,
+    ) -> impl Future<Output = Result<Option<String>>> + Send;
+}
+
+/// A trait for recognizing text in images locally (OCR).
+pub trait TextRecognizer {
+    /// Recognizes the lines of text in an image, e.g. on signs, menus, tickets or screenshots.
+    ///
+    /// # Arguments
+    ///
+    /// * `image_path` - A reference to the path of the image to be read.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<String>>` - A Result containing the recognized lines from top to bottom, empty if there is no text, or an error.
+    fn recognize_text(&self, image_path: &Path) -> Result<Vec<String>>;
 }
 
 /// A trait for working with XMP metadata in images.
@@ -114,6 +338,157 @@ pub trait XMPMetadata {
     /// * `Result<Vec<String>>` - A Result containing a vector of strings that represent the persons mentioned in the image metadata, or an error.
     fn get_persons(&self, path: &Path) -> Result<Vec<String>>;
 
+    /// Retrieves the hierarchical keywords (`lr:hierarchicalSubject`) of an image.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - A reference to the path of the image from which to retrieve the keywords.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<String>>` - A Result containing the keywords with their levels separated by `|`, e.g. `Places|Italy|Sicily`, or an error.
+    fn get_hierarchical_subjects(&self, path: &Path) -> Result<Vec<String>>;
+
+    /// Sets the hierarchical keywords (`lr:hierarchicalSubject`) of an image.
+    ///
+    /// Every level of the keywords is also added to the flat `dc:subject` list.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - A reference to the path of the image for which to set the keywords.
+    /// * `subjects` - A slice of keywords with their levels separated by `|`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - A Result indicating success or an error.
+    fn set_hierarchical_subjects(&self, path: &Path, subjects: &[String]) -> Result<()>;
+
+    /// Retrieves the camera and exposure settings of an image.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - A reference to the path of the image from which to retrieve the camera settings.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<CameraInfo>` - A Result containing the camera settings found in the metadata, or an error.
+    fn get_camera_info(&self, path: &Path) -> Result<CameraInfo>;
+
+    /// Retrieves the stored quality scores of an image.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - A reference to the path of the image from which to retrieve the quality scores.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<ImageQuality>>` - A Result containing an Option that represents the quality scores, or an error.
+    fn get_image_quality(&self, path: &Path) -> Result<Option<ImageQuality>>;
+
+    /// Stores the quality scores of an image.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - A reference to the path of the image for which to set the quality scores.
+    /// * `quality` - A reference to the quality scores to be stored.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - A Result indicating success or an error.
+    fn set_image_quality(&self, path: &Path, quality: &ImageQuality) -> Result<()>;
+
+    /// Retrieves the text recognized in an image, e.g. on signs or documents.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - A reference to the path of the image from which to retrieve the recognized text.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<String>>` - A Result containing the recognized text, which is empty for images without text, or None if the image has not been analyzed yet.
+    fn get_recognized_text(&self, path: &Path) -> Result<Option<String>>;
+
+    /// Stores the text recognized in an image.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - A reference to the path of the image for which to set the recognized text.
+    /// * `text` - A string slice with the recognized lines, empty if the image contains no text.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - A Result indicating success or an error.
+    fn set_recognized_text(&self, path: &Path, text: &str) -> Result<()>;
+
+    /// Retrieves the machine readable description of an image.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - A reference to the path of the image from which to retrieve the analysis.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<ImageAnalysis>>` - A Result containing the analysis with the description as caption, or None if the description has been generated as plain text.
+    fn get_image_analysis(&self, path: &Path) -> Result<Option<ImageAnalysis>>;
+
+    /// Stores the fields of a machine readable description besides the caption, which is stored with `set_description`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - A reference to the path of the image for which to set the analysis.
+    /// * `analysis` - A reference to the analysis to be stored, or None to remove the fields of an earlier analysis when the description has been generated as plain text.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - A Result indicating success or an error.
+    fn set_image_analysis(&self, path: &Path, analysis: Option<&ImageAnalysis>) -> Result<()>;
+
+    /// Retrieves the name and version of the prompt the description has been generated with.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - A reference to the path of the image from which to retrieve the prompt.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<String>>` - A Result containing the prompt, e.g. `traveler@1`, or None for descriptions written otherwise.
+    fn get_description_prompt(&self, path: &Path) -> Result<Option<String>>;
+
+    /// Stores the name and version of the prompt the description has been generated with.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - A reference to the path of the image for which to set the prompt.
+    /// * `prompt` - A string slice with the name and version of the prompt.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - A Result indicating success or an error.
+    fn set_description_prompt(&self, path: &Path, prompt: &str) -> Result<()>;
+
+    /// Retrieves the name of the model which generated the description.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - A reference to the path of the image from which to retrieve the model.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<String>>` - A Result containing the model, e.g. `llava:13b`, or None for descriptions written otherwise.
+    fn get_description_model(&self, path: &Path) -> Result<Option<String>>;
+
+    /// Stores the name of the model which generated the description.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - A reference to the path of the image for which to set the model.
+    /// * `model` - A string slice with the name of the model.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - A Result indicating success or an error.
+    fn set_description_model(&self, path: &Path, model: &str) -> Result<()>;
+
     fn get_created(&self, path: &Path) -> Result<DateTime<FixedOffset>>;
 
     fn set_created(&self, path: &Path, created: &DateTime<FixedOffset>) -> Result<()>;
@@ -126,11 +501,16 @@ pub trait VectorDB {
     /// # Arguments
     ///
     /// * `collection` - A string slice that represents the name of the collection to be created.
+    /// * `dimensions` - The number of dimensions of the description embeddings.
     ///
     /// # Returns
     ///
     /// * `Result<bool>` - A Result containing a boolean that indicates whether the collection was successfully created, or an error.
-    fn create_collection(&self, collection: &str) -> impl Future<Output = Result<bool>> + Send;
+    fn create_collection(
+        &self,
+        collection: &str,
+        dimensions: u64,
+    ) -> impl Future<Output = Result<bool>> + Send;
 
     /// Asynchronously deletes a collection from the vector database.
     ///
@@ -143,6 +523,20 @@ pub trait VectorDB {
     /// * `Result<bool>` - A Result containing a boolean that indicates whether the collection was successfully deleted, or an error.
     fn delete_collection(&self, text: &str) -> impl Future<Output = Result<bool>> + Send;
 
+    /// Asynchronously lists the named vectors of a collection in the vector database.
+    ///
+    /// # Arguments
+    ///
+    /// * `collection` - A string slice that represents the name of the collection.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<Vec<String>>>` - The names of the vectors, empty for a collection with a single unnamed vector, or `None` if the collection does not exist.
+    fn collection_vectors(
+        &self,
+        collection: &str,
+    ) -> impl Future<Output = Result<Option<Vec<String>>>> + Send;
+
     /// Asynchronously upserts points into a collection in the vector database.
     ///
     /// # Arguments
@@ -164,6 +558,7 @@ pub trait VectorDB {
     /// # Arguments
     ///
     /// * `collection_name` - A string slice that represents the name of the collection to be searched.
+    /// * `vector_name` - The stored vector to compare with - the description or the image embedding.
     /// * `input_vectors` - A slice of floats that represent the vectors to be searched for.
     /// * `payload_required` - A HashMap that contains the necessary payload for the search.
     ///
@@ -173,6 +568,7 @@ pub trait VectorDB {
     fn search_points(
         &self,
         collection_name: &str,
+        vector_name: VectorName,
         input_vectors: &[f32],
         payload_required: HashMap<String, String>,
     ) -> impl Future<Output = Result<VectorOutputList>> + Send;
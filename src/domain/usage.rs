@@ -0,0 +1,404 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+use futures::{stream::poll_fn, Stream, StreamExt};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    fs::{create_dir_all, write},
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::debug;
+
+tokio::task_local! {
+    // The folders the calls of a task are made for with their shares, see `in_folders`
+    static FOLDERS: Vec<(String, f64)>;
+    // The tokens reported for the calls of a task, see `Measurement`
+    static MEASUREMENT: Measurement;
+}
+
+/// The tokens and the time of a single chat or embedding call, as reported by the backend.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Usage {
+    /// The kind of call, e.g. `description` or `embeddings`.
+    pub operation: &'static str,
+    pub model: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub latency: Duration,
+}
+
+impl Usage {
+    /// Hands the usage of a call to the meter if one is set, and to the [`Measurement`] of the
+    /// task if it runs within one.
+    pub fn record(self, usage_meter: Option<&UsageMeter>) {
+        Measurement::report(&self);
+        if let Some(usage_meter) = usage_meter {
+            usage_meter.record(self);
+        }
+    }
+}
+
+/// Sums up the tokens the backends report for the calls made within its scope, e.g. to replace
+/// the estimate of a rate limited call by what it has actually spent.
+#[derive(Debug, Clone, Default)]
+pub struct Measurement {
+    tokens: Arc<Mutex<Option<u64>>>,
+}
+
+impl Measurement {
+    /// Runs the future with the usage of its calls measured.
+    pub async fn scope<F: Future>(&self, future: F) -> F::Output {
+        MEASUREMENT.scope(self.clone(), future).await
+    }
+
+    /// Measures the usage reported while the stream is polled, e.g. with its last fragment.
+    pub fn stream<S>(&self, mut stream: S) -> impl Stream<Item = S::Item>
+    where
+        S: Stream + Unpin,
+    {
+        let measurement = self.clone();
+        poll_fn(move |cx| {
+            MEASUREMENT.sync_scope(measurement.clone(), || stream.poll_next_unpin(cx))
+        })
+    }
+
+    /// Returns the tokens reported so far, or `None` if the backend has not reported any.
+    pub fn tokens(&self) -> Option<u64> {
+        *self.tokens.lock().expect("measurement lock")
+    }
+
+    /// Adds the tokens of a call to the measurement of the task, if it runs within one.
+    fn report(usage: &Usage) {
+        let _ = MEASUREMENT.try_with(|measurement| {
+            let mut tokens = measurement.tokens.lock().expect("measurement lock");
+            *tokens =
+                Some(tokens.unwrap_or_default() + usage.prompt_tokens + usage.completion_tokens);
+        });
+    }
+}
+
+/// Prices of the models per million tokens, for the prompt and the completion tokens.
+///
+/// Models without price, e.g. local ones, cost nothing.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Prices {
+    models: HashMap<String, (f64, f64)>,
+}
+
+impl Prices {
+    /// Parses a list like `gpt-4o-mini=0.15/0.60, text-embedding-3-small=0.02`, where a single
+    /// price counts for the prompt and the completion tokens alike.
+    pub fn parse(prices: &str) -> Result<Self> {
+        let mut models = HashMap::new();
+        for entry in prices.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (model, price) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid price {}, expected model=price", entry))?;
+            let (prompt, completion) = match price.split_once('/') {
+                Some((prompt, completion)) => (prompt.trim().parse()?, completion.trim().parse()?),
+                None => {
+                    let price: f64 = price.trim().parse()?;
+                    (price, price)
+                }
+            };
+            models.insert(model.trim().to_string(), (prompt, completion));
+        }
+        Ok(Self { models })
+    }
+
+    /// Returns the estimated cost of a call.
+    pub fn cost(&self, usage: &Usage) -> f64 {
+        self.models
+            .get(&usage.model)
+            .map(|(prompt, completion)| {
+                (usage.prompt_tokens as f64 * prompt + usage.completion_tokens as f64 * completion)
+                    / 1_000_000.0
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// The summed up usage of several calls.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct UsageTotals {
+    pub calls: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub latency_seconds: f64,
+    pub cost: f64,
+}
+
+impl UsageTotals {
+    fn add(&mut self, usage: &Usage, cost: f64) {
+        self.add_share(usage, cost, 1.0);
+    }
+
+    /// Adds the share of a call, e.g. of a batch serving several folders - it counts as a call
+    /// of each of them.
+    fn add_share(&mut self, usage: &Usage, cost: f64, share: f64) {
+        self.calls += 1;
+        self.prompt_tokens += (usage.prompt_tokens as f64 * share).round() as u64;
+        self.completion_tokens += (usage.completion_tokens as f64 * share).round() as u64;
+        self.latency_seconds += usage.latency.as_secs_f64() * share;
+        self.cost += cost * share;
+    }
+}
+
+impl fmt::Display for UsageTotals {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} calls, {} prompt and {} completion tokens, {:.1} seconds, cost {:.4}",
+            self.calls, self.prompt_tokens, self.completion_tokens, self.latency_seconds, self.cost
+        )
+    }
+}
+
+/// The usage of a run, in total, per model, per kind of call and per folder of photos.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UsageReport {
+    /// The name of the run, e.g. `descriptions`.
+    pub run: String,
+    pub started: DateTime<Local>,
+    pub finished: DateTime<Local>,
+    pub total: UsageTotals,
+    pub models: BTreeMap<String, UsageTotals>,
+    pub operations: BTreeMap<String, UsageTotals>,
+    pub folders: BTreeMap<String, UsageTotals>,
+}
+
+impl UsageReport {
+    /// Writes the report as JSON into the directory, named after the run and its start.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<PathBuf>` - The path of the report.
+    pub fn write<P: AsRef<Path>>(&self, dir: P) -> Result<PathBuf> {
+        let path = dir.as_ref().join(format!(
+            "{}-usage-{}.json",
+            self.run,
+            self.started.format("%Y%m%d-%H%M%S")
+        ));
+        create_dir_all(dir)?;
+        write(&path, serde_json::to_string_pretty(self)?)?;
+        Ok(path)
+    }
+}
+
+impl fmt::Display for UsageReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Usage of the {} run: {}", self.run, self.total)?;
+        for (model, totals) in &self.models {
+            writeln!(f, "  Model {}: {}", model, totals)?;
+        }
+        for (folder, totals) in &self.folders {
+            writeln!(f, "  Folder {}: {}", folder, totals)?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects the usage of all calls of a run, shared by the chat backends.
+#[derive(Debug)]
+pub struct UsageMeter {
+    prices: Prices,
+    report: Mutex<UsageReport>,
+}
+
+impl UsageMeter {
+    pub fn new(run: &str, prices: Prices) -> Self {
+        let now = Local::now();
+        Self {
+            prices,
+            report: Mutex::new(UsageReport {
+                run: run.to_string(),
+                started: now,
+                finished: now,
+                total: UsageTotals::default(),
+                models: BTreeMap::new(),
+                operations: BTreeMap::new(),
+                folders: BTreeMap::new(),
+            }),
+        }
+    }
+
+    /// Adds a call, to the folders of the task if it runs [`in_folder`] or [`in_folders`].
+    pub fn record(&self, usage: Usage) {
+        let cost = self.prices.cost(&usage);
+        debug!(
+            "Usage: {} {} - {} prompt and {} completion tokens in {:.2} seconds",
+            usage.operation,
+            usage.model,
+            usage.prompt_tokens,
+            usage.completion_tokens,
+            usage.latency.as_secs_f64()
+        );
+
+        let folders = FOLDERS.try_with(Vec::clone).unwrap_or_default();
+        let mut report = self.report.lock().expect("usage lock");
+        report.total.add(&usage, cost);
+        report
+            .models
+            .entry(usage.model.clone())
+            .or_default()
+            .add(&usage, cost);
+        report
+            .operations
+            .entry(usage.operation.to_string())
+            .or_default()
+            .add(&usage, cost);
+        // Without shares, e.g. of empty texts, the folders share the call evenly
+        let total: f64 = folders.iter().map(|(_, share)| share).sum();
+        let count = folders.len() as f64;
+        for (folder, share) in folders {
+            let share = match total > 0.0 {
+                true => share / total,
+                false => 1.0 / count,
+            };
+            report
+                .folders
+                .entry(folder)
+                .or_default()
+                .add_share(&usage, cost, share);
+        }
+    }
+
+    /// Returns the usage so far.
+    pub fn report(&self) -> UsageReport {
+        let mut report = self.report.lock().expect("usage lock").clone();
+        report.finished = Local::now();
+        report
+    }
+}
+
+/// Runs the future with its calls counted for the folder, e.g. the one of the photo it processes.
+pub async fn in_folder<F: Future>(folder: String, future: F) -> F::Output {
+    in_folders([(folder, 1.0)], future).await
+}
+
+/// Runs the future with its calls split between the folders by their shares, e.g. a batch of
+/// texts by the folders of the photos and the lengths of the texts.
+pub async fn in_folders<F, I>(shares: I, future: F) -> F::Output
+where
+    F: Future,
+    I: IntoIterator<Item = (String, f64)>,
+{
+    let mut folders: BTreeMap<String, f64> = BTreeMap::new();
+    for (folder, share) in shares {
+        *folders.entry(folder).or_default() += share;
+    }
+    FOLDERS.scope(folders.into_iter().collect(), future).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(model: &str, prompt_tokens: u64, completion_tokens: u64) -> Usage {
+        Usage {
+            operation: "description",
+            model: model.to_string(),
+            prompt_tokens,
+            completion_tokens,
+            latency: Duration::from_millis(500),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_measurement() {
+        let measurement = Measurement::default();
+        assert_eq!(measurement.tokens(), None);
+
+        measurement
+            .scope(async {
+                usage("gpt-4o", 1000, 100).record(None);
+                usage("gpt-4o", 500, 0).record(None);
+            })
+            .await;
+        // Calls outside the scope are not measured
+        usage("gpt-4o", 500, 0).record(None);
+        assert_eq!(measurement.tokens(), Some(1600));
+
+        // Streams report their usage while they are polled, e.g. with the last fragment
+        let measurement = Measurement::default();
+        let fragments = futures::stream::iter(["A", "beach"]).map(|fragment| {
+            if fragment == "beach" {
+                usage("gpt-4o", 300, 20).record(None);
+            }
+            fragment
+        });
+        let fragments: Vec<_> = measurement.stream(fragments).collect().await;
+        assert_eq!(fragments, vec!["A", "beach"]);
+        assert_eq!(measurement.tokens(), Some(320));
+    }
+
+    #[test]
+    fn test_prices() -> Result<()> {
+        let prices = Prices::parse("gpt-4o-mini=0.15/0.60, text-embedding-3-small = 0.02")?;
+        assert_eq!(prices.cost(&usage("gpt-4o-mini", 1_000_000, 500_000)), 0.45);
+        assert_eq!(
+            prices.cost(&usage("text-embedding-3-small", 2_000_000, 0)),
+            0.04
+        );
+        assert_eq!(prices.cost(&usage("llava:13b", 1_000_000, 1_000_000)), 0.0);
+
+        assert_eq!(Prices::parse("")?, Prices::default());
+        assert!(Prices::parse("gpt-4o-mini").is_err());
+        assert!(Prices::parse("gpt-4o-mini=cheap").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_usage_meter() -> Result<()> {
+        let meter = UsageMeter::new("descriptions", Prices::parse("gpt-4o=2/10")?);
+
+        in_folder("2023/Sicily".to_string(), async {
+            meter.record(usage("gpt-4o", 1000, 100));
+            meter.record(usage("llava:13b", 800, 50));
+        })
+        .await;
+        in_folder("2024/Norway".to_string(), async {
+            meter.record(usage("gpt-4o", 500, 100));
+        })
+        .await;
+        meter.record(usage("gpt-4o", 500, 0));
+
+        let report = meter.report();
+        assert_eq!(report.total.calls, 4);
+        assert_eq!(report.total.prompt_tokens, 2800);
+        assert_eq!(report.total.completion_tokens, 250);
+        assert_eq!(report.total.latency_seconds, 2.0);
+        assert_eq!(report.models["gpt-4o"].calls, 3);
+        assert_eq!(report.models["llava:13b"].cost, 0.0);
+        assert_eq!(report.operations["description"].calls, 4);
+        assert_eq!(report.folders["2023/Sicily"].calls, 2);
+        assert_eq!(report.folders["2023/Sicily"].cost, 0.003);
+        assert_eq!(report.folders["2024/Norway"].prompt_tokens, 500);
+        assert_eq!(report.folders.len(), 2);
+
+        // A batch is split between its folders
+        let meter = UsageMeter::new("embeddings", Prices::default());
+        let shares = [
+            ("2023/Sicily".to_string(), 30.0),
+            ("2024/Norway".to_string(), 10.0),
+            ("2023/Sicily".to_string(), 40.0),
+        ];
+        in_folders(shares, async { meter.record(usage("mxbai", 800, 0)) }).await;
+        let batch = meter.report();
+        assert_eq!(batch.folders["2023/Sicily"].prompt_tokens, 700);
+        assert_eq!(batch.folders["2023/Sicily"].calls, 1);
+        assert_eq!(batch.folders["2024/Norway"].prompt_tokens, 100);
+
+        let temp_dir = tempfile::tempdir()?;
+        let path = report.write(temp_dir.path())?;
+        let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        assert_eq!(json["run"], "descriptions");
+        assert_eq!(json["folders"]["2024/Norway"]["calls"], 1);
+
+        Ok(())
+    }
+}
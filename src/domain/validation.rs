@@ -0,0 +1,217 @@
+use super::{models::DescriptionContext, ports::DescriptionValidator};
+use regex::Regex;
+
+// Descriptions referring to the photo itself are regenerated on the next run, see `can_be_skipped`
+pub const IMAGE_REFERENCE_PATTERN: &str = r"(?i)\b(image|photo|picture|photograph)\b";
+// Words the prompt forbids, as the description should be confident
+const HEDGES: [&str; 9] = [
+    "likely",
+    "perhaps",
+    "probably",
+    "possibly",
+    "maybe",
+    "appears to",
+    "seems to",
+    "might be",
+    "could be",
+];
+// Shorter texts are too short to tell the language reliably
+const MIN_LANGUAGE_CHARS: usize = 40;
+
+/// Checks the descriptions against the rules of the description prompt - no references to the
+/// photo, no hedging, 1-3 sentences, a maximum length, the language and the hinted persons.
+#[derive(Debug, Clone)]
+pub struct DescriptionRules {
+    banned_phrases: Vec<String>,
+    hedges: Vec<String>,
+    max_sentences: usize,
+    max_chars: usize,
+    /// The language the description should be written in, e.g. `English`.
+    language: Option<String>,
+    image_reference: Regex,
+}
+
+impl Default for DescriptionRules {
+    fn default() -> Self {
+        Self {
+            banned_phrases: vec!["this scene".to_string()],
+            hedges: HEDGES.iter().map(|hedge| hedge.to_string()).collect(),
+            max_sentences: 3,
+            max_chars: 600,
+            language: None,
+            image_reference: Regex::new(IMAGE_REFERENCE_PATTERN).expect("valid pattern"),
+        }
+    }
+}
+
+impl DescriptionRules {
+    /// Checks that the descriptions are written in the given language, e.g. `German`.
+    pub fn with_language(mut self, language: &str) -> Self {
+        self.language = Some(language.to_string());
+        self
+    }
+
+    pub fn with_max_sentences(mut self, max_sentences: usize) -> Self {
+        self.max_sentences = max_sentences;
+        self
+    }
+
+    pub fn with_max_chars(mut self, max_chars: usize) -> Self {
+        self.max_chars = max_chars;
+        self
+    }
+
+    /// Adds phrases which must not appear in the descriptions, matched ignoring the case.
+    pub fn with_banned_phrases(mut self, phrases: &[&str]) -> Self {
+        self.banned_phrases
+            .extend(phrases.iter().map(|phrase| phrase.to_lowercase()));
+        self
+    }
+}
+
+impl DescriptionValidator for DescriptionRules {
+    fn validate(&self, description: &str, context: &DescriptionContext) -> Vec<String> {
+        let mut problems = Vec::new();
+        let lowercase = description.to_lowercase();
+
+        if let Some(reference) = self.image_reference.find(description) {
+            problems.push(format!(
+                "Do not refer to the photo itself (\"{}\")",
+                reference.as_str()
+            ));
+        }
+        for phrase in &self.banned_phrases {
+            if contains_phrase(&lowercase, phrase) {
+                problems.push(format!("Do not use the phrase \"{}\"", phrase));
+            }
+        }
+        for hedge in &self.hedges {
+            if contains_phrase(&lowercase, hedge) {
+                problems.push(format!("Be confident, do not use \"{}\"", hedge));
+            }
+        }
+
+        let sentences = count_sentences(description);
+        if sentences > self.max_sentences {
+            problems.push(format!(
+                "Use at most {} sentences instead of {}",
+                self.max_sentences, sentences
+            ));
+        }
+        let chars = description.chars().count();
+        if chars > self.max_chars {
+            problems.push(format!(
+                "Keep it shorter than {} characters instead of {}",
+                self.max_chars, chars
+            ));
+        }
+
+        if let Some(language) = &self.language {
+            if chars >= MIN_LANGUAGE_CHARS {
+                if let Some(info) = whatlang::detect(description) {
+                    let detected = info.lang().eng_name();
+                    if info.is_reliable() && !detected.eq_ignore_ascii_case(language) {
+                        problems.push(format!("Write in {} instead of {}", language, detected));
+                    }
+                }
+            }
+        }
+
+        for person in &context.persons {
+            if !mentions_person(&lowercase, person) {
+                problems.push(format!("Mention {} who is in the photo", person));
+            }
+        }
+
+        problems
+    }
+}
+
+/// Matches whole words only, so `maybe` does not match `Maybelline`.
+fn contains_phrase(lowercase: &str, phrase: &str) -> bool {
+    lowercase.match_indices(phrase).any(|(start, _)| {
+        let before = lowercase[..start].chars().next_back();
+        let after = lowercase[start + phrase.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
+
+/// Counts the sentences ending with `.`, `!` or `?`, and the unterminated last one.
+fn count_sentences(description: &str) -> usize {
+    let mut sentences = 0;
+    let mut in_sentence = false;
+    for c in description.chars() {
+        if matches!(c, '.' | '!' | '?') {
+            if in_sentence {
+                sentences += 1;
+            }
+            in_sentence = false;
+        } else if c.is_alphanumeric() {
+            in_sentence = true;
+        }
+    }
+    sentences + usize::from(in_sentence)
+}
+
+/// Persons count as mentioned by their full or their first name, e.g. `Alice` for `Alice Smith`.
+fn mentions_person(lowercase: &str, person: &str) -> bool {
+    let person = person.to_lowercase();
+    let first_name = person.split_whitespace().next().unwrap_or_default();
+    contains_phrase(lowercase, &person)
+        || (!first_name.is_empty() && contains_phrase(lowercase, first_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_description() {
+        let rules = DescriptionRules::default().with_language("English");
+        let context = DescriptionContext {
+            persons: vec!["Alice Smith".to_string()],
+            ..Default::default()
+        };
+        let description = "Golden light over the harbor of Cefalù, Alice watching the fishing boats return. Salt in the air and the murmur of the evening market.";
+        assert_eq!(rules.validate(description, &context), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_invalid_description() {
+        let rules = DescriptionRules::default()
+            .with_language("English")
+            .with_max_sentences(2);
+        let context = DescriptionContext {
+            persons: vec!["Bob".to_string()],
+            ..Default::default()
+        };
+
+        let problems = rules.validate(
+            "This photo shows a market. It is likely in Palermo. Fresh fish everywhere.",
+            &context,
+        );
+        assert_eq!(
+            problems,
+            vec![
+                "Do not refer to the photo itself (\"photo\")",
+                "Be confident, do not use \"likely\"",
+                "Use at most 2 sentences instead of 3",
+                "Mention Bob who is in the photo",
+            ]
+        );
+
+        let problems = rules.validate(
+            "Ein belebter Markt in der Altstadt von Palermo, frischer Fisch auf Eis und laute Händler, die ihre Waren anpreisen, während die Sonne über den Dächern untergeht.",
+            &DescriptionContext::default(),
+        );
+        assert_eq!(problems, vec!["Write in English instead of German"]);
+    }
+
+    #[test]
+    fn test_count_sentences() {
+        assert_eq!(count_sentences("description"), 1);
+        assert_eq!(count_sentences("A beach. Waves! Wind?"), 3);
+        assert_eq!(count_sentences("Wait... the tide!"), 2);
+        assert_eq!(count_sentences(""), 0);
+    }
+}
@@ -0,0 +1,252 @@
+use super::{bert::BertEmbedder, ollama::Ollama, openai::OpenAI, prompts::PromptTemplates};
+use crate::domain::{
+    models::{AnswerStream, DescriptionContext, EncodedImage, FollowUp, ImageAnalysis, Turn},
+    ports::{AnswerGenerator, TextEmbedder, VisionDescriber},
+    usage::UsageMeter,
+};
+use anyhow::{anyhow, Result};
+use std::{env::var, sync::Arc};
+
+/// What a backend is used for. Every role can be served by another backend and endpoint, e.g.
+/// the descriptions by a local Ollama box and the answers by a hosted API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendRole {
+    /// Describing the photos, configured with the `VISION_*` variables.
+    Vision,
+    /// Embedding the texts, configured with the `EMBEDDING_*` variables.
+    Embeddings,
+    /// Answering the questions of the searches, configured with the `ANSWER_*` variables.
+    Answers,
+}
+
+impl BackendRole {
+    /// Reads the variable of the role, e.g. `VISION_BACKEND` for `BACKEND`.
+    fn var(&self, name: &str) -> Option<String> {
+        let prefix = match self {
+            Self::Vision => "VISION",
+            Self::Embeddings => "EMBEDDING",
+            Self::Answers => "ANSWER",
+        };
+        var(format!("{}_{}", prefix, name)).ok()
+    }
+}
+
+/// The chat backend selected in `CHAT_BACKEND` - the OpenAI compatible API (default), which
+/// Ollama serves under `/v1` too, or the native Ollama API.
+#[derive(Debug, Clone)]
+pub enum ModelBackend {
+    OpenAI(OpenAI),
+    Ollama(Ollama),
+}
+
+impl ModelBackend {
+    /// Creates the backend of the role with the prompt templates.
+    ///
+    /// The role variables override the shared ones - `<ROLE>_BACKEND` (`openai` or `ollama`) the
+    /// `CHAT_BACKEND`, `<ROLE>_API_BASE` the `CHAT_API_BASE` or `OLLAMA_BASE_URL` and
+    /// `<ROLE>_API_KEY` the `CHAT_API_KEY`, where `<ROLE>` is `VISION`, `EMBEDDING` or `ANSWER`.
+    ///
+    /// Ollama gets the `CHAT_API_KEY` only as the shared backend, so a key of OpenAI is not sent
+    /// to the Ollama server of a role.
+    pub fn from_env(role: BackendRole, prompts: PromptTemplates) -> Result<Self> {
+        // load env from .env file
+        dotenv::dotenv().ok();
+        let role_backend = role.var("BACKEND");
+        let shared_backend = role_backend.is_none();
+        let backend = role_backend.or_else(|| var("CHAT_BACKEND").ok());
+        let api_base = role.var("API_BASE");
+
+        match backend.as_deref() {
+            None | Some("openai") => {
+                let mut chat = OpenAI::new().with_prompts(prompts);
+                if let Some(api_base) = api_base {
+                    chat = chat.with_api_base(&api_base);
+                }
+                if let Some(api_key) = role.var("API_KEY") {
+                    chat = chat.with_api_key(&api_key);
+                }
+                Ok(Self::OpenAI(chat))
+            }
+            Some("ollama") => {
+                let mut chat = Ollama::from_env()?.with_prompts(prompts);
+                if let Some(api_base) = api_base {
+                    chat = chat.with_base_url(&api_base);
+                }
+                let api_key = role
+                    .var("API_KEY")
+                    .or_else(|| var("CHAT_API_KEY").ok().filter(|_| shared_backend));
+                if let Some(api_key) = api_key {
+                    chat = chat.with_api_key(&api_key)?;
+                }
+                Ok(Self::Ollama(chat))
+            }
+            Some(backend) => Err(anyhow!(
+                "Unknown backend {} for {:?} - use openai or ollama",
+                backend,
+                role
+            )),
+        }
+    }
+
+    /// Counts the tokens, the time and the cost of all calls.
+    pub fn with_usage_meter(self, usage_meter: Arc<UsageMeter>) -> Self {
+        match self {
+            Self::OpenAI(chat) => Self::OpenAI(chat.with_usage_meter(usage_meter)),
+            Self::Ollama(chat) => Self::Ollama(chat.with_usage_meter(usage_meter)),
+        }
+    }
+
+    /// Logs the models of the run, if the backend can tell about them.
+    pub async fn log_models(&self) -> Result<()> {
+        match self {
+            Self::OpenAI(_) => Ok(()),
+            Self::Ollama(ollama) => ollama.log_models().await,
+        }
+    }
+}
+
+impl VisionDescriber for ModelBackend {
+    async fn get_image_description(
+        &self,
+        images: &[EncodedImage],
+        context: &DescriptionContext,
+        model: &str,
+    ) -> Result<String> {
+        match self {
+            Self::OpenAI(chat) => chat.get_image_description(images, context, model).await,
+            Self::Ollama(chat) => chat.get_image_description(images, context, model).await,
+        }
+    }
+
+    async fn get_image_analysis(
+        &self,
+        images: &[EncodedImage],
+        context: &DescriptionContext,
+        model: &str,
+    ) -> Result<ImageAnalysis> {
+        match self {
+            Self::OpenAI(chat) => chat.get_image_analysis(images, context, model).await,
+            Self::Ollama(chat) => chat.get_image_analysis(images, context, model).await,
+        }
+    }
+
+    fn image_models(&self) -> Vec<String> {
+        match self {
+            Self::OpenAI(chat) => chat.image_models(),
+            Self::Ollama(chat) => chat.image_models(),
+        }
+    }
+
+    fn description_prompt(&self) -> String {
+        match self {
+            Self::OpenAI(chat) => chat.description_prompt(),
+            Self::Ollama(chat) => chat.description_prompt(),
+        }
+    }
+}
+
+impl TextEmbedder for ModelBackend {
+    fn embedding_model(&self) -> String {
+        match self {
+            Self::OpenAI(chat) => chat.embedding_model(),
+            Self::Ollama(chat) => chat.embedding_model(),
+        }
+    }
+
+    async fn get_embeddings(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        match self {
+            Self::OpenAI(chat) => chat.get_embeddings(texts).await,
+            Self::Ollama(chat) => chat.get_embeddings(texts).await,
+        }
+    }
+}
+
+impl AnswerGenerator for ModelBackend {
+    async fn process_search_result(
+        &self,
+        question: &str,
+        history: &[Turn],
+        options: &[String],
+    ) -> Result<String> {
+        match self {
+            Self::OpenAI(chat) => chat.process_search_result(question, history, options).await,
+            Self::Ollama(chat) => chat.process_search_result(question, history, options).await,
+        }
+    }
+
+    async fn stream_search_result(
+        &self,
+        question: &str,
+        history: &[Turn],
+        options: &[String],
+    ) -> Result<AnswerStream> {
+        match self {
+            Self::OpenAI(chat) => chat.stream_search_result(question, history, options).await,
+            Self::Ollama(chat) => chat.stream_search_result(question, history, options).await,
+        }
+    }
+
+    async fn rewrite_follow_up(&self, question: &str, history: &[Turn]) -> Result<FollowUp> {
+        match self {
+            Self::OpenAI(chat) => chat.rewrite_follow_up(question, history).await,
+            Self::Ollama(chat) => chat.rewrite_follow_up(question, history).await,
+        }
+    }
+}
+
+/// The embedding backend of a run - the local model configured in `EMBEDDING_MODEL_DIR`, which
+/// needs no server at all, or else a chat backend.
+pub enum EmbeddingBackend<C> {
+    Chat(C),
+    Local(Box<BertEmbedder>),
+}
+
+impl<C> EmbeddingBackend<C> {
+    /// Loads the local model if one is configured, or else creates the chat backend.
+    ///
+    /// # Arguments
+    ///
+    /// * `chat` - Creates the chat backend, only called without local model.
+    pub fn from_env<F>(chat: F) -> Result<Self>
+    where
+        F: FnOnce() -> Result<C>,
+    {
+        match BertEmbedder::from_env()? {
+            Some(local) => Ok(Self::Local(Box::new(local))),
+            None => chat().map(Self::Chat),
+        }
+    }
+}
+
+impl<C> TextEmbedder for EmbeddingBackend<C>
+where
+    C: TextEmbedder + Sync,
+{
+    fn embedding_model(&self) -> String {
+        match self {
+            Self::Chat(chat) => chat.embedding_model(),
+            Self::Local(local) => local.embedding_model(),
+        }
+    }
+
+    async fn embedding_dimension(&self) -> Result<usize> {
+        match self {
+            Self::Chat(chat) => chat.embedding_dimension().await,
+            Self::Local(local) => local.embedding_dimension().await,
+        }
+    }
+
+    async fn get_embeddings(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        match self {
+            Self::Chat(chat) => chat.get_embeddings(texts).await,
+            Self::Local(local) => local.get_embeddings(texts).await,
+        }
+    }
+
+    async fn get_query_embeddings(&self, queries: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        match self {
+            Self::Chat(chat) => chat.get_query_embeddings(queries).await,
+            Self::Local(local) => local.get_query_embeddings(queries).await,
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use anyhow::{anyhow, Result};
+use candle_core::{Device, Tensor, D};
+use candle_nn::VarBuilder;
+use candle_transformers::models::bert::{BertModel, Config, DTYPE};
+use std::{
+    env::var,
+    fs::{read, read_to_string},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokenizers::{PaddingParams, Tokenizer, TruncationParams};
+use tokio::task::spawn_blocking;
+
+use crate::domain::ports::TextEmbedder;
+
+// The texts are embedded in batches, padded to the longest text of the batch
+const BATCH_SIZE: usize = 16;
+
+/// How the embeddings of the tokens are pooled into the embedding of the text.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Pooling {
+    /// The embedding of the first token, e.g. for bge and mxbai.
+    #[default]
+    Cls,
+    /// The mean of the embeddings of the tokens, e.g. for e5 and the sentence-transformers models.
+    Mean,
+}
+
+impl TryFrom<&str> for Pooling {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "cls" => Ok(Pooling::Cls),
+            "mean" => Ok(Pooling::Mean),
+            _ => Err(anyhow!("Unsupported pooling {}", value)),
+        }
+    }
+}
+
+/// Computes sentence embeddings of texts on the CPU with a BERT model, e.g.
+/// `mixedbread-ai/mxbai-embed-large-v1`, `BAAI/bge-small-en-v1.5` or `intfloat/e5-base-v2`.
+///
+/// The model directory needs the `config.json`, `model.safetensors` and `tokenizer.json` of the
+/// model. The directory name is reported as the name of the model.
+///
+/// The forward pass runs on the blocking threads of the runtime, so the other tasks go on
+/// meanwhile.
+pub struct BertEmbedder {
+    encoder: Arc<Encoder>,
+    model_name: String,
+    dimension: usize,
+    pooling: Pooling,
+    query_prefix: String,
+    passage_prefix: String,
+}
+
+/// The model with its tokenizer, shared with the blocking tasks computing the embeddings.
+struct Encoder {
+    model: BertModel,
+    tokenizer: Tokenizer,
+    device: Device,
+}
+
+impl BertEmbedder {
+    pub fn new<P: AsRef<Path>>(model_dir: P) -> Result<Self> {
+        let model_dir = model_dir.as_ref();
+        let device = Device::Cpu;
+
+        let config: Config = serde_json::from_str(&read_to_string(model_dir.join("config.json"))?)?;
+        let weights = read(model_dir.join("model.safetensors"))?;
+        let vb = VarBuilder::from_buffered_safetensors(weights, DTYPE, &device)?;
+        let model = BertModel::load(vb, &config)?;
+
+        let mut tokenizer = Tokenizer::from_file(model_dir.join("tokenizer.json"))
+            .map_err(|e| anyhow!("Failed to load embedding tokenizer: {}", e))?;
+        // The texts of a batch are padded to the longest one, long texts are cut off at the
+        // positions the model knows
+        if tokenizer.get_padding().is_none() {
+            tokenizer.with_padding(Some(PaddingParams {
+                pad_id: config.pad_token_id as u32,
+                ..Default::default()
+            }));
+        }
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: config.max_position_embeddings,
+                ..Default::default()
+            }))
+            .map_err(|e| anyhow!("Failed to configure embedding tokenizer: {}", e))?;
+
+        let model_name = model_dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("bert")
+            .to_string();
+
+        Ok(Self {
+            encoder: Arc::new(Encoder {
+                model,
+                tokenizer,
+                device,
+            }),
+            model_name,
+            dimension: config.hidden_size,
+            pooling: Pooling::default(),
+            query_prefix: String::new(),
+            passage_prefix: String::new(),
+        })
+    }
+
+    /// Loads the model from the directory configured in `EMBEDDING_MODEL_DIR`, pooled as
+    /// configured in `EMBEDDING_POOLING` (`cls` or `mean`), with the prefixes of
+    /// `EMBEDDING_QUERY_PREFIX` and `EMBEDDING_PASSAGE_PREFIX`, e.g. `query: ` and `passage: ` for
+    /// e5.
+    ///
+    /// Returns `None` if no directory is configured, which leaves the embeddings to the chat
+    /// backend.
+    pub fn from_env() -> Result<Option<Self>> {
+        // load env from .env file
+        dotenv::dotenv().ok();
+        let Ok(model_dir) = var("EMBEDDING_MODEL_DIR") else {
+            return Ok(None);
+        };
+        let pooling = match var("EMBEDDING_POOLING") {
+            Ok(pooling) => Pooling::try_from(pooling.as_str())?,
+            Err(_) => Pooling::default(),
+        };
+        Ok(Some(
+            Self::new(PathBuf::from(model_dir))?
+                .with_pooling(pooling)
+                .with_prefixes(
+                    &var("EMBEDDING_QUERY_PREFIX").unwrap_or_default(),
+                    &var("EMBEDDING_PASSAGE_PREFIX").unwrap_or_default(),
+                ),
+        ))
+    }
+
+    /// Pools the embeddings of the tokens as the model has been trained with.
+    pub fn with_pooling(mut self, pooling: Pooling) -> Self {
+        self.pooling = pooling;
+        self
+    }
+
+    /// Puts the prefixes in front of the questions and the texts searched, as the model has been
+    /// trained with, e.g. `query: ` and `passage: ` for e5.
+    pub fn with_prefixes(mut self, query_prefix: &str, passage_prefix: &str) -> Self {
+        self.query_prefix = query_prefix.to_string();
+        self.passage_prefix = passage_prefix.to_string();
+        self
+    }
+
+    /// Embeds the texts with the prefix in the batches of [`BATCH_SIZE`] on a blocking thread.
+    async fn embed(&self, texts: Vec<String>, prefix: &str) -> Result<Vec<Vec<f32>>> {
+        let texts: Vec<String> = texts
+            .into_iter()
+            .map(|text| format!("{}{}", prefix, text))
+            .collect();
+        let encoder = Arc::clone(&self.encoder);
+        let pooling = self.pooling;
+
+        spawn_blocking(move || {
+            let mut embeddings = Vec::with_capacity(texts.len());
+            for batch in texts.chunks(BATCH_SIZE) {
+                embeddings.extend(encoder.embed_batch(batch, pooling)?);
+            }
+            Ok(embeddings)
+        })
+        .await?
+    }
+}
+
+impl Encoder {
+    fn embed_batch(&self, texts: &[String], pooling: Pooling) -> Result<Vec<Vec<f32>>> {
+        let encodings = self
+            .tokenizer
+            .encode_batch(texts.to_vec(), true)
+            .map_err(|e| anyhow!("Failed to tokenize: {}", e))?;
+
+        let ids = encodings
+            .iter()
+            .map(|encoding| Tensor::new(encoding.get_ids(), &self.device))
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        let masks = encodings
+            .iter()
+            .map(|encoding| Tensor::new(encoding.get_attention_mask(), &self.device))
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        let input_ids = Tensor::stack(&ids, 0)?;
+        let attention_mask = Tensor::stack(&masks, 0)?;
+        let token_type_ids = input_ids.zeros_like()?;
+
+        let hidden_states =
+            self.model
+                .forward(&input_ids, &token_type_ids, Some(&attention_mask))?;
+        let embeddings = pool(&hidden_states, &attention_mask, pooling)?;
+        Ok(normalize(&embeddings)?.to_vec2::<f32>()?)
+    }
+}
+
+impl TextEmbedder for BertEmbedder {
+    fn embedding_model(&self) -> String {
+        self.model_name.clone()
+    }
+
+    async fn embedding_dimension(&self) -> Result<usize> {
+        Ok(self.dimension)
+    }
+
+    async fn get_embeddings(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        self.embed(texts, &self.passage_prefix).await
+    }
+
+    async fn get_query_embeddings(&self, queries: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        self.embed(queries, &self.query_prefix).await
+    }
+}
+
+/// Pools the hidden states `(batch, tokens, hidden)` into one embedding per text.
+fn pool(hidden_states: &Tensor, attention_mask: &Tensor, pooling: Pooling) -> Result<Tensor> {
+    match pooling {
+        Pooling::Cls => Ok(hidden_states.narrow(1, 0, 1)?.squeeze(1)?),
+        Pooling::Mean => {
+            // The padding tokens do not count
+            let mask = attention_mask
+                .to_dtype(hidden_states.dtype())?
+                .unsqueeze(2)?;
+            let sum = hidden_states.broadcast_mul(&mask)?.sum(1)?;
+            Ok(sum.broadcast_div(&mask.sum(1)?)?)
+        }
+    }
+}
+
+/// Scales the embeddings to unit length, so the dot product is the cosine similarity.
+fn normalize(embeddings: &Tensor) -> Result<Tensor> {
+    let norm = embeddings.sqr()?.sum_keepdim(D::Minus1)?.sqrt()?;
+    Ok(embeddings.broadcast_div(&norm)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool() -> Result<()> {
+        let device = Device::Cpu;
+        // Two texts of two tokens, the second token of the second text is padding
+        let hidden_states = Tensor::new(
+            &[[[3f32, 0.0], [1.0, 2.0]], [[0.0, 4.0], [9.0, 9.0]]],
+            &device,
+        )?;
+        let attention_mask = Tensor::new(&[[1u32, 1], [1, 0]], &device)?;
+
+        let cls = pool(&hidden_states, &attention_mask, Pooling::Cls)?;
+        assert_eq!(cls.to_vec2::<f32>()?, vec![vec![3.0, 0.0], vec![0.0, 4.0]]);
+
+        let mean = pool(&hidden_states, &attention_mask, Pooling::Mean)?;
+        assert_eq!(mean.to_vec2::<f32>()?, vec![vec![2.0, 1.0], vec![0.0, 4.0]]);
+
+        let normalized = normalize(&cls)?;
+        assert_eq!(
+            normalized.to_vec2::<f32>()?,
+            vec![vec![1.0, 0.0], vec![0.0, 1.0]]
+        );
+
+        assert_eq!(Pooling::try_from("MEAN")?, Pooling::Mean);
+        assert!(Pooling::try_from("max").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+use anyhow::{anyhow, Result};
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::clip::{div_l2_norm, ClipConfig, ClipModel};
+use image::{imageops::FilterType, DynamicImage};
+use std::{
+    env::var,
+    fs::read,
+    path::{Path, PathBuf},
+};
+use tokenizers::Tokenizer;
+
+use super::thumbnails::{open_oriented, ThumbnailCache};
+use crate::domain::ports::ImageEmbedder;
+
+// CLIP ViT-B/32 looks at 224x224 pixels
+const IMAGE_SIZE: u32 = 224;
+// The text tower only knows this many positions
+const MAX_TOKENS: usize = 77;
+const END_OF_TEXT: &str = "<|endoftext|>";
+// Normalization of the pixels the model has been trained with
+const MEAN: [f32; 3] = [0.481_454_7, 0.457_827_5, 0.408_210_7];
+const STD: [f32; 3] = [0.268_629_5, 0.261_302_6, 0.275_777_1];
+
+/// Computes CLIP embeddings of images and texts on the CPU.
+///
+/// The model directory needs the `model.safetensors` and `tokenizer.json` of
+/// `openai/clip-vit-base-patch32`.
+pub struct ClipEmbedder {
+    model: ClipModel,
+    tokenizer: Tokenizer,
+    device: Device,
+    thumbnail_cache: Option<ThumbnailCache>,
+}
+
+impl ClipEmbedder {
+    pub fn new<P: AsRef<Path>>(model_dir: P) -> Result<Self> {
+        let model_dir = model_dir.as_ref();
+        let device = Device::Cpu;
+
+        let weights = read(model_dir.join("model.safetensors"))?;
+        let vb = VarBuilder::from_buffered_safetensors(weights, DType::F32, &device)?;
+        let model = ClipModel::new(vb, &ClipConfig::vit_base_patch32())?;
+
+        let tokenizer = Tokenizer::from_file(model_dir.join("tokenizer.json"))
+            .map_err(|e| anyhow!("Failed to load CLIP tokenizer: {}", e))?;
+
+        Ok(Self {
+            model,
+            tokenizer,
+            device,
+            thumbnail_cache: None,
+        })
+    }
+
+    /// Loads the model from the directory configured in `CLIP_MODEL_DIR`.
+    ///
+    /// Returns `None` if no directory is configured, which disables the image embeddings.
+    pub fn from_env() -> Result<Option<Self>> {
+        // load env from .env file
+        dotenv::dotenv().ok();
+        match var("CLIP_MODEL_DIR") {
+            Ok(model_dir) => Self::new(PathBuf::from(model_dir)).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Reads the images from the thumbnail cache instead of decoding the full resolution source.
+    pub fn with_thumbnail_cache(mut self, thumbnail_cache: ThumbnailCache) -> Self {
+        self.thumbnail_cache = Some(thumbnail_cache);
+        self
+    }
+
+    fn load(&self, file_path: &Path) -> Result<DynamicImage> {
+        if let Some(cache) = &self.thumbnail_cache {
+            if let Some(size) = cache.size_for(IMAGE_SIZE) {
+                return cache.load(file_path, size);
+            }
+        }
+        open_oriented(file_path)
+    }
+
+    /// Converts a batch of one into the embedding vector.
+    fn to_embedding(features: &Tensor) -> Result<Vec<f32>> {
+        Ok(div_l2_norm(features)?.squeeze(0)?.to_vec1::<f32>()?)
+    }
+}
+
+impl ImageEmbedder for ClipEmbedder {
+    fn embed_image(&self, image_path: &Path) -> Result<Vec<f32>> {
+        let pixels = preprocess(&self.load(image_path)?);
+        let size = IMAGE_SIZE as usize;
+        let pixel_values = Tensor::from_vec(pixels, (1, 3, size, size), &self.device)?;
+
+        let features = self.model.get_image_features(&pixel_values)?;
+        Self::to_embedding(&features)
+    }
+
+    fn embed_text(&self, text: &str) -> Result<Vec<f32>> {
+        let encoding = self
+            .tokenizer
+            .encode(text, true)
+            .map_err(|e| anyhow!("Failed to tokenize {}: {}", text, e))?;
+
+        // Long texts are cut off, but must still end with the end of text token
+        let mut ids = encoding.get_ids().to_vec();
+        if ids.len() > MAX_TOKENS {
+            let end_of_text = self
+                .tokenizer
+                .token_to_id(END_OF_TEXT)
+                .ok_or_else(|| anyhow!("CLIP tokenizer without {}", END_OF_TEXT))?;
+            ids.truncate(MAX_TOKENS - 1);
+            ids.push(end_of_text);
+        }
+
+        let input_ids = Tensor::new(ids.as_slice(), &self.device)?.unsqueeze(0)?;
+        let features = self.model.get_text_features(&input_ids)?;
+        Self::to_embedding(&features)
+    }
+}
+
+/// Scales the shorter edge to the model input size, crops the center square and normalizes the
+/// pixels channel by channel.
+///
+/// Returns the pixels in channel, row, column order.
+fn preprocess(image: &DynamicImage) -> Vec<f32> {
+    let scale = IMAGE_SIZE as f32 / image.width().min(image.height()).max(1) as f32;
+    let width = ((image.width() as f32 * scale).round() as u32).max(IMAGE_SIZE);
+    let height = ((image.height() as f32 * scale).round() as u32).max(IMAGE_SIZE);
+
+    let resized = image.resize_exact(width, height, FilterType::Triangle);
+    let cropped = resized
+        .crop_imm(
+            (width - IMAGE_SIZE) / 2,
+            (height - IMAGE_SIZE) / 2,
+            IMAGE_SIZE,
+            IMAGE_SIZE,
+        )
+        .to_rgb8();
+
+    let mut pixels = Vec::with_capacity(3 * (IMAGE_SIZE * IMAGE_SIZE) as usize);
+    for channel in 0..3 {
+        pixels.extend(
+            cropped
+                .pixels()
+                .map(|p| (p.0[channel] as f32 / 255.0 - MEAN[channel]) / STD[channel]),
+        );
+    }
+    pixels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn test_preprocess() {
+        // A wide image, left half black and right half white
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(800, 400, |x, _| {
+            if x < 400 {
+                Rgb([0, 0, 0])
+            } else {
+                Rgb([255, 255, 255])
+            }
+        }));
+
+        let pixels = preprocess(&image);
+        let size = IMAGE_SIZE as usize;
+        assert_eq!(pixels.len(), 3 * size * size);
+
+        // The center crop keeps both halves, normalized per channel
+        let red_left = pixels[size * size / 2];
+        let red_right = pixels[size * size / 2 + size - 1];
+        assert!((red_left + MEAN[0] / STD[0]).abs() < 0.01, "{}", red_left);
+        assert!(
+            (red_right - (1.0 - MEAN[0]) / STD[0]).abs() < 0.01,
+            "{}",
+            red_right
+        );
+    }
+}
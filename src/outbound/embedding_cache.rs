@@ -0,0 +1,315 @@
+use anyhow::{anyhow, Context, Result};
+use std::{
+    env::var,
+    fs::{create_dir_all, read, rename, write},
+    path::{Path, PathBuf},
+};
+use tracing::{debug, warn};
+
+use crate::domain::ports::TextEmbedder;
+
+const CACHE_DIR: &str = ".photoscanner/embeddings";
+
+/// An on-disk cache of text embeddings, keyed by the embedding model and the hash of the text.
+///
+/// Embeddings are stored as little endian `f32` values in `<cache_dir>/<model>/<hash>.bin`, where
+/// the hash is computed from the text. A changed description gets a new hash and another model a
+/// separate directory, so entries never go stale.
+#[derive(Debug, Clone)]
+pub struct EmbeddingCache {
+    cache_dir: PathBuf,
+}
+
+impl Default for EmbeddingCache {
+    fn default() -> Self {
+        Self::new(CACHE_DIR)
+    }
+}
+
+impl EmbeddingCache {
+    pub fn new<P: AsRef<Path>>(cache_dir: P) -> Self {
+        Self {
+            cache_dir: cache_dir.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Creates the cache in the directory configured in `EMBEDDING_CACHE_DIR`.
+    pub fn from_env() -> Self {
+        // load env from .env file
+        dotenv::dotenv().ok();
+        var("EMBEDDING_CACHE_DIR")
+            .map(Self::new)
+            .unwrap_or_default()
+    }
+
+    /// Returns the cached embedding of a text, if any.
+    pub fn get(&self, model: &str, text: &str) -> Result<Option<Vec<f32>>> {
+        let path = self.embedding_path(model, text);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let bytes =
+            read(&path).with_context(|| format!("Failed to read embedding {}", path.display()))?;
+        if bytes.len() % 4 != 0 {
+            return Err(anyhow!("Invalid cached embedding {}", path.display()));
+        }
+
+        let embedding = bytes
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect();
+        Ok(Some(embedding))
+    }
+
+    /// Stores the embedding of a text.
+    pub fn put(&self, model: &str, text: &str, embedding: &[f32]) -> Result<()> {
+        let path = self.embedding_path(model, text);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+
+        let bytes: Vec<u8> = embedding
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+
+        // Write to a temporary file first, so that concurrent readers never see partial files
+        let temp_path = path.with_extension("bin.tmp");
+        write(&temp_path, bytes)?;
+        rename(&temp_path, &path)?;
+
+        Ok(())
+    }
+
+    fn embedding_path(&self, model: &str, text: &str) -> PathBuf {
+        // Model names like `nomic-embed-text:latest` or `org/model` become one directory
+        let model_dir: String = model
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let hash = blake3::hash(text.as_bytes()).to_hex();
+        self.cache_dir.join(model_dir).join(format!("{}.bin", hash))
+    }
+}
+
+/// Wraps any text embedder, answering the embeddings of known texts from the [`EmbeddingCache`].
+///
+/// Only the texts missing from the cache are sent to the model, so recreating a collection or
+/// switching the vector database needs no model calls for unchanged descriptions.
+pub struct CachedEmbedder<C> {
+    inner: C,
+    cache: EmbeddingCache,
+}
+
+impl<C> CachedEmbedder<C> {
+    pub fn new(inner: C, cache: EmbeddingCache) -> Self {
+        Self { inner, cache }
+    }
+
+    /// Returns the wrapped text embedder.
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Answers the known texts from the cache and embeds the others, as questions of searches
+    /// or as texts searched. The embeddings of the questions are kept apart, as some models embed
+    /// them differently.
+    async fn embed(&self, texts: Vec<String>, queries: bool) -> Result<Vec<Vec<f32>>>
+    where
+        C: TextEmbedder,
+    {
+        let model = match queries {
+            true => format!("{}-query", self.inner.embedding_model()),
+            false => self.inner.embedding_model(),
+        };
+        let mut embeddings: Vec<Option<Vec<f32>>> =
+            texts.iter().map(|text| self.cached(&model, text)).collect();
+
+        let missing: Vec<String> = texts
+            .iter()
+            .zip(&embeddings)
+            .filter(|(_, embedding)| embedding.is_none())
+            .map(|(text, _)| text.clone())
+            .collect();
+        debug!(
+            "Embeddings cache: {} of {} texts found",
+            texts.len() - missing.len(),
+            texts.len()
+        );
+
+        if !missing.is_empty() {
+            let computed = match queries {
+                true => self.inner.get_query_embeddings(missing.clone()).await?,
+                false => self.inner.get_embeddings(missing.clone()).await?,
+            };
+            if computed.len() != missing.len() {
+                return Err(anyhow!(
+                    "Expected {} embeddings, got {}",
+                    missing.len(),
+                    computed.len()
+                ));
+            }
+
+            for (text, embedding) in missing.iter().zip(&computed) {
+                if let Err(e) = self.cache.put(&model, text, embedding) {
+                    warn!("Error caching embedding: {}", e);
+                }
+            }
+
+            // Fill the gaps in the order of the texts
+            let mut computed = computed.into_iter();
+            for embedding in embeddings.iter_mut().filter(|e| e.is_none()) {
+                *embedding = computed.next();
+            }
+        }
+
+        Ok(embeddings.into_iter().flatten().collect())
+    }
+
+    /// Looks up a text, treating unreadable entries as missing.
+    fn cached(&self, model: &str, text: &str) -> Option<Vec<f32>> {
+        self.cache.get(model, text).unwrap_or_else(|e| {
+            warn!("Ignoring cached embedding: {}", e);
+            None
+        })
+    }
+}
+
+impl<C> TextEmbedder for CachedEmbedder<C>
+where
+    C: TextEmbedder + Sync,
+{
+    fn embedding_model(&self) -> String {
+        self.inner.embedding_model()
+    }
+
+    async fn embedding_dimension(&self) -> Result<usize> {
+        self.inner.embedding_dimension().await
+    }
+
+    async fn get_embeddings(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        self.embed(texts, false).await
+    }
+
+    async fn get_query_embeddings(&self, queries: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        self.embed(queries, true).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Embeds a text as its length, counting the embedded texts.
+    struct CountingEmbedder {
+        model: String,
+        embedded: AtomicU32,
+    }
+
+    impl CountingEmbedder {
+        fn new(model: &str) -> Self {
+            Self {
+                model: model.to_string(),
+                embedded: AtomicU32::new(0),
+            }
+        }
+
+        fn embedded(&self) -> u32 {
+            self.embedded.load(Ordering::SeqCst)
+        }
+    }
+
+    impl TextEmbedder for CountingEmbedder {
+        fn embedding_model(&self) -> String {
+            self.model.clone()
+        }
+
+        async fn get_embeddings(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+            self.embedded
+                .fetch_add(texts.len() as u32, Ordering::SeqCst);
+            Ok(texts
+                .iter()
+                .map(|text| vec![text.len() as f32, 0.5])
+                .collect())
+        }
+
+        async fn get_query_embeddings(&self, queries: Vec<String>) -> Result<Vec<Vec<f32>>> {
+            self.embedded
+                .fetch_add(queries.len() as u32, Ordering::SeqCst);
+            Ok(queries
+                .iter()
+                .map(|query| vec![query.len() as f32, 1.0])
+                .collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached_embeddings() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let chat = CachedEmbedder::new(
+            CountingEmbedder::new("nomic-embed-text:latest"),
+            EmbeddingCache::new(temp_dir.path()),
+        );
+
+        let texts = vec!["a beach".to_string(), "mountains".to_string()];
+        let embeddings = chat.get_embeddings(texts.clone()).await?;
+        assert_eq!(embeddings, vec![vec![7.0, 0.5], vec![9.0, 0.5]]);
+        assert_eq!(chat.inner().embedded(), 2);
+
+        // Only the new text is sent, the answers keep the order of the texts
+        let texts = vec![
+            "mountains".to_string(),
+            "a lake".to_string(),
+            "a beach".to_string(),
+        ];
+        let embeddings = chat.get_embeddings(texts).await?;
+        assert_eq!(
+            embeddings,
+            vec![vec![9.0, 0.5], vec![6.0, 0.5], vec![7.0, 0.5]]
+        );
+        assert_eq!(chat.inner().embedded(), 3);
+
+        // Another model does not share the entries
+        let other = CachedEmbedder::new(
+            CountingEmbedder::new("mxbai-embed-large"),
+            EmbeddingCache::new(temp_dir.path()),
+        );
+        other.get_embeddings(vec!["a beach".to_string()]).await?;
+        assert_eq!(other.inner().embedded(), 1);
+
+        // The question of a search does not share the entry of the same text searched
+        let embeddings = chat
+            .get_query_embeddings(vec!["a beach".to_string()])
+            .await?;
+        assert_eq!(embeddings, vec![vec![7.0, 1.0]]);
+        chat.get_query_embeddings(vec!["a beach".to_string()])
+            .await?;
+        assert_eq!(chat.inner().embedded(), 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_embedding_cache() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let cache = EmbeddingCache::new(temp_dir.path());
+
+        assert_eq!(cache.get("model", "text")?, None);
+        cache.put("model", "text", &[0.25, -1.0, f32::MAX])?;
+        assert_eq!(
+            cache.get("model", "text")?,
+            Some(vec![0.25, -1.0, f32::MAX])
+        );
+        assert_eq!(cache.get("model", "other text")?, None);
+
+        Ok(())
+    }
+}
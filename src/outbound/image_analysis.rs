@@ -0,0 +1,307 @@
+use anyhow::Result;
+use image::{DynamicImage, GrayImage};
+use std::{f32::consts::PI, path::Path};
+
+use super::thumbnails::{open_oriented, ThumbnailCache};
+use crate::domain::{
+    models::{ImageQuality, PaletteColor},
+    ports::ImageAnalyzer,
+};
+
+// All scores are computed at the same resolution, so that they are comparable between photos
+const ANALYSIS_SIZE: u32 = 672;
+// Luma values at or beyond these limits count as clipped
+const HIGHLIGHT_LIMIT: u8 = 250;
+const SHADOW_LIMIT: u8 = 5;
+// The palette is clustered from a small version of the image, which is plenty for its colors
+const PALETTE_SAMPLE_SIZE: u32 = 64;
+const PALETTE_SIZE: usize = 5;
+const KMEANS_MAX_ITERATIONS: usize = 20;
+
+#[derive(Debug, Clone, Default)]
+pub struct ImageCrateAnalyzer {
+    thumbnail_cache: Option<ThumbnailCache>,
+}
+
+impl ImageCrateAnalyzer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the images from the thumbnail cache instead of decoding the full resolution source.
+    pub fn with_thumbnail_cache(mut self, thumbnail_cache: ThumbnailCache) -> Self {
+        self.thumbnail_cache = Some(thumbnail_cache);
+        self
+    }
+
+    /// Loads the upright image downsized to the analysis resolution.
+    fn load(&self, file_path: &Path) -> Result<DynamicImage> {
+        let image = match &self.thumbnail_cache {
+            Some(cache) => match cache.size_for(ANALYSIS_SIZE) {
+                Some(size) => cache.load(file_path, size)?,
+                None => open_oriented(file_path)?,
+            },
+            None => open_oriented(file_path)?,
+        };
+        Ok(image.thumbnail(ANALYSIS_SIZE, ANALYSIS_SIZE))
+    }
+}
+
+impl ImageAnalyzer for ImageCrateAnalyzer {
+    fn analyze_quality(&self, image_path: &Path) -> Result<ImageQuality> {
+        let luma = self.load(image_path)?.to_luma8();
+
+        let (highlights_clipped, shadows_clipped) = clipping(&luma);
+        Ok(ImageQuality {
+            sharpness: laplacian_variance(&luma),
+            highlights_clipped,
+            shadows_clipped,
+            noise: noise_sigma(&luma),
+        })
+    }
+
+    fn extract_palette(&self, image_path: &Path) -> Result<Vec<PaletteColor>> {
+        let pixels: Vec<[f32; 3]> = self
+            .load(image_path)?
+            .thumbnail(PALETTE_SAMPLE_SIZE, PALETTE_SAMPLE_SIZE)
+            .to_rgb8()
+            .pixels()
+            .map(|p| p.0.map(f32::from))
+            .collect();
+
+        Ok(kmeans_palette(&pixels, PALETTE_SIZE))
+    }
+}
+
+/// Clusters the pixels into at most `k` colors with k-means, sorted by descending weight.
+fn kmeans_palette(pixels: &[[f32; 3]], k: usize) -> Vec<PaletteColor> {
+    let k = k.min(pixels.len());
+    if k == 0 {
+        return Vec::new();
+    }
+
+    // Spread the initial centroids over the pixels sorted by brightness, so results are repeatable
+    let mut sorted = pixels.to_vec();
+    sorted.sort_by(|a, b| brightness(a).total_cmp(&brightness(b)));
+    let mut centroids: Vec<[f32; 3]> = (0..k)
+        .map(|i| sorted[(2 * i + 1) * sorted.len() / (2 * k)])
+        .collect();
+
+    let mut assignments = vec![usize::MAX; pixels.len()];
+    let mut counts = vec![0usize; k];
+    for _ in 0..KMEANS_MAX_ITERATIONS {
+        let mut changed = false;
+        for (pixel, assignment) in pixels.iter().zip(assignments.iter_mut()) {
+            let nearest = nearest_centroid(&centroids, pixel);
+            if nearest != *assignment {
+                *assignment = nearest;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+
+        // Move each centroid to the mean of its pixels - empty clusters stay where they are
+        let mut sums = vec![[0.0f32; 3]; k];
+        counts = vec![0; k];
+        for (pixel, assignment) in pixels.iter().zip(&assignments) {
+            for (sum, channel) in sums[*assignment].iter_mut().zip(pixel) {
+                *sum += channel;
+            }
+            counts[*assignment] += 1;
+        }
+        for ((centroid, sum), count) in centroids.iter_mut().zip(&sums).zip(&counts) {
+            if *count > 0 {
+                *centroid = sum.map(|channel| channel / *count as f32);
+            }
+        }
+    }
+
+    let mut palette: Vec<PaletteColor> = centroids
+        .iter()
+        .zip(&counts)
+        .filter(|(_, count)| **count > 0)
+        .map(|(centroid, count)| PaletteColor {
+            rgb: centroid.map(|channel| channel.round() as u8),
+            weight: *count as f32 / pixels.len() as f32,
+        })
+        .collect();
+    palette.sort_by(|a, b| b.weight.total_cmp(&a.weight));
+    palette
+}
+
+fn brightness(pixel: &[f32; 3]) -> f32 {
+    0.299 * pixel[0] + 0.587 * pixel[1] + 0.114 * pixel[2]
+}
+
+fn nearest_centroid(centroids: &[[f32; 3]], pixel: &[f32; 3]) -> usize {
+    let distance = |centroid: &[f32; 3]| -> f32 {
+        centroid
+            .iter()
+            .zip(pixel)
+            .map(|(c, p)| (c - p) * (c - p))
+            .sum()
+    };
+    (0..centroids.len())
+        .min_by(|a, b| distance(&centroids[*a]).total_cmp(&distance(&centroids[*b])))
+        .unwrap_or_default()
+}
+
+/// Variance of the 4-neighbour Laplacian - edges in sharp photos make it large.
+fn laplacian_variance(luma: &GrayImage) -> f32 {
+    let (width, height) = luma.dimensions();
+    if width < 3 || height < 3 {
+        return 0.0;
+    }
+
+    let pixel = |x: u32, y: u32| luma.get_pixel(x, y).0[0] as f64;
+    let mut sum = 0.0;
+    let mut sum_squares = 0.0;
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let laplacian = pixel(x - 1, y) + pixel(x + 1, y) + pixel(x, y - 1) + pixel(x, y + 1)
+                - 4.0 * pixel(x, y);
+            sum += laplacian;
+            sum_squares += laplacian * laplacian;
+        }
+    }
+
+    let count = ((width - 2) * (height - 2)) as f64;
+    let mean = sum / count;
+    (sum_squares / count - mean * mean) as f32
+}
+
+/// Fractions of pixels with clipped highlights and clipped shadows.
+fn clipping(luma: &GrayImage) -> (f32, f32) {
+    let count = luma.pixels().len().max(1) as f32;
+    let highlights = luma.pixels().filter(|p| p.0[0] >= HIGHLIGHT_LIMIT).count() as f32;
+    let shadows = luma.pixels().filter(|p| p.0[0] <= SHADOW_LIMIT).count() as f32;
+    (highlights / count, shadows / count)
+}
+
+/// Estimates the standard deviation of the noise with the method of Immerkær
+/// ("Fast Noise Variance Estimation", 1996), which cancels out most of the image structure.
+fn noise_sigma(luma: &GrayImage) -> f32 {
+    let (width, height) = luma.dimensions();
+    if width < 3 || height < 3 {
+        return 0.0;
+    }
+
+    const MASK: [[f64; 3]; 3] = [[1.0, -2.0, 1.0], [-2.0, 4.0, -2.0], [1.0, -2.0, 1.0]];
+
+    let mut sum = 0.0;
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let mut response = 0.0;
+            for (dy, row) in MASK.iter().enumerate() {
+                for (dx, weight) in row.iter().enumerate() {
+                    response +=
+                        weight * luma.get_pixel(x + dx as u32 - 1, y + dy as u32 - 1).0[0] as f64;
+                }
+            }
+            sum += response.abs();
+        }
+    }
+
+    let count = ((width - 2) * (height - 2)) as f64;
+    ((PI as f64 / 2.0).sqrt() * sum / (6.0 * count)) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{imageops::blur, Luma};
+
+    #[test]
+    fn test_analyze_quality() -> Result<()> {
+        let analyzer = ImageCrateAnalyzer::new();
+
+        let quality = analyzer.analyze_quality(Path::new("testdata/example-full.jpg"))?;
+        assert!(!quality.is_blurry(), "{:?}", quality);
+        assert!((0.0..=1.0).contains(&quality.highlights_clipped));
+        assert!((0.0..=1.0).contains(&quality.shadows_clipped));
+        assert!(quality.noise > 0.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_palette() -> Result<()> {
+        let analyzer = ImageCrateAnalyzer::new();
+
+        let palette = analyzer.extract_palette(Path::new("testdata/example-full.jpg"))?;
+        assert!(!palette.is_empty() && palette.len() <= PALETTE_SIZE);
+        assert!(palette.windows(2).all(|w| w[0].weight >= w[1].weight));
+
+        let total: f32 = palette.iter().map(|c| c.weight).sum();
+        assert!((total - 1.0).abs() < 0.001, "{}", total);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_kmeans_palette() {
+        // Three quarters orange, one quarter blue
+        let mut pixels = vec![[255.0, 140.0, 0.0]; 300];
+        pixels.extend(vec![[20.0, 60.0, 200.0]; 100]);
+
+        let palette = kmeans_palette(&pixels, PALETTE_SIZE);
+        assert_eq!(palette.len(), 2);
+        assert_eq!(palette[0].rgb, [255, 140, 0]);
+        assert_eq!(palette[0].name(), "orange");
+        assert_eq!(palette[0].weight, 0.75);
+        assert_eq!(palette[1].name(), "blue");
+
+        assert!(kmeans_palette(&[], PALETTE_SIZE).is_empty());
+    }
+
+    #[test]
+    fn test_laplacian_variance_blur() -> Result<()> {
+        let luma = open_oriented(Path::new("testdata/example-full.jpg"))?
+            .thumbnail(ANALYSIS_SIZE, ANALYSIS_SIZE)
+            .to_luma8();
+        let blurred = blur(&luma, 8.0);
+
+        let sharpness = laplacian_variance(&luma);
+        let blurred_sharpness = laplacian_variance(&blurred);
+        assert!(blurred_sharpness < sharpness / 10.0);
+        assert!(
+            ImageQuality {
+                sharpness: blurred_sharpness,
+                ..ImageQuality::default()
+            }
+            .is_blurry(),
+            "{}",
+            blurred_sharpness
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_clipping() {
+        // Left half white, right half black
+        let luma = GrayImage::from_fn(10, 10, |x, _| if x < 5 { Luma([255]) } else { Luma([0]) });
+        assert_eq!(clipping(&luma), (0.5, 0.5));
+
+        let luma = GrayImage::from_pixel(10, 10, Luma([128]));
+        assert_eq!(clipping(&luma), (0.0, 0.0));
+    }
+
+    #[test]
+    fn test_noise_sigma() {
+        // A flat image has no noise, a checkerboard of +-20 a lot
+        let flat = GrayImage::from_pixel(32, 32, Luma([128]));
+        assert_eq!(noise_sigma(&flat), 0.0);
+
+        let noisy = GrayImage::from_fn(32, 32, |x, y| {
+            if (x + y) % 2 == 0 {
+                Luma([148])
+            } else {
+                Luma([108])
+            }
+        });
+        assert!(noise_sigma(&noisy) > 20.0);
+    }
+}
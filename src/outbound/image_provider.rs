@@ -1,35 +1,609 @@
-use anyhow::Result;
-use image::open;
-use std::{io::Cursor, path::Path};
+use anyhow::{anyhow, Result};
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder},
+    imageops::overlay,
+    DynamicImage, GenericImageView, Rgb, RgbImage,
+};
+use std::{collections::HashMap, env::var, path::Path};
 
 use base64::{prelude::BASE64_STANDARD, Engine};
 
-use crate::domain::ports::ImageEncoder;
+use super::{
+    openai::parse_model_list,
+    thumbnails::{open_oriented, ThumbnailCache},
+};
+use crate::domain::{models::EncodedImage, ports::ImageEncoder};
+
+const MAX_EDGE: u32 = 672;
+const JPEG_QUALITY: u8 = 75;
+// Images whose long edge exceeds the short edge by more than this are split into tiles
+const TILE_ASPECT_RATIO: f32 = 2.5;
+const MAX_TILES: u32 = 4;
+// Neighbouring tiles share this fraction of their length, so that nothing is cut in half
+const TILE_OVERLAP: f32 = 0.2;
+
+/// The format the images are sent to the model in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+    /// Lossless WebP - the quality setting does not apply.
+    WebP,
+}
+
+impl ImageFormat {
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Png => "image/png",
+            ImageFormat::WebP => "image/webp",
+        }
+    }
+}
+
+impl TryFrom<&str> for ImageFormat {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "jpeg" | "jpg" => Ok(ImageFormat::Jpeg),
+            "png" => Ok(ImageFormat::Png),
+            "webp" => Ok(ImageFormat::WebP),
+            _ => Err(anyhow!("Unsupported image format {}", value)),
+        }
+    }
+}
+
+/// Controls how images are resized and encoded for a multimodal model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageEncoderOptions {
+    /// The longest edge of the encoded image in pixels.
+    pub max_edge: u32,
+    pub format: ImageFormat,
+    /// The JPEG quality between 1 and 100.
+    pub quality: u8,
+    /// Pads the image with black borders to a square, as some models expect square input.
+    pub pad_to_square: bool,
+    /// Images with a larger ratio of long to short edge are sent as overview plus tiles.
+    pub tile_aspect_ratio: f32,
+    /// The maximum number of tiles next to the overview - 0 disables tiling.
+    pub max_tiles: u32,
+}
+
+impl Default for ImageEncoderOptions {
+    fn default() -> Self {
+        Self {
+            max_edge: MAX_EDGE,
+            format: ImageFormat::Jpeg,
+            quality: JPEG_QUALITY,
+            pad_to_square: false,
+            tile_aspect_ratio: TILE_ASPECT_RATIO,
+            max_tiles: MAX_TILES,
+        }
+    }
+}
+
+impl ImageEncoderOptions {
+    /// Returns the options matching the native input of a known multimodal model.
+    ///
+    /// Unknown models get the defaults, which suit llava.
+    pub fn for_model(model: &str) -> Self {
+        let model = model.to_ascii_lowercase();
+        if model.contains("qwen") {
+            // Qwen-VL works on 28 pixel patches and handles large inputs well
+            Self {
+                max_edge: 1176,
+                quality: 90,
+                ..Self::default()
+            }
+        } else if model.contains("minicpm") {
+            // MiniCPM-V slices images of up to 1.8 million pixels
+            Self {
+                max_edge: 1344,
+                quality: 90,
+                ..Self::default()
+            }
+        } else if model.contains("llama3.2-vision") {
+            // Llama 3.2 Vision uses square 560 pixel tiles
+            Self {
+                max_edge: 1120,
+                pad_to_square: true,
+                ..Self::default()
+            }
+        } else {
+            Self::default()
+        }
+    }
+
+    /// Returns the options for an image model, with the overrides of the environment.
+    ///
+    /// `IMAGE_MAX_EDGE`, `IMAGE_FORMAT`, `IMAGE_QUALITY`, `IMAGE_PAD_TO_SQUARE`,
+    /// `IMAGE_TILE_ASPECT_RATIO` and `IMAGE_MAX_TILES` override the preset of the model. Each of
+    /// them can be set for one model with the model name as suffix, e.g.
+    /// `IMAGE_MAX_EDGE_LLAVA_13B` for `llava:13b`, which takes precedence over the global one.
+    pub fn from_env(model: &str) -> Result<Self> {
+        // load env from .env file
+        dotenv::dotenv().ok();
+        Self::from_vars(model, |key| var(key).ok())
+    }
+
+    /// Returns the options for an image model, with the overrides looked up by their key.
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The name of the image model, empty for the options of all models.
+    /// * `lookup` - Returns the value of a variable, e.g. from the environment.
+    fn from_vars(model: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let mut options = Self::for_model(model);
+
+        // The variable of the model comes first, then the one for all models
+        let suffix = model_var_suffix(model);
+        let setting = |name: &str| {
+            suffix
+                .as_ref()
+                .and_then(|suffix| lookup(&format!("{}_{}", name, suffix)))
+                .or_else(|| lookup(name))
+        };
+
+        if let Some(max_edge) = setting("IMAGE_MAX_EDGE") {
+            options.max_edge = max_edge.parse()?;
+        }
+        if let Some(format) = setting("IMAGE_FORMAT") {
+            options.format = format.as_str().try_into()?;
+        }
+        if let Some(quality) = setting("IMAGE_QUALITY") {
+            options.quality = quality.parse()?;
+        }
+        if let Some(pad_to_square) = setting("IMAGE_PAD_TO_SQUARE") {
+            options.pad_to_square = pad_to_square.parse()?;
+        }
+        if let Some(tile_aspect_ratio) = setting("IMAGE_TILE_ASPECT_RATIO") {
+            options.tile_aspect_ratio = tile_aspect_ratio.parse()?;
+        }
+        if let Some(max_tiles) = setting("IMAGE_MAX_TILES") {
+            options.max_tiles = max_tiles.parse()?;
+        }
+
+        Ok(options)
+    }
+}
+
+/// Turns a model name into the suffix of its variables, e.g. `LLAMA3_2_VISION` for
+/// `llama3.2-vision`, or `None` for the options of all models.
+fn model_var_suffix(model: &str) -> Option<String> {
+    if model.is_empty() {
+        return None;
+    }
+    Some(
+        model
+            .chars()
+            .map(|c| match c.is_ascii_alphanumeric() {
+                true => c.to_ascii_uppercase(),
+                false => '_',
+            })
+            .collect(),
+    )
+}
 
 #[derive(Debug, Clone, Default)]
-pub struct ImageCrateEncoder;
+pub struct ImageCrateEncoder {
+    options: ImageEncoderOptions,
+    model_options: HashMap<String, ImageEncoderOptions>,
+    thumbnail_cache: Option<ThumbnailCache>,
+}
 
 impl ImageCrateEncoder {
     pub fn new() -> Self {
-        Self
+        Self::default()
+    }
+
+    /// Creates the encoder with the options for all models.
+    pub fn with_options(options: ImageEncoderOptions) -> Self {
+        Self {
+            options,
+            ..Self::default()
+        }
+    }
+
+    /// Creates the encoder with the preset of each model in `CHAT_MODEL_IMAGE`, see
+    /// [`ImageEncoderOptions::from_env`]. Other models get the defaults.
+    pub fn from_env() -> Result<Self> {
+        // load env from .env file
+        dotenv::dotenv().ok();
+        let mut encoder = Self::with_options(ImageEncoderOptions::from_env("")?);
+        for model in parse_model_list(&var("CHAT_MODEL_IMAGE").unwrap_or_default()) {
+            let options = ImageEncoderOptions::from_env(&model)?;
+            encoder = encoder.with_model_options(&model, options);
+        }
+        Ok(encoder)
+    }
+
+    /// Encodes the images for the model with its own options, e.g. the native resolution.
+    pub fn with_model_options(mut self, model: &str, options: ImageEncoderOptions) -> Self {
+        self.model_options.insert(model.to_string(), options);
+        self
+    }
+
+    /// Returns the options of the model, or the ones for all models.
+    fn options(&self, model: &str) -> &ImageEncoderOptions {
+        self.model_options.get(model).unwrap_or(&self.options)
+    }
+
+    /// Reads the images from the thumbnail cache instead of decoding the full resolution source.
+    pub fn with_thumbnail_cache(mut self, thumbnail_cache: ThumbnailCache) -> Self {
+        self.thumbnail_cache = Some(thumbnail_cache);
+        self
+    }
+
+    /// Loads the upright image, from the smallest cached thumbnail which is large enough if possible.
+    fn load(&self, file_path: &Path, max_edge: u32) -> Result<DynamicImage> {
+        if let Some(cache) = &self.thumbnail_cache {
+            if let Some(size) = cache.size_for(max_edge) {
+                return cache.load(file_path, size);
+            }
+        }
+        open_oriented(file_path)
     }
 }
 
+/// Resizes the image to fit into the bounds of the options and encodes it.
+fn encode(image: &DynamicImage, options: &ImageEncoderOptions) -> Result<EncodedImage> {
+    let max_edge = options.max_edge;
+    let mut resized_img = image.thumbnail(max_edge, max_edge);
+
+    if options.pad_to_square {
+        resized_img = pad_to_square(&resized_img);
+    }
+
+    // Create a buffer to hold the encoded image
+    let mut buffer = Vec::new();
+
+    // Not every format and model handles an alpha channel - always send RGB
+    let rgb_img = resized_img.to_rgb8();
+    match options.format {
+        ImageFormat::Jpeg => rgb_img
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut buffer, options.quality))?,
+        ImageFormat::Png => rgb_img.write_with_encoder(PngEncoder::new(&mut buffer))?,
+        ImageFormat::WebP => rgb_img.write_with_encoder(WebPEncoder::new_lossless(&mut buffer))?,
+    }
+
+    let image_base64 = BASE64_STANDARD.encode(buffer);
+    Ok(EncodedImage {
+        base64: image_base64,
+        mime_type: options.format.mime_type().to_string(),
+    })
+}
+
+/// Returns true if an image of the given source dimensions is too wide or too tall to keep
+/// its details in a single image.
+fn needs_tiles((width, height): (u32, u32), options: &ImageEncoderOptions) -> bool {
+    let long_edge = width.max(height);
+    let short_edge = width.min(height).max(1);
+    options.max_tiles > 0
+        && long_edge > options.max_edge
+        && long_edge as f32 / short_edge as f32 > options.tile_aspect_ratio
+}
+
 impl ImageEncoder for ImageCrateEncoder {
-    fn resize_and_base64encode_image(&self, file_path: &Path) -> Result<String> {
-        // Load the image from the specified file path
-        let image = open(file_path)?;
+    fn resize_and_base64encode_image(
+        &self,
+        file_path: &Path,
+        model: &str,
+    ) -> Result<Vec<EncodedImage>> {
+        let options = self.options(model);
+
+        // Load the image from the specified file path, upright as the camera has seen it
+        let image = self.load(file_path, options.max_edge)?;
+
+        // The overview of the whole image always comes first
+        let mut encoded = vec![encode(&image, options)?];
+
+        // A cached thumbnail is never larger than the overview - decide from the source instead
+        let dimensions = match self.thumbnail_cache {
+            Some(_) => image::image_dimensions(file_path)?,
+            None => image.dimensions(),
+        };
+
+        if needs_tiles(dimensions, options) {
+            // The cached thumbnails are too small for details - go back to the source
+            let image = match self.thumbnail_cache {
+                Some(_) => open_oriented(file_path)?,
+                None => image,
+            };
+            for (x, y, width, height) in tiles(image.width(), image.height(), options.max_tiles) {
+                encoded.push(encode(&image.crop_imm(x, y, width, height), options)?);
+            }
+        }
+
+        Ok(encoded)
+    }
+}
+
+/// Splits an image along its long edge into overlapping, roughly square tiles.
+///
+/// Returns the tiles as `(x, y, width, height)` from left to right or top to bottom.
+fn tiles(width: u32, height: u32, max_tiles: u32) -> Vec<(u32, u32, u32, u32)> {
+    let long_edge = width.max(height);
+    let short_edge = width.min(height).max(1);
+
+    // Enough tiles of the short edge length to cover the long edge with overlap
+    let ratio = long_edge as f32 / short_edge as f32;
+    let count = ((ratio - TILE_OVERLAP) / (1.0 - TILE_OVERLAP))
+        .ceil()
+        .clamp(2.0, max_tiles.max(2) as f32) as u32;
+
+    // With fewer tiles than needed, the tiles get longer instead
+    let length = (long_edge as f32 / (1.0 + (count - 1) as f32 * (1.0 - TILE_OVERLAP)))
+        .ceil()
+        .min(long_edge as f32) as u32;
+    let step = length as f32 * (1.0 - TILE_OVERLAP);
+
+    (0..count)
+        .map(|i| {
+            let start = ((i as f32 * step).round() as u32).min(long_edge - length);
+            if width >= height {
+                (start, 0, length, height)
+            } else {
+                (0, start, width, length)
+            }
+        })
+        .collect()
+}
+
+/// Centers the image on a black square canvas.
+fn pad_to_square(image: &DynamicImage) -> DynamicImage {
+    let size = image.width().max(image.height());
+    let mut canvas = RgbImage::from_pixel(size, size, Rgb([0, 0, 0]));
+
+    let x = (size - image.width()) / 2;
+    let y = (size - image.height()) / 2;
+    overlay(&mut canvas, &image.to_rgb8(), x.into(), y.into());
+
+    DynamicImage::ImageRgb8(canvas)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::load_from_memory;
+
+    fn decode(encoded: &[EncodedImage]) -> Result<DynamicImage> {
+        assert_eq!(encoded.len(), 1, "expected a single image");
+        decode_one(&encoded[0])
+    }
+
+    fn decode_one(encoded: &EncodedImage) -> Result<DynamicImage> {
+        let buffer = BASE64_STANDARD.decode(&encoded.base64)?;
+        Ok(load_from_memory(&buffer)?)
+    }
+
+    #[test]
+    fn test_resize_and_base64encode_image_default() -> Result<()> {
+        let encoder = ImageCrateEncoder::new();
+
+        let encoded = encoder
+            .resize_and_base64encode_image(Path::new("testdata/example-full.jpg"), "llava")?;
+        assert_eq!(encoded[0].mime_type, "image/jpeg");
+
+        let image = decode(&encoded)?;
+        assert_eq!(image.width().max(image.height()), MAX_EDGE);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resize_and_base64encode_image_options() -> Result<()> {
+        let encoder = ImageCrateEncoder::with_options(ImageEncoderOptions {
+            max_edge: 100,
+            format: ImageFormat::WebP,
+            quality: 90,
+            pad_to_square: true,
+            ..ImageEncoderOptions::default()
+        });
+
+        let encoded = encoder
+            .resize_and_base64encode_image(Path::new("testdata/example-full.jpg"), "llava")?;
+        assert_eq!(encoded[0].mime_type, "image/webp");
+
+        let image = decode(&encoded)?;
+        assert_eq!(image.dimensions(), (100, 100));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resize_and_base64encode_image_model_options() -> Result<()> {
+        // The fallback model gets its own preset, other models the one for all
+        let encoder = ImageCrateEncoder::new().with_model_options(
+            "qwen2.5vl:7b",
+            ImageEncoderOptions::for_model("qwen2.5vl:7b"),
+        );
+        let path = Path::new("testdata/example-full.jpg");
+
+        let image = decode(&encoder.resize_and_base64encode_image(path, "qwen2.5vl:7b")?)?;
+        assert_eq!(image.width().max(image.height()), 1176);
+        let image = decode(&encoder.resize_and_base64encode_image(path, "llava:13b")?)?;
+        assert_eq!(image.width().max(image.height()), MAX_EDGE);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resize_and_base64encode_image_orientation() -> Result<()> {
+        let encoder = ImageCrateEncoder::with_options(ImageEncoderOptions {
+            format: ImageFormat::Png,
+            ..ImageEncoderOptions::default()
+        });
+
+        // All fixtures show the same upright landscape image - red, green, blue and white quadrants -
+        // stored with each of the 8 EXIF orientations
+        for orientation in 1..=8 {
+            let path = format!("testdata/example-orientation-{}.jpg", orientation);
+            let image = decode(&encoder.resize_and_base64encode_image(Path::new(&path), "llava")?)?;
+            let image = image.to_rgb8();
+
+            assert!(
+                image.width() > image.height(),
+                "orientation {}",
+                orientation
+            );
+
+            let (right, bottom) = (image.width() - 1, image.height() - 1);
+            let corners = [
+                image.get_pixel(0, 0).0,
+                image.get_pixel(right, 0).0,
+                image.get_pixel(0, bottom).0,
+                image.get_pixel(right, bottom).0,
+            ];
+            let expected = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 255]];
+            for (corner, expected) in corners.iter().zip(expected) {
+                // Allow for JPEG compression artifacts
+                let matches = corner
+                    .iter()
+                    .zip(expected)
+                    .all(|(c, e): (&u8, u8)| c.abs_diff(e) < 64);
+                assert!(matches, "orientation {}: {:?}", orientation, corners);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resize_and_base64encode_image_thumbnail_cache() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let encoder =
+            ImageCrateEncoder::new().with_thumbnail_cache(ThumbnailCache::new(temp_dir.path()));
+
+        let path = Path::new("testdata/example-full.jpg");
+        let encoded = encoder.resize_and_base64encode_image(path, "llava")?;
+
+        let image = decode(&encoded)?;
+        assert_eq!(image.width().max(image.height()), MAX_EDGE);
+
+        // The thumbnail has been cached for the next run
+        assert!(ThumbnailCache::new(temp_dir.path())
+            .get_or_create(path, MAX_EDGE)?
+            .exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resize_and_base64encode_image_panorama() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("panorama.jpg");
+        RgbImage::from_fn(6000, 1000, |x, _| Rgb([(x / 24) as u8, 128, 64])).save(&path)?;
+
+        let encoder = ImageCrateEncoder::new();
+        let encoded = encoder.resize_and_base64encode_image(&path, "llava")?;
+
+        // The overview plus the maximum number of tiles
+        assert_eq!(encoded.len(), 1 + MAX_TILES as usize);
+
+        let overview = decode_one(&encoded[0])?;
+        assert_eq!(overview.dimensions(), (MAX_EDGE, 112));
+
+        // The tiles keep far more detail than the overview
+        for tile in &encoded[1..] {
+            let tile = decode_one(tile)?;
+            assert_eq!(tile.width(), MAX_EDGE);
+            assert!(tile.height() > overview.height() * 3);
+        }
+
+        // The thumbnail cache only serves the overview, the tiles still come from the source
+        let cache_dir = tempfile::tempdir()?;
+        let encoder =
+            ImageCrateEncoder::new().with_thumbnail_cache(ThumbnailCache::new(cache_dir.path()));
+        let encoded = encoder.resize_and_base64encode_image(&path, "llava")?;
+        assert_eq!(encoded.len(), 1 + MAX_TILES as usize);
+        assert_eq!(decode_one(&encoded[1])?.width(), MAX_EDGE);
+
+        // Tiling can be turned off
+        let encoder = ImageCrateEncoder::with_options(ImageEncoderOptions {
+            max_tiles: 0,
+            ..ImageEncoderOptions::default()
+        });
+        assert_eq!(
+            encoder.resize_and_base64encode_image(&path, "llava")?.len(),
+            1
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tiles() {
+        // A 6:1 panorama needs 8 tiles with overlap, but gets 4 longer ones
+        let wide = tiles(6000, 1000, 4);
+        assert_eq!(wide.len(), 4);
+        assert_eq!(wide[0].0, 0);
+
+        let (x, _, width, _) = wide[3];
+        assert_eq!(x + width, 6000);
+
+        // Neighbouring tiles overlap and all have the full height
+        for pair in wide.windows(2) {
+            assert!(pair[1].0 < pair[0].0 + pair[0].2);
+            assert_eq!(pair[0].3, 1000);
+        }
+
+        // Tall images are split from top to bottom
+        let tall = tiles(1000, 3000, 4);
+        assert_eq!(tall.len(), 4);
+        assert!(tall
+            .iter()
+            .all(|(x, _, width, _)| *x == 0 && *width == 1000));
+        assert_eq!(tall[3].1 + tall[3].3, 3000);
+    }
+
+    #[test]
+    fn test_image_encoder_options_for_model() -> Result<()> {
+        assert_eq!(
+            ImageEncoderOptions::for_model("llava:13b"),
+            ImageEncoderOptions::default()
+        );
+        assert_eq!(
+            ImageEncoderOptions::for_model("qwen2.5vl:7b").max_edge,
+            1176
+        );
+        assert_eq!(ImageEncoderOptions::for_model("minicpm-v").max_edge, 1344);
+        assert!(ImageEncoderOptions::for_model("llama3.2-vision:11b").pad_to_square);
+
+        assert_eq!(ImageFormat::try_from("PNG")?, ImageFormat::Png);
+        assert!(ImageFormat::try_from("gif").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_image_encoder_options_from_vars() -> Result<()> {
+        let vars = HashMap::from([
+            ("IMAGE_QUALITY", "60"),
+            ("IMAGE_MAX_EDGE_LLAVA_13B", "1024"),
+            ("IMAGE_QUALITY_LLAVA_13B", "95"),
+        ]);
+        let lookup = |key: &str| vars.get(key).map(|value| value.to_string());
+
+        // The variables of the model take precedence over the ones for all models
+        let llava = ImageEncoderOptions::from_vars("llava:13b", lookup)?;
+        assert_eq!(llava.max_edge, 1024);
+        assert_eq!(llava.quality, 95);
 
-        // Resize the image to 672x672
-        let resized_img = image.thumbnail(672, 672);
+        // Other models keep their preset, only changed by the variables for all models
+        let qwen = ImageEncoderOptions::from_vars("qwen2.5vl:7b", lookup)?;
+        assert_eq!(qwen.max_edge, 1176);
+        assert_eq!(qwen.quality, 60);
 
-        // Create a buffer to hold the encoded image
-        let mut buffer = Vec::new();
-        let mut cursor = Cursor::new(&mut buffer);
+        let default = ImageEncoderOptions::from_vars("", lookup)?;
+        assert_eq!(default.max_edge, MAX_EDGE);
+        assert_eq!(default.quality, 60);
 
-        resized_img.write_to(&mut cursor, image::ImageFormat::Jpeg)?;
+        assert_eq!(
+            model_var_suffix("llama3.2-vision").as_deref(),
+            Some("LLAMA3_2_VISION")
+        );
 
-        let image_base64 = BASE64_STANDARD.encode(buffer);
-        Ok(image_base64)
+        Ok(())
     }
 }
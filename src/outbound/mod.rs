@@ -1,5 +1,17 @@
+pub mod backend;
+pub mod bert;
+pub mod clip;
+pub mod embedding_cache;
+pub mod image_analysis;
 pub mod image_provider;
+pub mod nominatim;
+pub mod ocr;
+pub mod ollama;
 pub mod openai;
+pub mod prompts;
 pub mod qdrant;
+pub mod rate_limit;
+pub mod resilience;
 pub mod test_mocks;
+pub mod thumbnails;
 pub mod xmp;
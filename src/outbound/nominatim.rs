@@ -0,0 +1,267 @@
+use crate::domain::ports::Geocoder;
+use anyhow::{anyhow, Result};
+use reqwest::{header::USER_AGENT, StatusCode};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    env::var,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{sync::OnceCell, time::sleep};
+use tracing::{debug, warn};
+
+// The public Nominatim servers allow one request per second
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+// Coordinates are looked up once per ~100 m, the photos of a spot share the place
+const PRECISION: f64 = 1000.0;
+// Nominatim asks every application to identify itself
+const APPLICATION: &str = concat!("photo-scanner/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, Default, Deserialize)]
+struct Address {
+    tourism: Option<String>,
+    city: Option<String>,
+    town: Option<String>,
+    village: Option<String>,
+    hamlet: Option<String>,
+    state: Option<String>,
+    country: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReverseResponse {
+    #[serde(default)]
+    address: Option<Address>,
+    display_name: Option<String>,
+}
+
+impl ReverseResponse {
+    /// Names the place from the landmark down to the country, e.g. `Cefalù, Sicily, Italy`.
+    fn place(self) -> Option<String> {
+        let Some(address) = self.address else {
+            return self.display_name;
+        };
+        let locality = address
+            .city
+            .or(address.town)
+            .or(address.village)
+            .or(address.hamlet);
+        let parts: Vec<String> = [address.tourism, locality, address.state, address.country]
+            .into_iter()
+            .flatten()
+            .collect();
+        if parts.is_empty() {
+            self.display_name
+        } else {
+            Some(parts.join(", "))
+        }
+    }
+}
+
+/// The place of a spot, or why it could not be looked up, set by the first photo of the spot.
+type PlaceCell = Arc<OnceCell<Result<Option<String>, String>>>;
+
+/// Names the places of GPS coordinates with a Nominatim server, e.g. the public one of
+/// OpenStreetMap or a local instance.
+///
+/// The places are kept for the run and the requests are throttled to one per second. The photos
+/// of a spot wait for the lookup of the first one, and a failed lookup is not repeated. Once the
+/// server is unavailable, the remaining photos are not looked up at all.
+pub struct NominatimGeocoder {
+    client: reqwest::Client,
+    base_url: String,
+    language: Option<String>,
+    places: Mutex<HashMap<(i64, i64), PlaceCell>>,
+    unavailable: Mutex<Option<String>>,
+    last_request: tokio::sync::Mutex<Option<Instant>>,
+}
+
+impl NominatimGeocoder {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            language: None,
+            places: Mutex::new(HashMap::new()),
+            unavailable: Mutex::new(None),
+            last_request: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// Reads the server from `GEOCODER_URL`, e.g. `https://nominatim.openstreetmap.org`, and
+    /// the language of the places from `GEOCODER_LANGUAGE`, e.g. `en`.
+    ///
+    /// Returns `None` if no server is configured, which leaves the coordinates unnamed.
+    pub fn from_env() -> Option<Self> {
+        // load env from .env file
+        dotenv::dotenv().ok();
+        let geocoder = Self::new(&var("GEOCODER_URL").ok()?);
+        Some(match var("GEOCODER_LANGUAGE") {
+            Ok(language) => geocoder.with_language(&language),
+            Err(_) => geocoder,
+        })
+    }
+
+    /// Names the places in the language, e.g. `en` or `de`, instead of the local one.
+    pub fn with_language(mut self, language: &str) -> Self {
+        self.language = Some(language.to_string());
+        self
+    }
+
+    async fn lookup(&self, latitude: f64, longitude: f64) -> Result<Option<String>> {
+        // Wait for the turn of this request
+        let mut last_request = self.last_request.lock().await;
+        // The server may have failed while this request was waiting
+        if let Some(error) = self.unavailable.lock().expect("unavailable lock").as_ref() {
+            return Err(anyhow!("The geocoder is unavailable: {}", error));
+        }
+        if let Some(elapsed) = last_request.map(|last| last.elapsed()) {
+            if elapsed < MIN_INTERVAL {
+                sleep(MIN_INTERVAL - elapsed).await;
+            }
+        }
+        *last_request = Some(Instant::now());
+
+        let mut query = vec![
+            ("format", "jsonv2".to_string()),
+            ("lat", latitude.to_string()),
+            ("lon", longitude.to_string()),
+            ("zoom", "14".to_string()),
+        ];
+        if let Some(language) = &self.language {
+            query.push(("accept-language", language.clone()));
+        }
+        let response: ReverseResponse = self
+            .client
+            .get(format!("{}/reverse", self.base_url))
+            .header(USER_AGENT, APPLICATION)
+            .query(&query)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(response.place())
+    }
+}
+
+impl Geocoder for NominatimGeocoder {
+    async fn reverse_geocode(&self, latitude: f64, longitude: f64) -> Result<Option<String>> {
+        let key = (
+            (latitude * PRECISION).round() as i64,
+            (longitude * PRECISION).round() as i64,
+        );
+        let cell = Arc::clone(
+            self.places
+                .lock()
+                .expect("places lock")
+                .entry(key)
+                .or_default(),
+        );
+
+        let place = cell
+            .get_or_init(|| async {
+                let place = self.lookup(latitude, longitude).await;
+                debug!("Place of {},{}: {:?}", latitude, longitude, place);
+                place.map_err(|e| {
+                    if is_unavailable(&e) {
+                        let mut unavailable = self.unavailable.lock().expect("unavailable lock");
+                        if unavailable.is_none() {
+                            warn!("The geocoder is unavailable for the run: {:#}", e);
+                            *unavailable = Some(format!("{:#}", e));
+                        }
+                    }
+                    format!("{:#}", e)
+                })
+            })
+            .await;
+        place.clone().map_err(|e| anyhow!(e))
+    }
+}
+
+/// Tells a server which is down or overloaded from a lookup which failed for its coordinates.
+fn is_unavailable(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<reqwest::Error>().is_some_and(|error| {
+        error.is_connect()
+            || error.is_timeout()
+            || error.status().is_some_and(|status| {
+                status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+            })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use wiremock::{
+        matchers::{method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    #[tokio::test]
+    async fn test_reverse_geocode() -> Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/reverse"))
+            .and(query_param("lat", "38.0389"))
+            .and(query_param("accept-language", "en"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "display_name": "Via Vittorio Emanuele, Cefalù, Palermo, Sicilia, Italia",
+                "address": {
+                    "road": "Via Vittorio Emanuele",
+                    "town": "Cefalù",
+                    "state": "Sicily",
+                    "country": "Italy"
+                }
+            })))
+            // Nearby photos are answered from the places of the run
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/reverse"))
+            .and(query_param("lat", "0"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({ "error": "Unable to geocode" })),
+            )
+            .mount(&server)
+            .await;
+
+        let geocoder = NominatimGeocoder::new(&server.uri()).with_language("en");
+        // The photos of a spot described at the same time wait for the first lookup
+        let (place, other) = tokio::join!(
+            geocoder.reverse_geocode(38.0389, 14.0228),
+            geocoder.reverse_geocode(38.03891, 14.02279)
+        );
+        assert_eq!(place?.as_deref(), Some("Cefalù, Sicily, Italy"));
+        assert_eq!(other?.as_deref(), Some("Cefalù, Sicily, Italy"));
+        let place = geocoder.reverse_geocode(38.03891, 14.02279).await?;
+        assert_eq!(place.as_deref(), Some("Cefalù, Sicily, Italy"));
+
+        // The sea has no name
+        assert_eq!(geocoder.reverse_geocode(0.0, 0.0).await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reverse_geocode_unavailable() -> Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/reverse"))
+            .respond_with(ResponseTemplate::new(503))
+            // The remaining photos are not looked up once the server failed
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let geocoder = NominatimGeocoder::new(&server.uri());
+        assert!(geocoder.reverse_geocode(38.0389, 14.0228).await.is_err());
+        assert!(geocoder.reverse_geocode(38.0389, 14.0228).await.is_err());
+        assert!(geocoder.reverse_geocode(43.4682, 11.8801).await.is_err());
+
+        Ok(())
+    }
+}
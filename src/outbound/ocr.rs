@@ -0,0 +1,335 @@
+use anyhow::{anyhow, Result};
+use candle_core::{DType, Device, Tensor, D};
+use candle_nn::VarBuilder;
+use candle_transformers::models::{
+    trocr::{TrOCRConfig, TrOCRModel},
+    vit,
+};
+use image::{imageops::FilterType, DynamicImage, GrayImage};
+use serde_json::Value;
+use std::{
+    env::var,
+    fs::{read, read_to_string},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tokenizers::Tokenizer;
+
+use super::thumbnails::open_oriented;
+use crate::domain::ports::TextRecognizer;
+
+// TrOCR looks at 384x384 pixels
+const IMAGE_SIZE: u32 = 384;
+// Text lines are searched in a copy of at most this width
+const DETECTION_WIDTH: u32 = 1024;
+// Horizontal brightness steps of at least this much count as character edges
+const EDGE_THRESHOLD: u8 = 48;
+// A row belongs to a text line if this fraction of its pixels are character edges
+const ROW_EDGE_FRACTION: f32 = 0.02;
+// Bands outside these heights (in detection pixels) are rather structures than text
+const MIN_LINE_HEIGHT: u32 = 8;
+const MAX_LINE_HEIGHT: u32 = 96;
+// Text lines are at least this many times as wide as high
+const MIN_LINE_ASPECT: u32 = 2;
+const MAX_LINES: usize = 24;
+const MAX_TOKENS: usize = 64;
+
+/// Recognizes printed text on the CPU with TrOCR.
+///
+/// Text lines are found with a simple edge density heuristic and read one by one. The model
+/// directory needs the `model.safetensors` and `config.json` of `microsoft/trocr-base-printed`
+/// and a matching `tokenizer.json`.
+pub struct TrOcrRecognizer {
+    model: Mutex<TrOCRModel>,
+    decoder_config: TrOCRConfig,
+    tokenizer: Tokenizer,
+    device: Device,
+}
+
+impl TrOcrRecognizer {
+    pub fn new<P: AsRef<Path>>(model_dir: P) -> Result<Self> {
+        let model_dir = model_dir.as_ref();
+        let device = Device::Cpu;
+
+        let config: Value = serde_json::from_str(&read_to_string(model_dir.join("config.json"))?)?;
+        let encoder_config: vit::Config = serde_json::from_value(config["encoder"].clone())?;
+        let decoder_config: TrOCRConfig = serde_json::from_value(config["decoder"].clone())?;
+
+        let weights = read(model_dir.join("model.safetensors"))?;
+        let vb = VarBuilder::from_buffered_safetensors(weights, DType::F32, &device)?;
+        let model = TrOCRModel::new(&encoder_config, &decoder_config, vb)?;
+
+        let tokenizer = Tokenizer::from_file(model_dir.join("tokenizer.json"))
+            .map_err(|e| anyhow!("Failed to load OCR tokenizer: {}", e))?;
+
+        Ok(Self {
+            model: Mutex::new(model),
+            decoder_config,
+            tokenizer,
+            device,
+        })
+    }
+
+    /// Loads the model from the directory configured in `OCR_MODEL_DIR`.
+    ///
+    /// Returns `None` if no directory is configured, which disables the text recognition.
+    pub fn from_env() -> Result<Option<Self>> {
+        // load env from .env file
+        dotenv::dotenv().ok();
+        match var("OCR_MODEL_DIR") {
+            Ok(model_dir) => Self::new(PathBuf::from(model_dir)).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Reads a single line of text.
+    fn recognize_line(&self, line: &DynamicImage) -> Result<String> {
+        let size = IMAGE_SIZE as usize;
+        let pixel_values = Tensor::from_vec(preprocess(line), (1, 3, size, size), &self.device)?;
+
+        let mut model = self
+            .model
+            .lock()
+            .map_err(|_| anyhow!("OCR model lock poisoned"))?;
+        let token_ids = self.generate(&mut model, &pixel_values);
+        // The decoder caches the previous tokens, which must not leak into the next line
+        model.reset_kv_cache();
+
+        let text = self
+            .tokenizer
+            .decode(&token_ids?[1..], true)
+            .map_err(|e| anyhow!("Failed to decode OCR tokens: {}", e))?;
+        Ok(text.trim().to_string())
+    }
+
+    /// Greedily decodes the tokens of a line until the end of text.
+    fn generate(&self, model: &mut TrOCRModel, pixel_values: &Tensor) -> Result<Vec<u32>> {
+        let encoder_xs = model.encoder().forward(pixel_values)?;
+
+        let mut token_ids = vec![self.decoder_config.decoder_start_token_id];
+        for index in 0..MAX_TOKENS {
+            // After the first step, only the new token is fed - the others are cached
+            let context_size = if index >= 1 { 1 } else { token_ids.len() };
+            let start_pos = token_ids.len().saturating_sub(context_size);
+            let input_ids = Tensor::new(&token_ids[start_pos..], &self.device)?.unsqueeze(0)?;
+
+            let logits = model
+                .decode(&input_ids, &encoder_xs, start_pos)?
+                .squeeze(0)?;
+            let logits = logits.get(logits.dim(0)? - 1)?;
+            let token = logits.argmax(D::Minus1)?.to_scalar::<u32>()?;
+            if token == self.decoder_config.eos_token_id {
+                break;
+            }
+            token_ids.push(token);
+        }
+
+        Ok(token_ids)
+    }
+}
+
+impl TextRecognizer for TrOcrRecognizer {
+    fn recognize_text(&self, image_path: &Path) -> Result<Vec<String>> {
+        let image = open_oriented(image_path)?;
+
+        let mut lines = Vec::new();
+        for (x, y, width, height) in detect_text_lines(&image) {
+            let text = self.recognize_line(&image.crop_imm(x, y, width, height))?;
+            // Textures mistaken for text come back as noise
+            if is_plausible_text(&text) {
+                lines.push(text);
+            }
+        }
+
+        Ok(lines)
+    }
+}
+
+/// Finds the bounding boxes `(x, y, width, height)` of text lines from top to bottom.
+///
+/// Characters cause many sharp horizontal brightness steps, so rows full of them that form bands
+/// of a plausible height, and columns of those bands wide enough for a line, are taken as text.
+fn detect_text_lines(image: &DynamicImage) -> Vec<(u32, u32, u32, u32)> {
+    let luma = if image.width() > DETECTION_WIDTH {
+        image.resize(DETECTION_WIDTH, u32::MAX, FilterType::Triangle)
+    } else {
+        image.clone()
+    }
+    .to_luma8();
+    let scale = image.width() as f32 / luma.width().max(1) as f32;
+
+    let (width, height) = luma.dimensions();
+    let edges = edge_map(&luma);
+    let is_edge = |x: u32, y: u32| edges[(y * width + x) as usize];
+
+    let min_edges = (ROW_EDGE_FRACTION * width as f32).max(1.0) as usize;
+    let text_rows: Vec<bool> = (0..height)
+        .map(|y| (0..width).filter(|x| is_edge(*x, y)).count() >= min_edges)
+        .collect();
+
+    let mut lines = Vec::new();
+    for (top, bottom) in runs(&text_rows, 0) {
+        let band_height = bottom - top;
+        if !(MIN_LINE_HEIGHT..=MAX_LINE_HEIGHT).contains(&band_height) {
+            continue;
+        }
+
+        // Split the band into lines at gaps wider than the letter and word spacing
+        let text_columns: Vec<bool> = (0..width)
+            .map(|x| (top..bottom).any(|y| is_edge(x, y)))
+            .collect();
+        for (left, right) in runs(&text_columns, band_height) {
+            if right - left < band_height * MIN_LINE_ASPECT {
+                continue;
+            }
+
+            // Leave some margin around the characters
+            let padding = band_height / 4;
+            let left = left.saturating_sub(padding);
+            let top = top.saturating_sub(padding);
+            let right = (right + padding).min(width);
+            let bottom = (bottom + padding).min(height);
+
+            let to_source = |v: u32| (v as f32 * scale).round() as u32;
+            let x = to_source(left);
+            let y = to_source(top);
+            lines.push((
+                x,
+                y,
+                (to_source(right) - x).min(image.width() - x),
+                (to_source(bottom) - y).min(image.height() - y),
+            ));
+        }
+    }
+
+    lines.sort_by_key(|(x, y, _, _)| (*y, *x));
+    lines.truncate(MAX_LINES);
+    lines
+}
+
+/// Marks the pixels with a sharp brightness step to their right neighbour.
+fn edge_map(luma: &GrayImage) -> Vec<bool> {
+    let (width, height) = luma.dimensions();
+    let mut edges = vec![false; (width * height) as usize];
+    for y in 0..height {
+        for x in 0..width.saturating_sub(1) {
+            let step = luma.get_pixel(x, y).0[0].abs_diff(luma.get_pixel(x + 1, y).0[0]);
+            edges[(y * width + x) as usize] = step >= EDGE_THRESHOLD;
+        }
+    }
+    edges
+}
+
+/// Returns the `(start, end)` ranges of consecutive true values, bridging gaps up to `max_gap`.
+fn runs(values: &[bool], max_gap: u32) -> Vec<(u32, u32)> {
+    let mut runs = Vec::new();
+    let mut current: Option<(u32, u32)> = None;
+    for (index, value) in values.iter().enumerate() {
+        if !value {
+            continue;
+        }
+        let index = index as u32;
+        current = match current {
+            Some((start, end)) if index - end <= max_gap => Some((start, index + 1)),
+            Some(run) => {
+                runs.push(run);
+                Some((index, index + 1))
+            }
+            None => Some((index, index + 1)),
+        };
+    }
+    runs.extend(current);
+    runs
+}
+
+/// Returns true if the text has at least two letters or digits.
+fn is_plausible_text(text: &str) -> bool {
+    text.chars().filter(|c| c.is_alphanumeric()).count() >= 2
+}
+
+/// Scales the line to the model input size and normalizes the pixels to -1..1.
+///
+/// Returns the pixels in channel, row, column order.
+fn preprocess(image: &DynamicImage) -> Vec<f32> {
+    let resized = image
+        .resize_exact(IMAGE_SIZE, IMAGE_SIZE, FilterType::Triangle)
+        .to_rgb8();
+
+    let mut pixels = Vec::with_capacity(3 * (IMAGE_SIZE * IMAGE_SIZE) as usize);
+    for channel in 0..3 {
+        pixels.extend(resized.pixels().map(|p| p.0[channel] as f32 / 127.5 - 1.0));
+    }
+    pixels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Luma, Rgb, RgbImage};
+
+    /// Draws two lines of fake characters - narrow dark bars - on a bright background.
+    fn fake_document() -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(800, 400, |x, y| {
+            let in_line = (100..120).contains(&y) || (250..270).contains(&y);
+            if in_line && (100..600).contains(&x) && x % 6 < 2 {
+                Luma([20])
+            } else {
+                Luma([235])
+            }
+        }))
+    }
+
+    #[test]
+    fn test_detect_text_lines() {
+        let lines = detect_text_lines(&fake_document());
+        assert_eq!(lines.len(), 2, "{:?}", lines);
+
+        for ((x, y, width, height), line_top) in lines.iter().zip([100, 250]) {
+            assert!(*x <= 100 && x + width >= 598, "{:?}", lines);
+            assert!(*y <= line_top && y + height >= line_top + 20, "{:?}", lines);
+        }
+
+        // Plain areas contain no text
+        let plain = DynamicImage::ImageRgb8(RgbImage::from_pixel(800, 400, Rgb([90, 140, 200])));
+        assert!(detect_text_lines(&plain).is_empty());
+    }
+
+    #[test]
+    fn test_detect_text_lines_scaled() {
+        // Large images are searched downsized, but the boxes refer to the source
+        let large = fake_document().resize_exact(2400, 1200, FilterType::Nearest);
+        let lines = detect_text_lines(&large);
+        assert_eq!(lines.len(), 2, "{:?}", lines);
+
+        let (x, y, width, height) = lines[1];
+        assert!(x <= 300 && x + width >= 1790, "{:?}", lines);
+        assert!(y <= 750 && y + height >= 810, "{:?}", lines);
+    }
+
+    #[test]
+    fn test_runs() {
+        let values = [true, true, false, true, false, false, false, true];
+        assert_eq!(runs(&values, 0), vec![(0, 2), (3, 4), (7, 8)]);
+        assert_eq!(runs(&values, 1), vec![(0, 4), (7, 8)]);
+        assert_eq!(runs(&values, 3), vec![(0, 8)]);
+        assert!(runs(&[false, false], 3).is_empty());
+    }
+
+    #[test]
+    fn test_is_plausible_text() {
+        assert!(is_plausible_text("Conto 42,50"));
+        assert!(!is_plausible_text(" . , "));
+        assert!(!is_plausible_text("I"));
+    }
+
+    #[test]
+    fn test_preprocess() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(1000, 50, Rgb([255, 0, 255])));
+
+        let pixels = preprocess(&image);
+        let size = (IMAGE_SIZE * IMAGE_SIZE) as usize;
+        assert_eq!(pixels.len(), 3 * size);
+        assert_eq!(pixels[0], 1.0);
+        assert_eq!(pixels[size], -1.0);
+    }
+}
@@ -0,0 +1,1081 @@
+use super::{openai::parse_model_list, prompts::PromptTemplates};
+use crate::domain::{
+    models::{
+        AnswerStream, ChatError, DescriptionContext, EncodedImage, FollowUp, ImageAnalysis, Turn,
+    },
+    ports::{AnswerGenerator, TextEmbedder, VisionDescriber},
+    usage::{Usage, UsageMeter},
+};
+use anyhow::{anyhow, Context, Result};
+use futures::{
+    future::ready,
+    stream::{unfold, BoxStream},
+    Stream, StreamExt,
+};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION, RETRY_AFTER},
+    Response, StatusCode,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    env::var,
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, warn};
+
+const BASE_URL: &str = "http://localhost:11434";
+const EMBEDDING_MODEL: &str = "mxbai-embed-large";
+const CHAT_MODEL_MULTIMODAL: &str = "llava:13b";
+const CHAT_MODEL_TEXT: &str = "llama3.1:8b";
+const MAX_TOKENS: u32 = 512;
+
+/// Runtime options of the models, sent with every call.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OllamaOptions {
+    /// How long the model stays loaded after a call, e.g. `10m`, or `-1` to keep it loaded.
+    pub keep_alive: Option<String>,
+    /// The size of the context window in tokens - Ollama defaults to a small one.
+    pub num_ctx: Option<u32>,
+    /// Overrides the temperature of all calls.
+    pub temperature: Option<f32>,
+    /// Makes the answers reproducible.
+    pub seed: Option<i64>,
+}
+
+impl OllamaOptions {
+    /// Reads `OLLAMA_KEEP_ALIVE`, `OLLAMA_NUM_CTX`, `OLLAMA_TEMPERATURE` and `OLLAMA_SEED`.
+    pub fn from_env() -> Result<Self> {
+        // load env from .env file
+        dotenv::dotenv().ok();
+        let mut options = Self::default();
+
+        if let Ok(keep_alive) = var("OLLAMA_KEEP_ALIVE") {
+            options.keep_alive = Some(keep_alive);
+        }
+        if let Ok(num_ctx) = var("OLLAMA_NUM_CTX") {
+            options.num_ctx = Some(num_ctx.parse()?);
+        }
+        if let Ok(temperature) = var("OLLAMA_TEMPERATURE") {
+            options.temperature = Some(temperature.parse()?);
+        }
+        if let Ok(seed) = var("OLLAMA_SEED") {
+            options.seed = Some(seed.parse()?);
+        }
+
+        Ok(options)
+    }
+
+    /// Ollama takes durations like `10m` as string, and seconds or `-1` as number.
+    fn keep_alive(&self) -> Option<Value> {
+        self.keep_alive.as_ref().map(|keep_alive| {
+            keep_alive
+                .parse::<i64>()
+                .map(Value::from)
+                .unwrap_or_else(|_| Value::from(keep_alive.as_str()))
+        })
+    }
+
+    /// The options of a call - the configured temperature wins over the one of the call.
+    fn model_options(&self, temperature: Option<f32>, num_predict: Option<u32>) -> ModelOptions {
+        ModelOptions {
+            num_ctx: self.num_ctx,
+            temperature: self.temperature.or(temperature),
+            seed: self.seed,
+            num_predict,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ModelOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_ctx: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_predict: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Message {
+    role: String,
+    content: String,
+    /// Base64 encoded images, without the `data:` prefix.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>,
+}
+
+impl Message {
+    fn user(content: String, images: Vec<String>) -> Self {
+        Self {
+            role: "user".to_string(),
+            content,
+            images,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: Vec<Message>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<Value>,
+    options: ModelOptions,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatResponse {
+    message: Message,
+    #[serde(default)]
+    prompt_eval_count: u64,
+    #[serde(default)]
+    eval_count: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct EmbedRequest<'a> {
+    model: &'a str,
+    input: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<Value>,
+    options: ModelOptions,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbedResponse {
+    embeddings: Vec<Vec<f32>>,
+    #[serde(default)]
+    prompt_eval_count: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct TagsResponse {
+    models: Vec<TagsModel>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TagsModel {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ShowResponse {
+    #[serde(default)]
+    details: ShowDetails,
+    #[serde(default)]
+    model_info: serde_json::Map<String, Value>,
+    #[serde(default)]
+    capabilities: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ShowDetails {
+    #[serde(default)]
+    family: String,
+    #[serde(default)]
+    parameter_size: String,
+    #[serde(default)]
+    quantization_level: String,
+}
+
+/// What Ollama knows about an installed model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelInfo {
+    pub name: String,
+    /// The model family, e.g. `llama`.
+    pub family: String,
+    /// The number of parameters, e.g. `8.0B`.
+    pub parameter_size: String,
+    /// The quantization of the weights, e.g. `Q4_K_M`.
+    pub quantization_level: String,
+    /// The longest context the model was trained for, in tokens.
+    pub context_length: Option<u64>,
+    /// What the model can do, e.g. `completion`, `vision` or `embedding`.
+    pub capabilities: Vec<String>,
+}
+
+impl ModelInfo {
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+/// An error answer of the Ollama API, e.g. an unknown model or a crashed runner.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OllamaError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl fmt::Display for OllamaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Ollama answered {}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for OllamaError {}
+
+/// A client of the native Ollama API (`/api/chat`, `/api/embed`), which unlike the OpenAI
+/// compatible `/v1` endpoints supports `keep_alive`, the model options and metadata queries.
+#[derive(Debug, Clone)]
+pub struct Ollama {
+    client: reqwest::Client,
+    base_url: String,
+    chat_model: String,
+    multimodal_models: Vec<String>,
+    embedding_model: String,
+    options: OllamaOptions,
+    prompts: PromptTemplates,
+    usage_meter: Option<Arc<UsageMeter>>,
+}
+
+impl Default for Ollama {
+    fn default() -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: BASE_URL.to_string(),
+            chat_model: CHAT_MODEL_TEXT.to_string(),
+            multimodal_models: vec![CHAT_MODEL_MULTIMODAL.to_string()],
+            embedding_model: EMBEDDING_MODEL.to_string(),
+            options: OllamaOptions::default(),
+            prompts: PromptTemplates::default(),
+            usage_meter: None,
+        }
+    }
+}
+
+impl Ollama {
+    /// Reads the server from `OLLAMA_BASE_URL`, the models from `CHAT_MODEL`, `CHAT_MODEL_IMAGE`
+    /// and `CHAT_MODEL_EMBEDDINGS` and the options with [`OllamaOptions::from_env`].
+    pub fn from_env() -> Result<Self> {
+        // load env from .env file
+        dotenv::dotenv().ok();
+        let defaults = Self::default();
+
+        Ok(Self {
+            base_url: var("OLLAMA_BASE_URL").unwrap_or(defaults.base_url.clone()),
+            chat_model: var("CHAT_MODEL").unwrap_or(defaults.chat_model.clone()),
+            multimodal_models: var("CHAT_MODEL_IMAGE")
+                .map(|models| parse_model_list(&models))
+                .unwrap_or(defaults.multimodal_models.clone()),
+            embedding_model: var("CHAT_MODEL_EMBEDDINGS")
+                .unwrap_or(defaults.embedding_model.clone()),
+            options: OllamaOptions::from_env()?,
+            ..defaults
+        })
+    }
+
+    /// Talks to another server, e.g. `http://gpu-box:11434`.
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Authenticates with the API key as bearer token, e.g. at a proxy in front of the server.
+    pub fn with_api_key(mut self, api_key: &str) -> Result<Self> {
+        let mut authorization =
+            HeaderValue::from_str(&format!("Bearer {}", api_key)).context("Invalid API key")?;
+        authorization.set_sensitive(true);
+        self.client = reqwest::Client::builder()
+            .default_headers(HeaderMap::from_iter([(AUTHORIZATION, authorization)]))
+            .build()?;
+        Ok(self)
+    }
+
+    /// Replaces the models used for the texts, the images - the primary model first, followed by
+    /// its fallbacks - and the embeddings.
+    pub fn with_models(
+        mut self,
+        chat_model: &str,
+        multimodal_models: &[&str],
+        embedding_model: &str,
+    ) -> Self {
+        self.chat_model = chat_model.to_string();
+        self.multimodal_models = multimodal_models.iter().map(|m| m.to_string()).collect();
+        self.embedding_model = embedding_model.to_string();
+        self
+    }
+
+    pub fn with_options(mut self, options: OllamaOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Replaces the builtin prompt templates, e.g. with the ones selected for this run.
+    pub fn with_prompts(mut self, prompts: PromptTemplates) -> Self {
+        self.prompts = prompts;
+        self
+    }
+
+    /// Counts the tokens, the time and the cost of all calls.
+    pub fn with_usage_meter(mut self, usage_meter: Arc<UsageMeter>) -> Self {
+        self.usage_meter = Some(usage_meter);
+        self
+    }
+
+    /// Returns the names of the installed models.
+    pub async fn list_models(&self) -> Result<Vec<String>> {
+        let response = self
+            .client
+            .get(format!("{}/api/tags", self.base_url))
+            .send()
+            .await?;
+        let tags: TagsResponse = parse_response(response).await?;
+        Ok(tags.models.into_iter().map(|model| model.name).collect())
+    }
+
+    /// Returns family, size, context length and capabilities of an installed model.
+    pub async fn model_info(&self, model: &str) -> Result<ModelInfo> {
+        let response = self
+            .client
+            .post(format!("{}/api/show", self.base_url))
+            .json(&json!({ "model": model }))
+            .send()
+            .await?;
+        let show: ShowResponse = parse_response(response).await?;
+
+        // The keys are prefixed with the architecture, e.g. `llama.context_length`
+        let context_length = show
+            .model_info
+            .iter()
+            .find(|(key, _)| key.ends_with(".context_length"))
+            .and_then(|(_, value)| value.as_u64());
+
+        Ok(ModelInfo {
+            name: model.to_string(),
+            family: show.details.family,
+            parameter_size: show.details.parameter_size,
+            quantization_level: show.details.quantization_level,
+            context_length,
+            capabilities: show.capabilities,
+        })
+    }
+
+    /// Logs the models of the run, warning about an image model which cannot see images.
+    pub async fn log_models(&self) -> Result<()> {
+        let models = [&self.chat_model, &self.embedding_model]
+            .into_iter()
+            .chain(&self.multimodal_models);
+        for model in models {
+            let info = self
+                .model_info(model)
+                .await
+                .with_context(|| format!("Failed to query Ollama model {}", model))?;
+            info!(
+                "Ollama model {}: {} {} {}, context {:?}, capabilities {:?}",
+                info.name,
+                info.family,
+                info.parameter_size,
+                info.quantization_level,
+                info.context_length,
+                info.capabilities
+            );
+
+            // Older servers do not report capabilities
+            if self.multimodal_models.contains(model)
+                && !info.capabilities.is_empty()
+                && !info.has_capability("vision")
+            {
+                warn!("The image model {} does not support images", model);
+            }
+        }
+        Ok(())
+    }
+
+    async fn chat(
+        &self,
+        operation: &'static str,
+        model: &str,
+        messages: Vec<Message>,
+        format: Option<Value>,
+        temperature: Option<f32>,
+    ) -> Result<String> {
+        let request = ChatRequest {
+            model,
+            messages,
+            stream: false,
+            format,
+            keep_alive: self.options.keep_alive(),
+            options: self.options.model_options(temperature, Some(MAX_TOKENS)),
+        };
+
+        debug!(
+            "Ollama Request: {} {}",
+            request.model, request.messages[0].content
+        );
+        let start = Instant::now();
+        let response = self
+            .client
+            .post(format!("{}/api/chat", self.base_url))
+            .json(&request)
+            .send()
+            .await?;
+        let response: ChatResponse = parse_response(response).await?;
+        record_usage(
+            self.usage_meter.as_deref(),
+            operation,
+            model,
+            response.prompt_eval_count,
+            response.eval_count,
+            start,
+        );
+
+        Ok(response.message.content.trim().to_string())
+    }
+
+    /// Like [`Ollama::chat`], but streams the fragments of the answer as they are generated.
+    async fn chat_stream(
+        &self,
+        operation: &'static str,
+        model: &str,
+        messages: Vec<Message>,
+        temperature: Option<f32>,
+    ) -> Result<AnswerStream> {
+        let request = ChatRequest {
+            model,
+            messages,
+            stream: true,
+            format: None,
+            keep_alive: self.options.keep_alive(),
+            options: self.options.model_options(temperature, Some(MAX_TOKENS)),
+        };
+
+        debug!(
+            "Ollama Request: {} {}",
+            request.model, request.messages[0].content
+        );
+        let start = Instant::now();
+        let response = self
+            .client
+            .post(format!("{}/api/chat", self.base_url))
+            .json(&request)
+            .send()
+            .await?;
+        let response = check_status(response).await?;
+
+        // The last line counts the tokens of the whole answer
+        let usage_meter = self.usage_meter.clone();
+        let model = model.to_string();
+        Ok(json_lines(response.bytes_stream())
+            .filter_map(move |line| {
+                let fragment = match line.and_then(|line| parse_chunk(&line)) {
+                    Ok(chunk) => {
+                        if chunk.done {
+                            record_usage(
+                                usage_meter.as_deref(),
+                                operation,
+                                &model,
+                                chunk.prompt_eval_count,
+                                chunk.eval_count,
+                                start,
+                            );
+                        }
+                        chunk
+                            .message
+                            .map(|message| message.content)
+                            .filter(|content| !content.is_empty())
+                            .map(Ok)
+                    }
+                    Err(e) => Some(Err(e)),
+                };
+                ready(fragment)
+            })
+            .boxed())
+    }
+}
+
+/// One line of a streamed answer - a fragment of the message, or an error which occurred after
+/// the answer started. The last line is `done` and counts the tokens.
+#[derive(Debug, Deserialize)]
+struct ChatChunk {
+    message: Option<Message>,
+    error: Option<String>,
+    #[serde(default)]
+    done: bool,
+    #[serde(default)]
+    prompt_eval_count: u64,
+    #[serde(default)]
+    eval_count: u64,
+}
+
+/// Parses a streamed line, failing if it holds an error.
+fn parse_chunk(line: &[u8]) -> Result<ChatChunk> {
+    let chunk: ChatChunk = serde_json::from_slice(line)?;
+    if let Some(error) = chunk.error {
+        return Err(OllamaError {
+            status: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+            message: error,
+        }
+        .into());
+    }
+    Ok(chunk)
+}
+
+/// Records the tokens of a call which started at `start`, for the meter if one is set and the
+/// rate limiter.
+fn record_usage(
+    usage_meter: Option<&UsageMeter>,
+    operation: &'static str,
+    model: &str,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    start: Instant,
+) {
+    Usage {
+        operation,
+        model: model.to_string(),
+        prompt_tokens,
+        completion_tokens,
+        latency: start.elapsed(),
+    }
+    .record(usage_meter);
+}
+
+/// Splits the streamed body into its lines of JSON, which may span several chunks.
+fn json_lines<B>(
+    body: impl Stream<Item = reqwest::Result<B>> + Send + 'static,
+) -> BoxStream<'static, Result<Vec<u8>>>
+where
+    B: AsRef<[u8]> + Send + 'static,
+{
+    unfold(
+        (body.boxed(), Vec::new()),
+        |(mut body, mut buffer)| async move {
+            loop {
+                if let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+                    let line: Vec<u8> = buffer.drain(..=end).collect();
+                    if line.iter().all(u8::is_ascii_whitespace) {
+                        continue;
+                    }
+                    return Some((Ok(line), (body, buffer)));
+                }
+                match body.next().await {
+                    Some(Ok(chunk)) => buffer.extend_from_slice(chunk.as_ref()),
+                    Some(Err(e)) => return Some((Err(e.into()), (body, buffer))),
+                    // The last line may miss its line break
+                    None if buffer.iter().all(u8::is_ascii_whitespace) => return None,
+                    None => return Some((Ok(std::mem::take(&mut buffer)), (body, buffer))),
+                }
+            }
+        },
+    )
+    .boxed()
+}
+
+/// Puts the overview first, the detail tiles follow in the native `images` field.
+fn image_message(prompt: String, images: &[EncodedImage]) -> Message {
+    let prompt = if images.len() > 1 {
+        format!(
+            "{}\nThe first image is the photo, the others are overlapping details of the same photo, from left to right or top to bottom.",
+            prompt
+        )
+    } else {
+        prompt
+    };
+    let images = images.iter().map(|image| image.base64.clone()).collect();
+    Message::user(prompt, images)
+}
+
+/// Turns error answers into [`OllamaError`], and 429 into [`ChatError::RateLimited`].
+async fn parse_response<T: DeserializeOwned>(response: Response) -> Result<T> {
+    Ok(check_status(response).await?.json().await?)
+}
+
+/// Passes successful answers on, turning the others into errors.
+async fn check_status(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<f64>().ok())
+            .map(Duration::from_secs_f64);
+        return Err(ChatError::RateLimited { retry_after }.into());
+    }
+
+    // Errors come as {"error": "..."}
+    let body = response.text().await.unwrap_or_default();
+    let message = serde_json::from_str::<Value>(&body)
+        .ok()
+        .and_then(|value| value["error"].as_str().map(str::to_string))
+        .unwrap_or(body);
+    Err(OllamaError {
+        status: status.as_u16(),
+        message,
+    }
+    .into())
+}
+
+impl VisionDescriber for Ollama {
+    async fn get_image_description(
+        &self,
+        images: &[EncodedImage],
+        context: &DescriptionContext,
+        model: &str,
+    ) -> Result<String> {
+        let messages = vec![image_message(
+            self.prompts.render_description(context)?,
+            images,
+        )];
+        self.chat("description", model, messages, None, None).await
+    }
+
+    async fn get_image_analysis(
+        &self,
+        images: &[EncodedImage],
+        context: &DescriptionContext,
+        model: &str,
+    ) -> Result<ImageAnalysis> {
+        let schema = ImageAnalysis::schema();
+        let prompt = format!(
+            "{}\n{}",
+            self.prompts.render_description(context)?,
+            self.prompts.render_structured(&schema)?
+        );
+        let messages = vec![image_message(prompt, images)];
+
+        // Ollama constrains the answer to the JSON schema passed as format
+        let answer = self
+            .chat("analysis", model, messages, Some(schema.clone()), None)
+            .await?;
+
+        let error = match ImageAnalysis::from_json(&answer) {
+            Ok(analysis) => return Ok(analysis),
+            Err(e) => e,
+        };
+        warn!("Invalid JSON answer, repairing it: {}", error);
+
+        // Fixing the answer needs no image, so the text model does it
+        let messages = vec![Message::user(
+            self.prompts
+                .render_repair(&answer, &error.to_string(), &schema)?,
+            Vec::new(),
+        )];
+        let answer = self
+            .chat(
+                "repair",
+                &self.chat_model,
+                messages,
+                Some(schema),
+                Some(0.0),
+            )
+            .await?;
+
+        ImageAnalysis::from_json(&answer).context("Failed to repair the JSON answer")
+    }
+
+    fn image_models(&self) -> Vec<String> {
+        self.multimodal_models.clone()
+    }
+
+    fn description_prompt(&self) -> String {
+        self.prompts.description.id()
+    }
+}
+
+impl TextEmbedder for Ollama {
+    fn embedding_model(&self) -> String {
+        self.embedding_model.clone()
+    }
+
+    async fn get_embeddings(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let count = texts.len();
+        let request = EmbedRequest {
+            model: &self.embedding_model,
+            input: texts,
+            keep_alive: self.options.keep_alive(),
+            options: self.options.model_options(None, None),
+        };
+
+        let start = Instant::now();
+        let response = self
+            .client
+            .post(format!("{}/api/embed", self.base_url))
+            .json(&request)
+            .send()
+            .await?;
+        let response: EmbedResponse = parse_response(response).await?;
+        record_usage(
+            self.usage_meter.as_deref(),
+            "embeddings",
+            &self.embedding_model,
+            response.prompt_eval_count,
+            0,
+            start,
+        );
+
+        // The embeddings are in the same order as the input texts
+        if response.embeddings.len() != count {
+            return Err(anyhow!(
+                "Expected {} embeddings, got {}",
+                count,
+                response.embeddings.len()
+            ));
+        }
+        Ok(response.embeddings)
+    }
+}
+
+impl AnswerGenerator for Ollama {
+    async fn process_search_result(
+        &self,
+        question: &str,
+        history: &[Turn],
+        options: &[String],
+    ) -> Result<String> {
+        let messages = vec![Message::user(
+            self.prompts.render_answer(question, history, options)?,
+            Vec::new(),
+        )];
+        self.chat("answer", &self.chat_model, messages, None, Some(0.2))
+            .await
+    }
+
+    async fn stream_search_result(
+        &self,
+        question: &str,
+        history: &[Turn],
+        options: &[String],
+    ) -> Result<AnswerStream> {
+        let messages = vec![Message::user(
+            self.prompts.render_answer(question, history, options)?,
+            Vec::new(),
+        )];
+        self.chat_stream("answer", &self.chat_model, messages, Some(0.2))
+            .await
+    }
+
+    async fn rewrite_follow_up(&self, question: &str, history: &[Turn]) -> Result<FollowUp> {
+        let messages = vec![Message::user(
+            self.prompts.render_follow_up(question, history)?,
+            Vec::new(),
+        )];
+        let answer = self
+            .chat(
+                "rewrite",
+                &self.chat_model,
+                messages,
+                Some(FollowUp::schema()),
+                Some(0.0),
+            )
+            .await?;
+        FollowUp::from_json(&answer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        models::{SearchScope, Setting},
+        usage::Prices,
+    };
+    use wiremock::{
+        matchers::{body_json, body_partial_json, header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    fn ollama(server: &MockServer) -> Ollama {
+        Ollama::default()
+            .with_base_url(&server.uri())
+            .with_models("llama3.1:8b", &["llava:13b"], "mxbai-embed-large")
+            .with_options(OllamaOptions {
+                keep_alive: Some("10m".to_string()),
+                num_ctx: Some(8192),
+                temperature: None,
+                seed: Some(42),
+            })
+    }
+
+    fn chat_answer(content: &str) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(json!({
+            "model": "llava:13b",
+            "message": { "role": "assistant", "content": content },
+            "done": true,
+            "prompt_eval_count": 900,
+            "eval_count": 40
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_image_description() -> Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/chat"))
+            .and(body_partial_json(json!({
+                "model": "llava:13b",
+                "stream": false,
+                "keep_alive": "10m",
+                "options": { "num_ctx": 8192, "seed": 42, "num_predict": 512 },
+                "messages": [{ "role": "user", "images": ["b3ZlcnZpZXc=", "dGlsZQ=="] }]
+            })))
+            .respond_with(chat_answer(" A beach at sunset. "))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let images = ["b3ZlcnZpZXc=", "dGlsZQ=="].map(|base64| EncodedImage {
+            base64: base64.to_string(),
+            mime_type: "image/jpeg".to_string(),
+        });
+        let usage_meter = Arc::new(UsageMeter::new("test", Prices::default()));
+        let description = ollama(&server)
+            .with_usage_meter(Arc::clone(&usage_meter))
+            .get_image_description(&images, &DescriptionContext::default(), "llava:13b")
+            .await?;
+        assert_eq!(description, "A beach at sunset.");
+
+        let usage = usage_meter.report().models["llava:13b"];
+        assert_eq!(
+            (usage.calls, usage.prompt_tokens, usage.completion_tokens),
+            (1, 900, 40)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_image_analysis() -> Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/chat"))
+            .and(body_partial_json(json!({ "format": ImageAnalysis::schema() })))
+            .respond_with(chat_answer(
+                r#"{"caption": "A beach.", "title": "Beach", "keywords": ["beach"], "scene_type": "beach", "people_count": 0, "setting": "outdoor", "confidence": 0.8}"#,
+            ))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let images = [EncodedImage {
+            base64: "b3ZlcnZpZXc=".to_string(),
+            mime_type: "image/jpeg".to_string(),
+        }];
+        let analysis = ollama(&server)
+            .get_image_analysis(&images, &DescriptionContext::default(), "llava:13b")
+            .await?;
+        assert_eq!(analysis.title, "Beach");
+        assert_eq!(analysis.setting, Setting::Outdoor);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_follow_up() -> Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/chat"))
+            .and(body_partial_json(json!({ "format": FollowUp::schema() })))
+            .respond_with(chat_answer(
+                r#"{"query": "Photos of the beach in Cefalù with boats", "scope": "earlier"}"#,
+            ))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let history = [Turn {
+            question: "Photos of the beach in Cefalù".to_string(),
+            photos: vec!["Boats on the beach".to_string()],
+            folders: vec!["Cefalù".to_string()],
+            answer: "One photo shows boats on the beach.".to_string(),
+        }];
+        let follow_up = ollama(&server)
+            .rewrite_follow_up("Which of those have boats?", &history)
+            .await?;
+        assert_eq!(follow_up.query, "Photos of the beach in Cefalù with boats");
+        assert_eq!(follow_up.scope, SearchScope::Earlier);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_embeddings() -> Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/embed"))
+            .and(body_json(json!({
+                "model": "mxbai-embed-large",
+                "input": ["a beach", "mountains"],
+                "keep_alive": "10m",
+                "options": { "num_ctx": 8192, "seed": 42 }
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "model": "mxbai-embed-large",
+                "embeddings": [[0.1, 0.2], [0.3, 0.4]]
+            })))
+            .mount(&server)
+            .await;
+
+        let embeddings = ollama(&server)
+            .get_embeddings(vec!["a beach".to_string(), "mountains".to_string()])
+            .await?;
+        assert_eq!(embeddings, vec![vec![0.1, 0.2], vec![0.3, 0.4]]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_answer() -> Result<()> {
+        let server = MockServer::start().await;
+        let lines = [
+            r#"{"message": {"role": "assistant", "content": "At the "}, "done": false}"#,
+            r#"{"message": {"role": "assistant", "content": "beach"}, "done": false}"#,
+            r#"{"message": {"role": "assistant", "content": ""}, "done": true, "prompt_eval_count": 80, "eval_count": 2}"#,
+        ];
+        Mock::given(method("POST"))
+            .and(path("/api/chat"))
+            .and(body_partial_json(
+                json!({ "model": "llama3.1:8b", "stream": true }),
+            ))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_raw(lines.join("\n"), "application/x-ndjson"),
+            )
+            .mount(&server)
+            .await;
+
+        let usage_meter = Arc::new(UsageMeter::new("test", Prices::default()));
+        let fragments: Vec<String> = ollama(&server)
+            .with_usage_meter(Arc::clone(&usage_meter))
+            .stream_search_result("Where is the beach?", &[], &["a beach".to_string()])
+            .await?
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<_>>()?;
+        assert_eq!(fragments, vec!["At the ", "beach"]);
+
+        let usage = usage_meter.report().operations["answer"];
+        assert_eq!(
+            (usage.calls, usage.prompt_tokens, usage.completion_tokens),
+            (1, 80, 2)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_json_lines() -> Result<()> {
+        // Lines are split across chunks, the last one has no line break
+        let chunks: Vec<reqwest::Result<&[u8]>> =
+            vec![Ok(b"{\"a\":"), Ok(b"1}\n\n{\"b\""), Ok(b":2}")];
+        let lines: Vec<Vec<u8>> = json_lines(futures::stream::iter(chunks))
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<_>>()?;
+        assert_eq!(lines, vec![b"{\"a\":1}\n".to_vec(), b"{\"b\":2}".to_vec()]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_model_info() -> Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/show"))
+            .and(body_json(json!({ "model": "llava:13b" })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "details": { "family": "llama", "parameter_size": "13B", "quantization_level": "Q4_0" },
+                "model_info": { "general.architecture": "llama", "llama.context_length": 4096 },
+                "capabilities": ["completion", "vision"]
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/tags"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "models": [{ "name": "llava:13b" }, { "name": "mxbai-embed-large:latest" }]
+            })))
+            .mount(&server)
+            .await;
+
+        let ollama = ollama(&server);
+        let info = ollama.model_info("llava:13b").await?;
+        assert_eq!(info.family, "llama");
+        assert_eq!(info.context_length, Some(4096));
+        assert!(info.has_capability("vision"));
+        assert_eq!(
+            ollama.list_models().await?,
+            vec!["llava:13b", "mxbai-embed-large:latest"]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_api_key() -> Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/tags"))
+            .and(header("authorization", "Bearer secret"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "models": [{ "name": "llava:13b" }]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let ollama = ollama(&server).with_api_key("secret")?;
+        assert_eq!(ollama.list_models().await?, vec!["llava:13b"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_error_answers() -> Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/embed"))
+            .respond_with(
+                ResponseTemplate::new(404)
+                    .set_body_json(json!({ "error": "model \"mxbai-embed-large\" not found" })),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/chat"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "2"))
+            .mount(&server)
+            .await;
+
+        let ollama = ollama(&server);
+        let error = ollama
+            .get_embeddings(vec!["a beach".to_string()])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<OllamaError>(),
+            Some(&OllamaError {
+                status: 404,
+                message: "model \"mxbai-embed-large\" not found".to_string()
+            })
+        );
+
+        let error = ollama
+            .process_search_result("Where is the beach?", &[], &[])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ChatError>(),
+            Some(&ChatError::RateLimited {
+                retry_after: Some(Duration::from_secs(2))
+            })
+        );
+
+        Ok(())
+    }
+}
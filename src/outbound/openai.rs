@@ -1,18 +1,40 @@
-use crate::domain::ports::Chat;
-use anyhow::Result;
+use super::prompts::PromptTemplates;
+use crate::domain::{
+    models::{
+        AnswerStream, ChatError, DescriptionContext, EncodedImage, FollowUp, ImageAnalysis, Turn,
+    },
+    ports::{AnswerGenerator, TextEmbedder, VisionDescriber},
+    usage::{Usage, UsageMeter},
+};
+use anyhow::{Context, Result};
+use async_openai::error::{ApiError, OpenAIError};
 use async_openai::types::{
-    ChatCompletionRequestMessageContentPartTextArgs, CreateChatCompletionResponse,
+    ChatCompletionRequestMessageContentPartTextArgs, ChatCompletionRequestUserMessageContentPart,
+    ChatCompletionStreamOptions, CreateChatCompletionRequest, CreateChatCompletionResponse,
 };
 use async_openai::{
     config::OpenAIConfig,
     types::{
-        ChatCompletionRequestMessageContentPartImageArgs, ChatCompletionRequestSystemMessageArgs,
-        ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs,
-        CreateEmbeddingRequestArgs, EmbeddingInput, ImageDetail, ImageUrlArgs, Role,
+        ChatCompletionRequestMessageContentPartImageArgs, ChatCompletionRequestUserMessageArgs,
+        CreateChatCompletionRequestArgs, CreateEmbeddingRequestArgs, EmbeddingInput, ImageDetail,
+        ImageUrlArgs, ResponseFormat, ResponseFormatJsonSchema, Role,
     },
 };
-use std::{env::var, vec::Vec};
-use tracing::debug;
+use backoff::ExponentialBackoff;
+use futures::{
+    future::ready,
+    stream::{empty, once},
+    StreamExt,
+};
+use regex::Regex;
+use serde_json::Value;
+use std::{
+    env::var,
+    sync::Arc,
+    time::{Duration, Instant},
+    vec::Vec,
+};
+use tracing::{debug, warn};
 
 const EMBEDDING_MODEL: &str = "mxbai-embed-large";
 const BASE_URL: &str = "http://localhost:11434/v1";
@@ -23,8 +45,10 @@ const CHAT_MODEL_TEXT: &str = "llama3.1:8b";
 pub struct OpenAI {
     openai_client: async_openai::Client<OpenAIConfig>,
     chat_model: String,
-    multimodal_model: String,
+    multimodal_models: Vec<String>,
     embedding_model: String,
+    prompts: PromptTemplates,
+    usage_meter: Option<Arc<UsageMeter>>,
 }
 
 impl OpenAI {
@@ -37,100 +61,253 @@ impl OpenAI {
         let openai_config = OpenAIConfig::new()
             .with_api_base(api_base)
             .with_api_key(api_key.unwrap_or_default());
-        let openai_client = async_openai::Client::with_config(openai_config);
+        let openai_client = openai_client(openai_config);
 
         let chat_model = var("CHAT_MODEL").unwrap_or(CHAT_MODEL_TEXT.into());
-        let multimodal_model = var("CHAT_MODEL_IMAGE").unwrap_or(CHAT_MODEL_MULTIMODAL.into());
+        let multimodal_models =
+            parse_model_list(&var("CHAT_MODEL_IMAGE").unwrap_or(CHAT_MODEL_MULTIMODAL.into()));
         let embedding_model = var("CHAT_MODEL_EMBEDDINGS").unwrap_or(EMBEDDING_MODEL.into());
 
         OpenAI {
             openai_client,
             chat_model,
-            multimodal_model,
+            multimodal_models,
             embedding_model,
+            prompts: PromptTemplates::default(),
+            usage_meter: None,
         }
     }
-}
 
-impl Chat for OpenAI {
-    async fn get_image_description(
+    /// Talks to another endpoint, e.g. `http://gpu-box:11434/v1`.
+    pub fn with_api_base(mut self, api_base: &str) -> Self {
+        let openai_config = self.openai_client.config().clone().with_api_base(api_base);
+        self.openai_client = openai_client(openai_config);
+        self
+    }
+
+    pub fn with_api_key(mut self, api_key: &str) -> Self {
+        let openai_config = self.openai_client.config().clone().with_api_key(api_key);
+        self.openai_client = openai_client(openai_config);
+        self
+    }
+
+    /// Replaces the builtin prompt templates, e.g. with the ones selected for this run.
+    pub fn with_prompts(mut self, prompts: PromptTemplates) -> Self {
+        self.prompts = prompts;
+        self
+    }
+
+    /// Counts the tokens, the time and the cost of all calls.
+    pub fn with_usage_meter(mut self, usage_meter: Arc<UsageMeter>) -> Self {
+        self.usage_meter = Some(usage_meter);
+        self
+    }
+
+    /// Sends a chat request, recording the tokens the backend reports.
+    ///
+    /// # Arguments
+    ///
+    /// * `operation` - The kind of call, e.g. `description`.
+    /// * `request` - The chat request.
+    async fn complete(
         &self,
-        image: &str,
-        persons: &[String],
-        folder_name: &Option<String>,
+        operation: &'static str,
+        request: CreateChatCompletionRequest,
     ) -> Result<String> {
-        let mut messages = vec![
-                ChatCompletionRequestUserMessageArgs::default()
-                    .content("You are a traveler immersed in the world around you. Describe the scene with attention to cultural, geographical, and sensory details. Offer personal insights and reflections that reveal the atmosphere, local traditions, and unique experiences of the place. Bring the reader into the moment with vivid descriptions.")
-                    .build()?
-                    .into(),
-                 ChatCompletionRequestUserMessageArgs::default()
-                    .content(vec![
-                        ChatCompletionRequestMessageContentPartTextArgs::default()
-                            .text("The photo: ")
-                            .build()?
-                            .into(),
-                        ChatCompletionRequestMessageContentPartImageArgs::default()
-                            .image_url(
-                                ImageUrlArgs::default()
-                                    .url(format!("data:image/jpeg;base64,{}", image))
-                                    .detail(ImageDetail::High)
-                                    .build()?,
-                            )
-                            .build()?
-                            .into(),
-                        ])
-                    .build()?
-                    .into(),
-                ChatCompletionRequestUserMessageArgs::default()
-                    .content("Ensure the description is concise and engaging. Limit the description to 2-3 sentences.")
-                    .build()?
-                    .into(),
-                ChatCompletionRequestUserMessageArgs::default()
-                    .content("Avoid generating a description if the image is unclear. Be confident in the description and do not use words like 'likely' or 'perhaps'.")
-                    .build()?
-                    .into(),
-                ChatCompletionRequestUserMessageArgs::default()
-                    .content("Do not refer to the image explicitly. Avoid phrases such as 'This image shows' or 'In this photo' or 'This scene'. Focus on describing the essence of the scene directly without any verbs.")
-                    .build()?
-                    .into(),
-            ];
+        debug!("OpenAI Request: {:?}", request.messages);
+        // The answer names the snapshot, e.g. gpt-4o-mini-2024-07-18 - the prices name the model
+        let model = request.model.clone();
+        let start = Instant::now();
+        let response = self
+            .openai_client
+            .chat()
+            .create(request)
+            .await
+            .map_err(map_openai_error)?;
 
-        if !persons.is_empty() {
-            let message_content = format!(
-                "Use the person(s) {} as a hint who is in the photo when generating the image summary",
-                persons.join(", ")
+        if let Some(usage) = &response.usage {
+            record_usage(
+                self.usage_meter.as_deref(),
+                operation,
+                &model,
+                usage.prompt_tokens,
+                usage.completion_tokens,
+                start,
             );
+        }
+        Ok(process_openai_response(response))
+    }
 
-            let message = ChatCompletionRequestUserMessageArgs::default()
-                .content(message_content)
-                .build()?;
+    /// Builds the request answering the question from the search results.
+    fn answer_request(
+        &self,
+        question: &str,
+        history: &[Turn],
+        options: &[String],
+    ) -> Result<CreateChatCompletionRequest> {
+        let messages = vec![ChatCompletionRequestUserMessageArgs::default()
+            .content(self.prompts.render_answer(question, history, options)?)
+            .build()?
+            .into()];
 
-            messages.push(message.into());
-        }
+        Ok(CreateChatCompletionRequestArgs::default()
+            .max_tokens(512u16)
+            .model(&self.chat_model)
+            .messages(messages)
+            .temperature(0.2)
+            .build()?)
+    }
+}
+
+/// Creates the client - rate limited calls are not retried by the client, but surface as
+/// [`ChatError::RateLimited`].
+fn openai_client(openai_config: OpenAIConfig) -> async_openai::Client<OpenAIConfig> {
+    async_openai::Client::with_config(openai_config).with_backoff(ExponentialBackoff {
+        max_elapsed_time: Some(Duration::ZERO),
+        ..ExponentialBackoff::default()
+    })
+}
 
-        if let Some(folder) = folder_name {
-            let message_content = format!(
-                    "Use the folder {} as a hint where this photo was taken when generating the image summary",
-                    folder
-                );
+/// Builds the message parts for the overview image, followed by the detail tiles if any.
+fn image_parts(
+    images: &[EncodedImage],
+) -> Result<Vec<ChatCompletionRequestUserMessageContentPart>> {
+    let image_part = |image: &EncodedImage| -> Result<ChatCompletionRequestUserMessageContentPart> {
+        Ok(ChatCompletionRequestMessageContentPartImageArgs::default()
+            .image_url(
+                ImageUrlArgs::default()
+                    .url(format!("data:{};base64,{}", image.mime_type, image.base64))
+                    .detail(ImageDetail::High)
+                    .build()?,
+            )
+            .build()?
+            .into())
+    };
 
-            let message = ChatCompletionRequestUserMessageArgs::default()
-                .content(message_content)
-                .build()?;
+    let mut parts = Vec::new();
+    if let Some((overview, tiles)) = images.split_first() {
+        parts.push(
+            ChatCompletionRequestMessageContentPartTextArgs::default()
+                .text("The photo: ")
+                .build()?
+                .into(),
+        );
+        parts.push(image_part(overview)?);
 
-            messages.push(message.into());
+        if !tiles.is_empty() {
+            parts.push(
+                ChatCompletionRequestMessageContentPartTextArgs::default()
+                    .text("Overlapping details of the same photo, from left to right or top to bottom: ")
+                    .build()?
+                    .into(),
+            );
+            for tile in tiles {
+                parts.push(image_part(tile)?);
+            }
         }
+    }
+
+    Ok(parts)
+}
+
+impl VisionDescriber for OpenAI {
+    async fn get_image_description(
+        &self,
+        images: &[EncodedImage],
+        context: &DescriptionContext,
+        model: &str,
+    ) -> Result<String> {
+        let messages = vec![
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(self.prompts.render_description(context)?)
+                .build()?
+                .into(),
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(image_parts(images)?)
+                .build()?
+                .into(),
+        ];
 
         let request = CreateChatCompletionRequestArgs::default()
             .max_tokens(512u16)
-            .model(&self.multimodal_model)
+            .model(model)
             .messages(messages)
             .build()?;
 
-        debug!("OpenAI Request: {:?}", request.messages);
-        let response = self.openai_client.chat().create(request).await?;
-        Ok(process_openai_response(response))
+        self.complete("description", request).await
+    }
+
+    async fn get_image_analysis(
+        &self,
+        images: &[EncodedImage],
+        context: &DescriptionContext,
+        model: &str,
+    ) -> Result<ImageAnalysis> {
+        let schema = ImageAnalysis::schema();
+        let messages = vec![
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(self.prompts.render_description(context)?)
+                .build()?
+                .into(),
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(image_parts(images)?)
+                .build()?
+                .into(),
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(self.prompts.render_structured(&schema)?)
+                .build()?
+                .into(),
+        ];
+
+        let request = CreateChatCompletionRequestArgs::default()
+            .max_tokens(512u16)
+            .model(model)
+            .messages(messages)
+            .response_format(json_schema_format(&schema))
+            .build()?;
+
+        let answer = self.complete("analysis", request).await?;
+
+        let error = match ImageAnalysis::from_json(&answer) {
+            Ok(analysis) => return Ok(analysis),
+            Err(e) => e,
+        };
+        warn!("Invalid JSON answer, repairing it: {}", error);
+
+        // Fixing the answer needs no image, so the text model does it
+        let messages = vec![ChatCompletionRequestUserMessageArgs::default()
+            .content(
+                self.prompts
+                    .render_repair(&answer, &error.to_string(), &schema)?,
+            )
+            .build()?
+            .into()];
+
+        let request = CreateChatCompletionRequestArgs::default()
+            .max_tokens(512u16)
+            .model(&self.chat_model)
+            .messages(messages)
+            .response_format(json_schema_format(&schema))
+            .temperature(0.0)
+            .build()?;
+
+        let answer = self.complete("repair", request).await?;
+
+        ImageAnalysis::from_json(&answer).context("Failed to repair the JSON answer")
+    }
+
+    fn image_models(&self) -> Vec<String> {
+        self.multimodal_models.clone()
+    }
+
+    fn description_prompt(&self) -> String {
+        self.prompts.description.id()
+    }
+}
+
+impl TextEmbedder for OpenAI {
+    fn embedding_model(&self) -> String {
+        self.embedding_model.clone()
     }
 
     async fn get_embeddings(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
@@ -141,39 +318,203 @@ impl Chat for OpenAI {
             .input(input)
             .build()?;
 
-        let response = self.openai_client.embeddings().create(request).await?;
+        let start = Instant::now();
+        let response = self
+            .openai_client
+            .embeddings()
+            .create(request)
+            .await
+            .map_err(map_openai_error)?;
+        record_usage(
+            self.usage_meter.as_deref(),
+            "embeddings",
+            &self.embedding_model,
+            response.usage.prompt_tokens,
+            0,
+            start,
+        );
 
         // Extract all embeddings from the response - they are in the same order as the input texts
         let embeddings: Vec<Vec<f32>> = response.data.into_iter().map(|d| d.embedding).collect();
         Ok(embeddings)
     }
+}
 
-    async fn process_search_result(&self, question: &str, options: &[String]) -> Result<String> {
-        let messages =
-            vec![
-            ChatCompletionRequestSystemMessageArgs::default()
-                .content(
-                    "You are a helpful assistant answering the question using the provided options.",
-                )
-                .build()?
-                .into(),
-            ChatCompletionRequestUserMessageArgs::default()
-                .content(format!("\nQuestion: {}Options: {}", question, options.join("\n")))
-                .build()?
-                .into(),
-        ];
+impl AnswerGenerator for OpenAI {
+    async fn process_search_result(
+        &self,
+        question: &str,
+        history: &[Turn],
+        options: &[String],
+    ) -> Result<String> {
+        let request = self.answer_request(question, history, options)?;
+        self.complete("answer", request).await
+    }
+
+    async fn stream_search_result(
+        &self,
+        question: &str,
+        history: &[Turn],
+        options: &[String],
+    ) -> Result<AnswerStream> {
+        let mut request = self.answer_request(question, history, options)?;
+        // The usage comes with the last chunk, but only on request - the meter and the budget
+        // of the rate limiter count it
+        request.stream_options = Some(ChatCompletionStreamOptions {
+            include_usage: true,
+        });
+
+        debug!("OpenAI Request: {:?}", request.messages);
+        let model = request.model.clone();
+        let start = Instant::now();
+        let stream = self
+            .openai_client
+            .chat()
+            .create_stream(request)
+            .await
+            .map_err(map_openai_error)?;
+        let usage_meter = self.usage_meter.clone();
+        let mut fragments = stream
+            .filter_map(move |chunk| {
+                let fragment = match chunk {
+                    Ok(chunk) => {
+                        if let Some(usage) = &chunk.usage {
+                            record_usage(
+                                usage_meter.as_deref(),
+                                "answer",
+                                &model,
+                                usage.prompt_tokens,
+                                usage.completion_tokens,
+                                start,
+                            );
+                        }
+                        let text: String = chunk
+                            .choices
+                            .iter()
+                            .filter_map(|choice| choice.delta.content.as_deref())
+                            .collect();
+                        (!text.is_empty()).then_some(Ok(text))
+                    }
+                    Err(e) => Some(Err(map_openai_error(e))),
+                };
+                ready(fragment)
+            })
+            .boxed();
+
+        // Failed requests, e.g. rate limited ones, only show in the stream - wait for the first
+        // fragment so that they fail the call and can be retried.
+        match fragments.next().await {
+            Some(Err(e)) => Err(e),
+            Some(Ok(first)) => Ok(once(ready(Ok(first))).chain(fragments).boxed()),
+            None => Ok(empty().boxed()),
+        }
+    }
+
+    async fn rewrite_follow_up(&self, question: &str, history: &[Turn]) -> Result<FollowUp> {
+        let messages = vec![ChatCompletionRequestUserMessageArgs::default()
+            .content(self.prompts.render_follow_up(question, history)?)
+            .build()?
+            .into()];
 
         let request = CreateChatCompletionRequestArgs::default()
-            .max_tokens(512u16)
+            .max_tokens(256u16)
             .model(&self.chat_model)
             .messages(messages)
-            .temperature(0.2)
+            .response_format(json_schema_format(&FollowUp::schema()))
+            .temperature(0.0)
             .build()?;
 
-        debug!("OpenAI Request: {:?}", request.messages);
-        let response = self.openai_client.chat().create(request).await?;
-        Ok(process_openai_response(response))
+        let answer = self.complete("rewrite", request).await?;
+        FollowUp::from_json(&answer)
+    }
+}
+
+/// Records the tokens of a call which started at `start`, for the meter if one is set and the
+/// rate limiter.
+fn record_usage(
+    usage_meter: Option<&UsageMeter>,
+    operation: &'static str,
+    model: &str,
+    prompt_tokens: u32,
+    completion_tokens: u32,
+    start: Instant,
+) {
+    Usage {
+        operation,
+        model: model.to_string(),
+        prompt_tokens: prompt_tokens.into(),
+        completion_tokens: completion_tokens.into(),
+        latency: start.elapsed(),
     }
+    .record(usage_meter);
+}
+
+/// Splits a comma separated list of models, e.g. `llava:13b, llama3.2-vision`, the first being
+/// the primary model.
+pub(crate) fn parse_model_list(models: &str) -> Vec<String> {
+    models
+        .split(',')
+        .map(str::trim)
+        .filter(|model| !model.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Asks for an answer matching the JSON schema, which models with structured outputs enforce.
+fn json_schema_format(schema: &Value) -> ResponseFormat {
+    ResponseFormat::JsonSchema {
+        json_schema: ResponseFormatJsonSchema {
+            description: None,
+            name: "image_analysis".to_string(),
+            schema: Some(schema.clone()),
+            strict: Some(true),
+        },
+    }
+}
+
+/// Turns the answers asking to slow down into [`ChatError::RateLimited`].
+fn map_openai_error(error: OpenAIError) -> anyhow::Error {
+    match &error {
+        OpenAIError::ApiError(api_error) if is_rate_limit(api_error) => ChatError::RateLimited {
+            retry_after: parse_retry_after(&api_error.message),
+        }
+        .into(),
+        _ => error.into(),
+    }
+}
+
+/// OpenAI names the exhausted limit as type, compatible APIs use the code.
+fn is_rate_limit(api_error: &ApiError) -> bool {
+    let names = [api_error.r#type.as_deref(), api_error.code.as_deref()];
+    names
+        .iter()
+        .flatten()
+        .any(|name| matches!(*name, "requests" | "tokens") || name.contains("rate_limit"))
+}
+
+/// Reads the wait time from messages like "Please try again in 1.5s" or "in 6m0s".
+///
+/// The `Retry-After` header cannot be read here: async-openai consumes the response of a 429
+/// itself and passes on the error body only, as [`OpenAIError::ApiError`]. OpenAI and most
+/// compatible servers repeat the wait time in the message.
+fn parse_retry_after(message: &str) -> Option<Duration> {
+    let pattern = Regex::new(r"try again in ((?:\d+(?:\.\d+)?(?:ms|h|m|s))+)").ok()?;
+    let wait = pattern.captures(message)?.get(1)?.as_str();
+
+    let part = Regex::new(r"(\d+(?:\.\d+)?)(ms|h|m|s)").ok()?;
+    let seconds = part
+        .captures_iter(wait)
+        .map(|captures| {
+            let value: f64 = captures[1].parse().unwrap_or_default();
+            match &captures[2] {
+                "ms" => value / 1000.0,
+                "m" => value * 60.0,
+                "h" => value * 3600.0,
+                _ => value,
+            }
+        })
+        .sum();
+    Some(Duration::from_secs_f64(seconds))
 }
 
 fn process_openai_response(response: CreateChatCompletionResponse) -> String {
@@ -190,3 +531,101 @@ fn process_openai_response(response: CreateChatCompletionResponse) -> String {
         .collect::<Vec<_>>()
         .join(" ")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::usage::Prices;
+    use serde_json::json;
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    #[tokio::test]
+    async fn test_usage_of_requested_model() -> Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion",
+                "created": 1_700_000_000,
+                "model": "gpt-4o-mini-2024-07-18",
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": "Two photos show the beach." },
+                    "finish_reason": "stop"
+                }],
+                "usage": { "prompt_tokens": 1000, "completion_tokens": 100, "total_tokens": 1100 }
+            })))
+            .mount(&server)
+            .await;
+
+        let usage_meter = Arc::new(UsageMeter::new(
+            "test",
+            Prices::parse("gpt-4o-mini=0.15/0.60")?,
+        ));
+        let mut openai = OpenAI::new()
+            .with_api_base(&server.uri())
+            .with_usage_meter(Arc::clone(&usage_meter));
+        openai.chat_model = "gpt-4o-mini".to_string();
+        let answer = openai
+            .process_search_result("Where is the beach?", &[], &["A beach".to_string()])
+            .await?;
+        assert_eq!(answer, "Two photos show the beach.");
+
+        // The usage is priced under the requested model, not the snapshot of the answer
+        let report = usage_meter.report();
+        let usage = report.models["gpt-4o-mini"];
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (1000, 100));
+        assert!((usage.cost - 0.00021).abs() < 1e-9);
+        assert!(!report.models.contains_key("gpt-4o-mini-2024-07-18"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_map_openai_error() {
+        let rate_limit = OpenAIError::ApiError(ApiError {
+            message: "Rate limit reached for gpt-4o-mini on tokens per min (TPM): Limit 200000, Used 199500, Requested 1200. Please try again in 210ms.".to_string(),
+            r#type: Some("tokens".to_string()),
+            param: None,
+            code: Some("rate_limit_exceeded".to_string()),
+        });
+        let error = map_openai_error(rate_limit);
+        assert_eq!(
+            error.downcast_ref::<ChatError>(),
+            Some(&ChatError::RateLimited {
+                retry_after: Some(Duration::from_millis(210))
+            })
+        );
+
+        let invalid = OpenAIError::InvalidArgument("model".to_string());
+        assert!(map_openai_error(invalid)
+            .downcast_ref::<ChatError>()
+            .is_none());
+    }
+
+    #[test]
+    fn test_parse_model_list() {
+        assert_eq!(
+            parse_model_list("llava:13b, llama3.2-vision,"),
+            vec!["llava:13b", "llama3.2-vision"]
+        );
+        assert_eq!(parse_model_list("gpt-4o-mini"), vec!["gpt-4o-mini"]);
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(
+            parse_retry_after("Please try again in 1.5s."),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            parse_retry_after("Please try again in 6m0s. Visit our docs"),
+            Some(Duration::from_secs(360))
+        );
+        assert_eq!(parse_retry_after("Rate limit reached"), None);
+    }
+}
@@ -0,0 +1,390 @@
+use anyhow::{anyhow, Context, Result};
+use minijinja::{context, Environment, UndefinedBehavior, Value};
+use std::{
+    env::var,
+    fs::read_to_string,
+    path::{Path, PathBuf},
+};
+use tracing::info;
+
+use crate::domain::models::{DescriptionContext, Turn};
+
+const DESCRIPTION: &str = "description";
+const ANSWER: &str = "answer";
+const STRUCTURED: &str = "structured";
+const REPAIR: &str = "repair";
+const REWRITE: &str = "rewrite";
+const JSON_TEMPLATE: &str = "json";
+const FOLLOW_UP_TEMPLATE: &str = "follow_up";
+const DEFAULT_DESCRIPTION_TEMPLATE: &str = "traveler";
+const DEFAULT_ANSWER_TEMPLATE: &str = "assistant";
+const DEFAULT_LANGUAGE: &str = "English";
+
+// The templates shipped with the binaries, so they work from any directory
+const BUILTIN_TEMPLATES: [(&str, &str, &str); 5] = [
+    (
+        DESCRIPTION,
+        DEFAULT_DESCRIPTION_TEMPLATE,
+        include_str!("../../prompts/description/traveler.jinja"),
+    ),
+    (
+        ANSWER,
+        DEFAULT_ANSWER_TEMPLATE,
+        include_str!("../../prompts/answer/assistant.jinja"),
+    ),
+    (
+        STRUCTURED,
+        JSON_TEMPLATE,
+        include_str!("../../prompts/structured/json.jinja"),
+    ),
+    (
+        REPAIR,
+        JSON_TEMPLATE,
+        include_str!("../../prompts/repair/json.jinja"),
+    ),
+    (
+        REWRITE,
+        FOLLOW_UP_TEMPLATE,
+        include_str!("../../prompts/rewrite/follow_up.jinja"),
+    ),
+];
+
+/// A prompt written as MiniJinja template, starting with a `{# version: <version> #}` header.
+#[derive(Debug, Clone)]
+pub struct PromptTemplate {
+    pub name: String,
+    pub version: String,
+    source: String,
+}
+
+impl PromptTemplate {
+    /// Reads the version header and checks the syntax of the template.
+    pub fn parse(name: &str, source: &str) -> Result<Self> {
+        let version = source
+            .lines()
+            .next()
+            .and_then(|line| line.trim().strip_prefix("{#"))
+            .and_then(|line| line.strip_suffix("#}"))
+            .and_then(|line| line.trim().strip_prefix("version:"))
+            .map(|version| version.trim().to_string())
+            .filter(|version| !version.is_empty())
+            .ok_or_else(|| {
+                anyhow!(
+                    "Prompt template {} must start with a {{# version: <version> #}} header",
+                    name
+                )
+            })?;
+
+        environment()
+            .template_from_str(source)
+            .with_context(|| format!("Invalid prompt template {}", name))?;
+
+        Ok(Self {
+            name: name.to_string(),
+            version,
+            source: source.to_string(),
+        })
+    }
+
+    /// Returns name and version, e.g. `traveler@3` - stored with the results.
+    pub fn id(&self) -> String {
+        format!("{}@{}", self.name, self.version)
+    }
+
+    pub fn render(&self, context: Value) -> Result<String> {
+        let text = environment()
+            .template_from_str(&self.source)?
+            .render(context)
+            .with_context(|| format!("Failed to render prompt template {}", self.id()))?;
+        Ok(text.trim().to_string())
+    }
+}
+
+/// The prompt templates of a run - one for the image descriptions, one for the search answers,
+/// two asking for and repairing JSON answers and one rewriting follow-up questions.
+#[derive(Debug, Clone)]
+pub struct PromptTemplates {
+    pub description: PromptTemplate,
+    pub answer: PromptTemplate,
+    pub structured: PromptTemplate,
+    pub repair: PromptTemplate,
+    pub rewrite: PromptTemplate,
+    language: String,
+}
+
+impl Default for PromptTemplates {
+    fn default() -> Self {
+        Self::load(
+            None,
+            DEFAULT_DESCRIPTION_TEMPLATE,
+            DEFAULT_ANSWER_TEMPLATE,
+            DEFAULT_LANGUAGE,
+        )
+        .expect("The builtin prompt templates are valid")
+    }
+}
+
+impl PromptTemplates {
+    /// Loads the templates by name.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - An optional directory with `description/<name>.jinja`, `answer/<name>.jinja`, `structured/json.jinja` and `repair/json.jinja` files, searched before the builtin templates.
+    /// * `description` - The name of the template for the image descriptions.
+    /// * `answer` - The name of the template for the search answers.
+    /// * `language` - The language the model should write in.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Self>` - The templates, or an error if one is missing or invalid.
+    pub fn load(
+        dir: Option<&Path>,
+        description: &str,
+        answer: &str,
+        language: &str,
+    ) -> Result<Self> {
+        Ok(Self {
+            description: find_template(dir, DESCRIPTION, description)?,
+            answer: find_template(dir, ANSWER, answer)?,
+            structured: find_template(dir, STRUCTURED, JSON_TEMPLATE)?,
+            repair: find_template(dir, REPAIR, JSON_TEMPLATE)?,
+            rewrite: find_template(dir, REWRITE, FOLLOW_UP_TEMPLATE)?,
+            language: language.to_string(),
+        })
+    }
+
+    /// Loads the templates selected by `PROMPT_DESCRIPTION` and `PROMPT_ANSWER` from
+    /// `PROMPT_DIR`, written in `PROMPT_LANGUAGE`.
+    pub fn from_env() -> Result<Self> {
+        // load env from .env file
+        dotenv::dotenv().ok();
+        let dir = var("PROMPT_DIR").ok().map(PathBuf::from);
+        let description =
+            var("PROMPT_DESCRIPTION").unwrap_or(DEFAULT_DESCRIPTION_TEMPLATE.to_string());
+        let answer = var("PROMPT_ANSWER").unwrap_or(DEFAULT_ANSWER_TEMPLATE.to_string());
+        let language = var("PROMPT_LANGUAGE").unwrap_or(DEFAULT_LANGUAGE.to_string());
+
+        let templates = Self::load(dir.as_deref(), &description, &answer, &language)?;
+        info!(
+            "Prompt templates: description {}, answer {}, structured {}, repair {}, rewrite {}, \
+             language {}",
+            templates.description.id(),
+            templates.answer.id(),
+            templates.structured.id(),
+            templates.repair.id(),
+            templates.rewrite.id(),
+            templates.language
+        );
+        Ok(templates)
+    }
+
+    /// Returns the language the model should write in, e.g. `English`.
+    pub fn language(&self) -> &str {
+        &self.language
+    }
+
+    pub fn render_description(&self, description_context: &DescriptionContext) -> Result<String> {
+        self.description.render(context! {
+            language => self.language,
+            persons => description_context.persons,
+            folder => description_context.folder,
+            location => description_context.location,
+            place => description_context.place,
+            date => description_context.date,
+            season => description_context.season,
+            camera => description_context.camera,
+            previous_description => description_context.previous_description,
+            next_description => description_context.next_description,
+            feedback => description_context.feedback,
+        })
+    }
+
+    /// Renders the instructions to answer as JSON object matching the schema.
+    pub fn render_structured(&self, schema: &serde_json::Value) -> Result<String> {
+        self.structured.render(context! {
+            language => self.language,
+            schema => schema.to_string(),
+        })
+    }
+
+    /// Renders the request to fix an answer which did not match the schema.
+    pub fn render_repair(
+        &self,
+        answer: &str,
+        error: &str,
+        schema: &serde_json::Value,
+    ) -> Result<String> {
+        self.repair.render(context! {
+            answer => answer,
+            error => error,
+            schema => schema.to_string(),
+        })
+    }
+
+    /// Renders the request to turn a follow-up question into a search which stands alone.
+    pub fn render_follow_up(&self, question: &str, history: &[Turn]) -> Result<String> {
+        self.rewrite.render(context! {
+            language => self.language,
+            question => question,
+            history => history,
+        })
+    }
+
+    pub fn render_answer(
+        &self,
+        question: &str,
+        history: &[Turn],
+        options: &[String],
+    ) -> Result<String> {
+        self.answer.render(context! {
+            language => self.language,
+            question => question,
+            history => history,
+            options => options,
+        })
+    }
+}
+
+/// Trims the whitespace around the tags and fails on misspelled variables.
+fn environment() -> Environment<'static> {
+    let mut environment = Environment::new();
+    environment.set_trim_blocks(true);
+    environment.set_lstrip_blocks(true);
+    environment.set_undefined_behavior(UndefinedBehavior::Strict);
+    environment
+}
+
+/// Looks for the template in the directory first, then among the builtin templates.
+fn find_template(dir: Option<&Path>, kind: &str, name: &str) -> Result<PromptTemplate> {
+    if let Some(dir) = dir {
+        let path = dir.join(kind).join(format!("{}.jinja", name));
+        if path.exists() {
+            let source = read_to_string(&path)
+                .with_context(|| format!("Failed to read prompt template {}", path.display()))?;
+            return PromptTemplate::parse(name, &source);
+        }
+    }
+
+    BUILTIN_TEMPLATES
+        .iter()
+        .find(|(builtin_kind, builtin_name, _)| *builtin_kind == kind && *builtin_name == name)
+        .map(|(_, _, source)| PromptTemplate::parse(name, source))
+        .unwrap_or_else(|| Err(anyhow!("Unknown {} prompt template {}", kind, name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+
+    #[test]
+    fn test_render_builtin_templates() -> Result<()> {
+        let templates = PromptTemplates::default();
+        assert_eq!(templates.description.id(), "traveler@3");
+
+        let context = DescriptionContext {
+            persons: vec!["Alice".to_string(), "Bob".to_string()],
+            folder: Some("Rome 2023".to_string()),
+            ..Default::default()
+        };
+        let prompt = templates.render_description(&context)?;
+        assert!(prompt.starts_with("You are a traveler"));
+        assert!(prompt.contains("Write the description in English."));
+        assert!(prompt.contains("Use the person(s) Alice, Bob as a hint"));
+        assert!(prompt.contains("Use the folder Rome 2023 as a hint"));
+        // Unknown hints leave no trace
+        assert!(!prompt.contains("GPS"));
+        assert!(!prompt.contains("camera"));
+        assert!(!prompt.contains("rejected"));
+        assert!(!prompt.contains("series"));
+        assert!(!prompt.contains("\n\n"));
+
+        let context = DescriptionContext {
+            place: Some("Cefalù, Sicily, Italy".to_string()),
+            date: Some("2023-07-14".to_string()),
+            season: Some("summer".to_string()),
+            previous_description: Some("Fishing boats in the harbour".to_string()),
+            ..Default::default()
+        };
+        let prompt = templates.render_description(&context)?;
+        assert!(prompt.contains("Use the place Cefalù, Sicily, Italy as a hint"));
+        assert!(prompt.contains("Use the date 2023-07-14 (summer) as a hint"));
+        assert!(prompt.contains("part of a series"));
+        assert!(prompt.contains("Before: Fishing boats in the harbour"));
+        assert!(!prompt.contains("After:"));
+
+        let prompt = templates.render_answer(
+            "Where is the beach?",
+            &[],
+            &["a".to_string(), "b".to_string()],
+        )?;
+        assert!(prompt.ends_with("Question: Where is the beach?\nOptions:\na\nb"));
+        assert!(!prompt.contains("Earlier questions"));
+
+        let history = [Turn {
+            question: "Photos from Sicily".to_string(),
+            photos: vec!["a".to_string()],
+            folders: vec!["Cefalù".to_string()],
+            answer: "One of a beach".to_string(),
+        }];
+        let prompt = templates.render_answer("Which town?", &history, &["a".to_string()])?;
+        assert!(prompt.contains(
+            "Earlier questions and answers of this conversation:\n\
+             Question: Photos from Sicily\nAnswer: One of a beach\n\nQuestion: Which town?"
+        ));
+
+        let prompt = templates.render_follow_up("Which town?", &history)?;
+        assert!(prompt.contains(
+            "Question: Photos from Sicily\nPhotos:\n- a\nFolders: Cefalù\nAnswer: One of a beach"
+        ));
+        assert!(prompt.contains("Follow-up question: Which town?"));
+
+        let schema = serde_json::json!({ "type": "object" });
+        let prompt = templates.render_structured(&schema)?;
+        assert!(prompt.ends_with("{\"type\":\"object\"}"));
+        let prompt = templates.render_repair("{\"caption\": 1}", "invalid type", &schema)?;
+        assert!(prompt.starts_with("This answer is invalid - invalid type:\n{\"caption\": 1}"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_templates_from_dir() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        create_dir_all(temp_dir.path().join(DESCRIPTION))?;
+        write(
+            temp_dir.path().join(DESCRIPTION).join("short.jinja"),
+            "{# version: 2024-11 #}\nDescribe the photo in {{ language }}, taken on {{ date }}.",
+        )?;
+
+        // The answer template falls back to the builtin one
+        let templates =
+            PromptTemplates::load(Some(temp_dir.path()), "short", "assistant", "German")?;
+        assert_eq!(templates.description.id(), "short@2024-11");
+        assert_eq!(templates.answer.id(), "assistant@2");
+
+        let context = DescriptionContext {
+            date: Some("2023-07-14".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            templates.render_description(&context)?,
+            "Describe the photo in German, taken on 2023-07-14."
+        );
+
+        assert!(
+            PromptTemplates::load(Some(temp_dir.path()), "missing", "assistant", "German").is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_template() {
+        assert!(PromptTemplate::parse("no-version", "Describe the photo.").is_err());
+        assert!(PromptTemplate::parse("broken", "{# version: 1 #}\n{% if persons %}").is_err());
+        // Misspelled variables fail when rendering
+        let template = PromptTemplate::parse("typo", "{# version: 1 #}\n{{ persns }}").unwrap();
+        assert!(template.render(context! { persons => ["Alice"] }).is_err());
+    }
+}
@@ -1,23 +1,26 @@
 use crate::domain::{
-    models::{VectorInput, VectorOutput, VectorOutputList},
+    models::{VectorInput, VectorName, VectorOutput, VectorOutputList},
     ports::VectorDB,
 };
 use anyhow::{Error, Result};
 use qdrant_client::{
     qdrant::{
-        point_id::PointIdOptions, Condition, CreateCollectionBuilder, Distance, Filter,
-        GetPointsBuilder, PayloadIncludeSelector, PointId, PointStruct, RetrievedPoint,
-        ScalarQuantizationBuilder, ScoredPoint, SearchPointsBuilder, UpsertPointsBuilder,
-        VectorParamsBuilder,
+        point_id::PointIdOptions, vectors_config::Config, Condition, CreateCollectionBuilder,
+        Distance, Filter, GetPointsBuilder, PayloadIncludeSelector, PointId, PointStruct,
+        RetrievedPoint, ScalarQuantizationBuilder, ScoredPoint, SearchPointsBuilder,
+        UpsertPointsBuilder, Value, VectorParamsBuilder, VectorsConfigBuilder,
     },
     Payload, Qdrant,
 };
 use serde_json::json;
 use std::{collections::HashMap, env::var, vec};
 
+// Dimension of the CLIP ViT-B/32 image embeddings
+const IMAGE_DIMENSION: u64 = 512;
+
 pub struct QdrantClient {
     client: Qdrant,
-    dimensions: u64,
+    image_dimensions: u64,
 }
 
 impl QdrantClient {
@@ -25,22 +28,37 @@ impl QdrantClient {
         // load env from .env file
         dotenv::dotenv().ok();
         let url = var("QDRANT_GRPC_URL").expect("QDRANT_GRPC must be set in .env file");
-        let dimensions: u64 = var("QDRANT_GRPC_DIMENSION")
-            .expect("QDRANT_GRPC_DIMENSION must be set in .env file")
-            .parse()
-            .expect("QDRANT_GRPC_DIMENSION must be a valid u64");
+        let image_dimensions: u64 = var("QDRANT_GRPC_IMAGE_DIMENSION")
+            .map(|d| {
+                d.parse()
+                    .expect("QDRANT_GRPC_IMAGE_DIMENSION must be a valid u64")
+            })
+            .unwrap_or(IMAGE_DIMENSION);
 
         let client = Qdrant::from_url(&url).build()?;
-        Ok(Self { client, dimensions })
+        Ok(Self {
+            client,
+            image_dimensions,
+        })
     }
 }
 
 impl VectorDB for QdrantClient {
-    async fn create_collection(&self, collection: &str) -> Result<bool> {
+    async fn create_collection(&self, collection: &str, dimensions: u64) -> Result<bool> {
+        let mut vectors_config = VectorsConfigBuilder::default();
+        vectors_config.add_named_vector_params(
+            VectorName::Description.as_str(),
+            VectorParamsBuilder::new(dimensions, Distance::Cosine),
+        );
+        vectors_config.add_named_vector_params(
+            VectorName::Image.as_str(),
+            VectorParamsBuilder::new(self.image_dimensions, Distance::Cosine),
+        );
+
         self.client
             .create_collection(
                 CreateCollectionBuilder::new(collection)
-                    .vectors_config(VectorParamsBuilder::new(self.dimensions, Distance::Cosine))
+                    .vectors_config(vectors_config)
                     .quantization_config(ScalarQuantizationBuilder::default()),
             )
             .await
@@ -56,13 +74,47 @@ impl VectorDB for QdrantClient {
             .map_err(Error::from)
     }
 
+    async fn collection_vectors(&self, collection: &str) -> Result<Option<Vec<String>>> {
+        if !self.client.collection_exists(collection).await? {
+            return Ok(None);
+        }
+
+        let info = self.client.collection_info(collection).await?;
+        let vectors_config = info
+            .result
+            .and_then(|info| info.config)
+            .and_then(|config| config.params)
+            .and_then(|params| params.vectors_config)
+            .and_then(|vectors_config| vectors_config.config);
+
+        // Collections created before the named vectors have a single unnamed one
+        let names = match vectors_config {
+            Some(Config::ParamsMap(params)) => params.map.into_keys().collect(),
+            _ => Vec::new(),
+        };
+        Ok(Some(names))
+    }
+
     async fn upsert_points(&self, collection_name: &str, inputs: &[VectorInput]) -> Result<bool> {
         let points: Result<Vec<_>> = inputs
             .iter()
             .map(|i| {
                 let payload = json!(i.payload);
+
+                // Photos without image embedding only get the description vector
+                let mut vectors = HashMap::from([(
+                    VectorName::Description.as_str().to_string(),
+                    i.embedding.clone(),
+                )]);
+                if let Some(image_embedding) = &i.image_embedding {
+                    vectors.insert(
+                        VectorName::Image.as_str().to_string(),
+                        image_embedding.clone(),
+                    );
+                }
+
                 Payload::try_from(payload)
-                    .map(|payload| PointStruct::new(i.id, i.embedding.clone(), payload))
+                    .map(|payload| PointStruct::new(i.id, vectors, payload))
                     .map_err(Error::from)
             })
             .collect();
@@ -80,22 +132,27 @@ impl VectorDB for QdrantClient {
     async fn search_points(
         &self,
         collection_name: &str,
+        vector_name: VectorName,
         input_vectors: &[f32],
         payload_required: HashMap<String, String>,
     ) -> Result<VectorOutputList> {
         let filter: Vec<Condition> = payload_required
             .iter()
-            .map(|(key, value)| Condition::matches(key, value.to_string()))
+            .map(|(key, value)| match value.as_str() {
+                // Flags are stored as booleans, so they must be matched as such
+                "true" => Condition::matches(key, true),
+                "false" => Condition::matches(key, false),
+                _ => Condition::matches(key, value.to_string()),
+            })
             .collect();
         let response = self
             .client
             .search_points(
                 SearchPointsBuilder::new(collection_name, input_vectors, 10)
+                    .vector_name(vector_name.as_str())
                     .filter(Filter::all(filter))
-                    .with_payload(PayloadIncludeSelector::new(vec![
-                        "description".into(),
-                        "path".into(),
-                    ]))
+                    // The whole payload, so that results can be re-ranked by e.g. color
+                    .with_payload(true)
                     .build(),
             )
             .await?;
@@ -110,6 +167,9 @@ impl VectorDB for QdrantClient {
             .with_payload(PayloadIncludeSelector::new(vec![
                 "description".into(),
                 "path".into(),
+                "text".into(),
+                "payload_version".into(),
+                "image_vector".into(),
             ]))
             .build();
         let response = self.client.get_points(query).await?;
@@ -124,7 +184,7 @@ impl From<&ScoredPoint> for VectorOutput {
         let payload = point
             .payload
             .iter()
-            .map(|(k, v)| (k.clone(), v.to_string()))
+            .map(|(k, v)| (k.clone(), payload_value(v)))
             .collect::<HashMap<String, String>>();
 
         let score = point.score;
@@ -144,12 +204,21 @@ impl From<&ScoredPoint> for VectorOutput {
     }
 }
 
+/// Converts a payload value into a plain string, without the quotes of the JSON display.
+///
+/// # Arguments
+///
+/// * `value` - The payload value, e.g. a string, a number or a flag.
+fn payload_value(value: &Value) -> String {
+    value.as_str().cloned().unwrap_or_else(|| value.to_string())
+}
+
 impl From<&RetrievedPoint> for VectorOutput {
     fn from(point: &RetrievedPoint) -> Self {
         let payload: HashMap<_, _> = point
             .payload
             .iter()
-            .filter_map(|(k, v)| v.as_str().map(|s| (k.clone(), s.to_string())))
+            .map(|(k, v)| (k.clone(), payload_value(v)))
             .collect();
 
         let id = point
@@ -195,6 +264,27 @@ mod tests {
         assert_eq!(result.id, 123);
         assert_eq!(result.score, Some(0.9));
         assert_eq!(result.payload.len(), *payload_len);
+        // Strings come back without the quotes, so that they can be used as filters again
+        assert_eq!(result.payload["test"], "test");
+    }
+
+    #[test]
+    fn test_scored_point_payload_values() {
+        let mut payload = HashMap::new();
+        payload.insert("folder".to_string(), "2023-07-14 Cefalù".into());
+        payload.insert("iso".to_string(), 100.into());
+        payload.insert("blurry".to_string(), true.into());
+
+        let scored_point = ScoredPoint {
+            payload,
+            ..ScoredPoint::default()
+        };
+
+        let result = VectorOutput::from(&scored_point);
+
+        assert_eq!(result.payload["folder"], "2023-07-14 Cefalù");
+        assert_eq!(result.payload["iso"], "100");
+        assert_eq!(result.payload["blurry"], "true");
     }
 
     #[test]
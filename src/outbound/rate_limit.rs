@@ -0,0 +1,824 @@
+use anyhow::{anyhow, Result};
+use chrono::Local;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    env::var,
+    fs::{create_dir_all, read_to_string, write},
+    future::Future,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+use crate::domain::{
+    models::{
+        AnswerStream, ChatError, DescriptionContext, EncodedImage, FollowUp, ImageAnalysis, Turn,
+    },
+    ports::{AnswerGenerator, TextEmbedder, VisionDescriber},
+    usage::Measurement,
+};
+
+const BUDGET_FILE: &str = ".photoscanner/budget.json";
+const WINDOW: Duration = Duration::from_secs(60);
+// The limits are checked before the backend reports the usage, so the tokens are estimated
+const CHARS_PER_TOKEN: usize = 4;
+// Instructions of the prompt templates
+const PROMPT_TOKENS: u64 = 300;
+// A high detail image of up to 1024x1024 pixels, as counted by OpenAI
+const IMAGE_TOKENS: u64 = 765;
+// A streamed answer without reported usage is counted with the maximum length
+const STREAMED_ANSWER_TOKENS: u64 = 512;
+const MAX_RATE_LIMITED_RETRIES: u32 = 5;
+// Pause of all calls when the backend does not tell how long
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(20);
+
+/// Limits of a hosted endpoint - all optional.
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_minute: Option<u64>,
+    /// Spending cap per day, in the currency of the price.
+    pub daily_budget: Option<f64>,
+    pub cost_per_million_tokens: f64,
+    /// Where the spending of the day is kept, so that all runs of a day share the budget.
+    pub budget_file: PathBuf,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            requests_per_minute: None,
+            tokens_per_minute: None,
+            daily_budget: None,
+            cost_per_million_tokens: 0.0,
+            budget_file: PathBuf::from(BUDGET_FILE),
+        }
+    }
+}
+
+impl RateLimits {
+    /// Reads `CHAT_REQUESTS_PER_MINUTE`, `CHAT_TOKENS_PER_MINUTE`, `CHAT_DAILY_BUDGET`,
+    /// `CHAT_COST_PER_MILLION_TOKENS` and `CHAT_BUDGET_FILE`.
+    pub fn from_env() -> Result<Self> {
+        // load env from .env file
+        dotenv::dotenv().ok();
+        let mut limits = Self::default();
+
+        if let Ok(requests_per_minute) = var("CHAT_REQUESTS_PER_MINUTE") {
+            limits.requests_per_minute = Some(requests_per_minute.parse()?);
+        }
+        if let Ok(tokens_per_minute) = var("CHAT_TOKENS_PER_MINUTE") {
+            limits.tokens_per_minute = Some(tokens_per_minute.parse()?);
+        }
+        if let Ok(daily_budget) = var("CHAT_DAILY_BUDGET") {
+            limits.daily_budget = Some(daily_budget.parse()?);
+        }
+        if let Ok(cost_per_million_tokens) = var("CHAT_COST_PER_MILLION_TOKENS") {
+            limits.cost_per_million_tokens = cost_per_million_tokens.parse()?;
+        }
+        if let Ok(budget_file) = var("CHAT_BUDGET_FILE") {
+            limits.budget_file = PathBuf::from(budget_file);
+        }
+
+        if limits.daily_budget.is_some() && limits.cost_per_million_tokens <= 0.0 {
+            return Err(anyhow!(
+                "CHAT_DAILY_BUDGET needs the price in CHAT_COST_PER_MILLION_TOKENS"
+            ));
+        }
+
+        Ok(limits)
+    }
+
+    fn cost(&self, tokens: u64) -> f64 {
+        tokens as f64 * self.cost_per_million_tokens / 1_000_000.0
+    }
+}
+
+/// The tokens and the cost spent on a day.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct DailySpend {
+    date: String,
+    tokens: u64,
+    cost: f64,
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    /// Start and estimated tokens of the calls within the window.
+    calls: VecDeque<(Instant, u64)>,
+    paused_until: Option<Instant>,
+    spend: DailySpend,
+    /// Estimated tokens of the calls running now, which are not spent yet but may be any moment.
+    reserved: u64,
+}
+
+/// Wraps any backend - describing, embedding or answering - throttling the calls to the limits
+/// of a hosted endpoint.
+///
+/// All calls of a run share the requests and tokens per minute. Answers telling the client to
+/// slow down pause all calls, and once the daily budget is spent the calls fail with
+/// [`ChatError::BudgetExhausted`], which stops the run.
+pub struct RateLimited<C> {
+    inner: C,
+    limits: RateLimits,
+    window: Duration,
+    state: Arc<Mutex<LimiterState>>,
+}
+
+impl<C> RateLimited<C> {
+    pub fn new(inner: C, limits: RateLimits) -> Self {
+        let spend = match read_to_string(&limits.budget_file) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                warn!(
+                    "Ignoring invalid budget file {}: {}",
+                    limits.budget_file.display(),
+                    e
+                );
+                DailySpend::default()
+            }),
+            Err(_) => DailySpend::default(),
+        };
+
+        Self {
+            inner,
+            limits,
+            window: WINDOW,
+            state: Arc::new(Mutex::new(LimiterState {
+                calls: VecDeque::new(),
+                paused_until: None,
+                spend,
+                reserved: 0,
+            })),
+        }
+    }
+
+    /// Wraps another backend, sharing the limits, the pauses and the budget with this one - e.g.
+    /// the embeddings and the answers of a search.
+    pub fn share<D>(&self, inner: D) -> RateLimited<D> {
+        RateLimited {
+            inner,
+            limits: self.limits.clone(),
+            window: self.window,
+            state: Arc::clone(&self.state),
+        }
+    }
+
+    /// Runs the call once the limits allow it, repeating it while the backend is rate limited.
+    ///
+    /// The budget is charged with the tokens the backend reports, or the estimate if it reports
+    /// none.
+    ///
+    /// # Arguments
+    ///
+    /// * `operation` - A string slice naming the call in the logs.
+    /// * `tokens` - The estimated tokens of the request.
+    /// * `call` - The call to the wrapped backend.
+    /// * `answer_tokens` - Estimates the tokens of the answer.
+    async fn call<T, F, Fut>(
+        &self,
+        operation: &str,
+        tokens: u64,
+        call: F,
+        answer_tokens: fn(&T) -> u64,
+    ) -> Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let (value, measurement) = self.reserve_and_call(operation, tokens, call).await?;
+        let spent = measurement
+            .tokens()
+            .unwrap_or_else(|| tokens + answer_tokens(&value));
+        self.settle(tokens, spent);
+        Ok(value)
+    }
+
+    /// Runs the call like [`RateLimited::call`], but leaves the reservation of a successful
+    /// call to the caller, e.g. until a streamed answer is finished.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(T, Measurement)>` - The answer and the usage the backend has reported so far.
+    async fn reserve_and_call<T, F, Fut>(
+        &self,
+        operation: &str,
+        tokens: u64,
+        call: F,
+    ) -> Result<(T, Measurement)>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut rate_limited = 0;
+        loop {
+            self.acquire(tokens).await?;
+
+            let measurement = Measurement::default();
+            let error = match measurement.scope(call()).await {
+                Ok(value) => return Ok((value, measurement)),
+                Err(e) => {
+                    self.settle(tokens, measurement.tokens().unwrap_or_default());
+                    e
+                }
+            };
+
+            let retry_after = match error.downcast_ref::<ChatError>() {
+                Some(ChatError::RateLimited { retry_after }) => {
+                    retry_after.unwrap_or(DEFAULT_RETRY_AFTER)
+                }
+                _ => return Err(error),
+            };
+            if rate_limited >= MAX_RATE_LIMITED_RETRIES {
+                return Err(error);
+            }
+            rate_limited += 1;
+
+            warn!(
+                "{} rate limited, pausing all calls for {:.1} seconds",
+                operation,
+                retry_after.as_secs_f64()
+            );
+            let paused_until = Instant::now() + retry_after;
+            let mut state = self.state.lock().expect("limiter lock");
+            state.paused_until = state.paused_until.max(Some(paused_until));
+        }
+    }
+
+    /// Waits until the call fits into the limits, or fails if it does not fit into the budget.
+    ///
+    /// The tokens are reserved in the budget until the call is settled, so that concurrent
+    /// calls cannot overspend it together.
+    async fn acquire(&self, tokens: u64) -> Result<()> {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().expect("limiter lock");
+
+                if let Some(budget) = self.limits.daily_budget {
+                    roll_over(&mut state.spend);
+                    let cost = self.limits.cost(state.reserved + tokens);
+                    if state.spend.cost + cost > budget {
+                        return Err(ChatError::BudgetExhausted {
+                            spent: state.spend.cost,
+                            budget,
+                        }
+                        .into());
+                    }
+                }
+
+                let now = Instant::now();
+                let window = self.window;
+                while state
+                    .calls
+                    .front()
+                    .is_some_and(|(start, _)| now.duration_since(*start) >= window)
+                {
+                    state.calls.pop_front();
+                }
+
+                let requests_fit = self
+                    .limits
+                    .requests_per_minute
+                    .is_none_or(|limit| state.calls.len() < limit as usize);
+                // A call larger than the limit runs alone instead of waiting forever
+                let used_tokens: u64 = state.calls.iter().map(|(_, tokens)| tokens).sum();
+                let tokens_fit = self
+                    .limits
+                    .tokens_per_minute
+                    .is_none_or(|limit| used_tokens + tokens <= limit || state.calls.is_empty());
+
+                match state
+                    .paused_until
+                    .filter(|paused_until| *paused_until > now)
+                {
+                    Some(paused_until) => paused_until - now,
+                    None if requests_fit && tokens_fit => {
+                        state.calls.push_back((now, tokens));
+                        state.reserved += tokens;
+                        return Ok(());
+                    }
+                    // Wait until the oldest call leaves the window
+                    None => state
+                        .calls
+                        .front()
+                        .map(|(start, _)| (*start + window).saturating_duration_since(now))
+                        .unwrap_or_default(),
+                }
+            };
+            sleep(wait).await;
+        }
+    }
+
+    /// Replaces the reservation of a call with the tokens it has spent, see [`settle`].
+    fn settle(&self, reserved: u64, tokens: u64) {
+        settle(&self.state, &self.limits, reserved, tokens);
+    }
+
+    /// Logs the spending of the day, e.g. at the end of a run.
+    pub fn log_spend(&self) {
+        if let Some(budget) = self.limits.daily_budget {
+            let state = self.state.lock().expect("limiter lock");
+            info!(
+                "Spent today: {} tokens, {:.2} of {:.2}",
+                state.spend.tokens, state.spend.cost, budget
+            );
+        }
+    }
+}
+
+/// Settles the reservation of a streamed answer once the stream is finished or dropped.
+struct StreamSettlement {
+    state: Arc<Mutex<LimiterState>>,
+    limits: RateLimits,
+    reserved: u64,
+    measurement: Measurement,
+}
+
+impl Drop for StreamSettlement {
+    fn drop(&mut self) {
+        let spent = self
+            .measurement
+            .tokens()
+            .unwrap_or(self.reserved + STREAMED_ANSWER_TOKENS);
+        settle(&self.state, &self.limits, self.reserved, spent);
+    }
+}
+
+/// Replaces the reservation of a call with the tokens it has spent - none if it failed - and
+/// stores the spending of the day.
+fn settle(state: &Mutex<LimiterState>, limits: &RateLimits, reserved: u64, tokens: u64) {
+    let spend = {
+        let mut state = state.lock().expect("limiter lock");
+        state.reserved = state.reserved.saturating_sub(reserved);
+        if limits.daily_budget.is_none() || tokens == 0 {
+            return;
+        }
+        roll_over(&mut state.spend);
+        state.spend.tokens += tokens;
+        state.spend.cost += limits.cost(tokens);
+        state.spend.clone()
+    };
+
+    let budget_file = &limits.budget_file;
+    if let Some(parent) = budget_file.parent() {
+        create_dir_all(parent).ok();
+    }
+    let stored = serde_json::to_string(&spend)
+        .map_err(anyhow::Error::from)
+        .and_then(|json| Ok(write(budget_file, json)?));
+    if let Err(e) = stored {
+        warn!(
+            "Error storing the budget file {}: {}",
+            budget_file.display(),
+            e
+        );
+    }
+}
+
+impl<C> VisionDescriber for RateLimited<C>
+where
+    C: VisionDescriber + Sync,
+{
+    async fn get_image_description(
+        &self,
+        images: &[EncodedImage],
+        context: &DescriptionContext,
+        model: &str,
+    ) -> Result<String> {
+        self.call(
+            "Image description",
+            image_tokens(images, context),
+            || self.inner.get_image_description(images, context, model),
+            |description| text_tokens(description),
+        )
+        .await
+    }
+
+    async fn get_image_analysis(
+        &self,
+        images: &[EncodedImage],
+        context: &DescriptionContext,
+        model: &str,
+    ) -> Result<ImageAnalysis> {
+        self.call(
+            "Image analysis",
+            image_tokens(images, context),
+            || self.inner.get_image_analysis(images, context, model),
+            |analysis| {
+                text_tokens(&analysis.caption)
+                    + text_tokens(&analysis.title)
+                    + text_tokens(&analysis.keywords.join(", "))
+            },
+        )
+        .await
+    }
+
+    fn image_models(&self) -> Vec<String> {
+        self.inner.image_models()
+    }
+
+    fn description_prompt(&self) -> String {
+        self.inner.description_prompt()
+    }
+}
+
+impl<C> TextEmbedder for RateLimited<C>
+where
+    C: TextEmbedder + Sync,
+{
+    fn embedding_model(&self) -> String {
+        self.inner.embedding_model()
+    }
+
+    async fn get_embeddings(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let tokens = texts.iter().map(|text| text_tokens(text)).sum();
+        self.call(
+            "Embeddings",
+            tokens,
+            || self.inner.get_embeddings(texts.clone()),
+            |_| 0,
+        )
+        .await
+    }
+
+    async fn get_query_embeddings(&self, queries: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let tokens = queries.iter().map(|query| text_tokens(query)).sum();
+        self.call(
+            "Query embeddings",
+            tokens,
+            || self.inner.get_query_embeddings(queries.clone()),
+            |_| 0,
+        )
+        .await
+    }
+}
+
+impl<C> AnswerGenerator for RateLimited<C>
+where
+    C: AnswerGenerator + Sync,
+{
+    async fn process_search_result(
+        &self,
+        question: &str,
+        history: &[Turn],
+        options: &[String],
+    ) -> Result<String> {
+        self.call(
+            "Search answer",
+            search_tokens(question, history, options),
+            || self.inner.process_search_result(question, history, options),
+            |answer| text_tokens(answer),
+        )
+        .await
+    }
+
+    async fn stream_search_result(
+        &self,
+        question: &str,
+        history: &[Turn],
+        options: &[String],
+    ) -> Result<AnswerStream> {
+        let tokens = search_tokens(question, history, options);
+        let (fragments, measurement) = self
+            .reserve_and_call("Search answer stream", tokens, || {
+                self.inner.stream_search_result(question, history, options)
+            })
+            .await?;
+
+        // The usage comes with the end of the stream, the tokens stay reserved until then
+        let settlement = StreamSettlement {
+            state: Arc::clone(&self.state),
+            limits: self.limits.clone(),
+            reserved: tokens,
+            measurement: measurement.clone(),
+        };
+        Ok(measurement
+            .stream(fragments)
+            .map(move |fragment| {
+                let _settlement = &settlement;
+                fragment
+            })
+            .boxed())
+    }
+
+    async fn rewrite_follow_up(&self, question: &str, history: &[Turn]) -> Result<FollowUp> {
+        self.call(
+            "Follow-up rewrite",
+            search_tokens(question, history, &[]),
+            || self.inner.rewrite_follow_up(question, history),
+            |follow_up| text_tokens(&follow_up.query),
+        )
+        .await
+    }
+}
+
+/// Estimates the tokens of a request answering a question from the search results.
+fn search_tokens(question: &str, history: &[Turn], options: &[String]) -> u64 {
+    PROMPT_TOKENS
+        + text_tokens(question)
+        + history
+            .iter()
+            .map(|turn| text_tokens(&turn.question) + text_tokens(&turn.answer))
+            .sum::<u64>()
+        + options
+            .iter()
+            .map(|option| text_tokens(option))
+            .sum::<u64>()
+}
+
+fn text_tokens(text: &str) -> u64 {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN) as u64
+}
+
+/// Estimates the tokens of a request describing an image, with its prompt and hints.
+fn image_tokens(images: &[EncodedImage], context: &DescriptionContext) -> u64 {
+    let hints = [
+        context.persons.join(", "),
+        context.folder.clone().unwrap_or_default(),
+        context.location.clone().unwrap_or_default(),
+        context.place.clone().unwrap_or_default(),
+        context.date.clone().unwrap_or_default(),
+        context.season.clone().unwrap_or_default(),
+        context.camera.join(", "),
+        context.previous_description.clone().unwrap_or_default(),
+        context.next_description.clone().unwrap_or_default(),
+    ];
+    PROMPT_TOKENS
+        + images.len() as u64 * IMAGE_TOKENS
+        + hints.iter().map(|hint| text_tokens(hint)).sum::<u64>()
+}
+
+/// Starts a new day with an empty budget.
+fn roll_over(spend: &mut DailySpend) {
+    let today = Local::now().format("%Y-%m-%d").to_string();
+    if spend.date != today {
+        *spend = DailySpend {
+            date: today,
+            ..DailySpend::default()
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{models::SearchScope, usage::Usage};
+    use futures::TryStreamExt;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Answers the embeddings after the delay, rate limited for the first calls.
+    struct ThrottledEmbedder {
+        calls: AtomicU32,
+        rate_limited: u32,
+        delay: Duration,
+    }
+
+    impl ThrottledEmbedder {
+        fn new(rate_limited: u32) -> Self {
+            Self {
+                calls: AtomicU32::new(0),
+                rate_limited,
+                delay: Duration::ZERO,
+            }
+        }
+    }
+
+    impl TextEmbedder for ThrottledEmbedder {
+        fn embedding_model(&self) -> String {
+            "mock".to_string()
+        }
+
+        async fn get_embeddings(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+            if self.calls.fetch_add(1, Ordering::SeqCst) < self.rate_limited {
+                return Err(ChatError::RateLimited {
+                    retry_after: Some(Duration::from_millis(50)),
+                }
+                .into());
+            }
+            sleep(self.delay).await;
+            Ok(texts.iter().map(|_| vec![1.0]).collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_requests_per_minute() -> Result<()> {
+        let limits = RateLimits {
+            requests_per_minute: Some(2),
+            ..RateLimits::default()
+        };
+        let mut chat = RateLimited::new(ThrottledEmbedder::new(0), limits);
+        chat.window = Duration::from_millis(100);
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            chat.get_embeddings(vec!["text".to_string()]).await?;
+        }
+
+        // The third call waits for the first to leave the window
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_calls_pause() -> Result<()> {
+        let chat = RateLimited::new(ThrottledEmbedder::new(2), RateLimits::default());
+        let start = Instant::now();
+
+        chat.get_embeddings(vec!["text".to_string()]).await?;
+
+        assert_eq!(chat.inner.calls.load(Ordering::SeqCst), 3);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_daily_budget() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let limits = RateLimits {
+            daily_budget: Some(1.0),
+            // 4 characters make a token and cost 0.4
+            cost_per_million_tokens: 400_000.0,
+            budget_file: temp_dir.path().join("budget.json"),
+            ..RateLimits::default()
+        };
+        let text = vec!["text".to_string()];
+
+        let chat = RateLimited::new(ThrottledEmbedder::new(0), limits.clone());
+        chat.get_embeddings(text.clone()).await?;
+        chat.get_embeddings(text.clone()).await?;
+        let error = chat.get_embeddings(text.clone()).await.unwrap_err();
+        assert!(ChatError::is_budget_exhausted(&error));
+
+        // The next run of the day continues with the spending stored in the budget file
+        let chat = RateLimited::new(ThrottledEmbedder::new(0), limits);
+        let error = chat.get_embeddings(text).await.unwrap_err();
+        assert!(ChatError::is_budget_exhausted(&error));
+        assert_eq!(chat.inner.calls.load(Ordering::SeqCst), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_daily_budget_concurrent() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let limits = RateLimits {
+            daily_budget: Some(1.0),
+            cost_per_million_tokens: 400_000.0,
+            budget_file: temp_dir.path().join("budget.json"),
+            ..RateLimits::default()
+        };
+        let text = vec!["text".to_string()];
+        let inner = ThrottledEmbedder {
+            delay: Duration::from_millis(50),
+            ..ThrottledEmbedder::new(0)
+        };
+
+        // The running calls reserve their tokens, so the third one does not fit anymore
+        let chat = RateLimited::new(inner, limits);
+        let (first, second, third) = tokio::join!(
+            chat.get_embeddings(text.clone()),
+            chat.get_embeddings(text.clone()),
+            chat.get_embeddings(text.clone())
+        );
+        assert!(first.is_ok() && second.is_ok());
+        assert!(ChatError::is_budget_exhausted(&third.unwrap_err()));
+        assert_eq!(chat.inner.calls.load(Ordering::SeqCst), 2);
+
+        Ok(())
+    }
+
+    /// Reports the tokens of its embeddings and answers, like a hosted backend.
+    struct MeasuredModel;
+
+    impl TextEmbedder for MeasuredModel {
+        fn embedding_model(&self) -> String {
+            "mock".to_string()
+        }
+
+        async fn get_embeddings(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+            usage(600, 0).record(None);
+            Ok(texts.iter().map(|_| vec![1.0]).collect())
+        }
+    }
+
+    impl AnswerGenerator for MeasuredModel {
+        async fn process_search_result(
+            &self,
+            _question: &str,
+            _history: &[Turn],
+            _options: &[String],
+        ) -> Result<String> {
+            Ok("answer".to_string())
+        }
+
+        async fn stream_search_result(
+            &self,
+            _question: &str,
+            _history: &[Turn],
+            _options: &[String],
+        ) -> Result<AnswerStream> {
+            // The usage comes with the last fragment
+            let fragments = futures::stream::iter(["A ", "beach"]).map(|fragment| {
+                if fragment == "beach" {
+                    usage(250, 50).record(None);
+                }
+                Ok(fragment.to_string())
+            });
+            Ok(fragments.boxed())
+        }
+
+        async fn rewrite_follow_up(&self, question: &str, _history: &[Turn]) -> Result<FollowUp> {
+            Ok(FollowUp {
+                query: question.to_string(),
+                scope: SearchScope::All,
+            })
+        }
+    }
+
+    fn usage(prompt_tokens: u64, completion_tokens: u64) -> Usage {
+        Usage {
+            operation: "test",
+            model: "mock".to_string(),
+            prompt_tokens,
+            completion_tokens,
+            latency: Duration::ZERO,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_measured_usage() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let limits = RateLimits {
+            daily_budget: Some(1.0),
+            // 1000 tokens cost 1.0
+            cost_per_million_tokens: 1000.0,
+            budget_file: temp_dir.path().join("budget.json"),
+            ..RateLimits::default()
+        };
+        let chat = RateLimited::new(MeasuredModel, limits);
+
+        // The budget is charged with the reported tokens, not the estimated single one
+        let answer: Vec<String> = chat
+            .stream_search_result("Where?", &[], &[])
+            .await?
+            .try_collect()
+            .await?;
+        assert_eq!(answer.concat(), "A beach");
+        assert_eq!(chat.state.lock().unwrap().spend.tokens, 300);
+        assert_eq!(chat.state.lock().unwrap().reserved, 0);
+
+        chat.get_embeddings(vec!["text".to_string()]).await?;
+        assert_eq!(chat.state.lock().unwrap().spend.tokens, 900);
+        chat.get_embeddings(vec!["text".to_string()]).await?;
+        let error = chat
+            .get_embeddings(vec!["text".to_string()])
+            .await
+            .unwrap_err();
+        assert!(ChatError::is_budget_exhausted(&error));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shared_budget() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let limits = RateLimits {
+            daily_budget: Some(1.0),
+            cost_per_million_tokens: 400_000.0,
+            budget_file: temp_dir.path().join("budget.json"),
+            ..RateLimits::default()
+        };
+        let text = vec!["text".to_string()];
+
+        // The embeddings and the answers of a search spend the same budget
+        let chat = RateLimited::new(ThrottledEmbedder::new(0), limits);
+        let other = chat.share(ThrottledEmbedder::new(0));
+        chat.get_embeddings(text.clone()).await?;
+        other.get_embeddings(text.clone()).await?;
+        let error = chat.get_embeddings(text).await.unwrap_err();
+        assert!(ChatError::is_budget_exhausted(&error));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_image_tokens() {
+        let image = EncodedImage {
+            base64: "image_base64".to_string(),
+            mime_type: "image/jpeg".to_string(),
+        };
+        let context = DescriptionContext {
+            folder: Some("Rome".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            image_tokens(&[image.clone(), image], &context),
+            PROMPT_TOKENS + 2 * IMAGE_TOKENS + 1
+        );
+        assert_eq!(text_tokens("hello"), 2);
+    }
+}
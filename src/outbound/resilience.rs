@@ -0,0 +1,522 @@
+use anyhow::{anyhow, Result};
+use async_openai::error::OpenAIError;
+use std::{
+    env::var,
+    future::Future,
+    io::ErrorKind,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::time::{error::Elapsed, sleep, timeout};
+use tracing::{info, warn};
+
+use super::ollama::OllamaError;
+use crate::domain::{
+    models::{
+        AnswerStream, ChatError, DescriptionContext, EncodedImage, FollowUp, ImageAnalysis, Turn,
+    },
+    ports::{AnswerGenerator, TextEmbedder, VisionDescriber},
+};
+
+/// How often and how long to retry failed calls, and when to pause all calls.
+#[derive(Debug, Clone)]
+pub struct ResilienceOptions {
+    /// Retries of a call after the first attempt.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for every further retry.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Time after which a call counts as failed, e.g. when the model hangs.
+    pub timeout: Duration,
+    /// Consecutive failures which open the circuit and pause all calls.
+    pub failure_threshold: u32,
+    /// First pause of an open circuit, doubled while the backend stays unavailable.
+    pub cooldown: Duration,
+    pub max_cooldown: Duration,
+}
+
+impl Default for ResilienceOptions {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            timeout: Duration::from_secs(600),
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(30),
+            max_cooldown: Duration::from_secs(600),
+        }
+    }
+}
+
+impl ResilienceOptions {
+    /// Reads `CHAT_MAX_RETRIES`, `CHAT_TIMEOUT`, `CHAT_FAILURE_THRESHOLD` and `CHAT_COOLDOWN`,
+    /// durations in seconds.
+    pub fn from_env() -> Result<Self> {
+        // load env from .env file
+        dotenv::dotenv().ok();
+        let mut options = Self::default();
+
+        if let Ok(max_retries) = var("CHAT_MAX_RETRIES") {
+            options.max_retries = max_retries.parse()?;
+        }
+        if let Ok(timeout) = var("CHAT_TIMEOUT") {
+            options.timeout = Duration::from_secs(timeout.parse()?);
+        }
+        if let Ok(failure_threshold) = var("CHAT_FAILURE_THRESHOLD") {
+            options.failure_threshold = failure_threshold.parse()?;
+        }
+        if let Ok(cooldown) = var("CHAT_COOLDOWN") {
+            options.cooldown = Duration::from_secs(cooldown.parse()?);
+        }
+
+        Ok(options)
+    }
+
+    /// Returns the delay before the given retry - exponential with jitter, so that the
+    /// concurrent calls do not hit the recovering backend at the same time.
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff);
+        backoff.mul_f64(0.5 + rand::random::<f64>() / 2.0)
+    }
+}
+
+#[derive(Debug)]
+struct CircuitState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    cooldown: Duration,
+}
+
+/// Wraps any backend - describing, embedding or answering - retrying calls which failed for a
+/// transient reason.
+///
+/// Once the backend keeps failing, e.g. while Ollama restarts, the circuit opens and every call
+/// waits until the backend is tried again, instead of skipping one file after the other.
+pub struct Resilient<C> {
+    inner: C,
+    options: ResilienceOptions,
+    circuit: Mutex<CircuitState>,
+}
+
+impl<C> Resilient<C> {
+    pub fn new(inner: C, options: ResilienceOptions) -> Self {
+        let circuit = Mutex::new(CircuitState {
+            consecutive_failures: 0,
+            open_until: None,
+            cooldown: options.cooldown,
+        });
+        Self {
+            inner,
+            options,
+            circuit,
+        }
+    }
+
+    /// Runs the call until it succeeds, fails permanently or runs out of retries.
+    async fn call<T, F, Fut>(&self, operation: &str, call: F) -> Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut retry = 0;
+        loop {
+            // Waiting for the backend does not use up the retries
+            if self.wait_for_circuit().await {
+                retry = 0;
+            }
+
+            let error = match timeout(self.options.timeout, call()).await {
+                Ok(Ok(value)) => {
+                    self.record_success();
+                    return Ok(value);
+                }
+                Ok(Err(e)) => e,
+                Err(elapsed) => anyhow!(elapsed),
+            };
+
+            if !is_retryable(&error) {
+                return Err(error);
+            }
+            self.record_failure();
+
+            if retry >= self.options.max_retries {
+                return Err(error.context(format!("{} failed after {} retries", operation, retry)));
+            }
+
+            let backoff = self.options.backoff(retry);
+            warn!(
+                "{} failed, retrying in {:.1} seconds: {:#}",
+                operation,
+                backoff.as_secs_f64(),
+                error
+            );
+            sleep(backoff).await;
+            retry += 1;
+        }
+    }
+
+    /// Waits while the circuit is open. Returns true if it waited.
+    async fn wait_for_circuit(&self) -> bool {
+        let open_until = self.circuit.lock().expect("circuit lock").open_until;
+        let pause =
+            open_until.map(|open_until| open_until.saturating_duration_since(Instant::now()));
+        match pause {
+            Some(pause) if !pause.is_zero() => {
+                sleep(pause).await;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn record_success(&self) {
+        let mut circuit = self.circuit.lock().expect("circuit lock");
+        if circuit.consecutive_failures >= self.options.failure_threshold {
+            info!("Chat backend available again");
+        }
+        circuit.consecutive_failures = 0;
+        circuit.open_until = None;
+        circuit.cooldown = self.options.cooldown;
+    }
+
+    /// Opens the circuit after too many consecutive failures. Once it has been open, the first
+    /// failure after the pause opens it again, for twice as long.
+    fn record_failure(&self) {
+        let mut circuit = self.circuit.lock().expect("circuit lock");
+        circuit.consecutive_failures += 1;
+
+        let now = Instant::now();
+        let is_open = circuit
+            .open_until
+            .is_some_and(|open_until| open_until > now);
+        if !is_open && circuit.consecutive_failures >= self.options.failure_threshold {
+            warn!(
+                "Chat backend failed {} times in a row, pausing for {} seconds",
+                circuit.consecutive_failures,
+                circuit.cooldown.as_secs()
+            );
+            circuit.open_until = Some(now + circuit.cooldown);
+            circuit.cooldown = circuit
+                .cooldown
+                .saturating_mul(2)
+                .min(self.options.max_cooldown);
+        }
+    }
+}
+
+impl<C> VisionDescriber for Resilient<C>
+where
+    C: VisionDescriber + Sync,
+{
+    async fn get_image_description(
+        &self,
+        images: &[EncodedImage],
+        context: &DescriptionContext,
+        model: &str,
+    ) -> Result<String> {
+        self.call("Image description", || {
+            self.inner.get_image_description(images, context, model)
+        })
+        .await
+    }
+
+    async fn get_image_analysis(
+        &self,
+        images: &[EncodedImage],
+        context: &DescriptionContext,
+        model: &str,
+    ) -> Result<ImageAnalysis> {
+        self.call("Image analysis", || {
+            self.inner.get_image_analysis(images, context, model)
+        })
+        .await
+    }
+
+    fn image_models(&self) -> Vec<String> {
+        self.inner.image_models()
+    }
+
+    fn description_prompt(&self) -> String {
+        self.inner.description_prompt()
+    }
+}
+
+impl<C> TextEmbedder for Resilient<C>
+where
+    C: TextEmbedder + Sync,
+{
+    fn embedding_model(&self) -> String {
+        self.inner.embedding_model()
+    }
+
+    async fn get_embeddings(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        self.call("Embeddings", || self.inner.get_embeddings(texts.clone()))
+            .await
+    }
+
+    async fn get_query_embeddings(&self, queries: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        self.call("Query embeddings", || {
+            self.inner.get_query_embeddings(queries.clone())
+        })
+        .await
+    }
+}
+
+impl<C> AnswerGenerator for Resilient<C>
+where
+    C: AnswerGenerator + Sync,
+{
+    async fn process_search_result(
+        &self,
+        question: &str,
+        history: &[Turn],
+        options: &[String],
+    ) -> Result<String> {
+        self.call("Search answer", || {
+            self.inner.process_search_result(question, history, options)
+        })
+        .await
+    }
+
+    /// Retries starting the stream only - fragments which already arrived cannot be taken back.
+    async fn stream_search_result(
+        &self,
+        question: &str,
+        history: &[Turn],
+        options: &[String],
+    ) -> Result<AnswerStream> {
+        self.call("Search answer stream", || {
+            self.inner.stream_search_result(question, history, options)
+        })
+        .await
+    }
+
+    async fn rewrite_follow_up(&self, question: &str, history: &[Turn]) -> Result<FollowUp> {
+        self.call("Follow-up rewrite", || {
+            self.inner.rewrite_follow_up(question, history)
+        })
+        .await
+    }
+}
+
+/// Tells transient failures, e.g. timeouts, server errors and dropped connections, from
+/// permanent ones like invalid requests, which fail the same way when retried.
+fn is_retryable(error: &anyhow::Error) -> bool {
+    for cause in error.chain() {
+        if cause.is::<Elapsed>() {
+            return true;
+        }
+        // The rate limiter waits for the backend and retries itself, the budget stays spent
+        if cause.is::<ChatError>() {
+            return false;
+        }
+        if let Some(error) = cause.downcast_ref::<OpenAIError>() {
+            return match error {
+                OpenAIError::Reqwest(error) => {
+                    error.is_timeout()
+                        || error.is_connect()
+                        || error.is_request()
+                        || error.is_body()
+                        || error
+                            .status()
+                            .is_some_and(|status| status.is_server_error() || status == 429)
+                }
+                // Ollama reports crashed and overloaded models as api_error
+                OpenAIError::ApiError(error) => {
+                    matches!(error.r#type.as_deref(), Some("api_error" | "server_error"))
+                }
+                // Proxies answer with HTML error pages while the backend restarts
+                OpenAIError::JSONDeserialize(_) | OpenAIError::StreamError(_) => true,
+                _ => false,
+            };
+        }
+        // Ollama answers 500 while a model runner crashes or is out of memory
+        if let Some(error) = cause.downcast_ref::<OllamaError>() {
+            return error.status >= 500;
+        }
+        if let Some(error) = cause.downcast_ref::<reqwest::Error>() {
+            return error.is_timeout()
+                || error.is_connect()
+                || error.is_request()
+                || error.is_body();
+        }
+        if let Some(error) = cause.downcast_ref::<std::io::Error>() {
+            return matches!(
+                error.kind(),
+                ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::ConnectionRefused
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::TimedOut
+                    | ErrorKind::UnexpectedEof
+            );
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::error::ApiError;
+    use std::{
+        io,
+        sync::atomic::{AtomicU32, Ordering},
+    };
+
+    /// Fails the first calls with the given kind of error.
+    struct FlakyEmbedder {
+        calls: AtomicU32,
+        failures: u32,
+        error_kind: ErrorKind,
+    }
+
+    impl FlakyEmbedder {
+        fn new(failures: u32, error_kind: ErrorKind) -> Self {
+            Self {
+                calls: AtomicU32::new(0),
+                failures,
+                error_kind,
+            }
+        }
+
+        fn calls(&self) -> u32 {
+            self.calls.load(Ordering::SeqCst)
+        }
+    }
+
+    impl TextEmbedder for FlakyEmbedder {
+        fn embedding_model(&self) -> String {
+            "mock".to_string()
+        }
+
+        async fn get_embeddings(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+            let call = self.calls.fetch_add(1, Ordering::SeqCst);
+            if call < self.failures {
+                return Err(io::Error::from(self.error_kind).into());
+            }
+            Ok(texts.iter().map(|_| vec![1.0]).collect())
+        }
+    }
+
+    fn fast_options() -> ResilienceOptions {
+        ResilienceOptions {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(5),
+            timeout: Duration::from_secs(5),
+            failure_threshold: 10,
+            cooldown: Duration::from_millis(50),
+            max_cooldown: Duration::from_millis(200),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient_errors() -> Result<()> {
+        let chat = Resilient::new(
+            FlakyEmbedder::new(2, ErrorKind::ConnectionReset),
+            fast_options(),
+        );
+
+        let embeddings = chat.get_embeddings(vec!["text".to_string()]).await?;
+
+        assert_eq!(embeddings, vec![vec![1.0]]);
+        assert_eq!(chat.inner.calls(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_give_up_after_retries() {
+        let chat = Resilient::new(
+            FlakyEmbedder::new(u32::MAX, ErrorKind::ConnectionRefused),
+            fast_options(),
+        );
+
+        assert!(chat.get_embeddings(vec!["text".to_string()]).await.is_err());
+        assert_eq!(chat.inner.calls(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_permanent_errors_are_not_retried() {
+        let chat = Resilient::new(
+            FlakyEmbedder::new(1, ErrorKind::PermissionDenied),
+            fast_options(),
+        );
+
+        assert!(chat.get_embeddings(vec!["text".to_string()]).await.is_err());
+        assert_eq!(chat.inner.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_pauses_calls() -> Result<()> {
+        let options = ResilienceOptions {
+            failure_threshold: 2,
+            ..fast_options()
+        };
+        let chat = Resilient::new(FlakyEmbedder::new(5, ErrorKind::TimedOut), options);
+        let start = Instant::now();
+
+        // The pauses do not use up the retries, so the call outlasts the outage
+        chat.get_embeddings(vec!["text".to_string()]).await?;
+
+        assert_eq!(chat.inner.calls(), 6);
+        // Open after the 2nd failure for 50ms, then after every further failure for 100ms and 200ms
+        assert!(start.elapsed() >= Duration::from_millis(550));
+
+        // Success closes the circuit
+        let circuit = chat.circuit.lock().unwrap();
+        assert_eq!(circuit.consecutive_failures, 0);
+        assert_eq!(circuit.cooldown, Duration::from_millis(50));
+        Ok(())
+    }
+
+    #[test]
+    fn test_backoff() {
+        let options = ResilienceOptions::default();
+        for retry in 0..10 {
+            let backoff = options.backoff(retry);
+            let max = Duration::from_secs(2u64.pow(retry)).min(options.max_backoff);
+            assert!(backoff >= max / 2 && backoff <= max, "{:?}", backoff);
+        }
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        let api_error = |r#type: &str| {
+            anyhow!(OpenAIError::ApiError(ApiError {
+                message: "error".to_string(),
+                r#type: Some(r#type.to_string()),
+                param: None,
+                code: None,
+            }))
+        };
+
+        assert!(is_retryable(&api_error("api_error")));
+        assert!(!is_retryable(&api_error("invalid_request_error")));
+        assert!(is_retryable(
+            &anyhow!(io::Error::from(ErrorKind::ConnectionReset)).context("Embeddings")
+        ));
+        let ollama_error = |status| {
+            anyhow!(OllamaError {
+                status,
+                message: "error".to_string(),
+            })
+        };
+        assert!(is_retryable(&ollama_error(500)));
+        assert!(!is_retryable(&ollama_error(404)));
+        assert!(!is_retryable(&anyhow!(OpenAIError::InvalidArgument(
+            "model".to_string()
+        ))));
+        assert!(!is_retryable(&anyhow!(
+            "The answer contains no JSON object"
+        )));
+        // The rate limiter below retries these itself
+        assert!(!is_retryable(&anyhow!(ChatError::RateLimited {
+            retry_after: None
+        })));
+    }
+}
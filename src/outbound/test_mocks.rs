@@ -1,59 +1,405 @@
 #[cfg(test)]
 pub mod tests {
-    use std::{collections::HashMap, sync::Mutex};
+    use std::{collections::HashMap, path::Path, sync::Mutex};
 
     use anyhow::Result;
+    use futures::StreamExt;
+    use qdrant_client::{
+        qdrant::{self, PointId, RetrievedPoint, ScoredPoint},
+        Payload,
+    };
     use rand::{rng, Rng};
+    use serde_json::{json, Value};
     use tracing::debug;
 
     use crate::domain::{
-        models::{VectorInput, VectorOutput},
-        ports::{Chat, VectorDB},
+        models::{
+            AnswerStream, ChatError, DescriptionContext, EncodedImage, FollowUp, ImageAnalysis,
+            SearchScope, Setting, Turn, VectorInput, VectorName, VectorOutput,
+        },
+        ports::{
+            AnswerGenerator, Geocoder, ImageEmbedder, ImageEncoder, TextEmbedder, TextRecognizer,
+            VectorDB, VisionDescriber,
+        },
     };
 
     #[derive(Clone, Debug)]
-    pub struct ChatMock;
+    pub struct ModelMock;
 
-    impl Chat for ChatMock {
+    impl VisionDescriber for ModelMock {
         async fn get_image_description(
             &self,
-            _image_base64: &str,
-            _persons: &[String],
-            _folder_name: &Option<String>,
+            _images: &[EncodedImage],
+            _context: &DescriptionContext,
+            _model: &str,
         ) -> Result<String> {
             Ok("description".to_string())
         }
 
+        async fn get_image_analysis(
+            &self,
+            _images: &[EncodedImage],
+            _context: &DescriptionContext,
+            _model: &str,
+        ) -> Result<ImageAnalysis> {
+            Ok(ImageAnalysis {
+                caption: "description".to_string(),
+                title: "title".to_string(),
+                keywords: vec!["keyword".to_string()],
+                scene_type: "landscape".to_string(),
+                people_count: 0,
+                setting: Setting::Outdoor,
+                confidence: 0.9,
+            })
+        }
+
+        fn image_models(&self) -> Vec<String> {
+            vec!["mock".to_string()]
+        }
+
+        fn description_prompt(&self) -> String {
+            "mock@1".to_string()
+        }
+    }
+
+    impl TextEmbedder for ModelMock {
+        fn embedding_model(&self) -> String {
+            "mock".to_string()
+        }
+
         async fn get_embeddings(&self, _texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
             let mut rng = rng();
             let embedding: Vec<f32> = (0..1536).map(|_| rng.random()).collect();
             Ok(vec![embedding])
         }
+    }
+
+    /// The question, the earlier turns and the photos of an answer.
+    pub type AnswerCall = (String, Vec<Turn>, Vec<String>);
+
+    /// An answer backend recording the questions, the earlier turns and the photos it is asked
+    /// about, which rewrites the follow-up questions as told.
+    #[derive(Debug, Default)]
+    pub struct AnswerRecorderMock {
+        calls: Mutex<Vec<AnswerCall>>,
+        follow_ups: Mutex<Vec<FollowUp>>,
+    }
+
+    impl AnswerRecorderMock {
+        /// Rewrites the follow-up questions into the given searches, in order - the remaining
+        /// questions are searched as they are.
+        pub fn with_follow_ups(follow_ups: Vec<FollowUp>) -> Self {
+            Self {
+                follow_ups: Mutex::new(follow_ups),
+                ..Default::default()
+            }
+        }
 
+        pub fn calls(&self) -> Vec<AnswerCall> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    impl AnswerGenerator for AnswerRecorderMock {
         async fn process_search_result(
             &self,
-            _question: &str,
-            _options: &[String],
+            question: &str,
+            history: &[Turn],
+            options: &[String],
+        ) -> Result<String> {
+            let mut calls = self.calls.lock().unwrap();
+            calls.push((question.to_string(), history.to_vec(), options.to_vec()));
+            Ok(format!("answer {}", calls.len()))
+        }
+
+        async fn stream_search_result(
+            &self,
+            question: &str,
+            history: &[Turn],
+            options: &[String],
+        ) -> Result<AnswerStream> {
+            let answer = self
+                .process_search_result(question, history, options)
+                .await?;
+            Ok(futures::stream::once(async { Ok(answer) }).boxed())
+        }
+
+        async fn rewrite_follow_up(&self, question: &str, _history: &[Turn]) -> Result<FollowUp> {
+            let mut follow_ups = self.follow_ups.lock().unwrap();
+            if follow_ups.is_empty() {
+                return Ok(FollowUp {
+                    query: question.to_string(),
+                    scope: SearchScope::All,
+                });
+            }
+            Ok(follow_ups.remove(0))
+        }
+    }
+
+    /// A vision backend whose daily budget is spent.
+    #[derive(Clone, Debug)]
+    pub struct BudgetExhaustedDescriberMock;
+
+    impl VisionDescriber for BudgetExhaustedDescriberMock {
+        async fn get_image_description(
+            &self,
+            _images: &[EncodedImage],
+            _context: &DescriptionContext,
+            _model: &str,
+        ) -> Result<String> {
+            Err(budget_exhausted())
+        }
+
+        async fn get_image_analysis(
+            &self,
+            _images: &[EncodedImage],
+            _context: &DescriptionContext,
+            _model: &str,
+        ) -> Result<ImageAnalysis> {
+            Err(budget_exhausted())
+        }
+
+        fn image_models(&self) -> Vec<String> {
+            vec!["mock".to_string()]
+        }
+
+        fn description_prompt(&self) -> String {
+            "mock@1".to_string()
+        }
+    }
+
+    /// An image encoder which fails for the given model and encodes a placeholder for the others.
+    #[derive(Clone, Debug)]
+    pub struct FailingEncoderMock {
+        pub failing_model: String,
+    }
+
+    impl ImageEncoder for FailingEncoderMock {
+        fn resize_and_base64encode_image(
+            &self,
+            _image_path: &Path,
+            model: &str,
+        ) -> Result<Vec<EncodedImage>> {
+            if model == self.failing_model {
+                return Err(anyhow::anyhow!("Unsupported image format for {}", model));
+            }
+            Ok(vec![EncodedImage {
+                base64: "image_base64".to_string(),
+                mime_type: "image/jpeg".to_string(),
+            }])
+        }
+    }
+
+    /// A vision backend whose primary image model refuses, while the fallback answers like [`ModelMock`].
+    #[derive(Clone, Debug)]
+    pub struct RefusingDescriberMock;
+
+    impl VisionDescriber for RefusingDescriberMock {
+        async fn get_image_description(
+            &self,
+            images: &[EncodedImage],
+            context: &DescriptionContext,
+            model: &str,
+        ) -> Result<String> {
+            match model {
+                "refusing" => Ok("I'm sorry, I can't help with that.".to_string()),
+                _ => {
+                    ModelMock
+                        .get_image_description(images, context, model)
+                        .await
+                }
+            }
+        }
+
+        async fn get_image_analysis(
+            &self,
+            images: &[EncodedImage],
+            context: &DescriptionContext,
+            model: &str,
+        ) -> Result<ImageAnalysis> {
+            ModelMock.get_image_analysis(images, context, model).await
+        }
+
+        fn image_models(&self) -> Vec<String> {
+            vec!["refusing".to_string(), "mock".to_string()]
+        }
+
+        fn description_prompt(&self) -> String {
+            ModelMock.description_prompt()
+        }
+    }
+
+    /// A vision backend which hedges, unless told what was wrong with its previous description.
+    #[derive(Clone, Debug)]
+    pub struct HedgingDescriberMock;
+
+    impl VisionDescriber for HedgingDescriberMock {
+        async fn get_image_description(
+            &self,
+            _images: &[EncodedImage],
+            context: &DescriptionContext,
+            _model: &str,
         ) -> Result<String> {
-            unimplemented!()
+            match context.feedback.is_empty() {
+                true => Ok("Most likely a beach.".to_string()),
+                false => Ok("A sandy beach at sunset.".to_string()),
+            }
+        }
+
+        async fn get_image_analysis(
+            &self,
+            images: &[EncodedImage],
+            context: &DescriptionContext,
+            model: &str,
+        ) -> Result<ImageAnalysis> {
+            ModelMock.get_image_analysis(images, context, model).await
+        }
+
+        fn image_models(&self) -> Vec<String> {
+            ModelMock.image_models()
+        }
+
+        fn description_prompt(&self) -> String {
+            ModelMock.description_prompt()
+        }
+    }
+
+    /// A vision backend whose primary model hedges whatever the feedback, the fallback succeeds.
+    #[derive(Clone, Debug)]
+    pub struct StubbornDescriberMock;
+
+    impl VisionDescriber for StubbornDescriberMock {
+        async fn get_image_description(
+            &self,
+            images: &[EncodedImage],
+            context: &DescriptionContext,
+            model: &str,
+        ) -> Result<String> {
+            match model {
+                "stubborn" => Ok("Most likely a beach.".to_string()),
+                _ => {
+                    ModelMock
+                        .get_image_description(images, context, model)
+                        .await
+                }
+            }
+        }
+
+        async fn get_image_analysis(
+            &self,
+            images: &[EncodedImage],
+            context: &DescriptionContext,
+            model: &str,
+        ) -> Result<ImageAnalysis> {
+            ModelMock.get_image_analysis(images, context, model).await
+        }
+
+        fn image_models(&self) -> Vec<String> {
+            vec!["stubborn".to_string(), "mock".to_string()]
+        }
+
+        fn description_prompt(&self) -> String {
+            ModelMock.description_prompt()
+        }
+    }
+
+    /// A vision backend continuing the description of the photo taken before.
+    #[derive(Clone, Debug)]
+    pub struct SeriesDescriberMock;
+
+    impl VisionDescriber for SeriesDescriberMock {
+        async fn get_image_description(
+            &self,
+            _images: &[EncodedImage],
+            context: &DescriptionContext,
+            _model: &str,
+        ) -> Result<String> {
+            Ok(match &context.previous_description {
+                Some(previous) => format!("{} Later the sun sets.", previous),
+                None => "A beach in the morning.".to_string(),
+            })
+        }
+
+        async fn get_image_analysis(
+            &self,
+            images: &[EncodedImage],
+            context: &DescriptionContext,
+            model: &str,
+        ) -> Result<ImageAnalysis> {
+            ModelMock.get_image_analysis(images, context, model).await
+        }
+
+        fn image_models(&self) -> Vec<String> {
+            ModelMock.image_models()
+        }
+
+        fn description_prompt(&self) -> String {
+            ModelMock.description_prompt()
+        }
+    }
+
+    fn budget_exhausted() -> anyhow::Error {
+        ChatError::BudgetExhausted {
+            spent: 1.0,
+            budget: 1.0,
+        }
+        .into()
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct ImageEmbedderMock;
+
+    impl ImageEmbedder for ImageEmbedderMock {
+        fn embed_image(&self, _image_path: &Path) -> Result<Vec<f32>> {
+            Ok(vec![1.0, 0.0, 0.0])
+        }
+
+        fn embed_text(&self, _text: &str) -> Result<Vec<f32>> {
+            Ok(vec![0.0, 1.0, 0.0])
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct GeocoderMock;
+
+    impl Geocoder for GeocoderMock {
+        async fn reverse_geocode(&self, _latitude: f64, _longitude: f64) -> Result<Option<String>> {
+            Ok(Some("Arezzo, Tuscany, Italy".to_string()))
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct TextRecognizerMock;
+
+    impl TextRecognizer for TextRecognizerMock {
+        fn recognize_text(&self, _image_path: &Path) -> Result<Vec<String>> {
+            Ok(vec!["TRATTORIA DA NINO".to_string()])
         }
     }
 
     #[derive(Default)]
     pub struct VectorDBMock {
         store_embeddings: Mutex<HashMap<String, Vec<VectorInput>>>,
+        unnamed_vector: Mutex<bool>,
     }
 
     impl VectorDBMock {
         pub fn new() -> Self {
             Self {
                 store_embeddings: Mutex::new(HashMap::new()),
+                unnamed_vector: Mutex::new(false),
             }
         }
+
+        /// Pretends the existing collections were created before the named vectors.
+        pub fn with_unnamed_vector(self) -> Self {
+            *self.unnamed_vector.lock().unwrap() = true;
+            self
+        }
     }
 
     impl VectorDB for VectorDBMock {
-        async fn create_collection(&self, collection_name: &str) -> Result<bool> {
+        async fn create_collection(&self, collection_name: &str, _dimensions: u64) -> Result<bool> {
             let mut store = self.store_embeddings.lock().unwrap();
             if !store.contains_key(collection_name) {
                 store.insert(collection_name.to_string(), Vec::new());
@@ -64,9 +410,25 @@ pub mod tests {
         async fn delete_collection(&self, collection_name: &str) -> Result<bool> {
             let mut store = self.store_embeddings.lock().unwrap();
             let result = store.remove(collection_name);
+            // A collection created again gets the named vectors
+            *self.unnamed_vector.lock().unwrap() = false;
             Ok(result.is_some())
         }
 
+        async fn collection_vectors(&self, collection_name: &str) -> Result<Option<Vec<String>>> {
+            let store = self.store_embeddings.lock().unwrap();
+            if !store.contains_key(collection_name) {
+                return Ok(None);
+            }
+            if *self.unnamed_vector.lock().unwrap() {
+                return Ok(Some(Vec::new()));
+            }
+            Ok(Some(vec![
+                VectorName::Description.as_str().to_string(),
+                VectorName::Image.as_str().to_string(),
+            ]))
+        }
+
         async fn find_by_id(
             &self,
             collection_name: &str,
@@ -77,14 +439,15 @@ pub mod tests {
                 .get(collection_name)
                 .expect("Collection missing in store");
 
-            let result = collection
-                .iter()
-                .find(|v| v.id == *id)
-                .map(|v| VectorOutput {
-                    id: v.id,
-                    score: None,
-                    payload: v.payload.clone(),
-                });
+            let result = collection.iter().find(|v| v.id == *id).map(|v| {
+                // Converted like the points retrieved from Qdrant
+                let point = RetrievedPoint {
+                    id: Some(PointId::from(v.id)),
+                    payload: qdrant_payload(&v.payload),
+                    ..RetrievedPoint::default()
+                };
+                VectorOutput::from(&point)
+            });
             Ok(result)
         }
 
@@ -103,11 +466,7 @@ pub mod tests {
                 }
 
                 // Insert a new entry
-                collection.push(VectorInput::new(
-                    input.id,
-                    input.embedding.clone(),
-                    input.payload.clone(),
-                ));
+                collection.push(input.clone());
             });
             Ok(true)
         }
@@ -115,8 +474,9 @@ pub mod tests {
         async fn search_points(
             &self,
             collection_name: &str,
+            vector_name: VectorName,
             _input_vectors: &[f32],
-            _payload_required: HashMap<String, String>,
+            payload_required: HashMap<String, String>,
         ) -> Result<Vec<VectorOutput>> {
             let store = self.store_embeddings.lock().unwrap();
             match store.get(collection_name) {
@@ -128,13 +488,30 @@ pub mod tests {
 
                     entries
                         .iter()
+                        // Only entries with an image embedding can be found by image
+                        .filter(|entry| {
+                            vector_name == VectorName::Description
+                                || entry.image_embedding.is_some()
+                        })
                         .map(|entry| {
-                            Ok(VectorOutput {
-                                id: entry.id,
+                            // Converted like the points found by Qdrant
+                            let point = ScoredPoint {
+                                id: Some(PointId::from(entry.id)),
+                                payload: qdrant_payload(&entry.payload),
+                                ..ScoredPoint::default()
+                            };
+                            VectorOutput {
                                 score: None,
-                                payload: entry.payload.clone(),
-                            })
+                                ..VectorOutput::from(&point)
+                            }
                         })
+                        // Like Qdrant, only the entries with all the required payload are found
+                        .filter(|output| {
+                            payload_required
+                                .iter()
+                                .all(|(key, value)| output.payload.get(key) == Some(value))
+                        })
+                        .map(Ok)
                         .collect()
                 }
                 None => Ok(Vec::new()),
@@ -142,23 +519,38 @@ pub mod tests {
         }
     }
 
+    /// Converts a stored payload into the payload of a Qdrant point.
+    fn qdrant_payload(payload: &HashMap<String, Value>) -> HashMap<String, qdrant::Value> {
+        Payload::try_from(json!(payload))
+            .expect("Payload must be a JSON object")
+            .into()
+    }
+
     #[tokio::test]
-    async fn test_chat_mock() {
-        let chat_mock = ChatMock;
+    async fn test_model_mock() {
+        let model_mock = ModelMock;
 
         // Test get_image_description
-        let description = chat_mock
-            .get_image_description("image_base64", &[], &None)
+        let image = EncodedImage {
+            base64: "image_base64".to_string(),
+            mime_type: "image/jpeg".to_string(),
+        };
+        let description = model_mock
+            .get_image_description(&[image], &DescriptionContext::default(), "mock")
             .await
             .unwrap();
         assert_eq!(description, "description");
 
         // Test get_embeddings
-        let embeddings = chat_mock
+        let embeddings = model_mock
             .get_embeddings(vec!["test".to_string()])
             .await
             .unwrap();
         assert_eq!(embeddings[0].len(), 1536);
+
+        // The dimension is probed with an embedding by default
+        let dimension = model_mock.embedding_dimension().await.unwrap();
+        assert_eq!(dimension, 1536);
     }
 
     #[tokio::test]
@@ -169,7 +561,10 @@ pub mod tests {
         let collection = "test";
 
         // Create collection
-        let created = vector_db_mock.create_collection(collection).await.unwrap();
+        let created = vector_db_mock
+            .create_collection(collection, 3)
+            .await
+            .unwrap();
         assert!(created);
 
         // Test upsert_points
@@ -182,14 +577,19 @@ pub mod tests {
 
         // Test search_points
         let outputs = vector_db_mock
-            .search_points("test", &[0.1, 0.2, 0.3], HashMap::new())
+            .search_points(
+                "test",
+                VectorName::Description,
+                &[0.1, 0.2, 0.3],
+                HashMap::new(),
+            )
             .await
             .unwrap();
         assert_eq!(outputs.len(), 1);
         assert_eq!(outputs[0].id, id);
 
         // Test upsert_points with existing ID
-        let payload = HashMap::from([("key".to_string(), "value".to_string())]);
+        let payload = HashMap::from([("key".to_string(), json!("value"))]);
         input.payload = payload;
 
         let upserted = vector_db_mock
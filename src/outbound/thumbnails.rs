@@ -0,0 +1,361 @@
+use anyhow::{Context, Result};
+use image::{codecs::webp::WebPEncoder, open, DynamicImage, ImageDecoder, ImageReader};
+use std::{
+    collections::HashSet,
+    env::var,
+    fs::{create_dir_all, read, read_dir, read_to_string, remove_file, rename, write},
+    path::{Path, PathBuf},
+};
+use tracing::{debug, warn};
+
+const CACHE_DIR: &str = ".photoscanner/thumbs";
+// Directory of the index from the path of a source to the hash of its thumbnails
+const INDEX_DIR: &str = "sources";
+// JPEG segments holding metadata - EXIF and XMP (APP1), IPTC (APP13) and comments
+const METADATA_MARKERS: [u8; 3] = [0xE1, 0xED, 0xFE];
+// Start of the compressed image data, which runs to the end of the file
+const START_OF_SCAN: u8 = 0xDA;
+// Preview for UIs, default model input and large model input
+pub const THUMBNAIL_SIZES: [u32; 3] = [256, 672, 1344];
+
+/// A content addressed on-disk cache of downsized, upright images.
+///
+/// Thumbnails are stored as lossless WebP in `<cache_dir>/<size>/<hash>.webp`, where the hash is
+/// computed from the image data of the source and its orientation, without the metadata. Writing
+/// descriptions or other XMP keeps the thumbnail, while edited pixels get a new hash and therefore
+/// a new thumbnail. The thumbnails of removed or edited photos are left behind until [`prune`].
+///
+/// [`prune`]: ThumbnailCache::prune
+#[derive(Debug, Clone)]
+pub struct ThumbnailCache {
+    cache_dir: PathBuf,
+    sizes: Vec<u32>,
+}
+
+impl Default for ThumbnailCache {
+    fn default() -> Self {
+        Self::new(CACHE_DIR)
+    }
+}
+
+impl ThumbnailCache {
+    pub fn new<P: AsRef<Path>>(cache_dir: P) -> Self {
+        Self {
+            cache_dir: cache_dir.as_ref().to_path_buf(),
+            sizes: THUMBNAIL_SIZES.to_vec(),
+        }
+    }
+
+    /// Creates the cache in the directory configured in `THUMBNAIL_CACHE_DIR`.
+    pub fn from_env() -> Self {
+        // load env from .env file
+        dotenv::dotenv().ok();
+        var("THUMBNAIL_CACHE_DIR")
+            .map(Self::new)
+            .unwrap_or_default()
+    }
+
+    /// Returns the thumbnail sizes (longest edge in pixels) kept in the cache.
+    pub fn sizes(&self) -> &[u32] {
+        &self.sizes
+    }
+
+    /// Returns the smallest cached size which is at least as large as the requested one.
+    pub fn size_for(&self, max_edge: u32) -> Option<u32> {
+        self.sizes.iter().copied().filter(|s| *s >= max_edge).min()
+    }
+
+    /// Returns the path of the thumbnail of a source image, creating all sizes if missing.
+    pub fn get_or_create(&self, source: &Path, size: u32) -> Result<PathBuf> {
+        let hash = content_hash(source)?;
+        let thumbnail_path = self.thumbnail_path(&hash, size);
+
+        if !thumbnail_path.exists() {
+            debug!("Creating thumbnails for {}", source.display());
+            self.create(source, &hash)?;
+            if let Err(e) = self.remember(source, &hash) {
+                warn!("Error indexing thumbnails of {}: {}", source.display(), e);
+            }
+        }
+
+        Ok(thumbnail_path)
+    }
+
+    /// Loads the thumbnail of a source image, creating all sizes if missing.
+    pub fn load(&self, source: &Path, size: u32) -> Result<DynamicImage> {
+        let thumbnail_path = self.get_or_create(source, size)?;
+        open(&thumbnail_path)
+            .with_context(|| format!("Failed to open thumbnail {}", thumbnail_path.display()))
+    }
+
+    /// Removes the thumbnails which belong to none of the source images, e.g. of edited or
+    /// deleted photos.
+    ///
+    /// Sources which cannot be read are skipped, and the thumbnails last created or kept for them
+    /// stay in the cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `sources` - All source images using the cache.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<usize>` - The number of removed thumbnails.
+    pub fn prune(&self, sources: &[PathBuf]) -> Result<usize> {
+        let mut hashes = HashSet::new();
+        let mut index_entries = HashSet::new();
+        for source in sources {
+            let index_path = self.index_path(source);
+            match content_hash(source) {
+                Ok(hash) => {
+                    if let Err(e) = self.remember(source, &hash) {
+                        warn!("Error indexing thumbnails of {}: {}", source.display(), e);
+                    }
+                    hashes.insert(hash);
+                }
+                Err(e) => match self.remembered(source) {
+                    Some(hash) => {
+                        warn!("Keeping thumbnails of {}: {}", source.display(), e);
+                        hashes.insert(hash);
+                    }
+                    None => warn!("Skipping {}: {}", source.display(), e),
+                },
+            }
+            index_entries.insert(index_path);
+        }
+
+        let mut removed = 0;
+        for size in &self.sizes {
+            let dir = self.cache_dir.join(size.to_string());
+            if !dir.is_dir() {
+                continue;
+            }
+            for entry in read_dir(dir)? {
+                let path = entry?.path();
+                let known = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .is_some_and(|hash| hashes.contains(hash));
+                if !known {
+                    debug!("Removing thumbnail {}", path.display());
+                    remove_file(&path)?;
+                    removed += 1;
+                }
+            }
+        }
+
+        // The index only keeps the sources still using the cache
+        let index_dir = self.cache_dir.join(INDEX_DIR);
+        if index_dir.is_dir() {
+            for entry in read_dir(index_dir)? {
+                let path = entry?.path();
+                if !index_entries.contains(&path) {
+                    remove_file(&path)?;
+                }
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Returns the path of the index entry holding the hash of the source's thumbnails.
+    fn index_path(&self, source: &Path) -> PathBuf {
+        let key = blake3::hash(source.to_string_lossy().as_bytes());
+        self.cache_dir.join(INDEX_DIR).join(key.to_hex().as_str())
+    }
+
+    /// Records the hash of the source's thumbnails, so that they can be told apart from stale
+    /// ones even while the source cannot be read.
+    fn remember(&self, source: &Path, hash: &str) -> Result<()> {
+        if self.remembered(source).as_deref() == Some(hash) {
+            return Ok(());
+        }
+        let index_path = self.index_path(source);
+        if let Some(parent) = index_path.parent() {
+            create_dir_all(parent)?;
+        }
+        write(index_path, hash)?;
+        Ok(())
+    }
+
+    /// Returns the hash of the source's thumbnails recorded in the index.
+    fn remembered(&self, source: &Path) -> Option<String> {
+        read_to_string(self.index_path(source)).ok()
+    }
+
+    fn thumbnail_path(&self, hash: &str, size: u32) -> PathBuf {
+        self.cache_dir
+            .join(size.to_string())
+            .join(format!("{}.webp", hash))
+    }
+
+    /// Decodes the source once and writes the thumbnails of all sizes.
+    fn create(&self, source: &Path, hash: &str) -> Result<()> {
+        let image = open_oriented(source)?;
+
+        for size in &self.sizes {
+            let thumbnail_path = self.thumbnail_path(hash, *size);
+            if let Some(parent) = thumbnail_path.parent() {
+                create_dir_all(parent)?;
+            }
+
+            let mut buffer = Vec::new();
+            image
+                .thumbnail(*size, *size)
+                .to_rgb8()
+                .write_with_encoder(WebPEncoder::new_lossless(&mut buffer))?;
+
+            // Write to a temporary file first, so that concurrent readers never see partial files
+            let temp_path = thumbnail_path.with_extension("webp.tmp");
+            write(&temp_path, buffer)?;
+            rename(&temp_path, &thumbnail_path)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Opens an image and applies its EXIF orientation, so that e.g. portrait phone photos are upright.
+pub fn open_oriented(file_path: &Path) -> Result<DynamicImage> {
+    let mut decoder = ImageReader::open(file_path)?
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder.orientation()?;
+
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    Ok(image)
+}
+
+/// Computes the hex encoded hash of the image data and the orientation of the file.
+///
+/// The metadata segments of JPEG files are left out, other files are hashed as a whole.
+fn content_hash(path: &Path) -> Result<String> {
+    let content = read(path)?;
+    let orientation = ImageReader::open(path)?
+        .with_guessed_format()?
+        .into_decoder()?
+        .orientation()?;
+
+    let mut hasher = blake3::Hasher::new();
+    for part in image_data(&content) {
+        hasher.update(part);
+    }
+    hasher.update(format!("{:?}", orientation).as_bytes());
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Splits a JPEG file into its segments without the metadata ones, or returns the whole content
+/// if it is no JPEG file or cannot be parsed.
+fn image_data(content: &[u8]) -> Vec<&[u8]> {
+    if !content.starts_with(&[0xFF, 0xD8]) {
+        return vec![content];
+    }
+
+    let mut parts = Vec::new();
+    let mut position = 2;
+    while position + 4 <= content.len() && content[position] == 0xFF {
+        let marker = content[position + 1];
+        if marker == START_OF_SCAN {
+            parts.push(&content[position..]);
+            return parts;
+        }
+        let length = u16::from_be_bytes([content[position + 2], content[position + 3]]) as usize;
+        let end = position + 2 + length;
+        if length < 2 || end > content.len() {
+            break;
+        }
+        if !METADATA_MARKERS.contains(&marker) {
+            parts.push(&content[position..end]);
+        }
+        position = end;
+    }
+    vec![content]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{domain::ports::XMPMetadata, outbound::xmp::XMPToolkitMetadata};
+    use image::GenericImageView;
+    use std::fs::copy;
+
+    #[test]
+    fn test_get_or_create() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let cache = ThumbnailCache::new(temp_dir.path().join("thumbs"));
+
+        let source = temp_dir.path().join("example-full.jpg");
+        copy("testdata/example-full.jpg", &source)?;
+
+        let thumbnail_path = cache.get_or_create(&source, 256)?;
+        assert!(thumbnail_path.starts_with(temp_dir.path().join("thumbs").join("256")));
+
+        // All sizes are created at once
+        for size in cache.sizes() {
+            let image = cache.load(&source, *size)?;
+            let (width, height) = image.dimensions();
+            assert_eq!(width.max(height), *size);
+        }
+
+        // Same content, same thumbnail
+        assert_eq!(cache.get_or_create(&source, 256)?, thumbnail_path);
+
+        // Changed metadata, same thumbnail
+        XMPToolkitMetadata::new().set_description(&source, "A beach in Sicily")?;
+        assert_eq!(cache.get_or_create(&source, 256)?, thumbnail_path);
+
+        // Changed content, new thumbnail
+        copy("testdata/example-persons.jpg", &source)?;
+        assert_ne!(cache.get_or_create(&source, 256)?, thumbnail_path);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let cache = ThumbnailCache::new(temp_dir.path().join("thumbs"));
+
+        let source = temp_dir.path().join("example-full.jpg");
+        copy("testdata/example-full.jpg", &source)?;
+        let old_thumbnail = cache.get_or_create(&source, 256)?;
+
+        // The edited photo leaves the old thumbnails of all sizes behind
+        copy("testdata/example-persons.jpg", &source)?;
+        let thumbnail = cache.get_or_create(&source, 256)?;
+
+        assert_eq!(
+            cache.prune(std::slice::from_ref(&source))?,
+            cache.sizes().len()
+        );
+        assert!(!old_thumbnail.exists());
+        assert!(thumbnail.exists());
+        assert_eq!(cache.prune(std::slice::from_ref(&source))?, 0);
+
+        // A source which cannot be read keeps its thumbnails, while the stale ones still go
+        let other = temp_dir.path().join("example-persons.jpg");
+        copy("testdata/example-full.jpg", &other)?;
+        let other_thumbnail = cache.get_or_create(&other, 256)?;
+        write(&source, b"not an image")?;
+        assert_eq!(
+            cache.prune(std::slice::from_ref(&source))?,
+            cache.sizes().len()
+        );
+        assert!(thumbnail.exists());
+        assert!(!other_thumbnail.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_size_for() {
+        let cache = ThumbnailCache::default();
+
+        assert_eq!(cache.size_for(100), Some(256));
+        assert_eq!(cache.size_for(672), Some(672));
+        assert_eq!(cache.size_for(1000), Some(1344));
+        assert_eq!(cache.size_for(2000), None);
+    }
+}
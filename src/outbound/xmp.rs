@@ -1,14 +1,35 @@
-use crate::domain::ports::XMPMetadata;
+use crate::domain::{
+    models::{flatten_hierarchical_subjects, CameraInfo, ImageAnalysis, ImageQuality, Setting},
+    ports::XMPMetadata,
+};
 use anyhow::{anyhow, Context, Result};
 use chrono::{DateTime, FixedOffset};
 use std::path::Path;
 use tracing::{debug, warn};
 use xmp_toolkit::{
     xmp_gps::{exif_latitude_to_decimal, exif_longitude_to_decimal},
-    xmp_ns::{DC, EXIF, PHOTOSHOP, XMP},
+    xmp_ns::{DC, EXIF, PHOTOSHOP, TIFF, XMP},
     IterOptions, OpenFileOptions, XmpDateTime, XmpFile, XmpMeta, XmpTime, XmpTimeZone, XmpValue,
 };
 
+// Lightroom namespace holding the hierarchical keywords
+const LIGHTROOM: &str = "http://ns.adobe.com/lightroom/1.0/";
+// EXIF 2.3 namespace holding the lens model and ISO
+const EXIF_EX: &str = "http://cipa.jp/exif/1.0/";
+// Adobe auxiliary EXIF namespace, used for the lens by older software
+const EXIF_AUX: &str = "http://ns.adobe.com/exif/1.0/aux/";
+// Our own namespace for data computed by the photo scanner
+const PHOTO_SCANNER: &str = "https://github.com/psytraxx/photo-scanner-rs/ns/1.0/";
+// The properties of a machine readable description besides the caption
+const ANALYSIS_PROPERTIES: [&str; 6] = [
+    "Title",
+    "Keywords",
+    "SceneType",
+    "Setting",
+    "PeopleCount",
+    "Confidence",
+];
+
 #[derive(Debug, Clone, Default)]
 pub struct XMPToolkitMetadata;
 
@@ -105,6 +126,369 @@ impl XMPMetadata for XMPToolkitMetadata {
         Ok(names)
     }
 
+    fn get_hierarchical_subjects(&self, path: &Path) -> Result<Vec<String>> {
+        XmpMeta::register_namespace(LIGHTROOM, "lr")?;
+
+        let mut xmp_file = open(path, false)?;
+        let xmp = xmp_file
+            .xmp()
+            .context("XMPMetadata not found get_hierarchical_subjects")?;
+
+        let subjects: Vec<String> = xmp
+            .property_array(LIGHTROOM, "hierarchicalSubject")
+            .map(|x| x.value)
+            .collect();
+        debug!("Hierarchical subjects in XMP data: {:?}", subjects);
+
+        Ok(subjects)
+    }
+
+    fn set_hierarchical_subjects(&self, path: &Path, subjects: &[String]) -> Result<()> {
+        XmpMeta::register_namespace(LIGHTROOM, "lr")?;
+
+        let mut xmp_file = open(path, true)?;
+        let mut xmp = xmp_file
+            .xmp()
+            .context("XMPMetadata not found set_hierarchical_subjects")
+            .or(XmpMeta::new())?;
+
+        // Drop the levels which only the previous hierarchy produced, so that removed or renamed
+        // branches do not linger. Levels the new hierarchy still has stay where they are, like
+        // the other flat keywords, and the missing levels of the new hierarchy are added.
+        let previous: Vec<String> = xmp
+            .property_array(LIGHTROOM, "hierarchicalSubject")
+            .map(|x| x.value)
+            .collect();
+        let levels = flatten_hierarchical_subjects(subjects);
+        let stale_levels: Vec<String> = flatten_hierarchical_subjects(&previous)
+            .into_iter()
+            .filter(|level| !levels.contains(level))
+            .collect();
+        let mut keywords: Vec<String> = xmp
+            .property_array(DC, "subject")
+            .map(|x| x.value)
+            .filter(|keyword| !stale_levels.contains(keyword))
+            .collect();
+        for keyword in levels {
+            if !keywords.contains(&keyword) {
+                keywords.push(keyword);
+            }
+        }
+
+        replace_bag(&mut xmp, LIGHTROOM, "hierarchicalSubject", subjects)?;
+        replace_bag(&mut xmp, DC, "subject", &keywords)?;
+
+        xmp_file.put_xmp(&xmp)?;
+
+        // this writes the XMP data to the file
+        xmp_file.close();
+
+        Ok(())
+    }
+
+    fn get_camera_info(&self, path: &Path) -> Result<CameraInfo> {
+        let mut xmp_file = open(path, false)?;
+        let xmp = xmp_file
+            .xmp()
+            .context("XMPMetadata not found get_camera_info")?;
+
+        let text = |namespace: &str, name: &str| {
+            xmp.property(namespace, name)
+                .map(|val| val.value.trim().to_string())
+                .filter(|val| !val.is_empty())
+        };
+        let rational = |namespace: &str, name: &str| {
+            xmp.property(namespace, name)
+                .and_then(|val| parse_rational(&val.value))
+        };
+
+        let iso = xmp
+            .property_array(EXIF, "ISOSpeedRatings")
+            .next()
+            .map(|val| val.value)
+            .or_else(|| text(EXIF_EX, "PhotographicSensitivity"))
+            .and_then(|val| val.parse().ok());
+
+        let camera_info = CameraInfo {
+            make: text(TIFF, "Make"),
+            model: text(TIFF, "Model"),
+            lens_model: text(EXIF_EX, "LensModel").or_else(|| text(EXIF_AUX, "Lens")),
+            focal_length: rational(EXIF, "FocalLength"),
+            focal_length_35mm: rational(EXIF, "FocalLengthIn35mmFilm"),
+            aperture: rational(EXIF, "FNumber"),
+            iso,
+            exposure_time: rational(EXIF, "ExposureTime"),
+        };
+        debug!("Camera info in XMP data: {:?}", camera_info);
+
+        Ok(camera_info)
+    }
+
+    fn get_image_quality(&self, path: &Path) -> Result<Option<ImageQuality>> {
+        XmpMeta::register_namespace(PHOTO_SCANNER, "photoscanner")?;
+
+        let mut xmp_file = open(path, false)?;
+        let xmp = xmp_file
+            .xmp()
+            .context("XMPMetadata not found get_image_quality")?;
+
+        let score = |name: &str| {
+            xmp.property_f64(PHOTO_SCANNER, name)
+                .map(|v| v.value as f32)
+        };
+
+        let quality = score("QualitySharpness").map(|sharpness| ImageQuality {
+            sharpness,
+            highlights_clipped: score("QualityHighlightsClipped").unwrap_or_default(),
+            shadows_clipped: score("QualityShadowsClipped").unwrap_or_default(),
+            noise: score("QualityNoise").unwrap_or_default(),
+        });
+        debug!("Image quality in XMP data: {:?}", quality);
+
+        Ok(quality)
+    }
+
+    fn set_image_quality(&self, path: &Path, quality: &ImageQuality) -> Result<()> {
+        XmpMeta::register_namespace(PHOTO_SCANNER, "photoscanner")?;
+
+        let mut xmp_file = open(path, true)?;
+        let mut xmp = xmp_file
+            .xmp()
+            .context("XMPMetadata not found set_image_quality")
+            .or(XmpMeta::new())?;
+
+        let scores = [
+            ("QualitySharpness", quality.sharpness),
+            ("QualityHighlightsClipped", quality.highlights_clipped),
+            ("QualityShadowsClipped", quality.shadows_clipped),
+            ("QualityNoise", quality.noise),
+        ];
+        for (name, score) in scores {
+            xmp.set_property_f64(PHOTO_SCANNER, name, &XmpValue::new(score as f64))?;
+        }
+
+        xmp_file.put_xmp(&xmp)?;
+
+        // this writes the XMP data to the file
+        xmp_file.close();
+
+        Ok(())
+    }
+
+    fn get_recognized_text(&self, path: &Path) -> Result<Option<String>> {
+        XmpMeta::register_namespace(PHOTO_SCANNER, "photoscanner")?;
+
+        let mut xmp_file = open(path, false)?;
+        let xmp = xmp_file
+            .xmp()
+            .context("XMPMetadata not found get_recognized_text")?;
+
+        // Photos without text only carry the flag, as empty values do not survive serialization
+        let text = xmp
+            .property_bool(PHOTO_SCANNER, "TextRecognized")
+            .filter(|recognized| recognized.value)
+            .map(|_| {
+                xmp.property(PHOTO_SCANNER, "RecognizedText")
+                    .map(|v| v.value)
+                    .unwrap_or_default()
+            });
+        debug!("Recognized text in XMP data: {:?}", text);
+
+        Ok(text)
+    }
+
+    fn set_recognized_text(&self, path: &Path, text: &str) -> Result<()> {
+        XmpMeta::register_namespace(PHOTO_SCANNER, "photoscanner")?;
+
+        let mut xmp_file = open(path, true)?;
+        let mut xmp = xmp_file
+            .xmp()
+            .context("XMPMetadata not found set_recognized_text")
+            .or(XmpMeta::new())?;
+
+        xmp.delete_property(PHOTO_SCANNER, "RecognizedText")?;
+        if !text.is_empty() {
+            xmp.set_property(
+                PHOTO_SCANNER,
+                "RecognizedText",
+                &XmpValue::new(text.to_string()),
+            )?;
+        }
+        xmp.set_property_bool(PHOTO_SCANNER, "TextRecognized", &XmpValue::new(true))?;
+
+        xmp_file.put_xmp(&xmp)?;
+
+        // this writes the XMP data to the file
+        xmp_file.close();
+
+        Ok(())
+    }
+
+    fn get_image_analysis(&self, path: &Path) -> Result<Option<ImageAnalysis>> {
+        XmpMeta::register_namespace(PHOTO_SCANNER, "photoscanner")?;
+
+        let Some(caption) = self.get_description(path)? else {
+            return Ok(None);
+        };
+
+        let mut xmp_file = open(path, false)?;
+        let xmp = xmp_file
+            .xmp()
+            .context("XMPMetadata not found get_image_analysis")?;
+
+        let text = |name: &str| xmp.property(PHOTO_SCANNER, name).map(|v| v.value);
+
+        // The title is only present for descriptions generated as JSON
+        let analysis = text("Title").map(|title| ImageAnalysis {
+            caption,
+            title,
+            keywords: xmp
+                .property_array(PHOTO_SCANNER, "Keywords")
+                .map(|x| x.value)
+                .collect(),
+            scene_type: text("SceneType").unwrap_or_default(),
+            people_count: xmp
+                .property_i32(PHOTO_SCANNER, "PeopleCount")
+                .map(|v| v.value.max(0) as u32)
+                .unwrap_or_default(),
+            setting: text("Setting")
+                .and_then(|setting| Setting::try_from(setting.as_str()).ok())
+                .unwrap_or(Setting::Unknown),
+            confidence: xmp
+                .property_f64(PHOTO_SCANNER, "Confidence")
+                .map(|v| v.value as f32)
+                .unwrap_or_default(),
+        });
+        debug!("Image analysis in XMP data: {:?}", analysis);
+
+        Ok(analysis)
+    }
+
+    fn set_image_analysis(&self, path: &Path, analysis: Option<&ImageAnalysis>) -> Result<()> {
+        XmpMeta::register_namespace(PHOTO_SCANNER, "photoscanner")?;
+
+        let mut xmp_file = open(path, true)?;
+        let mut xmp = xmp_file
+            .xmp()
+            .context("XMPMetadata not found set_image_analysis")
+            .or(XmpMeta::new())?;
+
+        // A plain text description must not be paired with the fields of an earlier analysis
+        let Some(analysis) = analysis else {
+            for name in ANALYSIS_PROPERTIES {
+                xmp.delete_property(PHOTO_SCANNER, name)?;
+            }
+            xmp_file.put_xmp(&xmp)?;
+            xmp_file.close();
+            return Ok(());
+        };
+
+        let texts = [
+            ("Title", analysis.title.as_str()),
+            ("SceneType", analysis.scene_type.as_str()),
+            ("Setting", analysis.setting.as_str()),
+        ];
+        for (name, text) in texts {
+            xmp.set_property(PHOTO_SCANNER, name, &XmpValue::new(text.to_string()))?;
+        }
+        replace_bag(&mut xmp, PHOTO_SCANNER, "Keywords", &analysis.keywords)?;
+        xmp.set_property_i32(
+            PHOTO_SCANNER,
+            "PeopleCount",
+            &XmpValue::new(analysis.people_count as i32),
+        )?;
+        xmp.set_property_f64(
+            PHOTO_SCANNER,
+            "Confidence",
+            &XmpValue::new(analysis.confidence as f64),
+        )?;
+
+        xmp_file.put_xmp(&xmp)?;
+
+        // this writes the XMP data to the file
+        xmp_file.close();
+
+        Ok(())
+    }
+
+    fn get_description_prompt(&self, path: &Path) -> Result<Option<String>> {
+        XmpMeta::register_namespace(PHOTO_SCANNER, "photoscanner")?;
+
+        let mut xmp_file = open(path, false)?;
+        let xmp = xmp_file
+            .xmp()
+            .context("XMPMetadata not found get_description_prompt")?;
+
+        let prompt = xmp
+            .property(PHOTO_SCANNER, "DescriptionPrompt")
+            .map(|v| v.value);
+        debug!("Description prompt in XMP data: {:?}", prompt);
+
+        Ok(prompt)
+    }
+
+    fn set_description_prompt(&self, path: &Path, prompt: &str) -> Result<()> {
+        XmpMeta::register_namespace(PHOTO_SCANNER, "photoscanner")?;
+
+        let mut xmp_file = open(path, true)?;
+        let mut xmp = xmp_file
+            .xmp()
+            .context("XMPMetadata not found set_description_prompt")
+            .or(XmpMeta::new())?;
+
+        xmp.set_property(
+            PHOTO_SCANNER,
+            "DescriptionPrompt",
+            &XmpValue::new(prompt.to_string()),
+        )?;
+
+        xmp_file.put_xmp(&xmp)?;
+
+        // this writes the XMP data to the file
+        xmp_file.close();
+
+        Ok(())
+    }
+
+    fn get_description_model(&self, path: &Path) -> Result<Option<String>> {
+        XmpMeta::register_namespace(PHOTO_SCANNER, "photoscanner")?;
+
+        let mut xmp_file = open(path, false)?;
+        let xmp = xmp_file
+            .xmp()
+            .context("XMPMetadata not found get_description_model")?;
+
+        let model = xmp
+            .property(PHOTO_SCANNER, "DescriptionModel")
+            .map(|v| v.value);
+        debug!("Description model in XMP data: {:?}", model);
+
+        Ok(model)
+    }
+
+    fn set_description_model(&self, path: &Path, model: &str) -> Result<()> {
+        XmpMeta::register_namespace(PHOTO_SCANNER, "photoscanner")?;
+
+        let mut xmp_file = open(path, true)?;
+        let mut xmp = xmp_file
+            .xmp()
+            .context("XMPMetadata not found set_description_model")
+            .or(XmpMeta::new())?;
+
+        xmp.set_property(
+            PHOTO_SCANNER,
+            "DescriptionModel",
+            &XmpValue::new(model.to_string()),
+        )?;
+
+        xmp_file.put_xmp(&xmp)?;
+
+        // this writes the XMP data to the file
+        xmp_file.close();
+
+        Ok(())
+    }
+
     fn get_created(&self, path: &Path) -> Result<DateTime<FixedOffset>> {
         let mut xmp_file = open(path, false)?;
         let xmp = xmp_file
@@ -164,6 +548,34 @@ impl XMPMetadata for XMPToolkitMetadata {
     }
 }
 
+/// Parses an EXIF rational like `71/10` or a plain number like `27` into a float.
+fn parse_rational(value: &str) -> Option<f32> {
+    match value.trim().split_once('/') {
+        Some((numerator, denominator)) => {
+            let numerator: f32 = numerator.trim().parse().ok()?;
+            let denominator: f32 = denominator.trim().parse().ok()?;
+            if denominator == 0.0 {
+                None
+            } else {
+                Some(numerator / denominator)
+            }
+        }
+        None => value.trim().parse().ok(),
+    }
+}
+
+/// Replaces the content of an unordered array (rdf:Bag) property with the given items.
+fn replace_bag(xmp: &mut XmpMeta, namespace: &str, name: &str, items: &[String]) -> Result<()> {
+    xmp.delete_property(namespace, name)?;
+
+    let array_name = XmpValue::from(name).set_is_array(true);
+    for item in items {
+        xmp.append_array_item(namespace, &array_name, &XmpValue::from(item.as_str()))?;
+    }
+
+    Ok(())
+}
+
 fn open(path: &Path, allow_update: bool) -> Result<XmpFile> {
     let mut xmp_file = XmpFile::new()?;
 
@@ -362,6 +774,280 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_set_and_get_hierarchical_subjects() -> Result<()> {
+        initialize();
+        let temp_dir = tempfile::tempdir()?;
+        let destination_file_path = temp_dir.path().join("example-no-xmp.jpg");
+
+        // Copy an existing JPEG file to the temporary directory
+        let source_file = PathBuf::from("testdata/example-no-xmp.jpg");
+        copy(&source_file, &destination_file_path)?;
+
+        let tool = XMPToolkitMetadata::new();
+
+        let subjects = vec![
+            "Places|Italy|Sicily".to_string(),
+            "People|Family|Anna".to_string(),
+        ];
+        tool.set_hierarchical_subjects(&destination_file_path, &subjects)?;
+
+        // Check that the hierarchical subjects have been written correctly
+        let subjects_out = tool.get_hierarchical_subjects(&destination_file_path)?;
+        assert_eq!(subjects, subjects_out);
+
+        // Check that every level ended up in the flat dc:subject list
+        let mut xmp_file = open(&destination_file_path, false)?;
+        let xmp = xmp_file.xmp().unwrap();
+        let keywords: Vec<String> = xmp.property_array(DC, "subject").map(|x| x.value).collect();
+        assert_eq!(
+            keywords,
+            vec!["Places", "Italy", "Sicily", "People", "Family", "Anna"]
+        );
+
+        xmp_file.close();
+
+        // Add a flat keyword that is not part of any hierarchy
+        let mut xmp_file = open(&destination_file_path, true)?;
+        let mut xmp = xmp_file.xmp().unwrap();
+        let mut flat = keywords;
+        flat.push("Sunset".to_string());
+        replace_bag(&mut xmp, DC, "subject", &flat)?;
+        xmp_file.put_xmp(&xmp)?;
+        xmp_file.close();
+
+        // Writing again must not duplicate the flat keywords and must drop the
+        // levels of the removed branch while keeping the unrelated keyword
+        let subjects = vec![
+            "Places|Italy|Sicily".to_string(),
+            "People|Friends".to_string(),
+        ];
+        tool.set_hierarchical_subjects(&destination_file_path, &subjects)?;
+        let subjects_out = tool.get_hierarchical_subjects(&destination_file_path)?;
+        assert_eq!(subjects_out, subjects);
+
+        let mut xmp_file = open(&destination_file_path, false)?;
+        let xmp = xmp_file.xmp().unwrap();
+        let keywords: Vec<String> = xmp.property_array(DC, "subject").map(|x| x.value).collect();
+        assert_eq!(
+            keywords,
+            vec!["Places", "Italy", "Sicily", "People", "Sunset", "Friends"]
+        );
+        xmp_file.close();
+
+        // A flat keyword equal to a level is kept as long as the new hierarchy still has it
+        let subjects = vec!["Trips|Italy".to_string()];
+        tool.set_hierarchical_subjects(&destination_file_path, &subjects)?;
+
+        let mut xmp_file = open(&destination_file_path, false)?;
+        let xmp = xmp_file.xmp().unwrap();
+        let keywords: Vec<String> = xmp.property_array(DC, "subject").map(|x| x.value).collect();
+        assert_eq!(keywords, vec!["Italy", "Sunset", "Trips"]);
+
+        // Clean up by deleting the temporary file
+        remove_file(&destination_file_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_camera_info() -> Result<()> {
+        initialize();
+        let path = Path::new("testdata/example-full.jpg");
+        let tool = XMPToolkitMetadata::new();
+
+        let camera_info = tool.get_camera_info(path)?;
+        assert_eq!(
+            camera_info,
+            CameraInfo {
+                make: Some("Canon".to_string()),
+                model: Some("Canon EOS 5D Mark III".to_string()),
+                lens_model: Some("24-70mm".to_string()),
+                focal_length: Some(54.0),
+                focal_length_35mm: None,
+                aperture: Some(7.1),
+                iso: Some(100),
+                exposure_time: Some(1.0 / 320.0),
+            }
+        );
+
+        // Phone photo with a 35mm equivalent focal length
+        let path = Path::new("testdata/example-persons.jpg");
+        let camera_info = tool.get_camera_info(path)?;
+        assert_eq!(camera_info.model, Some("Pixel 6a".to_string()));
+        assert_eq!(camera_info.focal_length_35mm, Some(27.0));
+        assert_eq!(camera_info.iso, Some(523));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_and_get_image_quality() -> Result<()> {
+        initialize();
+        let temp_dir = tempfile::tempdir()?;
+        let destination_file_path = temp_dir.path().join("example-full.jpg");
+
+        // Copy an existing JPEG file to the temporary directory
+        let source_file = PathBuf::from("testdata/example-full.jpg");
+        copy(&source_file, &destination_file_path)?;
+
+        let tool = XMPToolkitMetadata::new();
+        assert_eq!(tool.get_image_quality(&destination_file_path)?, None);
+
+        let quality = ImageQuality {
+            sharpness: 123.5,
+            highlights_clipped: 0.25,
+            shadows_clipped: 0.125,
+            noise: 2.5,
+        };
+        tool.set_image_quality(&destination_file_path, &quality)?;
+
+        let quality_out = tool.get_image_quality(&destination_file_path)?;
+        assert_eq!(quality_out, Some(quality));
+
+        // Clean up by deleting the temporary file
+        remove_file(&destination_file_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_and_get_recognized_text() -> Result<()> {
+        initialize();
+        let temp_dir = tempfile::tempdir()?;
+        let destination_file_path = temp_dir.path().join("example-full.jpg");
+
+        // Copy an existing JPEG file to the temporary directory
+        let source_file = PathBuf::from("testdata/example-full.jpg");
+        copy(&source_file, &destination_file_path)?;
+
+        let tool = XMPToolkitMetadata::new();
+        assert_eq!(tool.get_recognized_text(&destination_file_path)?, None);
+
+        let text = "TRATTORIA DA NINO\nConto 42,50 EUR";
+        tool.set_recognized_text(&destination_file_path, text)?;
+        assert_eq!(
+            tool.get_recognized_text(&destination_file_path)?,
+            Some(text.to_string())
+        );
+
+        // Photos without text are remembered as analyzed
+        tool.set_recognized_text(&destination_file_path, "")?;
+        assert_eq!(
+            tool.get_recognized_text(&destination_file_path)?,
+            Some(String::new())
+        );
+
+        // Clean up by deleting the temporary file
+        remove_file(&destination_file_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_and_get_image_analysis() -> Result<()> {
+        initialize();
+        let temp_dir = tempfile::tempdir()?;
+        let destination_file_path = temp_dir.path().join("example-full.jpg");
+
+        // Copy an existing JPEG file to the temporary directory
+        let source_file = PathBuf::from("testdata/example-full.jpg");
+        copy(&source_file, &destination_file_path)?;
+
+        let tool = XMPToolkitMetadata::new();
+        let analysis = ImageAnalysis {
+            caption: "A sunny beach.".to_string(),
+            title: "Beach".to_string(),
+            keywords: vec!["beach".to_string(), "sea".to_string()],
+            scene_type: "landscape".to_string(),
+            people_count: 2,
+            setting: Setting::Outdoor,
+            confidence: 0.75,
+        };
+
+        // Plain text descriptions carry no analysis
+        tool.set_description(&destination_file_path, &analysis.caption)?;
+        assert_eq!(tool.get_image_analysis(&destination_file_path)?, None);
+
+        tool.set_image_analysis(&destination_file_path, Some(&analysis))?;
+        assert_eq!(
+            tool.get_image_analysis(&destination_file_path)?,
+            Some(analysis)
+        );
+
+        // A plain text description removes the fields of the earlier analysis
+        tool.set_image_analysis(&destination_file_path, None)?;
+        assert_eq!(tool.get_image_analysis(&destination_file_path)?, None);
+        let mut xmp_file = open(&destination_file_path, false)?;
+        let xmp = xmp_file.xmp().context("XMPMetadata not found")?;
+        for name in ANALYSIS_PROPERTIES {
+            assert!(!xmp.contains_property(PHOTO_SCANNER, name), "{}", name);
+        }
+
+        // Clean up by deleting the temporary file
+        remove_file(&destination_file_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_and_get_description_prompt() -> Result<()> {
+        initialize();
+        let temp_dir = tempfile::tempdir()?;
+        let destination_file_path = temp_dir.path().join("example-full.jpg");
+
+        // Copy an existing JPEG file to the temporary directory
+        let source_file = PathBuf::from("testdata/example-full.jpg");
+        copy(&source_file, &destination_file_path)?;
+
+        let tool = XMPToolkitMetadata::new();
+        assert_eq!(tool.get_description_prompt(&destination_file_path)?, None);
+
+        tool.set_description_prompt(&destination_file_path, "traveler@1")?;
+        assert_eq!(
+            tool.get_description_prompt(&destination_file_path)?,
+            Some("traveler@1".to_string())
+        );
+
+        // Clean up by deleting the temporary file
+        remove_file(&destination_file_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_and_get_description_model() -> Result<()> {
+        initialize();
+        let temp_dir = tempfile::tempdir()?;
+        let destination_file_path = temp_dir.path().join("example-full.jpg");
+
+        // Copy an existing JPEG file to the temporary directory
+        let source_file = PathBuf::from("testdata/example-full.jpg");
+        copy(&source_file, &destination_file_path)?;
+
+        let tool = XMPToolkitMetadata::new();
+        assert_eq!(tool.get_description_model(&destination_file_path)?, None);
+
+        tool.set_description_model(&destination_file_path, "llava:13b")?;
+        assert_eq!(
+            tool.get_description_model(&destination_file_path)?,
+            Some("llava:13b".to_string())
+        );
+
+        // Clean up by deleting the temporary file
+        remove_file(&destination_file_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_rational() {
+        assert_eq!(parse_rational("71/10"), Some(7.1));
+        assert_eq!(parse_rational("27"), Some(27.0));
+        assert_eq!(parse_rational("0/0"), None);
+        assert_eq!(parse_rational("abc"), None);
+    }
+
     #[test]
     fn test_get_geolocation() -> Result<()> {
         initialize();